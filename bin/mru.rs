@@ -35,10 +35,14 @@ enum Mru {
     MerDate(mer::merdate::MerDate),
 
     Calibrate(calibrate::Calibrate),
+    #[clap(name = "caldata")]
+    CalData(caldata::CalData),
     Anaglyph(anaglyph::Anaglyph),
+    BundleAdjust(bundleadjust::BundleAdjust),
     Composite(composite::Composite),
     Crop(crop::Crop),
     Debayer(debayer::Debayer),
+    Deblock(deblock::Deblock),
 
     #[clap(name = "diffgif")]
     DiffGif(diffgif::DiffGif),
@@ -46,8 +50,14 @@ enum Mru {
     MeanStack(meanstack::MeanStack),
     HpcFilter(hpcfilter::HpcFilter),
     Inpaint(inpaint::Inpaint),
+    Iof(iof::Iof),
     Levels(levels::Levels),
     Info(info::Info),
+    Spectra(spectra::Spectra),
+    Stereo(stereo::Stereo),
+    StereoMatch(stereomatch::StereoMatch),
+    StereoRender(stereorender::StereoRender),
+    WhiteBalance(whitebalance::WhiteBalance),
     Xeye(xeye::CrossEye),
 }
 
@@ -73,6 +83,9 @@ async fn main() {
         Mru::Calibrate(args) => {
             args.run().await;
         }
+        Mru::CalData(args) => {
+            args.run().await;
+        }
         Mru::MslDate(args) => {
             args.run().await;
         }
@@ -100,6 +113,9 @@ async fn main() {
         Mru::Anaglyph(args) => {
             args.run().await;
         }
+        Mru::BundleAdjust(args) => {
+            args.run().await;
+        }
         Mru::Composite(args) => {
             args.run().await;
         }
@@ -109,6 +125,9 @@ async fn main() {
         Mru::Debayer(args) => {
             args.run().await;
         }
+        Mru::Deblock(args) => {
+            args.run().await;
+        }
         Mru::DiffGif(args) => {
             args.run().await;
         }
@@ -124,12 +143,30 @@ async fn main() {
         Mru::Inpaint(args) => {
             args.run().await;
         }
+        Mru::Iof(args) => {
+            args.run().await;
+        }
         Mru::Levels(args) => {
             args.run().await;
         }
+        Mru::WhiteBalance(args) => {
+            args.run().await;
+        }
+        Mru::Spectra(args) => {
+            args.run().await;
+        }
         Mru::Info(args) => {
             args.run().await;
         }
+        Mru::Stereo(args) => {
+            args.run().await;
+        }
+        Mru::StereoMatch(args) => {
+            args.run().await;
+        }
+        Mru::StereoRender(args) => {
+            args.run().await;
+        }
         Mru::Xeye(args) => {
             args.run().await;
         }
@@ -1,10 +1,14 @@
 use async_trait::async_trait;
-use mars_raw_utils::prelude::*;
+use mars_raw_utils::{
+    anaglyph::{AnaglyphMode, AnaglyphOptions},
+    prelude::*,
+};
 use sciimg::{prelude::*, vector::Vector};
 
 use crate::subs::runnable::RunnableSubcommand;
 
 use std::process;
+use std::str::FromStr;
 
 #[derive(clap::Args)]
 #[clap(author, version, about = "Generate anaglyph from stereo pair", long_about = None)]
@@ -20,7 +24,129 @@ pub struct Anaglyph {
 
     #[clap(long, short, help = "Monochrome color (before converting to red/blue)")]
     mono: bool,
+
+    #[clap(
+        long,
+        short = 'M',
+        help = "Color mode: color, half-color, optimized, dubois-red-cyan, dubois-green-magenta or dubois-amber-blue (default color)"
+    )]
+    mode: Option<String>,
+
+    #[clap(
+        long,
+        help = "Custom mode of 18 comma separated weights of the left then right red, green and blue for each output band"
+    )]
+    matrix: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        allow_hyphen_values(true),
+        help = "Pixels to move the right eye to the right"
+    )]
+    parallax: Option<i32>,
+
+    #[clap(long, short, help = "Converge on the image center")]
+    converge: bool,
+}
+
+/// Anaglyph options from the mode, matrix, parallax and convergence arguments, exiting if any
+/// are invalid
+pub fn anaglyph_options(
+    mode: &Option<String>,
+    matrix: &Option<String>,
+    parallax: Option<i32>,
+    converge: bool,
+) -> AnaglyphOptions {
+    let mode = match (mode, matrix) {
+        (Some(_), Some(_)) => {
+            eprintln!("Error: A custom matrix can't be used with a color mode");
+            process::exit(1);
+        }
+        (_, Some(m)) => match anaglyph::parse_matrix(m) {
+            Ok(m) => AnaglyphMode::Custom(m),
+            Err(why) => {
+                eprintln!("Error: Invalid anaglyph matrix: {}", why);
+                process::exit(1);
+            }
+        },
+        (Some(m), None) => match AnaglyphMode::from_str(m) {
+            Ok(mode) => mode,
+            Err(_) => {
+                eprintln!("Error: Invalid anaglyph mode: {}", m);
+                process::exit(1);
+            }
+        },
+        (None, None) => AnaglyphMode::Color,
+    };
+    if converge && parallax.is_some() {
+        eprintln!("Error: Parallax and convergence can't both be set");
+        process::exit(1);
+    }
+    AnaglyphOptions {
+        mode,
+        parallax: parallax.unwrap_or(0),
+        auto_converge: converge,
+    }
+}
+
+/// Renders a red/cyan anaglyph of a stereo pair, reprojecting both eyes into the linearized
+/// left camera model
+pub fn create_anaglyph(
+    left_image_path: &str,
+    right_image_path: &str,
+    out_file_path: &str,
+    mono: bool,
+    options: &AnaglyphOptions,
+) -> error::Result<()> {
+    let mut left_img = MarsImage::open(String::from(left_image_path), Instrument::M20MastcamZLeft);
+    let mut right_img =
+        MarsImage::open(String::from(right_image_path), Instrument::M20MastcamZRight);
+
+    if mono {
+        vprintln!("Converting input images to monochrome...");
+        left_img.to_mono();
+        right_img.to_mono();
+    }
+
+    let left_cahv = left_img.camera_model()?;
+    let right_cahv = right_img.camera_model()?;
+
+    let ground = Vector::new(0.0, 0.0, 1.84566);
+
+    let mut left_map = RgbImage::create_masked(left_img.image.width, left_img.image.height, false);
+    let mut right_map = left_map.clone();
+    let output_model = left_cahv.linearize(
+        left_img.image.width,
+        left_img.image.height,
+        left_img.image.width,
+        left_img.image.height,
+    )?;
+
+    anaglyph::process_image(
+        &right_img,
+        &mut right_map,
+        &right_cahv,
+        &output_model,
+        &ground,
+        Eye::DontCare,
+    );
+    anaglyph::process_image(
+        &left_img,
+        &mut left_map,
+        &left_cahv,
+        &output_model,
+        &ground,
+        Eye::DontCare,
+    );
+
+    let mut map = anaglyph::flatten(&anaglyph::combine(&left_map, &right_map, options, 255.0));
+
+    map.normalize_to_16bit_with_max(255.0);
+    map.save(out_file_path);
+    Ok(())
 }
+
 #[async_trait]
 impl RunnableSubcommand for Anaglyph {
     async fn run(&self) {
@@ -48,65 +174,15 @@ impl RunnableSubcommand for Anaglyph {
             process::exit(1);
         }
 
-        let mut left_img = MarsImage::open(left_image_path, Instrument::M20MastcamZLeft);
-        let mut right_img = MarsImage::open(right_image_path, Instrument::M20MastcamZRight);
-
-        if self.mono {
-            vprintln!("Converting input images to monochrome...");
-            left_img.to_mono();
-            right_img.to_mono();
+        if let Err(why) = create_anaglyph(
+            &left_image_path,
+            &right_image_path,
+            out_file_path,
+            self.mono,
+            &anaglyph_options(&self.mode, &self.matrix, self.parallax, self.converge),
+        ) {
+            eprintln!("Error: {}", why);
+            process::exit(2);
         }
-
-        let left_cahv = if let Some(left_md) = &left_img.metadata {
-            if left_md.camera_model_component_list.is_valid() {
-                left_md.camera_model_component_list.clone()
-            } else {
-                process::exit(2);
-            }
-        } else {
-            process::exit(1);
-        };
-
-        let right_cahv = if let Some(right_md) = &right_img.metadata {
-            if right_md.camera_model_component_list.is_valid() {
-                right_md.camera_model_component_list.clone()
-            } else {
-                process::exit(2);
-            }
-        } else {
-            process::exit(1);
-        };
-
-        let ground = Vector::new(0.0, 0.0, 1.84566);
-
-        let mut map = RgbImage::create(left_img.image.width, left_img.image.height);
-        let output_model = left_cahv
-            .linearize(
-                left_img.image.width,
-                left_img.image.height,
-                left_img.image.width,
-                left_img.image.height,
-            )
-            .unwrap();
-
-        anaglyph::process_image(
-            &right_img,
-            &mut map,
-            &right_cahv,
-            &output_model,
-            &ground,
-            Eye::Right,
-        );
-        anaglyph::process_image(
-            &left_img,
-            &mut map,
-            &left_cahv,
-            &output_model,
-            &ground,
-            Eye::Left,
-        );
-
-        map.normalize_to_16bit_with_max(255.0);
-        map.save(out_file_path);
     }
 }
@@ -0,0 +1,90 @@
+use mars_raw_utils::{bundleadjust, prelude::*};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Correct camera pointing from features matched between overlapping images", long_about = None)]
+pub struct BundleAdjust {
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Input images",
+        multiple_values(true)
+    )]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short,
+        help = "Index of the input whose pointing is held fixed (default 0)"
+    )]
+    fixed: Option<usize>,
+
+    #[clap(
+        long,
+        short = 'n',
+        help = "Most features detected per image (default 1000)"
+    )]
+    max_features: Option<usize>,
+
+    #[clap(
+        long,
+        short = 'e',
+        help = "Largest pointing error searched for, in pixels (default 40)"
+    )]
+    max_error: Option<f64>,
+}
+
+/// Runs bundle adjustment on the given images, exiting on failure
+pub fn adjust_files(in_files: &[String], options: &bundleadjust::AdjustOptions) {
+    match bundleadjust::adjust_files(in_files, options) {
+        Ok(changes) => {
+            for (f, change) in in_files.iter().zip(changes.iter()) {
+                println!("{}: pointing corrected by {:.4} degrees", f, change);
+            }
+        }
+        Err(why) => {
+            eprintln!("Error adjusting camera pointing: {}", why);
+            process::exit(2);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for BundleAdjust {
+    async fn run(&self) {
+        print::print_experimental();
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+
+        if let Some(in_file) = in_files.iter().find(|f| !path::file_exists(f)) {
+            eprintln!("File not found: {}", in_file);
+            process::exit(1);
+        }
+
+        if in_files.len() < 2 {
+            eprintln!("At least two overlapping images are needed");
+            process::exit(1);
+        }
+
+        let mut options = bundleadjust::AdjustOptions::default();
+        if let Some(fixed) = self.fixed {
+            options.fixed_image = fixed;
+        }
+        if let Some(max_features) = self.max_features {
+            options.detector.max_features = max_features;
+        }
+        if let Some(max_error) = self.max_error {
+            options.max_error = max_error;
+        }
+
+        adjust_files(&in_files, &options);
+    }
+}
@@ -0,0 +1,111 @@
+use mars_raw_utils::{calibfile, decompanding};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Print the effective calibration data configuration", long_about = None)]
+pub struct CalData {
+    #[clap(long, short, help = "Only show entries whose key contains this text")]
+    filter: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        help = "Check that the configured lookup tables can be loaded and inverted"
+    )]
+    validate_ilt: bool,
+}
+
+/// Lookup table files named by `ilt` entries, including those of per-product rules
+fn ilt_files(key: &str, value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(s) if key.ends_with(".ilt") => vec![s.clone()],
+        toml::Value::Array(a) if key.ends_with(".ilt_products") => a
+            .iter()
+            .filter_map(|p| p.get("ilt").and_then(|v| v.as_str()).map(String::from))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+fn validate_ilt_file(key: &str, file: &str) {
+    match decompanding::load_ilt_file(file) {
+        Ok(ilt) => {
+            let v = decompanding::validate_ilt(&ilt);
+            if v.is_invertible() {
+                println!("{}: {} ({} bit, invertible)", key, file, v.bit_depth);
+            } else {
+                println!(
+                    "{}: {} ({} bit, codes not invertible: {:?})",
+                    key, file, v.bit_depth, v.roundtrip_errors
+                );
+            }
+        }
+        Err(why) => println!("{}: {} (error: {})", key, file, why),
+    }
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for CalData {
+    async fn run(&self) {
+        let layer_files = match calibfile::locate_config_layers() {
+            Ok(l) => l,
+            Err(why) => {
+                eprintln!("Unable to locate calibration configuration: {}", why);
+                process::exit(1);
+            }
+        };
+
+        let merged = match calibfile::load_merged_config() {
+            Ok(m) => m,
+            Err(why) => {
+                eprintln!("Error loading calibration configuration: {}", why);
+                process::exit(1);
+            }
+        };
+
+        if let Err(why) = merged.to_config() {
+            eprintln!("Warning: Merged configuration is incomplete: {}", why);
+        }
+
+        if self.validate_ilt {
+            for key in merged.origins.keys() {
+                if let Some(value) = merged.value_at(key) {
+                    for file in ilt_files(key, value) {
+                        validate_ilt_file(key, &file);
+                    }
+                }
+            }
+            return;
+        }
+
+        println!("Configuration layers (later layers take precedence):");
+        for (i, f) in layer_files.iter().enumerate() {
+            println!("  {}: {}", i + 1, f);
+        }
+        println!();
+
+        let key_width = merged.origins.keys().map(|k| k.len()).max().unwrap_or(0);
+
+        for (key, origin) in merged.origins.iter() {
+            if let Some(f) = &self.filter {
+                if !key.contains(f.as_str()) {
+                    continue;
+                }
+            }
+            let value = match merged.value_at(key) {
+                Some(v) => v.to_string(),
+                None => String::from("?"),
+            };
+            println!(
+                "{:width$} = {}    ({})",
+                key,
+                value,
+                origin,
+                width = key_width
+            );
+        }
+    }
+}
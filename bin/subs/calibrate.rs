@@ -2,10 +2,15 @@ use mars_raw_utils::prelude::*;
 
 use crate::subs::runnable::RunnableSubcommand;
 
+use mars_raw_utils::{
+    colorspace::ColorSpace, debayer::DebayerMethod, productid, whitebalance::WhiteBalanceMethod,
+};
+
 use backtrace::Backtrace;
 use rayon::prelude::*;
 use std::panic;
 use std::process;
+use std::str::FromStr;
 
 #[derive(clap::Args)]
 #[clap(author, version, about = "Batch raw image calibration", long_about = None)]
@@ -43,8 +48,46 @@ pub struct Calibrate {
     #[clap(long, short = 'w', help = "HPC window size")]
     hpc_window: Option<i32>,
 
+    #[clap(long, help = "Write a map of the hot pixels replaced")]
+    hpc_map: bool,
+
     #[clap(long, short = 'P', help = "Calibration profile", multiple_values(true))]
     profile: Option<Vec<String>>,
+
+    #[clap(
+        long,
+        short = 'W',
+        help = "Automatic white balance (grayworld, whitepatch, sky, caltarget)"
+    )]
+    white_balance: Option<String>,
+
+    #[clap(
+        long,
+        short = 'T',
+        help = "Calibration target patches for caltarget white balance"
+    )]
+    white_balance_target: Option<String>,
+
+    #[clap(
+        long,
+        short = 'M',
+        help = "Color matrix file, may contain {sol} and {instrument}"
+    )]
+    color_matrix_file: Option<String>,
+
+    #[clap(
+        long,
+        short = 'O',
+        help = "Output color space (native, linear, srgb, displayp3)"
+    )]
+    output_color_space: Option<String>,
+
+    #[clap(
+        long,
+        short = 'D',
+        help = "Debayer method (sciimg, malvar, bilinear, vng, superpixel)"
+    )]
+    debayer_method: Option<String>,
 }
 
 impl Calibrate {
@@ -69,6 +112,10 @@ impl Calibrate {
             // If a default instrument was passed in, try and use that
             if let Some(instrument) = default_instrument {
                 calibrator_for_instrument_from_str(instrument)
+            } else if let Some(instrument) = productid::instrument_for_file(input_file) {
+                // Otherwise decode the instrument from the product id in the file name
+                vprintln!("Detected {:?} from product id", instrument);
+                calibrator_for_instrument(instrument)
             } else {
                 vprintln!("We don't know what instrument was used!");
                 None // Otherwise, we don't know the instrument.
@@ -80,6 +127,39 @@ use async_trait::async_trait;
 #[async_trait]
 impl RunnableSubcommand for Calibrate {
     async fn run(&self) {
+        let white_balance = match &self.white_balance {
+            Some(m) => match WhiteBalanceMethod::from_str(m) {
+                Ok(method) => method,
+                Err(why) => {
+                    eprintln!("Invalid white balance method '{}': {}", m, why);
+                    process::exit(1);
+                }
+            },
+            None => WhiteBalanceMethod::None,
+        };
+
+        let output_color_space = match &self.output_color_space {
+            Some(c) => match ColorSpace::from_str(c) {
+                Ok(cs) => cs,
+                Err(why) => {
+                    eprintln!("Invalid output color space '{}': {}", c, why);
+                    process::exit(1);
+                }
+            },
+            None => ColorSpace::Native,
+        };
+
+        let debayer_method = match &self.debayer_method {
+            Some(m) => match DebayerMethod::from_str(m) {
+                Ok(method) => method,
+                Err(why) => {
+                    eprintln!("Invalid debayer method '{}': {}", m, why);
+                    process::exit(1);
+                }
+            },
+            None => DebayerMethod::Sciimg,
+        };
+
         let cal_context = CalProfile {
             apply_ilt: !self.raw,
             red_scalar: self.red_weight.unwrap_or(1.0),
@@ -89,7 +169,14 @@ impl RunnableSubcommand for Calibrate {
             color_noise_reduction_amount: self.color_noise_reduction_amount.unwrap_or(0),
             hot_pixel_detection_threshold: self.hpc_threshold.unwrap_or(0.0),
             hot_pixel_window_size: self.hpc_window.unwrap_or(3),
+            hot_pixel_map: self.hpc_map,
             filename_suffix: String::from(constants::OUTPUT_FILENAME_APPEND),
+            white_balance,
+            white_balance_target: self.white_balance_target.clone(),
+            color_matrix_file: self.color_matrix_file.clone(),
+            output_color_space,
+            debayer_method,
+            ..CalProfile::default()
         };
 
         let profiles: Vec<String> = match &self.profile {
@@ -1,9 +1,13 @@
-use crate::subs::runnable::RunnableSubcommand;
+use crate::subs::{bundleadjust, runnable::RunnableSubcommand};
 use async_trait::async_trait;
-use mars_raw_utils::{composite, prelude::*};
+use mars_raw_utils::{
+    blend, bundleadjust::AdjustOptions, composite, frames, metadata, pngstream, prelude::*,
+    projection, util,
+};
 use sciimg::{prelude::*, quaternion::Quaternion};
 
 use std::process;
+use std::str::FromStr;
 
 #[derive(clap::Args)]
 #[clap(author, version, about = "Create composite mosaic", long_about = None)]
@@ -25,7 +29,223 @@ pub struct Composite {
 
     #[clap(long, short = 'r', help = "Azimuth rotation")]
     azimuth: Option<f64>,
+
+    #[clap(
+        long,
+        short,
+        help = "Seam blending (overwrite, feather, multiband)",
+        default_value = "overwrite"
+    )]
+    blend: String,
+
+    #[clap(
+        long,
+        short,
+        help = "Exposure compensation (none, gain, gain-offset)",
+        default_value = "none"
+    )]
+    exposure: String,
+
+    #[clap(long, short, help = "Pyramid levels for multiband blending")]
+    levels: Option<usize>,
+
+    #[clap(
+        long,
+        short,
+        help = "Output projection (equirectangular, cylindrical, polar, stereographic, perspective, camera)",
+        default_value = "equirectangular"
+    )]
+    projection: String,
+
+    #[clap(
+        long,
+        short,
+        help = "Field of view as azimuth_min,azimuth_max,elevation_min,elevation_max in degrees",
+        allow_hyphen_values(true)
+    )]
+    fov: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        help = "Center of azimuthal projections as azimuth,elevation in degrees (default nadir)",
+        allow_hyphen_values(true)
+    )]
+    center: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        help = "Viewpoint distance for the perspective projection, in sphere radii"
+    )]
+    distance: Option<f64>,
+
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Image whose camera model the camera projection renders into (default first input)"
+    )]
+    target: Option<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short = 'F',
+        help = "Frame the mosaic is assembled in (rover, local-level, site)",
+        default_value = "rover"
+    )]
+    frame: String,
+
+    #[clap(
+        long,
+        short = 'T',
+        help = "Size of the tiles rendered in parallel, in pixels"
+    )]
+    tile_size: Option<usize>,
+
+    #[clap(
+        long,
+        short = 'B',
+        help = "Correct camera pointing from matched features first, updating the metadata sidecars"
+    )]
+    bundle_adjust: bool,
+
+    #[clap(
+        long,
+        short,
+        help = "Write the output PNG incrementally instead of holding the mosaic in memory"
+    )]
+    stream: bool,
+}
+
+/// Pyramid levels used for multiband blending when not specified
+const DEFAULT_BLEND_LEVELS: usize = 5;
+
+/// Tile size used when not specified
+const DEFAULT_TILE_SIZE: usize = 1024;
+
+fn parse_center(s: &str) -> Option<projection::AzimuthalCenter> {
+    let values: Vec<f64> = s
+        .split(',')
+        .map(|v| v.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+    match values[..] {
+        [azimuth, elevation] => Some(projection::AzimuthalCenter::new(azimuth, elevation)),
+        _ => None,
+    }
+}
+
+impl Composite {
+    fn projection_spec(&self, in_files: &[String]) -> projection::ProjectionSpec {
+        let projection_type = match projection::ProjectionType::from_str(&self.projection) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Invalid projection '{}': {}", self.projection, e);
+                process::exit(1);
+            }
+        };
+
+        let fov = self
+            .fov
+            .as_ref()
+            .map(|f| match projection::FieldOfView::from_str(f) {
+                Ok(fov) => fov,
+                Err(e) => {
+                    eprintln!("Invalid field of view '{}': {}", f, e);
+                    process::exit(1);
+                }
+            });
+
+        let center = match &self.center {
+            Some(c) => match parse_center(c) {
+                Some(center) => center,
+                None => {
+                    eprintln!("Invalid projection center '{}'", c);
+                    process::exit(1);
+                }
+            },
+            None => projection::AzimuthalCenter::nadir(),
+        };
+
+        let target = if projection_type == projection::ProjectionType::Camera {
+            let target = match &self.target {
+                Some(t) => String::from(t.as_os_str().to_str().unwrap()),
+                None => in_files[0].to_owned(),
+            };
+            match projection::CameraTarget::open(&target) {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    eprintln!("Unable to read target image {}: {}", target, e);
+                    process::exit(2);
+                }
+            }
+        } else {
+            None
+        };
+
+        let spec = projection::ProjectionSpec {
+            projection_type,
+            fov,
+            center,
+            distance: self.distance.unwrap_or(0.0),
+            target,
+        };
+
+        if let Err(e) = spec.build() {
+            eprintln!("Unable to set up projection: {}", e);
+            process::exit(2);
+        }
+        spec
+    }
+
+    fn mosaic_frame(
+        &self,
+        first_image: &MarsImage,
+        in_files: &[String],
+        projection_type: projection::ProjectionType,
+    ) -> frames::MosaicFrame {
+        let frame = match frames::Frame::from_str(&self.frame) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("Invalid frame '{}': {}", self.frame, e);
+                process::exit(1);
+            }
+        };
+
+        if frame != frames::Frame::RoverNav && projection_type == projection::ProjectionType::Camera
+        {
+            eprintln!("The camera projection can only be used with the rover frame");
+            process::exit(1);
+        }
+
+        let md = match &first_image.metadata {
+            Some(md) if md.camera_model_component_list.is_valid() => md,
+            _ => {
+                eprintln!("Cannot determine initial camera origin");
+                process::exit(2);
+            }
+        };
+
+        // Rover positions are relative to the origin of their site
+        let mut sites: Vec<u32> = in_files
+            .iter()
+            .map(|f| util::replace_image_extension(f, "-metadata.json"))
+            .filter(|f| path::file_exists(f))
+            .filter_map(|f| metadata::load_image_metadata(&f).ok()?.site)
+            .collect();
+        sites.sort_unstable();
+        sites.dedup();
+        if sites.len() > 1 {
+            eprintln!(
+                "Warning: Inputs span sites {:?}. Rover positions from different sites are not relative to a common origin",
+                sites
+            );
+        }
+
+        frames::MosaicFrame::new(frame, md)
+    }
 }
+
 #[async_trait]
 impl RunnableSubcommand for Composite {
     async fn run(&self) {
@@ -39,20 +259,34 @@ impl RunnableSubcommand for Composite {
 
         let output = self.output.as_os_str().to_str().unwrap();
 
-        let azimuth_rotation: f64 = self.azimuth.unwrap_or(0.0);
+        if let Some(in_file) = in_files.iter().find(|f| !path::file_exists(f)) {
+            eprintln!("File not found: {}", in_file);
+            process::exit(1);
+        }
+
+        if self.bundle_adjust {
+            bundleadjust::adjust_files(&in_files, &AdjustOptions::default());
+        }
+
+        let projection_spec = self.projection_spec(&in_files);
+
+        // The camera projection is fixed to the target camera's pointing
+        let azimuth_rotation: f64 =
+            if projection_spec.projection_type == projection::ProjectionType::Camera {
+                0.0
+            } else {
+                self.azimuth.unwrap_or(0.0)
+            };
 
         let quat = Quaternion::from_pitch_roll_yaw(0.0, 0.0, azimuth_rotation.to_radians());
 
-        let map_context = composite::determine_map_context(&in_files, &quat);
+        let first_image = MarsImage::open(in_files[0].to_owned(), Instrument::M20MastcamZLeft);
+        let frame = self.mosaic_frame(&first_image, &in_files, projection_spec.projection_type);
+
+        let map_context =
+            composite::determine_map_context(&in_files, &quat, &frame, &projection_spec);
         vprintln!("Map Context: {:?}", map_context);
-        vprintln!(
-            "FOV Vertical: {}",
-            map_context.top_lat - map_context.bottom_lat
-        );
-        vprintln!(
-            "FOV Horizontal: {}",
-            map_context.right_lon - map_context.left_lon
-        );
+        vprintln!("Map Size: {}x{}", map_context.width, map_context.height);
 
         if map_context.width == 0 {
             eprintln!("Output expected to have zero width. Cannot continue with that. Exiting...");
@@ -62,33 +296,108 @@ impl RunnableSubcommand for Composite {
             process::exit(1);
         }
 
-        let mut map = RgbImage::create_masked(map_context.width, map_context.height, true);
+        let blend_mode = match blend::BlendMode::from_str(&self.blend) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("Invalid blend mode '{}': {}", self.blend, e);
+                process::exit(1);
+            }
+        };
 
-        let first_image = MarsImage::open(in_files[0].to_owned(), Instrument::M20MastcamZLeft);
-        let initial_origin = if let Some(model) = composite::get_cahvor(&first_image) {
-            model.c()
-        } else {
-            eprintln!("Cannot determine initial camera origin");
-            process::exit(2);
+        let exposure = match blend::ExposureCompensation::from_str(&self.exposure) {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Invalid exposure compensation '{}': {}", self.exposure, e);
+                process::exit(1);
+            }
         };
 
-        for in_file in in_files.iter() {
-            if path::file_exists(in_file) {
-                vprintln!("Processing File: {}", in_file);
-                composite::process_file(
-                    in_file,
-                    &map_context,
-                    &mut map,
-                    self.anaglyph,
-                    &quat,
-                    &initial_origin,
-                );
-            } else {
-                eprintln!("File not found: {}", in_file);
+        let options = composite::MosaicOptions {
+            anaglyph: self.anaglyph,
+            quat,
+            frame,
+            projection: projection_spec,
+        };
+        let tile_size = self.tile_size.unwrap_or(DEFAULT_TILE_SIZE);
+        let overwrite = blend_mode == blend::BlendMode::Overwrite
+            && exposure == blend::ExposureCompensation::None;
+
+        if self.stream {
+            if !overwrite {
+                eprintln!("Streamed output only supports overwrite blending without exposure compensation");
+                process::exit(1);
+            }
+            if !output.to_lowercase().ends_with(".png") {
+                eprintln!("Streamed output must be a PNG");
                 process::exit(1);
             }
+
+            let mut writer = match pngstream::PngStreamWriter::create(
+                output,
+                map_context.width,
+                map_context.height,
+                255.0,
+            ) {
+                Ok(w) => w,
+                Err(e) => {
+                    eprintln!("Unable to create output {}: {}", output, e);
+                    process::exit(1);
+                }
+            };
+            composite::render_strips(&in_files, &map_context, &options, tile_size, |_, strip| {
+                if let Err(e) = writer.write_rows(strip) {
+                    eprintln!("Error writing output: {}", e);
+                    process::exit(1);
+                }
+            });
+            if let Err(e) = writer.finish() {
+                eprintln!("Error writing output: {}", e);
+                process::exit(1);
+            }
+            return;
         }
 
+        let mut map = if overwrite {
+            let mut map = RgbImage::create_masked(map_context.width, map_context.height, false);
+            composite::render_strips(
+                &in_files,
+                &map_context,
+                &options,
+                tile_size,
+                |strip_y, strip| {
+                    for y in 0..strip.height {
+                        for x in 0..strip.width {
+                            if strip.get_alpha_at(x, y) {
+                                for b in 0..3 {
+                                    map.put(
+                                        x,
+                                        strip_y + y,
+                                        strip.get_band(b).get(x, y).unwrap(),
+                                        b,
+                                    );
+                                }
+                                map.put_alpha(x, strip_y + y, true);
+                            }
+                        }
+                    }
+                },
+            );
+            map
+        } else {
+            let mut layers = composite::project_layers(&in_files, &map_context, &options);
+
+            let compensation = blend::estimate_exposure(&layers, exposure);
+            blend::apply_exposure(&mut layers, &compensation);
+
+            blend::blend(
+                &layers,
+                map_context.width,
+                map_context.height,
+                blend_mode,
+                self.levels.unwrap_or(DEFAULT_BLEND_LEVELS),
+            )
+        };
+
         map.normalize_to_16bit_with_max(255.0);
         map.save(output);
     }
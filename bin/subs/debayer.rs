@@ -1,8 +1,13 @@
-use mars_raw_utils::prelude::*;
-use sciimg::prelude::*;
+use mars_raw_utils::{
+    debayer::{CfaPattern, DebayerMethod},
+    prelude::*,
+};
 
 use crate::subs::runnable::RunnableSubcommand;
 
+use std::process;
+use std::str::FromStr;
+
 #[derive(clap::Args)]
 #[clap(author, version, about = "Batch image debayering", long_about = None)]
 pub struct Debayer {
@@ -14,22 +19,60 @@ pub struct Debayer {
         multiple_values(true)
     )]
     input_files: Vec<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short,
+        help = "Debayer method (sciimg, malvar, bilinear, vng, superpixel)"
+    )]
+    method: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        help = "CFA pattern at the sensor origin (RGGB, GRBG, GBRG, BGGR)"
+    )]
+    pattern: Option<String>,
 }
 
 #[async_trait::async_trait]
 impl RunnableSubcommand for Debayer {
     async fn run(&self) {
+        let method = match &self.method {
+            Some(m) => match DebayerMethod::from_str(m) {
+                Ok(method) => method,
+                Err(why) => {
+                    eprintln!("Invalid debayer method '{}': {}", m, why);
+                    process::exit(1);
+                }
+            },
+            None => DebayerMethod::Sciimg,
+        };
+
+        let sensor_pattern = match &self.pattern {
+            Some(p) => match CfaPattern::from_str(p) {
+                Ok(pattern) => pattern,
+                Err(why) => {
+                    eprintln!("Invalid CFA pattern '{}': {}", p, why);
+                    process::exit(1);
+                }
+            },
+            None => CfaPattern::Rggb,
+        };
+
         for in_file in self.input_files.iter() {
             if in_file.exists() {
                 vprintln!("Processing File: {:?}", in_file);
 
-                let mut raw =
-                    RgbImage::open(&String::from(in_file.as_os_str().to_str().unwrap())).unwrap();
+                let mut raw = MarsImage::open(
+                    String::from(in_file.as_os_str().to_str().unwrap()),
+                    Instrument::None,
+                );
 
                 let out_file =
                     util::append_file_name(in_file.as_os_str().to_str().unwrap(), "debayer");
 
-                if !raw.is_grayscale() {
+                if !raw.image.is_grayscale() {
                     vprintln!(
                         "WARNING: Image doesn't appear to be grayscale as would be expected."
                     );
@@ -37,10 +80,23 @@ impl RunnableSubcommand for Debayer {
                 }
 
                 vprintln!("Debayering image...");
-                raw.debayer();
-
-                vprintln!("Writing to disk...");
-                raw.save(&out_file);
+                // Phase follows the subframe origin recorded in the metadata, if any
+                let result = if method == DebayerMethod::Sciimg {
+                    raw.debayer();
+                    Ok(())
+                } else {
+                    CfaPattern::for_image(sensor_pattern, &raw)
+                        .and_then(|pattern| raw.debayer_with_pattern(method, pattern))
+                };
+                match result {
+                    Ok(_) => {
+                        vprintln!("Writing to disk...");
+                        raw.save(&out_file);
+                    }
+                    Err(why) => {
+                        eprintln!("Error debayering {:?}: {}", in_file, why);
+                    }
+                }
             } else {
                 eprintln!("File not found: {:?}", in_file);
             }
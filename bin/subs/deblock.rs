@@ -0,0 +1,107 @@
+use mars_raw_utils::{
+    deblock::{self, GridAlignment, JpegGrid},
+    prelude::*,
+};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+use std::str::FromStr;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Reduce JPEG block artifacts", long_about = None)]
+pub struct Deblock {
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Input images",
+        multiple_values(true)
+    )]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[clap(long, short, help = "Deblocking strength (default 1.0)")]
+    strength: Option<f32>,
+
+    #[clap(long, short, help = "Block grid alignment (image, sensor)")]
+    grid: Option<String>,
+
+    #[clap(long, short = 'x', help = "Column of the first block boundary")]
+    offset_x: Option<usize>,
+
+    #[clap(long, short = 'y', help = "Row of the first block boundary")]
+    offset_y: Option<usize>,
+
+    #[clap(
+        long,
+        short = 'Q',
+        help = "Don't constrain the result to the JPEG quantization table"
+    )]
+    no_quant: bool,
+
+    #[clap(long, short = 'c', help = "Inputs are raw color filter array mosaics")]
+    cfa: bool,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Deblock {
+    async fn run(&self) {
+        let strength = self.strength.unwrap_or(1.0);
+        if strength < 0.0 {
+            eprintln!("Strength cannot be less than zero!");
+            process::exit(1);
+        }
+
+        let grid = match &self.grid {
+            Some(g) => match JpegGrid::from_str(g) {
+                Ok(grid) => grid,
+                Err(why) => {
+                    eprintln!("Invalid grid alignment '{}': {}", g, why);
+                    process::exit(1);
+                }
+            },
+            None => JpegGrid::Image,
+        };
+
+        for in_file in self.input_files.iter() {
+            if in_file.exists() {
+                vprintln!("Processing File: {:?}", in_file);
+                let in_file = in_file.as_os_str().to_str().unwrap();
+
+                let mut raw = MarsImage::open(String::from(in_file), Instrument::None);
+
+                let mut alignment = GridAlignment::for_image(&raw, grid);
+                if self.offset_x.is_some() || self.offset_y.is_some() {
+                    alignment = GridAlignment::new(
+                        self.offset_x.unwrap_or(alignment.offset_x),
+                        self.offset_y.unwrap_or(alignment.offset_y),
+                    );
+                }
+
+                let quant = if self.no_quant {
+                    None
+                } else {
+                    match deblock::read_quant_table(in_file) {
+                        Ok(q) => Some(q),
+                        Err(why) => {
+                            vprintln!("No quantization table available: {}", why);
+                            None
+                        }
+                    }
+                };
+
+                vprintln!(
+                    "Deblocking with grid offset {},{}...",
+                    alignment.offset_x,
+                    alignment.offset_y
+                );
+                deblock::deblock_image(&mut raw, &alignment, strength, quant.as_ref(), self.cfa);
+
+                vprintln!("Writing to disk...");
+                raw.save(&util::append_file_name(in_file, "deblock"));
+            } else {
+                eprintln!("File not found: {:?}", in_file);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use mars_raw_utils::prelude::*;
+use mars_raw_utils::{hotpixel, prelude::*};
 use sciimg::prelude::*;
 
 use crate::subs::runnable::RunnableSubcommand;
@@ -22,6 +22,46 @@ pub struct HpcFilter {
 
     #[clap(long, short = 'w', help = "HPC window size")]
     window: Option<i32>,
+
+    #[clap(
+        long,
+        short = 'T',
+        help = "Detect hot pixels from the temporal median of the input sequence"
+    )]
+    temporal: bool,
+
+    #[clap(long, short = 'm', help = "Write a map of the replaced pixels")]
+    map: bool,
+
+    #[clap(
+        long,
+        short = 'M',
+        help = "Known hot pixel map to replace instead of detecting"
+    )]
+    map_file: Option<String>,
+
+    #[clap(long, short = 'c', help = "Inputs are raw color filter array mosaics")]
+    cfa: bool,
+}
+
+impl HpcFilter {
+    fn save_corrected(&self, in_file: &str, raw: &mut MarsImage, map: &ImageBuffer) {
+        vprintln!("Replaced {} hot pixels", hotpixel::count_hot_pixels(map));
+
+        // DON'T ASSUME THIS!
+        let data_max = 255.0;
+
+        vprintln!("Normalizing...");
+        raw.image.normalize_to_16bit_with_max(data_max);
+
+        vprintln!("Writing to disk...");
+        let out_file = util::append_file_name(in_file, "hpc");
+        raw.save(&out_file);
+
+        if self.map {
+            hotpixel::save_map(map, &hotpixel::map_file_for(&out_file));
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -36,31 +76,77 @@ impl RunnableSubcommand for HpcFilter {
             process::exit(1);
         }
 
-        for in_file in self.input_files.iter() {
-            if in_file.exists() {
-                vprintln!("Processing File: {:?}", in_file);
-                let mut raw =
-                    RgbImage::open(&String::from(in_file.as_os_str().to_str().unwrap())).unwrap();
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .filter(|f| {
+                if !f.exists() {
+                    eprintln!("File not found: {:?}", f);
+                }
+                f.exists()
+            })
+            .map(|f| String::from(f.as_os_str().to_str().unwrap()))
+            .collect();
 
-                vprintln!(
-                    "Hot pixel correction with variance threshold {}...",
-                    threshold
-                );
-                raw.hot_pixel_correction(window_size, threshold);
+        let mut images: Vec<MarsImage> = in_files
+            .iter()
+            .map(|f| {
+                vprintln!("Processing File: {}", f);
+                MarsImage::open(f.clone(), Instrument::None)
+            })
+            .collect();
 
-                // DON'T ASSUME THIS!
-                let data_max = 255.0;
-
-                vprintln!("Normalizing...");
-                raw.normalize_to_16bit_with_max(data_max);
-
-                vprintln!("Writing to disk...");
-
-                let out_file = util::append_file_name(in_file.as_os_str().to_str().unwrap(), "hpc");
-                raw.save(&out_file);
+        // A known or temporal map applies to every frame in the same way
+        let shared_map = if let Some(map_file) = &self.map_file {
+            match hotpixel::load_map(map_file) {
+                Ok(m) => Some(m),
+                Err(why) => {
+                    eprintln!("Error loading hot pixel map {}: {}", map_file, why);
+                    process::exit(1);
+                }
+            }
+        } else if self.temporal {
+            if threshold <= 0.0 {
+                eprintln!("Temporal detection requires a threshold greater than zero");
+                process::exit(1);
+            }
+            vprintln!(
+                "Temporal hot pixel detection over {} frames with threshold {}...",
+                images.len(),
+                threshold
+            );
+            let frames: Vec<&ImageBuffer> = images.iter().map(|i| i.image.get_band(0)).collect();
+            let map = if self.cfa {
+                hotpixel::temporal_map_mosaic(&frames, window_size, threshold)
             } else {
-                eprintln!("File not found: {:?}", in_file);
+                hotpixel::temporal_map(&frames, window_size, threshold)
+            };
+            match map {
+                Ok(m) => Some(m),
+                Err(why) => {
+                    eprintln!("Error detecting hot pixels: {}", why);
+                    process::exit(1);
+                }
             }
+        } else {
+            None
+        };
+
+        for (in_file, raw) in in_files.iter().zip(images.iter_mut()) {
+            let map = match &shared_map {
+                Some(map) => {
+                    hotpixel::correct_image_with_map(raw, map, window_size, self.cfa);
+                    map.clone()
+                }
+                None => {
+                    vprintln!(
+                        "Hot pixel correction with variance threshold {}...",
+                        threshold
+                    );
+                    raw.hot_pixel_correction_with_map(window_size, threshold, self.cfa)
+                }
+            };
+            self.save_corrected(in_file, raw, &map);
         }
     }
 }
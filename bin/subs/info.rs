@@ -94,6 +94,11 @@ impl RunnableSubcommand for Info {
                     println!("Decompanded:                 {}", md.decompand.yesno());
                     println!("Debayered:                   {}", md.debayer.yesno());
                     println!("Flatfielded:                 {}", md.flatfield.yesno());
+                    if let Some(flats) = md.flat_files {
+                        for f in flats.iter() {
+                            println!("Flat File:                   {} ({:.3})", f.file, f.weight);
+                        }
+                    }
                     println!("Radiometric Correction:      {}", md.radiometric.yesno());
                     println!("Inpainted:                   {}", md.inpaint.yesno());
                     println!("Cropped:                     {}", md.cropped.yesno());
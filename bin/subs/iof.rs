@@ -0,0 +1,109 @@
+use mars_raw_utils::{prelude::*, radiometry};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Convert calibrated images to I/F using a calibration target frame", long_about = None)]
+pub struct Iof {
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Calibrated input images",
+        multiple_values(true)
+    )]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Calibrated reference image of the calibration target"
+    )]
+    reference: std::path::PathBuf,
+
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "TOML file of calibration target patches in the reference image"
+    )]
+    target: std::path::PathBuf,
+
+    #[clap(
+        long,
+        short,
+        help = "Maximum time from the reference image, in seconds (default 3600)"
+    )]
+    max_time: Option<f64>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Iof {
+    async fn run(&self) {
+        let max_time = self.max_time.unwrap_or(3600.0);
+
+        let target = match radiometry::load_caltarget(self.target.as_os_str().to_str().unwrap()) {
+            Ok(t) => t,
+            Err(why) => {
+                eprintln!(
+                    "Error loading calibration target {:?}: {}",
+                    self.target, why
+                );
+                process::exit(1);
+            }
+        };
+
+        let reference_file = String::from(self.reference.as_os_str().to_str().unwrap());
+        if !self.reference.exists() {
+            eprintln!("Reference image not found: {}", reference_file);
+            process::exit(1);
+        }
+        let reference = MarsImage::open16(reference_file.clone(), Instrument::None);
+
+        let coefficients =
+            match radiometry::derive_coefficients(&reference, &reference_file, &target) {
+                Ok(c) => c,
+                Err(why) => {
+                    eprintln!("Error deriving calibration coefficients: {}", why);
+                    process::exit(1);
+                }
+            };
+        println!("I/F gains: {:?}", coefficients.gains);
+
+        if coefficients.reference_sclk.is_none() {
+            eprintln!("Reference image has no spacecraft clock in its metadata, cannot match frames by time");
+            process::exit(1);
+        }
+
+        for in_file in self.input_files.iter() {
+            if !in_file.exists() {
+                eprintln!("File not found: {:?}", in_file);
+                continue;
+            }
+            let in_file = String::from(in_file.as_os_str().to_str().unwrap());
+            vprintln!("Processing File: {}", in_file);
+
+            let mut img = MarsImage::open16(in_file.clone(), Instrument::None);
+            if !radiometry::is_close_in_time(&coefficients, &img, max_time) {
+                vprintln!("Not within {}s of the reference image, skipping", max_time);
+                print_warn(&path::basename(&in_file));
+                continue;
+            }
+
+            match radiometry::apply_coefficients(&mut img, &coefficients) {
+                Ok(_) => {
+                    let out_file = util::append_file_name(&in_file, "iof");
+                    img.save(&out_file);
+                    print_done(&path::basename(&in_file));
+                }
+                Err(why) => {
+                    eprintln!("Error calibrating {}: {}", in_file, why);
+                    print_fail(&path::basename(&in_file));
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use mars_raw_utils::prelude::*;
+use mars_raw_utils::{cosmicray, hotpixel, prelude::*};
 use sciimg::prelude::*;
 
 use crate::subs::runnable::RunnableSubcommand;
@@ -19,6 +19,23 @@ pub struct MeanStack {
 
     #[clap(long, short, parse(from_os_str), help = "Output image")]
     output: std::path::PathBuf,
+
+    #[clap(
+        long,
+        short,
+        help = "Reject cosmic rays by sigma clipping across the aligned inputs"
+    )]
+    sigma_clip: Option<f32>,
+
+    #[clap(
+        long,
+        short = 'c',
+        help = "Remove cosmic rays from each input with L.A.Cosmic"
+    )]
+    lacosmic: bool,
+
+    #[clap(long, short, help = "Write a mask of the rejected pixels")]
+    mask: bool,
 }
 
 #[async_trait::async_trait]
@@ -30,13 +47,40 @@ impl RunnableSubcommand for MeanStack {
         let mut count: ImageBuffer = ImageBuffer::new_empty().unwrap();
         let mut ones: ImageBuffer = ImageBuffer::new_empty().unwrap();
 
+        // Sigma clipping needs every frame at once, a plain mean is accumulated as we go
+        let mut frames: Vec<RgbImage> = vec![];
+        let mut masks: Vec<ImageBuffer> = vec![];
+
+        let lacosmic_params = cosmicray::LaCosmicParams::default();
+
         for in_file in self.input_files.iter() {
             if in_file.exists() {
                 vprintln!("Processing File: {:?}", in_file);
 
-                let raw =
+                let mut raw =
                     RgbImage::open(&String::from(in_file.as_os_str().to_str().unwrap())).unwrap();
 
+                if self.lacosmic {
+                    vprintln!("Removing cosmic rays with L.A.Cosmic...");
+                    for b in 0..raw.num_bands() {
+                        let (cleaned, mask) =
+                            cosmicray::lacosmic(raw.get_band(b), &lacosmic_params);
+                        raw.set_band(&cleaned, b);
+                        masks.push(mask);
+                    }
+                }
+
+                if self.sigma_clip.is_some() {
+                    if !frames.is_empty()
+                        && (raw.width != frames[0].width || raw.height != frames[0].height)
+                    {
+                        eprintln!("Input image has differing dimensions, cannot continue");
+                        process::exit(1);
+                    }
+                    frames.push(raw);
+                    continue;
+                }
+
                 if mean.is_empty() {
                     mean = raw;
                     count = ImageBuffer::new(mean.width, mean.height).unwrap();
@@ -56,12 +100,43 @@ impl RunnableSubcommand for MeanStack {
             }
         }
 
-        if !mean.is_empty() {
+        if let Some(sigma) = self.sigma_clip {
+            if !frames.is_empty() {
+                vprintln!("Sigma clipping {} frames at {} sigma", frames.len(), sigma);
+                mean = frames[0].clone();
+                for b in 0..mean.num_bands() {
+                    let bands: Vec<&ImageBuffer> = frames.iter().map(|f| f.get_band(b)).collect();
+                    match cosmicray::sigma_clip_mean(&bands, sigma, 5) {
+                        Ok((band_mean, band_masks)) => {
+                            mean.set_band(&band_mean, b);
+                            masks.extend(band_masks);
+                        }
+                        Err(why) => {
+                            eprintln!("Error sigma clipping inputs: {}", why);
+                            process::exit(1);
+                        }
+                    }
+                }
+            }
+        } else if !mean.is_empty() {
             mean.divide_from_each(&count);
+        }
 
+        if !mean.is_empty() {
             if path::parent_exists_and_writable(output) {
                 vprintln!("Writing image to {}", output);
                 mean.save(output);
+
+                if self.mask && !masks.is_empty() {
+                    let mask = cosmicray::merge_masks(&masks);
+                    let mask_file = cosmicray::mask_file_for(output);
+                    vprintln!(
+                        "Writing mask of {} rejected pixels to {}",
+                        cosmicray::count_rejected(&mask),
+                        mask_file
+                    );
+                    hotpixel::save_map(&mask, &mask_file);
+                }
             } else {
                 eprintln!("Unable to write output image, parent doesn't exist or is not writable");
             }
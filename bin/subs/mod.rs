@@ -8,15 +8,24 @@ pub mod nsyt;
 
 // Multimission subcommands:
 pub mod anaglyph;
+pub mod bundleadjust;
+pub mod caldata;
 pub mod calibrate;
 pub mod composite;
 pub mod crop;
 pub mod debayer;
+pub mod deblock;
 pub mod diffgif;
 pub mod focusmerge;
 pub mod hpcfilter;
 pub mod info;
 pub mod inpaint;
+pub mod iof;
 pub mod levels;
 pub mod meanstack;
+pub mod spectra;
+pub mod stereo;
+pub mod stereomatch;
+pub mod stereorender;
+pub mod whitebalance;
 pub mod xeye;
@@ -0,0 +1,210 @@
+use mars_raw_utils::{filters, filters::FilterPosition, prelude::*, spectral};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::fs::File;
+use std::io::Write;
+use std::process;
+use std::str::FromStr;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Build a multispectral cube and region spectra from calibrated filter images", long_about = None)]
+pub struct Spectra {
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Calibrated input images",
+        multiple_values(true)
+    )]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short,
+        help = "Output base path (writes .img, .hdr, and _spectra.csv)"
+    )]
+    output: String,
+
+    #[clap(long, short = 'I', help = "Force instrument")]
+    instrument: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        help = "Region of interest as x,y,width,height",
+        multiple_values(true)
+    )]
+    roi: Vec<String>,
+
+    #[clap(
+        long,
+        short = 'R',
+        parse(from_os_str),
+        help = "TOML file of [[roi]] regions"
+    )]
+    roi_file: Option<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short = 'g',
+        help = "Co-register bands, searching up to this many pixels of offset"
+    )]
+    register: Option<usize>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Spectra {
+    async fn run(&self) {
+        let mut rois: Vec<spectral::Roi> = vec![];
+        for (i, r) in self.roi.iter().enumerate() {
+            match spectral::Roi::from_str_with_name(r, &format!("roi{}", i + 1)) {
+                Ok(roi) => rois.push(roi),
+                Err(why) => {
+                    eprintln!("{}: {}", why, r);
+                    process::exit(1);
+                }
+            }
+        }
+
+        if let Some(roi_file) = &self.roi_file {
+            match spectral::load_rois(roi_file.as_os_str().to_str().unwrap()) {
+                Ok(mut r) => rois.append(&mut r),
+                Err(why) => {
+                    eprintln!("Error loading ROI file {:?}: {}", roi_file, why);
+                    process::exit(1);
+                }
+            }
+        }
+
+        let mut cube = spectral::SpectralCube::new();
+        let mut cube_metadata: Option<metadata::Metadata> = None;
+
+        for in_file in self.input_files.iter() {
+            if !in_file.exists() {
+                eprintln!("File not found: {:?}", in_file);
+                continue;
+            }
+            let in_file = String::from(in_file.as_os_str().to_str().unwrap());
+            vprintln!("Processing File: {}", in_file);
+
+            let mut img = MarsImage::open16(in_file.clone(), Instrument::None);
+            let instrument = match (&img.metadata, &self.instrument) {
+                (_, Some(instrument)) => instrument,
+                (Some(md), None) => &md.instrument,
+                (None, None) => {
+                    eprintln!("Cannot determine instrument for {}, skipping", in_file);
+                    continue;
+                }
+            };
+            img.instrument = match Instrument::from_str(instrument) {
+                Ok(i) => i,
+                Err(why) => {
+                    eprintln!("Invalid instrument '{}': {}", instrument, why);
+                    process::exit(1);
+                }
+            };
+
+            let filter = match FilterPosition::for_image(img.instrument, &img.metadata, &in_file) {
+                Some(f) => f,
+                None => {
+                    eprintln!("Cannot determine filter for {}, skipping", in_file);
+                    continue;
+                }
+            };
+
+            let wavelengths = match filters::band_wavelengths(img.instrument, &filter) {
+                Some(w) => w,
+                None => {
+                    eprintln!(
+                        "No wavelengths known for filter {} on {:?}, skipping",
+                        filter.name(),
+                        img.instrument
+                    );
+                    continue;
+                }
+            };
+
+            vprintln!(
+                "Filter {} with wavelengths {:?}",
+                filter.name(),
+                wavelengths
+            );
+            if let Err(why) = cube.add_frame(&img, &filter, &wavelengths) {
+                eprintln!("Cannot add {} to cube: {}", in_file, why);
+                process::exit(1);
+            }
+
+            if cube_metadata.is_none() {
+                cube_metadata = img.metadata.clone();
+            }
+        }
+
+        if cube.is_empty() {
+            eprintln!("No usable input images");
+            process::exit(1);
+        }
+
+        println!(
+            "Cube is {}x{} with {} bands: {:?}",
+            cube.width,
+            cube.height,
+            cube.bands.len(),
+            cube.wavelengths()
+        );
+
+        if let Some(max_shift) = self.register {
+            vprintln!("Co-registering bands...");
+            if let Err(why) = cube.register(max_shift) {
+                eprintln!("Error registering bands: {}", why);
+                process::exit(1);
+            }
+        }
+
+        if let Err(why) = cube.save_envi(&self.output) {
+            eprintln!("Error writing cube: {}", why);
+            process::exit(1);
+        }
+
+        if let Some(md) = &cube_metadata {
+            if let Err(why) = cube.save_metadata(&self.output, md) {
+                eprintln!("Error writing cube metadata: {}", why);
+                process::exit(1);
+            }
+        }
+
+        if rois.is_empty() {
+            return;
+        }
+
+        let csv_path = format!("{}_spectra.csv", self.output);
+        vprintln!("Writing region spectra to {}", csv_path);
+        // Band values are I/F when the inputs were converted with `mru iof`, otherwise DN
+        let units = cube.value_units();
+        let mut csv = format!("roi,band,wavelength,mean_{},stddev_{}\n", units, units);
+        for roi in rois.iter() {
+            match cube.roi_spectrum(roi) {
+                Ok(samples) => {
+                    for s in samples.iter() {
+                        csv += &format!(
+                            "{},{},{:.1},{},{}\n",
+                            roi.name, s.band_name, s.wavelength, s.mean, s.stddev
+                        );
+                    }
+                }
+                Err(why) => {
+                    eprintln!("Region {} ({:?}): {}", roi.name, roi, why);
+                    process::exit(1);
+                }
+            }
+        }
+
+        match File::create(&csv_path) {
+            Ok(mut f) => f.write_all(csv.as_bytes()).unwrap(),
+            Err(why) => {
+                eprintln!("Error creating {}: {}", csv_path, why);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use mars_raw_utils::{
+    frames::Frame,
+    mesh::{ExportFrame, GridMesh},
+    prelude::*,
+    stereo,
+};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+use std::str::FromStr;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Compute disparity, range and XYZ from a stereo pair", long_about = None)]
+pub struct Stereo {
+    #[clap(long, short, parse(from_os_str), help = "Left image")]
+    left: std::path::PathBuf,
+
+    #[clap(long, short, parse(from_os_str), help = "Right image")]
+    right: std::path::PathBuf,
+
+    #[clap(
+        long,
+        short,
+        help = "Output base path, to which '-disparity.png', '-range', '-xyz' and '-rectified.png' are appended"
+    )]
+    output: String,
+
+    #[clap(
+        long,
+        short = 'd',
+        help = "Number of disparities searched (default 64)"
+    )]
+    disparities: Option<usize>,
+
+    #[clap(long, help = "Penalty for small disparity changes (default 3)")]
+    p1: Option<u16>,
+
+    #[clap(long, help = "Penalty for large disparity changes (default 20)")]
+    p2: Option<u16>,
+
+    #[clap(
+        long,
+        short = 'u',
+        help = "Uniqueness margin of the best match, as a fraction (default 0.05)"
+    )]
+    uniqueness: Option<f32>,
+
+    #[clap(
+        long,
+        short = 'p',
+        help = "Write a colored point cloud to '-points.ply'"
+    )]
+    ply: bool,
+
+    #[clap(
+        long,
+        short = 'm',
+        help = "Write a mesh textured with the rectified image to '-mesh.obj'"
+    )]
+    obj: bool,
+
+    #[clap(
+        long,
+        short = 'F',
+        help = "Frame of the point cloud and mesh: camera, rover, locallevel or site (default rover)"
+    )]
+    frame: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Stereo {
+    async fn run(&self) {
+        print::print_experimental();
+
+        let left_file = self.left.as_os_str().to_str().unwrap();
+        let right_file = self.right.as_os_str().to_str().unwrap();
+        for f in [left_file, right_file] {
+            if !path::file_exists(f) {
+                eprintln!("File not found: {}", f);
+                process::exit(1);
+            }
+        }
+
+        let mut options = stereo::StereoOptions::default();
+        if let Some(disparities) = self.disparities {
+            options.num_disparities = disparities;
+        }
+        if let Some(p1) = self.p1 {
+            options.p1 = p1;
+        }
+        if let Some(p2) = self.p2 {
+            options.p2 = p2;
+        }
+        if let Some(uniqueness) = self.uniqueness {
+            options.uniqueness = uniqueness;
+        }
+        let frame = match &self.frame {
+            Some(f) => match ExportFrame::from_str(f) {
+                Ok(frame) => frame,
+                Err(_) => {
+                    eprintln!("Invalid frame: {}", f);
+                    process::exit(1);
+                }
+            },
+            None => ExportFrame::Rover(Frame::RoverNav),
+        };
+
+        if options.p2 < options.p1 {
+            eprintln!("P2 must be at least P1");
+            process::exit(1);
+        }
+
+        let products = match stereo::process_files(left_file, right_file, &self.output, &options) {
+            Ok(products) => products,
+            Err(why) => {
+                eprintln!("Error computing stereo: {}", why);
+                process::exit(2);
+            }
+        };
+        println!("{} pixels triangulated", products.range.num_points());
+
+        if self.ply || self.obj {
+            let mesh = GridMesh::from_stereo(&products, frame);
+            if self.ply {
+                if let Err(why) = mesh.save_ply(&format!("{}-points.ply", self.output)) {
+                    eprintln!("Error writing point cloud: {}", why);
+                    process::exit(2);
+                }
+            }
+            if self.obj {
+                if let Err(why) = mesh.save_obj(
+                    &format!("{}-mesh.obj", self.output),
+                    &format!("{}-rectified.png", self.output),
+                ) {
+                    eprintln!("Error writing mesh: {}", why);
+                    process::exit(2);
+                }
+            }
+        }
+    }
+}
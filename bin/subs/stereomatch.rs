@@ -0,0 +1,151 @@
+use mars_raw_utils::{prelude::*, stereo, stereopairs};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::path::Path;
+use std::process;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Pair left and right eye images in a directory and process each pair", long_about = None)]
+pub struct StereoMatch {
+    #[clap(long, short, help = "Directory of input images")]
+    input_dir: String,
+
+    #[clap(long, short, help = "Output directory (default the input directory)")]
+    output_dir: Option<String>,
+
+    #[clap(
+        long,
+        short = 'M',
+        help = "Output for each pair: anaglyph, xeye, stereo or none (default anaglyph)"
+    )]
+    mode: Option<String>,
+
+    #[clap(long, short, help = "Monochrome anaglyphs")]
+    mono: bool,
+
+    #[clap(
+        long,
+        short = 'a',
+        help = "Anaglyph color mode, as for the anaglyph subcommand (default color)"
+    )]
+    anaglyph_mode: Option<String>,
+
+    #[clap(long, short, help = "Converge anaglyphs on the image center")]
+    converge: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PairOutput {
+    Anaglyph,
+    CrossEye,
+    Stereo,
+    None,
+}
+
+fn file_stem(file_path: &str) -> String {
+    Path::new(file_path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for StereoMatch {
+    async fn run(&self) {
+        print::print_experimental();
+
+        let output = match self.mode.as_deref().unwrap_or("anaglyph") {
+            "anaglyph" => PairOutput::Anaglyph,
+            "xeye" => PairOutput::CrossEye,
+            "stereo" => PairOutput::Stereo,
+            "none" => PairOutput::None,
+            m => {
+                eprintln!("Invalid output mode: {}", m);
+                process::exit(1);
+            }
+        };
+
+        let files = match stereopairs::image_files_in(&self.input_dir) {
+            Ok(files) => files,
+            Err(why) => {
+                eprintln!("Error reading {}: {}", self.input_dir, why);
+                process::exit(1);
+            }
+        };
+
+        let output_dir = self.output_dir.as_ref().unwrap_or(&self.input_dir);
+        if !path::is_dir(output_dir) {
+            eprintln!("Output directory not found: {}", output_dir);
+            process::exit(1);
+        }
+
+        // Outputs of earlier runs carry the left image's product id
+        let files: Vec<String> = files
+            .into_iter()
+            .filter(|f| {
+                let stem = file_stem(f);
+                !(stem.ends_with("-anaglyph")
+                    || stem.ends_with("-xeye")
+                    || stem.contains("-stereo-"))
+            })
+            .collect();
+
+        let anaglyph_options = crate::subs::anaglyph::anaglyph_options(
+            &self.anaglyph_mode,
+            &None,
+            None,
+            self.converge,
+        );
+
+        let matches = stereopairs::find_pairs(&files);
+        for f in matches.unpaired.iter() {
+            println!("Unpaired: {}", f);
+        }
+        println!(
+            "{} pairs, {} unpaired",
+            matches.pairs.len(),
+            matches.unpaired.len()
+        );
+
+        let mut failed = 0;
+        for pair in matches.pairs.iter() {
+            println!("Pair: {} {}", pair.left, pair.right);
+            let base = Path::new(output_dir)
+                .join(file_stem(&pair.left))
+                .to_string_lossy()
+                .to_string();
+            let result = match output {
+                PairOutput::Anaglyph => crate::subs::anaglyph::create_anaglyph(
+                    &pair.left,
+                    &pair.right,
+                    &format!("{}-anaglyph.png", base),
+                    self.mono,
+                    &anaglyph_options,
+                ),
+                PairOutput::CrossEye => crate::subs::xeye::create_cross_eye(
+                    &pair.left,
+                    &pair.right,
+                    &format!("{}-xeye.png", base),
+                ),
+                PairOutput::Stereo => stereo::process_files(
+                    &pair.left,
+                    &pair.right,
+                    &format!("{}-stereo", base),
+                    &stereo::StereoOptions::default(),
+                )
+                .map(|_| ()),
+                PairOutput::None => Ok(()),
+            };
+            if let Err(why) = result {
+                eprintln!("Error processing {}: {}", pair.left, why);
+                failed += 1;
+            }
+        }
+
+        if failed > 0 {
+            eprintln!("{} pairs failed", failed);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use mars_raw_utils::{
+    anaglyph,
+    prelude::*,
+    stereo,
+    stereorender::{self, StereoRenderMode},
+};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+use std::str::FromStr;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Render a stereo pair as a wiggle animation, side-by-side, over-under or MPO", long_about = None)]
+pub struct StereoRender {
+    #[clap(long, short, parse(from_os_str), help = "Left image")]
+    left: std::path::PathBuf,
+
+    #[clap(long, short, parse(from_os_str), help = "Right image")]
+    right: std::path::PathBuf,
+
+    #[clap(long, short, parse(from_os_str), help = "Output image")]
+    output: std::path::PathBuf,
+
+    #[clap(
+        long,
+        short,
+        help = "Output: wiggle-gif, wiggle-apng, side-by-side, over-under or mpo (default wiggle-gif)"
+    )]
+    mode: Option<String>,
+
+    #[clap(long, short, help = "Wiggle frame delay in milliseconds (default 150)")]
+    delay: Option<u16>,
+
+    #[clap(
+        long,
+        short,
+        allow_hyphen_values(true),
+        help = "Pixels to move the right eye to the right"
+    )]
+    parallax: Option<i32>,
+
+    #[clap(long, short, help = "Converge on the image center")]
+    converge: bool,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for StereoRender {
+    async fn run(&self) {
+        print::print_experimental();
+
+        let left_image_path = String::from(self.left.as_os_str().to_str().unwrap());
+        let right_image_path = String::from(self.right.as_os_str().to_str().unwrap());
+        let out_file_path = self.output.as_os_str().to_str().unwrap();
+
+        let mode = match StereoRenderMode::from_str(self.mode.as_deref().unwrap_or("wiggle-gif")) {
+            Ok(mode) => mode,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                process::exit(1);
+            }
+        };
+
+        if self.converge && self.parallax.is_some() {
+            eprintln!("Error: Parallax and convergence can't both be set");
+            process::exit(1);
+        }
+
+        if !path::file_exists(&left_image_path) {
+            eprintln!("Error: File not found (left eye): {}", left_image_path);
+            process::exit(1);
+        }
+
+        if !path::file_exists(&right_image_path) {
+            eprintln!("Error: File not found (right eye): {}", right_image_path);
+            process::exit(1);
+        }
+
+        if !path::parent_exists_and_writable(out_file_path) {
+            eprintln!(
+                "Error: Output file directory not found or is not writable: {}",
+                out_file_path
+            );
+            process::exit(1);
+        }
+
+        let (left, right, geometry) =
+            match stereo::load_rectified_pair(&left_image_path, &right_image_path) {
+                Ok(pair) => pair,
+                Err(why) => {
+                    eprintln!("Error: {}", why);
+                    process::exit(1);
+                }
+            };
+
+        let parallax = if self.converge {
+            let p = anaglyph::center_parallax(&left, &right);
+            vprintln!(
+                "Converging on the image center with a parallax of {} pixels",
+                p
+            );
+            p
+        } else {
+            self.parallax.unwrap_or(0)
+        };
+        let right = if parallax != 0 {
+            stereorender::shift_horizontal(&right, parallax)
+        } else {
+            right
+        };
+
+        if let Err(why) = stereorender::save(
+            &left,
+            &right,
+            mode,
+            self.delay.unwrap_or(150),
+            &geometry,
+            out_file_path,
+        ) {
+            eprintln!("Error: {}", why);
+            process::exit(2);
+        }
+    }
+}
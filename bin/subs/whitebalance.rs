@@ -0,0 +1,97 @@
+use mars_raw_utils::{calprofile::CalProfile, whitebalance, whitebalance::WhiteBalanceMethod};
+
+use crate::subs::runnable::RunnableSubcommand;
+
+use std::process;
+use std::str::FromStr;
+
+#[derive(clap::Args)]
+#[clap(author, version, about = "Compute a reusable color matrix by automatic white balance", long_about = None)]
+pub struct WhiteBalance {
+    #[clap(
+        long,
+        short,
+        parse(from_os_str),
+        help = "Calibrated input images",
+        multiple_values(true)
+    )]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[clap(
+        long,
+        short,
+        help = "White balance method (grayworld, whitepatch, sky, caltarget)"
+    )]
+    method: String,
+
+    #[clap(
+        long,
+        short,
+        help = "Calibration target patches for caltarget white balance"
+    )]
+    target: Option<String>,
+
+    #[clap(
+        long,
+        short,
+        help = "Fraction of the frame taken as sky (default 0.15)"
+    )]
+    sky_fraction: Option<f32>,
+
+    #[clap(long, short, parse(from_os_str), help = "Output color matrix file")]
+    output: std::path::PathBuf,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for WhiteBalance {
+    async fn run(&self) {
+        let method = match WhiteBalanceMethod::from_str(&self.method) {
+            Ok(m) => m,
+            Err(why) => {
+                eprintln!("Invalid white balance method '{}': {}", self.method, why);
+                process::exit(1);
+            }
+        };
+
+        let defaults = CalProfile::default();
+        let cal_context = CalProfile {
+            white_balance: method,
+            white_balance_target: self.target.clone(),
+            white_balance_sky_fraction: self
+                .sky_fraction
+                .unwrap_or(defaults.white_balance_sky_fraction),
+            ..defaults
+        };
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .filter(|f| {
+                if !f.exists() {
+                    eprintln!("File not found: {:?}", f);
+                }
+                f.exists()
+            })
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+
+        let matrix = match whitebalance::color_matrix_for_images(&in_files, method, &cal_context) {
+            Ok(m) => m,
+            Err(why) => {
+                eprintln!("Error computing color matrix: {}", why);
+                process::exit(1);
+            }
+        };
+
+        println!(
+            "Gains: {}, {}, {}",
+            matrix.matrix[0][0], matrix.matrix[1][1], matrix.matrix[2][2]
+        );
+
+        let output = self.output.as_os_str().to_str().unwrap();
+        if let Err(why) = matrix.save(output) {
+            eprintln!("Error writing color matrix to {}: {}", output, why);
+            process::exit(1);
+        }
+    }
+}
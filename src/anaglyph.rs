@@ -1,6 +1,8 @@
-use crate::prelude::*;
+use crate::{constants, prelude::*};
 use sciimg::{prelude::*, vector::Vector};
 
+use std::str::FromStr;
+
 pub fn process_image(
     img: &MarsImage,
     map: &mut RgbImage,
@@ -72,3 +74,259 @@ pub fn process_image(
         }
     }
 }
+
+/// Weights of the output red, green and blue from the left eye's red, green and blue followed
+/// by the right eye's
+pub type AnaglyphMatrix = [[f32; 6]; 3];
+
+/// How the colors of the two eyes are mixed into an anaglyph
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnaglyphMode {
+    /// Red from the left eye, green and blue from the right
+    Color,
+
+    /// Luminance of the left eye in red, green and blue from the right
+    HalfColor,
+
+    /// Red from the left eye's green and blue, which reduces retinal rivalry with saturated reds
+    Optimized,
+
+    /// Dubois least squares projections for red/cyan glasses
+    DuboisRedCyan,
+
+    /// Dubois least squares projections for green/magenta glasses
+    DuboisGreenMagenta,
+
+    /// Dubois least squares projections for amber/blue glasses
+    DuboisAmberBlue,
+
+    Custom(AnaglyphMatrix),
+}
+
+impl FromStr for AnaglyphMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<AnaglyphMode, Self::Err> {
+        match s.to_lowercase().replace('_', "-").as_str() {
+            "color" | "full-color" => Ok(AnaglyphMode::Color),
+            "half-color" => Ok(AnaglyphMode::HalfColor),
+            "optimized" => Ok(AnaglyphMode::Optimized),
+            "dubois" | "dubois-red-cyan" => Ok(AnaglyphMode::DuboisRedCyan),
+            "dubois-green-magenta" => Ok(AnaglyphMode::DuboisGreenMagenta),
+            "dubois-amber-blue" => Ok(AnaglyphMode::DuboisAmberBlue),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+const LUMINANCE: [f32; 3] = [0.299, 0.587, 0.114];
+
+impl AnaglyphMode {
+    pub fn matrix(&self) -> AnaglyphMatrix {
+        let [lr, lg, lb] = LUMINANCE;
+        match self {
+            AnaglyphMode::Color => [
+                [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+            ],
+            AnaglyphMode::HalfColor => [
+                [lr, lg, lb, 0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+            ],
+            AnaglyphMode::Optimized => [
+                [0.0, 0.7, 0.3, 0.0, 0.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 0.0, 0.0, 1.0],
+            ],
+            AnaglyphMode::DuboisRedCyan => [
+                [0.437, 0.449, 0.164, -0.011, -0.032, -0.007],
+                [-0.062, -0.062, -0.024, 0.377, 0.761, 0.009],
+                [-0.048, -0.050, -0.017, -0.026, -0.093, 1.234],
+            ],
+            AnaglyphMode::DuboisGreenMagenta => [
+                [-0.062, -0.158, -0.039, 0.529, 0.705, 0.024],
+                [0.284, 0.668, 0.143, -0.016, -0.015, -0.065],
+                [-0.015, -0.027, 0.021, 0.009, 0.075, 0.937],
+            ],
+            AnaglyphMode::DuboisAmberBlue => [
+                [1.062, -0.205, 0.299, -0.016, -0.123, -0.017],
+                [-0.026, 0.908, 0.068, 0.006, 0.062, -0.017],
+                [-0.038, -0.173, 0.022, 0.094, 0.185, 0.911],
+            ],
+            AnaglyphMode::Custom(m) => *m,
+        }
+    }
+}
+
+/// Parses a matrix of 18 comma separated weights, row by row
+pub fn parse_matrix(s: &str) -> error::Result<AnaglyphMatrix> {
+    let values: Vec<f32> = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| constants::status::INVALID_FLOAT_VALUE)?;
+    if values.len() != 18 {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+    let mut m = [[0.0; 6]; 3];
+    for (i, v) in values.into_iter().enumerate() {
+        m[i / 6][i % 6] = v;
+    }
+    Ok(m)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnaglyphOptions {
+    pub mode: AnaglyphMode,
+
+    /// Pixels the right eye is moved to the right before mixing. Moving it right brings the
+    /// scene forward, towards the screen.
+    pub parallax: i32,
+
+    /// Sets the parallax so the center of the image has none
+    pub auto_converge: bool,
+}
+
+impl Default for AnaglyphOptions {
+    fn default() -> Self {
+        AnaglyphOptions {
+            mode: AnaglyphMode::Color,
+            parallax: 0,
+            auto_converge: false,
+        }
+    }
+}
+
+fn luminance_at(img: &RgbImage, x: usize, y: usize) -> Option<f32> {
+    if !img.get_alpha_at(x, y) {
+        return None;
+    }
+    Some(
+        (0..3)
+            .map(|b| img.get_band(b).get(x, y).unwrap() * LUMINANCE[b])
+            .sum(),
+    )
+}
+
+/// The parallax, in pixels, that best lines up the right eye with the left over the center of
+/// the image, searched up to an eighth of the width either way
+pub fn center_parallax(left: &RgbImage, right: &RgbImage) -> i32 {
+    let (w, h) = (left.width, left.height);
+    let max_shift = (w / 8) as i32;
+    let (x0, x1) = (w * 3 / 8, w * 5 / 8);
+    let (y0, y1) = (h * 3 / 8, h * 5 / 8);
+
+    // Normalized cross correlation, so exposure differences between the eyes don't matter
+    let score = |shift: i32| -> Option<f64> {
+        let mut pairs = vec![];
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let rx = x as i32 - shift;
+                if rx < 0 || rx >= w as i32 {
+                    continue;
+                }
+                if let (Some(l), Some(r)) = (
+                    luminance_at(left, x, y),
+                    luminance_at(right, rx as usize, y),
+                ) {
+                    pairs.push((l as f64, r as f64));
+                }
+            }
+        }
+        if pairs.len() < 16 {
+            return None;
+        }
+        let n = pairs.len() as f64;
+        let (ml, mr) = pairs
+            .iter()
+            .fold((0.0, 0.0), |(a, b), (l, r)| (a + l / n, b + r / n));
+        let (mut slr, mut sll, mut srr) = (0.0, 0.0, 0.0);
+        for (l, r) in pairs.iter() {
+            slr += (l - ml) * (r - mr);
+            sll += (l - ml) * (l - ml);
+            srr += (r - mr) * (r - mr);
+        }
+        if sll <= 0.0 || srr <= 0.0 {
+            None
+        } else {
+            Some(slr / (sll * srr).sqrt())
+        }
+    };
+
+    (-max_shift..=max_shift)
+        .filter_map(|s| score(s).map(|c| (s, c)))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.abs().cmp(&a.0.abs())))
+        .map(|(s, _)| s)
+        .unwrap_or(0)
+}
+
+/// Mixes full color renderings of the left and right eyes into an anaglyph, clamping to
+/// 0 - `max`. Pixels seen by only one eye get that eye's share.
+pub fn combine(left: &RgbImage, right: &RgbImage, options: &AnaglyphOptions, max: f32) -> RgbImage {
+    let parallax = if options.auto_converge {
+        let p = center_parallax(left, right);
+        vprintln!(
+            "Converging on the image center with a parallax of {} pixels",
+            p
+        );
+        p
+    } else {
+        options.parallax
+    };
+    let m = options.mode.matrix();
+
+    let mut out = RgbImage::create_masked(left.width, left.height, false);
+    for y in 0..left.height {
+        for x in 0..left.width {
+            let rx = x as i32 - parallax;
+            let l = (0..3)
+                .map(|b| left.get_band(b).get(x, y).unwrap())
+                .collect::<Vec<f32>>();
+            let r = if rx >= 0 && (rx as usize) < right.width {
+                (0..3)
+                    .map(|b| right.get_band(b).get(rx as usize, y).unwrap())
+                    .collect::<Vec<f32>>()
+            } else {
+                vec![0.0; 3]
+            };
+            let has_left = left.get_alpha_at(x, y);
+            let has_right =
+                rx >= 0 && (rx as usize) < right.width && right.get_alpha_at(rx as usize, y);
+            if !has_left && !has_right {
+                continue;
+            }
+            out.put_alpha(x, y, true);
+            for (band, row) in m.iter().enumerate() {
+                let mut v = 0.0;
+                for c in 0..3 {
+                    if has_left {
+                        v += row[c] * l[c];
+                    }
+                    if has_right {
+                        v += row[c + 3] * r[c];
+                    }
+                }
+                out.put(x, y, v.clamp(0.0, max), band);
+            }
+        }
+    }
+    out
+}
+
+/// Copy of an image without its alpha channel, black where it was transparent, so it saves as
+/// plain RGB
+pub fn flatten(img: &RgbImage) -> RgbImage {
+    let mut out = RgbImage::new_with_bands(img.width, img.height, 3, img.get_mode()).unwrap();
+    for y in 0..img.height {
+        for x in 0..img.width {
+            if img.get_alpha_at(x, y) {
+                for b in 0..3 {
+                    out.put(x, y, img.get_band(b).get(x, y).unwrap(), b);
+                }
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,768 @@
+use crate::{constants, drawable::Drawable, enums::Eye, vprintln};
+
+use sciimg::prelude::*;
+
+use std::str::FromStr;
+
+/// How overlapping images are combined into a mosaic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Later images are painted over earlier ones
+    Overwrite,
+
+    /// Images are weighted by their distance from the edge of their footprint
+    Feather,
+
+    /// Laplacian pyramid blending. Low frequencies are blended over wide transitions and
+    /// fine detail over narrow ones, hiding seams without ghosting.
+    Multiband,
+}
+
+impl FromStr for BlendMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<BlendMode, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "overwrite" | "none" => Ok(BlendMode::Overwrite),
+            "feather" => Ok(BlendMode::Feather),
+            "multiband" | "pyramid" => Ok(BlendMode::Multiband),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Per-image exposure correction estimated from the overlaps of a mosaic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExposureCompensation {
+    None,
+    Gain,
+    GainOffset,
+}
+
+impl FromStr for ExposureCompensation {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ExposureCompensation, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "none" => Ok(ExposureCompensation::None),
+            "gain" => Ok(ExposureCompensation::Gain),
+            "gainoffset" => Ok(ExposureCompensation::GainOffset),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Standard deviation of the difference between overlapping pixels, as a fraction of the mean
+/// brightness of the overlaps
+const SIGMA_NOISE: f64 = 0.04;
+
+/// Standard deviation of the prior keeping gains near one
+const SIGMA_GAIN: f64 = 0.1;
+
+/// Standard deviation of the prior keeping offsets near zero, as a fraction of the mean
+/// brightness of the overlaps
+const SIGMA_OFFSET: f64 = 0.04;
+
+/// One image projected onto the mosaic, stored at the size of its bounding box
+pub struct Layer {
+    /// Position of the layer's top left corner in the mosaic
+    pub x: usize,
+    pub y: usize,
+    /// Projected image, with alpha set where the input covers the mosaic
+    pub image: RgbImage,
+    pub eye: Eye,
+}
+
+impl Layer {
+    pub fn new(x: usize, y: usize, width: usize, height: usize, eye: Eye) -> Layer {
+        Layer {
+            x,
+            y,
+            image: RgbImage::create_masked(width, height, false),
+            eye,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.image.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.image.height
+    }
+
+    /// Whether the layer covers a mosaic pixel
+    pub fn covers(&self, mx: usize, my: usize) -> bool {
+        mx >= self.x
+            && my >= self.y
+            && mx < self.x + self.width()
+            && my < self.y + self.height()
+            && self.image.get_alpha_at(mx - self.x, my - self.y)
+    }
+
+    /// Mean of the bands at a mosaic pixel covered by the layer
+    fn luminance_at(&self, mx: usize, my: usize) -> f64 {
+        let n = self.image.num_bands();
+        (0..n)
+            .map(|b| {
+                self.image
+                    .get_band(b)
+                    .get(mx - self.x, my - self.y)
+                    .unwrap() as f64
+            })
+            .sum::<f64>()
+            / n as f64
+    }
+}
+
+/// A single band of floating point samples
+#[derive(Debug, Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Plane {
+        Plane {
+            width,
+            height,
+            data: vec![0.0; width * height],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    fn get_clamped(&self, x: i64, y: i64) -> f32 {
+        let cx = x.clamp(0, self.width as i64 - 1) as usize;
+        let cy = y.clamp(0, self.height as i64 - 1) as usize;
+        self.get(cx, cy)
+    }
+
+    fn put(&mut self, x: usize, y: usize, v: f32) {
+        self.data[y * self.width + x] = v;
+    }
+
+    fn multiply(&self, other: &Plane) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(other.data.iter())
+                .map(|(a, b)| a * b)
+                .collect(),
+        }
+    }
+
+    fn subtract(&self, other: &Plane) -> Plane {
+        Plane {
+            width: self.width,
+            height: self.height,
+            data: self
+                .data
+                .iter()
+                .zip(other.data.iter())
+                .map(|(a, b)| a - b)
+                .collect(),
+        }
+    }
+
+    /// Separable 5-tap binomial blur followed by dropping every other row and column
+    fn reduce(&self) -> Plane {
+        const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let w = self.width.div_ceil(2);
+        let h = self.height.div_ceil(2);
+
+        let mut horiz = Plane::new(w, self.height);
+        for y in 0..self.height {
+            for x in 0..w {
+                let sx = (x * 2) as i64;
+                let v: f32 = KERNEL
+                    .iter()
+                    .enumerate()
+                    .map(|(i, k)| k * self.get_clamped(sx + i as i64 - 2, y as i64))
+                    .sum();
+                horiz.put(x, y, v);
+            }
+        }
+
+        let mut reduced = Plane::new(w, h);
+        for y in 0..h {
+            for x in 0..w {
+                let sy = (y * 2) as i64;
+                let v: f32 = KERNEL
+                    .iter()
+                    .enumerate()
+                    .map(|(i, k)| k * horiz.get_clamped(x as i64, sy + i as i64 - 2))
+                    .sum();
+                reduced.put(x, y, v);
+            }
+        }
+        reduced
+    }
+
+    /// Bilinear upsampling to twice the size, cropped to `width` x `height`. Even samples
+    /// land on the samples `reduce` kept.
+    fn expand(&self, width: usize, height: usize) -> Plane {
+        let mut expanded = Plane::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let x0 = (x / 2) as i64;
+                let y0 = (y / 2) as i64;
+                let x1 = if x & 1 == 1 { x0 + 1 } else { x0 };
+                let y1 = if y & 1 == 1 { y0 + 1 } else { y0 };
+                let v = (self.get_clamped(x0, y0)
+                    + self.get_clamped(x1, y0)
+                    + self.get_clamped(x0, y1)
+                    + self.get_clamped(x1, y1))
+                    / 4.0;
+                expanded.put(x, y, v);
+            }
+        }
+        expanded
+    }
+
+    /// Fills samples without coverage from the surrounding covered samples by averaging
+    /// down a pyramid and interpolating back up ("push-pull")
+    fn fill(&mut self, coverage: &Plane) {
+        if coverage.data.iter().all(|c| *c > 0.0) || coverage.data.iter().all(|c| *c <= 0.0) {
+            return;
+        }
+        let weighted = self.multiply(coverage).reduce();
+        let reduced_coverage = coverage.reduce();
+        let mut coarse = Plane {
+            width: weighted.width,
+            height: weighted.height,
+            data: weighted
+                .data
+                .iter()
+                .zip(reduced_coverage.data.iter())
+                .map(|(v, c)| if *c > 0.0 { v / c } else { 0.0 })
+                .collect(),
+        };
+        if coarse.width > 1 || coarse.height > 1 {
+            coarse.fill(&reduced_coverage);
+        }
+        let expanded = coarse.expand(self.width, self.height);
+        for i in 0..self.data.len() {
+            if coverage.data[i] <= 0.0 {
+                self.data[i] = expanded.data[i];
+            }
+        }
+    }
+}
+
+/// Distance from each covered pixel of a layer to the edge of its footprint, the weight used
+/// for feathering. Computed with a two pass chamfer transform.
+fn feather_weights(layer: &Layer) -> Plane {
+    let w = layer.width();
+    let h = layer.height();
+    let diag = std::f32::consts::SQRT_2;
+
+    let mut dist = Plane::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            if layer.image.get_alpha_at(x, y) {
+                dist.put(x, y, f32::MAX);
+            }
+        }
+    }
+
+    // Pixels beyond the bounding box count as outside the footprint
+    let at = |d: &Plane, x: i64, y: i64| -> f32 {
+        if x < 0 || y < 0 || x >= w as i64 || y >= h as i64 {
+            0.0
+        } else {
+            d.get(x as usize, y as usize)
+        }
+    };
+
+    for y in 0..h as i64 {
+        for x in 0..w as i64 {
+            let v = dist.get(x as usize, y as usize);
+            if v > 0.0 {
+                let m = v
+                    .min(at(&dist, x - 1, y) + 1.0)
+                    .min(at(&dist, x, y - 1) + 1.0)
+                    .min(at(&dist, x - 1, y - 1) + diag)
+                    .min(at(&dist, x + 1, y - 1) + diag);
+                dist.put(x as usize, y as usize, m);
+            }
+        }
+    }
+    for y in (0..h as i64).rev() {
+        for x in (0..w as i64).rev() {
+            let v = dist.get(x as usize, y as usize);
+            if v > 0.0 {
+                let m = v
+                    .min(at(&dist, x + 1, y) + 1.0)
+                    .min(at(&dist, x, y + 1) + 1.0)
+                    .min(at(&dist, x + 1, y + 1) + diag)
+                    .min(at(&dist, x - 1, y + 1) + diag);
+                dist.put(x as usize, y as usize, m);
+            }
+        }
+    }
+    dist
+}
+
+/// Solves a dense linear system by Gaussian elimination with partial pivoting
+pub(crate) fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        for row in col + 1..n {
+            let f = a[row][col] / a[col][col];
+            let pivot_row = a[col].clone();
+            for (v, p) in a[row].iter_mut().zip(pivot_row.iter()).skip(col) {
+                *v -= f * p;
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - s) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Sums over the pixels two layers share, enough to express the squared difference of any
+/// gain and offset applied to each
+#[derive(Default)]
+struct OverlapStats {
+    n: f64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_yy: f64,
+    sum_xy: f64,
+}
+
+fn overlap_stats(a: &Layer, b: &Layer) -> OverlapStats {
+    let mut stats = OverlapStats::default();
+    let x0 = a.x.max(b.x);
+    let y0 = a.y.max(b.y);
+    let x1 = (a.x + a.width()).min(b.x + b.width());
+    let y1 = (a.y + a.height()).min(b.y + b.height());
+    for my in y0..y1.max(y0) {
+        for mx in x0..x1.max(x0) {
+            if a.covers(mx, my) && b.covers(mx, my) {
+                let x = a.luminance_at(mx, my);
+                let y = b.luminance_at(mx, my);
+                stats.n += 1.0;
+                stats.sum_x += x;
+                stats.sum_y += y;
+                stats.sum_xx += x * x;
+                stats.sum_yy += y * y;
+                stats.sum_xy += x * y;
+            }
+        }
+    }
+    stats
+}
+
+/// Estimates a gain and offset for each layer that minimize the differences between
+/// overlapping layers across the whole mosaic (Brown & Lowe, 2007), with priors keeping gains
+/// near one and offsets near zero. Layers without overlaps are left unchanged.
+pub fn estimate_exposure(layers: &[Layer], mode: ExposureCompensation) -> Vec<(f32, f32)> {
+    let n = layers.len();
+    let identity = vec![(1.0, 0.0); n];
+    if mode == ExposureCompensation::None || n < 2 {
+        return identity;
+    }
+
+    let mut pairs = vec![];
+    for i in 0..n {
+        for j in i + 1..n {
+            let s = overlap_stats(&layers[i], &layers[j]);
+            if s.n > 0.0 {
+                pairs.push((i, j, s));
+            }
+        }
+    }
+    let total: f64 = pairs.iter().map(|(_, _, s)| s.n * 2.0).sum();
+    if total == 0.0 {
+        vprintln!("No overlaps between images, skipping exposure compensation");
+        return identity;
+    }
+    let level = pairs.iter().map(|(_, _, s)| s.sum_x + s.sum_y).sum::<f64>() / total;
+    if level <= 0.0 {
+        return identity;
+    }
+
+    // Unknowns are the gains, followed by the offsets when solving for them
+    let with_offset = mode == ExposureCompensation::GainOffset;
+    let unknowns = if with_offset { n * 2 } else { n };
+    let mut a = vec![vec![0.0; unknowns]; unknowns];
+    let mut b = vec![0.0; unknowns];
+    let mut overlap = vec![0.0; n];
+    let noise = 1.0 / (SIGMA_NOISE * level).powi(2);
+
+    for (i, j, s) in pairs.iter() {
+        overlap[*i] += s.n;
+        overlap[*j] += s.n;
+
+        let mut add = |r: usize, c: usize, v: f64| {
+            a[r][c] += v * noise;
+            if r != c {
+                a[c][r] += v * noise;
+            }
+        };
+        add(*i, *i, s.sum_xx);
+        add(*j, *j, s.sum_yy);
+        add(*i, *j, -s.sum_xy);
+        if with_offset {
+            let (oi, oj) = (n + i, n + j);
+            add(*i, oi, s.sum_x);
+            add(oi, oi, s.n);
+            add(*j, oj, s.sum_y);
+            add(oj, oj, s.n);
+            add(*i, oj, -s.sum_x);
+            add(oi, *j, -s.sum_y);
+            add(oi, oj, -s.n);
+        }
+    }
+
+    for i in 0..n {
+        let prior = overlap[i].max(1.0);
+        a[i][i] += prior / SIGMA_GAIN.powi(2);
+        b[i] += prior / SIGMA_GAIN.powi(2);
+        if with_offset {
+            a[n + i][n + i] += prior / (SIGMA_OFFSET * level).powi(2);
+        }
+    }
+
+    match solve(a, b) {
+        Some(x) => (0..n)
+            .map(|i| {
+                let offset = if with_offset { x[n + i] } else { 0.0 };
+                vprintln!("Image {} gain {:.4}, offset {:.4}", i, x[i], offset);
+                (x[i] as f32, offset as f32)
+            })
+            .collect(),
+        None => {
+            vprintln!("Unable to solve for exposure compensation");
+            identity
+        }
+    }
+}
+
+/// Applies a gain and offset to the covered pixels of each layer
+pub fn apply_exposure(layers: &mut [Layer], compensation: &[(f32, f32)]) {
+    for (layer, (gain, offset)) in layers.iter_mut().zip(compensation.iter()) {
+        for b in 0..layer.image.num_bands() {
+            let mut band = layer.image.get_band(b).clone();
+            for y in 0..band.height {
+                for x in 0..band.width {
+                    if layer.image.get_alpha_at(x, y) {
+                        band.put(x, y, (band.get(x, y).unwrap() * gain + offset).max(0.0));
+                    }
+                }
+            }
+            layer.image.set_band(&band, b);
+        }
+    }
+}
+
+/// Layer bands and coverage as planes, padded to `width` x `height`
+fn layer_planes(layer: &Layer, width: usize, height: usize) -> (Vec<Plane>, Plane) {
+    let mut bands: Vec<Plane> = (0..3).map(|_| Plane::new(width, height)).collect();
+    let mut coverage = Plane::new(width, height);
+    for y in 0..layer.height().min(height) {
+        for x in 0..layer.width().min(width) {
+            if layer.image.get_alpha_at(x, y) {
+                coverage.put(x, y, 1.0);
+                for (b, band) in bands.iter_mut().enumerate() {
+                    let src = b.min(layer.image.num_bands() - 1);
+                    band.put(x, y, layer.image.get_band(src).get(x, y).unwrap());
+                }
+            }
+        }
+    }
+    (bands, coverage)
+}
+
+fn overwrite(layers: &[&Layer], width: usize, height: usize) -> RgbImage {
+    let mut map = RgbImage::create_masked(width, height, false);
+    for layer in layers.iter() {
+        for y in 0..layer.height() {
+            for x in 0..layer.width() {
+                let (mx, my) = (layer.x + x, layer.y + y);
+                if mx < width && my < height && layer.image.get_alpha_at(x, y) {
+                    for b in 0..3 {
+                        let v = layer.image.get_band(b).get(x, y).unwrap();
+                        map.put(mx, my, v, b);
+                    }
+                    map.put_alpha(mx, my, true);
+                }
+            }
+        }
+    }
+    map
+}
+
+fn feather(layers: &[&Layer], width: usize, height: usize) -> RgbImage {
+    let mut sums: Vec<Plane> = (0..3).map(|_| Plane::new(width, height)).collect();
+    let mut weights = Plane::new(width, height);
+
+    for layer in layers.iter() {
+        let w = feather_weights(layer);
+        for y in 0..layer.height() {
+            for x in 0..layer.width() {
+                let (mx, my) = (layer.x + x, layer.y + y);
+                let wv = w.get(x, y);
+                if mx < width && my < height && wv > 0.0 {
+                    for (b, sum) in sums.iter_mut().enumerate() {
+                        let v = layer.image.get_band(b).get(x, y).unwrap();
+                        sum.put(mx, my, sum.get(mx, my) + v * wv);
+                    }
+                    weights.put(mx, my, weights.get(mx, my) + wv);
+                }
+            }
+        }
+    }
+
+    let mut map = RgbImage::create_masked(width, height, false);
+    for y in 0..height {
+        for x in 0..width {
+            let wv = weights.get(x, y);
+            if wv > 0.0 {
+                for (b, sum) in sums.iter().enumerate() {
+                    map.put(x, y, sum.get(x, y) / wv, b);
+                }
+                map.put_alpha(x, y, true);
+            }
+        }
+    }
+    map
+}
+
+fn multiband(layers: &[&Layer], width: usize, height: usize, levels: usize) -> RgbImage {
+    let levels = levels.max(1);
+    let align = 1 << (levels - 1);
+
+    // Each mosaic pixel is owned by the layer it lies deepest inside of
+    let mut best = Plane::new(width, height);
+    let mut owner: Vec<i64> = vec![-1; width * height];
+    for (i, layer) in layers.iter().enumerate() {
+        let w = feather_weights(layer);
+        for y in 0..layer.height() {
+            for x in 0..layer.width() {
+                let (mx, my) = (layer.x + x, layer.y + y);
+                if mx < width && my < height && w.get(x, y) > best.get(mx, my) {
+                    best.put(mx, my, w.get(x, y));
+                    owner[my * width + mx] = i as i64;
+                }
+            }
+        }
+    }
+
+    let level_sizes: Vec<(usize, usize)> = (0..levels)
+        .map(|k| (width.div_ceil(1 << k), height.div_ceil(1 << k)))
+        .collect();
+
+    let mut sums: Vec<Vec<Plane>> = level_sizes
+        .iter()
+        .map(|(w, h)| (0..3).map(|_| Plane::new(*w, *h)).collect())
+        .collect();
+    let mut weights: Vec<Plane> = level_sizes
+        .iter()
+        .map(|(w, h)| Plane::new(*w, *h))
+        .collect();
+
+    for (i, layer) in layers.iter().enumerate() {
+        vprintln!("Blending layer {} of {}", i + 1, layers.len());
+
+        // Align the layer to the coarsest level so every level lines up with the mosaic
+        let x0 = layer.x - layer.x % align;
+        let y0 = layer.y - layer.y % align;
+        let pw = (layer.x + layer.width() - x0).div_ceil(align) * align;
+        let ph = (layer.y + layer.height() - y0).div_ceil(align) * align;
+
+        let mut shifted = Layer::new(0, 0, pw, ph, layer.eye);
+        for y in 0..layer.height() {
+            for x in 0..layer.width() {
+                let (sx, sy) = (layer.x - x0 + x, layer.y - y0 + y);
+                if layer.image.get_alpha_at(x, y) {
+                    for b in 0..3 {
+                        shifted
+                            .image
+                            .put(sx, sy, layer.image.get_band(b).get(x, y).unwrap(), b);
+                    }
+                    shifted.image.put_alpha(sx, sy, true);
+                }
+            }
+        }
+        let (mut bands, coverage) = layer_planes(&shifted, pw, ph);
+        for band in bands.iter_mut() {
+            band.fill(&coverage);
+        }
+
+        let mut mask = Plane::new(pw, ph);
+        for y in 0..ph {
+            for x in 0..pw {
+                let (mx, my) = (x0 + x, y0 + y);
+                if mx < width && my < height && owner[my * width + mx] == i as i64 {
+                    mask.put(x, y, 1.0);
+                }
+            }
+        }
+
+        let mut mask_level = mask.multiply(&coverage);
+        let mut coverage_level = coverage;
+        let mut gaussians = bands;
+        for k in 0..levels {
+            let (next_gaussians, laplacians) = if k + 1 < levels {
+                let next: Vec<Plane> = gaussians.iter().map(|g| g.reduce()).collect();
+                let lap: Vec<Plane> = gaussians
+                    .iter()
+                    .zip(next.iter())
+                    .map(|(g, n)| g.subtract(&n.expand(g.width, g.height)))
+                    .collect();
+                (next, lap)
+            } else {
+                (vec![], gaussians.clone())
+            };
+
+            let weight = mask_level.multiply(&coverage_level);
+            let (lw, lh) = level_sizes[k];
+            let (ox, oy) = (x0 >> k, y0 >> k);
+            for y in 0..weight.height {
+                for x in 0..weight.width {
+                    let (mx, my) = (ox + x, oy + y);
+                    let wv = weight.get(x, y);
+                    if mx < lw && my < lh && wv > 0.0 {
+                        for (b, lap) in laplacians.iter().enumerate() {
+                            let s = &mut sums[k][b];
+                            s.put(mx, my, s.get(mx, my) + lap.get(x, y) * wv);
+                        }
+                        let prev = weights[k].get(mx, my);
+                        weights[k].put(mx, my, prev + wv);
+                    }
+                }
+            }
+
+            if k + 1 < levels {
+                mask_level = mask_level.reduce();
+                coverage_level = coverage_level.reduce();
+                gaussians = next_gaussians;
+            }
+        }
+    }
+
+    // Collapse the blended pyramid from the coarsest level. Samples no layer contributed to
+    // are filled so they don't pull down their neighbors when expanded.
+    let normalized = |k: usize, b: usize| -> Plane {
+        let s = &sums[k][b];
+        let mut plane = Plane {
+            width: s.width,
+            height: s.height,
+            data: s
+                .data
+                .iter()
+                .zip(weights[k].data.iter())
+                .map(|(v, w)| if *w > 0.0 { v / w } else { 0.0 })
+                .collect(),
+        };
+        let coverage = Plane {
+            width: s.width,
+            height: s.height,
+            data: weights[k]
+                .data
+                .iter()
+                .map(|w| if *w > 0.0 { 1.0 } else { 0.0 })
+                .collect(),
+        };
+        plane.fill(&coverage);
+        plane
+    };
+
+    let mut map = RgbImage::create_masked(width, height, false);
+    for b in 0..3 {
+        let mut result = normalized(levels - 1, b);
+        for k in (0..levels - 1).rev() {
+            let (lw, lh) = level_sizes[k];
+            let lap = normalized(k, b);
+            let expanded = result.expand(lw, lh);
+            result = Plane {
+                width: lw,
+                height: lh,
+                data: expanded
+                    .data
+                    .iter()
+                    .zip(lap.data.iter())
+                    .map(|(e, l)| e + l)
+                    .collect(),
+            };
+        }
+        for y in 0..height {
+            for x in 0..width {
+                if owner[y * width + x] >= 0 {
+                    map.put(x, y, result.get(x, y).max(0.0), b);
+                    map.put_alpha(x, y, true);
+                }
+            }
+        }
+    }
+    map
+}
+
+fn blend_group(
+    layers: &[&Layer],
+    width: usize,
+    height: usize,
+    mode: BlendMode,
+    levels: usize,
+) -> RgbImage {
+    match mode {
+        BlendMode::Overwrite => overwrite(layers, width, height),
+        BlendMode::Feather => feather(layers, width, height),
+        BlendMode::Multiband => multiband(layers, width, height, levels),
+    }
+}
+
+/// Combines layers into a mosaic of `width` x `height`. `levels` is the number of pyramid
+/// levels used by multiband blending. When layers are tagged with an eye, each eye is blended
+/// separately and the two mosaics are combined into a red/cyan anaglyph.
+pub fn blend(
+    layers: &[Layer],
+    width: usize,
+    height: usize,
+    mode: BlendMode,
+    levels: usize,
+) -> RgbImage {
+    if layers.iter().all(|l| l.eye == Eye::DontCare) {
+        let all: Vec<&Layer> = layers.iter().collect();
+        return blend_group(&all, width, height, mode, levels);
+    }
+
+    let left: Vec<&Layer> = layers.iter().filter(|l| l.eye != Eye::Right).collect();
+    let right: Vec<&Layer> = layers.iter().filter(|l| l.eye != Eye::Left).collect();
+    let mut map = blend_group(&left, width, height, mode, levels);
+    let right_map = blend_group(&right, width, height, mode, levels);
+    for y in 0..height {
+        for x in 0..width {
+            if right_map.get_alpha_at(x, y) {
+                map.put(x, y, right_map.get_band(1).get(x, y).unwrap(), 1);
+                map.put(x, y, right_map.get_band(2).get(x, y).unwrap(), 2);
+                map.put_alpha(x, y, true);
+            } else {
+                map.put(x, y, 0.0, 1);
+                map.put(x, y, 0.0, 2);
+            }
+        }
+    }
+    map
+}
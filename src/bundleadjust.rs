@@ -0,0 +1,467 @@
+use crate::{
+    blend, constants,
+    features::{self, DetectorOptions, Feature, MatchOptions},
+    image::MarsImage,
+    prelude::*,
+    util, vprintln,
+};
+
+use rayon::prelude::*;
+use sciimg::{prelude::*, quaternion::Quaternion, vector::Vector};
+
+/// A point seen in two images, as pixel coordinates (x, y) in each
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TiePoint {
+    pub image_a: usize,
+    pub image_b: usize,
+    pub a: (f64, f64),
+    pub b: (f64, f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdjustOptions {
+    pub detector: DetectorOptions,
+    pub matcher: MatchOptions,
+    /// Largest disagreement, in pixels, between a match and where the published pointing puts
+    /// it
+    pub max_error: f64,
+    /// Fewest consistent matches needed to tie two images together
+    pub min_matches: usize,
+    /// Image whose pointing is held fixed
+    pub fixed_image: usize,
+    pub iterations: usize,
+}
+
+impl Default for AdjustOptions {
+    fn default() -> Self {
+        AdjustOptions {
+            detector: DetectorOptions::default(),
+            matcher: MatchOptions::default(),
+            max_error: 40.0,
+            min_matches: 8,
+            fixed_image: 0,
+            iterations: 10,
+        }
+    }
+}
+
+/// Samples per side of the grid used to check whether two images overlap
+const OVERLAP_SAMPLES: usize = 5;
+
+/// Standard deviation of the prior keeping corrections small, in radians
+const SIGMA_ROTATION: f64 = 0.02;
+
+/// Residual, in pixels, beyond which tie points are down-weighted
+const HUBER_PIXELS: f64 = 2.0;
+
+fn look_direction(model: &CameraModel, (x, y): (f64, f64)) -> Option<Vector> {
+    model
+        .ls_to_look_vector(&ImageCoordinate { line: y, sample: x })
+        .ok()
+        .map(|lv| lv.look_direction.normalized())
+}
+
+/// Pixel coordinates (x, y) where a model sees a direction from its camera center
+fn direction_to_xy(model: &CameraModel, direction: &Vector) -> Option<(f64, f64)> {
+    if direction.dot_product(&model.a()) <= 0.0 {
+        return None;
+    }
+    let ic = model.xyz_to_ls(&model.c().add(&direction.scale(1000.0)), false);
+    Some((ic.sample, ic.line))
+}
+
+/// Where a pixel of image a lands in image b, going by their camera models
+pub fn predict(
+    model_a: &CameraModel,
+    model_b: &CameraModel,
+    point: (f64, f64),
+) -> Option<(f64, f64)> {
+    direction_to_xy(model_b, &look_direction(model_a, point)?)
+}
+
+fn overlaps(
+    model_a: &CameraModel,
+    size_a: (usize, usize),
+    model_b: &CameraModel,
+    size_b: (usize, usize),
+) -> bool {
+    (0..OVERLAP_SAMPLES).any(|i| {
+        (0..OVERLAP_SAMPLES).any(|j| {
+            let x = size_a.0 as f64 * i as f64 / (OVERLAP_SAMPLES - 1) as f64;
+            let y = size_a.1 as f64 * j as f64 / (OVERLAP_SAMPLES - 1) as f64;
+            predict(model_a, model_b, (x, y)).is_some_and(|(bx, by)| {
+                bx >= 0.0 && by >= 0.0 && bx < size_b.0 as f64 && by < size_b.1 as f64
+            })
+        })
+    })
+}
+
+/// Pixel coordinates (x, y) of a feature matched between two images
+type PixelPair = ((f64, f64), (f64, f64));
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    values[values.len() / 2]
+}
+
+/// Keeps matches that agree with the camera models to within `max_error` pixels and with each
+/// other, since a small pointing error shifts every match between two images alike
+fn consistent_matches(
+    model_a: &CameraModel,
+    model_b: &CameraModel,
+    pairs: Vec<PixelPair>,
+    max_error: f64,
+) -> Vec<PixelPair> {
+    let with_offsets: Vec<(PixelPair, (f64, f64))> = pairs
+        .into_iter()
+        .filter_map(|(a, b)| {
+            let (px, py) = predict(model_a, model_b, a)?;
+            let offset = (b.0 - px, b.1 - py);
+            if offset.0.hypot(offset.1) <= max_error {
+                Some(((a, b), offset))
+            } else {
+                None
+            }
+        })
+        .collect();
+    if with_offsets.is_empty() {
+        return vec![];
+    }
+
+    let mx = median(&mut with_offsets.iter().map(|(_, o)| o.0).collect::<Vec<f64>>());
+    let my = median(&mut with_offsets.iter().map(|(_, o)| o.1).collect::<Vec<f64>>());
+    let deviations: Vec<f64> = with_offsets
+        .iter()
+        .map(|(_, o)| (o.0 - mx).hypot(o.1 - my))
+        .collect();
+    let limit = 3.0 * median(&mut deviations.clone()) + 1.0;
+
+    with_offsets
+        .into_iter()
+        .zip(deviations)
+        .filter(|(_, d)| *d <= limit)
+        .map(|(m, _)| m.0)
+        .collect()
+}
+
+/// Finds tie points between every pair of overlapping images. Images are given by their
+/// luminance (see `features::luminance`) and camera model.
+pub fn find_tie_points(
+    images: &[ImageBuffer],
+    models: &[CameraModel],
+    options: &AdjustOptions,
+) -> Vec<TiePoint> {
+    let detected: Vec<Vec<Feature>> = images
+        .par_iter()
+        .map(|img| features::detect_features(img, &options.detector))
+        .collect();
+    for (i, f) in detected.iter().enumerate() {
+        vprintln!("Image {}: {} features", i, f.len());
+    }
+
+    let mut candidates: Vec<(usize, usize)> = vec![];
+    for a in 0..images.len() {
+        for b in (a + 1)..images.len() {
+            if overlaps(
+                &models[a],
+                (images[a].width, images[a].height),
+                &models[b],
+                (images[b].width, images[b].height),
+            ) {
+                candidates.push((a, b));
+            }
+        }
+    }
+
+    let matched: Vec<_> = candidates
+        .par_iter()
+        .map(|(a, b)| {
+            (
+                *a,
+                *b,
+                features::match_features(&detected[*a], &detected[*b], &options.matcher),
+            )
+        })
+        .collect();
+
+    let mut ties = vec![];
+    for (a, b, matches) in matched {
+        let point = |f: &Feature| (f.keypoint.x as f64, f.keypoint.y as f64);
+        let pairs = matches
+            .iter()
+            .map(|(ia, ib)| (point(&detected[a][*ia]), point(&detected[b][*ib])))
+            .collect();
+        let consistent = consistent_matches(&models[a], &models[b], pairs, options.max_error);
+        vprintln!(
+            "Images {} and {}: {} matches, {} consistent",
+            a,
+            b,
+            matches.len(),
+            consistent.len()
+        );
+        if consistent.len() >= options.min_matches {
+            ties.extend(consistent.into_iter().map(|(pa, pb)| TiePoint {
+                image_a: a,
+                image_b: b,
+                a: pa,
+                b: pb,
+            }));
+        }
+    }
+    ties
+}
+
+/// Cross product matrix of a vector, so that `[v]x w = v x w`
+fn cross_matrix(v: &Vector) -> [[f64; 3]; 3] {
+    [[0.0, -v.z, v.y], [v.z, 0.0, -v.x], [-v.y, v.x, 0.0]]
+}
+
+/// Solves for the rotation of each camera, about its center, that brings the look directions of
+/// its tie points into agreement with the other images. Rotations are in the frame of the camera
+/// models. The fixed image keeps its pointing and images without tie points are left as they
+/// are.
+pub fn solve_rotations(
+    models: &[CameraModel],
+    ties: &[TiePoint],
+    fixed_image: usize,
+    iterations: usize,
+) -> Vec<Quaternion> {
+    let n = models.len();
+    let mut rotations = vec![Quaternion::default(); n];
+
+    // Unknowns are three small angles for every image but the fixed one
+    let unknown = |i: usize| -> Option<usize> {
+        match i {
+            i if i == fixed_image => None,
+            i if i < fixed_image => Some(i * 3),
+            i => Some((i - 1) * 3),
+        }
+    };
+    let size = n.saturating_sub(1) * 3;
+    if size == 0 {
+        return rotations;
+    }
+
+    let observations: Vec<(&TiePoint, Vector, Vector)> = ties
+        .iter()
+        .filter_map(|t| {
+            Some((
+                t,
+                look_direction(&models[t.image_a], t.a)?,
+                look_direction(&models[t.image_b], t.b)?,
+            ))
+        })
+        .collect();
+
+    // Sum of the steps taken for each image, which the prior keeps small
+    let mut totals = vec![Vector::new(0.0, 0.0, 0.0); n];
+
+    let pixel_angle =
+        models.iter().map(|m| m.pixel_angle_horiz()).sum::<f64>() / models.len() as f64;
+    let huber = HUBER_PIXELS * pixel_angle;
+    let prior = (pixel_angle / SIGMA_ROTATION).powi(2);
+
+    for iteration in 0..iterations {
+        let mut normal = vec![vec![0.0; size]; size];
+        let mut rhs = vec![0.0; size];
+        let mut sum_sq = 0.0;
+
+        for (t, da, db) in observations.iter() {
+            let ua = rotations[t.image_a].rotate_vector(da);
+            let ub = rotations[t.image_b].rotate_vector(db);
+            let r = ua.subtract(&ub);
+            let len = r.len();
+            sum_sq += len * len;
+            let w = if len <= huber { 1.0 } else { huber / len };
+
+            // r(da, db) ~ r - [ua]x da + [ub]x db
+            let ja = cross_matrix(&ua).map(|row| row.map(|v| -v));
+            let jb = cross_matrix(&ub);
+            let r = [r.x, r.y, r.z];
+            let blocks = [(unknown(t.image_a), ja), (unknown(t.image_b), jb)];
+
+            for (ui, ji) in blocks.iter() {
+                let Some(ui) = ui else { continue };
+                for (uk, jk) in blocks.iter() {
+                    let Some(uk) = uk else { continue };
+                    for p in 0..3 {
+                        for q in 0..3 {
+                            let v: f64 = (0..3).map(|k| ji[k][p] * jk[k][q]).sum();
+                            normal[ui + p][uk + q] += w * v;
+                        }
+                    }
+                }
+                for p in 0..3 {
+                    let v: f64 = (0..3).map(|k| ji[k][p] * r[k]).sum();
+                    rhs[ui + p] -= w * v;
+                }
+            }
+        }
+
+        for (i, row) in normal.iter_mut().enumerate() {
+            row[i] += prior;
+        }
+        // The prior pulls the total correction, not just this step, towards zero
+        for (i, total) in totals.iter().enumerate() {
+            if let Some(u) = unknown(i) {
+                rhs[u] -= prior * total.x;
+                rhs[u + 1] -= prior * total.y;
+                rhs[u + 2] -= prior * total.z;
+            }
+        }
+
+        let step = match blend::solve(normal, rhs) {
+            Some(s) => s,
+            None => break,
+        };
+
+        let mut largest: f64 = 0.0;
+        for (i, (rotation, total)) in rotations.iter_mut().zip(totals.iter_mut()).enumerate() {
+            if let Some(u) = unknown(i) {
+                let delta = Vector::new(step[u], step[u + 1], step[u + 2]);
+                *total = total.add(&delta);
+                let angle = delta.len();
+                largest = largest.max(angle);
+                if angle > 0.0 {
+                    *rotation = Quaternion::from_axis_and_angle(&delta, angle)
+                        .times(rotation)
+                        .normalized();
+                }
+            }
+        }
+
+        vprintln!(
+            "Iteration {}: RMS residual {:.3} pixels, largest step {:.5} degrees",
+            iteration,
+            (sum_sq / observations.len().max(1) as f64).sqrt() / pixel_angle,
+            largest.to_degrees()
+        );
+        if largest < pixel_angle * 1e-3 {
+            break;
+        }
+    }
+    rotations
+}
+
+/// A camera model turned about its center
+pub fn rotate_model(model: &CameraModel, rotation: &Quaternion) -> CameraModel {
+    let r = |v: Vector| rotation.rotate_vector(&v);
+    match model.model_type() {
+        ModelType::CAHV => CameraModel::new(Box::new(Cahv {
+            c: model.c(),
+            a: r(model.a()),
+            h: r(model.h()),
+            v: r(model.v()),
+        })),
+        ModelType::CAHVOR => CameraModel::new(Box::new(Cahvor {
+            c: model.c(),
+            a: r(model.a()),
+            h: r(model.h()),
+            v: r(model.v()),
+            o: r(model.o()),
+            r: model.r(),
+        })),
+        ModelType::CAHVORE => {
+            // Linearity isn't exposed by the model, only in its serialized form
+            let linearity = model
+                .serialize()
+                .split(';')
+                .nth(7)
+                .and_then(|l| l.trim().parse::<f64>().ok())
+                .unwrap_or(LINEARITY_PERSPECTIVE);
+            CameraModel::new(Box::new(Cahvore {
+                c: model.c(),
+                a: r(model.a()),
+                h: r(model.h()),
+                v: r(model.v()),
+                o: r(model.o()),
+                r: model.r(),
+                e: model.e(),
+                pupil_type: PupilType::General,
+                linearity,
+            }))
+        }
+    }
+}
+
+/// Finds tie points between overlapping images and solves for the corrected camera model of
+/// each. Models are in the same order as the inputs.
+pub fn adjust(
+    images: &[ImageBuffer],
+    models: &[CameraModel],
+    options: &AdjustOptions,
+) -> (Vec<CameraModel>, Vec<TiePoint>) {
+    let ties = find_tie_points(images, models, options);
+    vprintln!("{} tie points", ties.len());
+    let rotations = solve_rotations(models, &ties, options.fixed_image, options.iterations);
+    let adjusted = models
+        .iter()
+        .zip(rotations.iter())
+        .map(|(m, q)| rotate_model(m, q))
+        .collect();
+    (adjusted, ties)
+}
+
+/// Adjusts the pointing of a set of images and writes the corrected camera models to their
+/// metadata sidecars. Images adjusted before are adjusted again from their published models,
+/// which are kept in `original_camera_model`, so corrections don't accumulate. Returns the
+/// change in pointing of each image from its published model, in degrees.
+pub fn adjust_files(input_files: &[String], options: &AdjustOptions) -> error::Result<Vec<f64>> {
+    if options.fixed_image >= input_files.len() {
+        return Err(constants::status::INDEX_OUT_OF_RANGE);
+    }
+
+    let images: Vec<MarsImage> = input_files
+        .iter()
+        .map(|f| MarsImage::open(f.to_owned(), Instrument::None))
+        .collect();
+
+    let mut models = vec![];
+    for (img, f) in images.iter().zip(input_files.iter()) {
+        match &img.metadata {
+            Some(md) if md.original_camera_model.is_valid() => {
+                vprintln!("Adjusting {} from its published camera model", f);
+                models.push(md.original_camera_model.clone())
+            }
+            Some(md) if md.camera_model_component_list.is_valid() => {
+                models.push(md.camera_model_component_list.clone())
+            }
+            _ => {
+                vprintln!("Camera model not found for {}", f);
+                return Err(constants::status::CAMERA_MODEL_NOT_FOUND);
+            }
+        }
+    }
+
+    let luminance: Vec<ImageBuffer> = images
+        .iter()
+        .map(|img| features::luminance(&img.image))
+        .collect();
+
+    let (adjusted, _) = adjust(&luminance, &models, options);
+
+    let mut changes = vec![];
+    for ((img, f), (before, after)) in images
+        .into_iter()
+        .zip(input_files.iter())
+        .zip(models.into_iter().zip(adjusted))
+    {
+        let change = before
+            .a()
+            .dot_product(&after.a())
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        changes.push(change);
+
+        let mut md = img.metadata.unwrap();
+        md.original_camera_model = before;
+        md.camera_model_component_list = after;
+        md.pointing_adjusted = true;
+        if util::save_image_json(f, &md, false, None).is_err() {
+            vprintln!("Unable to write metadata for {}", f);
+            return Err(constants::status::ERROR_WRITING_METADATA);
+        }
+    }
+    Ok(changes)
+}
@@ -1,17 +1,24 @@
 use std::env;
 
-use crate::{constants, enums, path};
+use crate::{constants, enums, filters::FilterPosition, path, vprintln};
 
 use sciimg::error;
 
 extern crate dirs;
 
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::Read;
 
 //use serde_derive::Deserialize;
 use serde::Deserialize;
 
+/// Per-user overrides, looked for in ~/.marsdata
+pub const CALDATA_LOCAL_FILE: &str = "caldata.local.toml";
+
+/// Per-project overrides, looked for in the current working directory
+pub const PROJECT_CONFIG_FILE: &str = "mru.toml";
+
 #[derive(Deserialize)]
 #[allow(dead_code)]
 pub struct Config {
@@ -27,6 +34,38 @@ pub struct InstrumentProperties {
     pub flat: String,
     pub inpaint_mask: String,
     pub mask: String,
+
+    // Decompanding lookup table file
+    #[serde(default)]
+    pub ilt: Option<String>,
+
+    // Lookup tables for products whose id contains the pattern, checked in order
+    #[serde(default)]
+    pub ilt_products: Vec<IltProduct>,
+
+    // Bayer pattern at the sensor origin ("RGGB", "GRBG", ...)
+    #[serde(default)]
+    pub cfa_pattern: Option<String>,
+
+    // Keyed by filter name ("L1", "R6", ...)
+    #[serde(default)]
+    pub filters: HashMap<String, FilterProperties>,
+}
+
+/// Calibration values specific to a single filter wheel position. Anything left out
+/// falls back to the instrument-wide value.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FilterProperties {
+    pub flat: Option<String>,
+    pub scalar: Option<f32>,
+    pub wavelengths: Option<Vec<f32>>,
+}
+
+/// A lookup table used for products whose id contains `pattern`
+#[derive(Deserialize, Clone, Debug)]
+pub struct IltProduct {
+    pub pattern: String,
+    pub ilt: String,
 }
 
 #[allow(non_snake_case)]
@@ -74,23 +113,177 @@ pub struct NsytCalData {
     pub icc: InstrumentProperties,
 }
 
-pub fn load_caldata_mapping_file() -> error::Result<Config> {
-    let cal_file_path = locate_calibration_file(&String::from("caldata.toml"));
+/// A single configuration file participating in the layered calibration configuration.
+pub struct ConfigLayer {
+    pub origin: String,
+    pub toml: String,
+}
 
-    match cal_file_path {
-        Ok(caldata_toml) => {
-            let mut file = match File::open(&caldata_toml) {
-                Err(why) => panic!("couldn't open {}", why),
-                Ok(file) => file,
-            };
+/// The result of merging the configuration layers. Each leaf value is tracked by its
+/// dotted key path (e.g. `m20.watson.flat`) back to the file that last set it.
+pub struct MergedConfig {
+    pub value: toml::Value,
+    pub origins: BTreeMap<String, String>,
+}
 
-            let mut buf: Vec<u8> = Vec::default();
-            file.read_to_end(&mut buf).unwrap();
-            let toml = String::from_utf8(buf).unwrap();
+impl MergedConfig {
+    pub fn to_config(&self) -> error::Result<Config> {
+        match self.value.clone().try_into::<Config>() {
+            Ok(config) => Ok(config),
+            Err(why) => {
+                eprintln!("Error parsing calibration configuration: {}", why);
+                Err("Error parsing calibration configuration")
+            }
+        }
+    }
 
-            let config: Config = toml::from_str(&toml).unwrap();
+    /// Returns the file that supplied the value at the dotted key path
+    pub fn origin_of(&self, key: &str) -> Option<&String> {
+        self.origins.get(key)
+    }
 
-            Ok(config)
+    /// Returns the merged value at the dotted key path
+    pub fn value_at(&self, key: &str) -> Option<&toml::Value> {
+        let mut v = &self.value;
+        for k in key.split('.') {
+            v = v.get(k)?;
+        }
+        Some(v)
+    }
+}
+
+fn merge_value(
+    into: &mut toml::Value,
+    from: &toml::Value,
+    key_path: &str,
+    origin: &str,
+    origins: &mut BTreeMap<String, String>,
+) {
+    match (into, from) {
+        (toml::Value::Table(into_table), toml::Value::Table(from_table)) => {
+            for (k, v) in from_table.iter() {
+                let child_path = if key_path.is_empty() {
+                    k.clone()
+                } else {
+                    format!("{}.{}", key_path, k)
+                };
+
+                match into_table.get_mut(k) {
+                    Some(existing) if existing.is_table() && v.is_table() => {
+                        merge_value(existing, v, &child_path, origin, origins);
+                    }
+                    _ => {
+                        into_table.insert(k.clone(), v.clone());
+                        record_origins(v, &child_path, origin, origins);
+                    }
+                }
+            }
+        }
+        (into, from) => {
+            *into = from.clone();
+            record_origins(from, key_path, origin, origins);
+        }
+    }
+}
+
+fn record_origins(
+    value: &toml::Value,
+    key_path: &str,
+    origin: &str,
+    origins: &mut BTreeMap<String, String>,
+) {
+    // Replacing a value drops whatever was recorded for it and beneath it, whether the new
+    // value is a table or not.
+    let prefix = format!("{}.", key_path);
+    origins.retain(|k, _| k != key_path && !k.starts_with(&prefix));
+    match value {
+        toml::Value::Table(table) => {
+            for (k, v) in table.iter() {
+                record_origins(v, &format!("{}{}", prefix, k), origin, origins);
+            }
+        }
+        _ => {
+            origins.insert(key_path.to_string(), origin.to_string());
+        }
+    }
+}
+
+/// Merges configuration layers in order. Later layers override individual values
+/// of earlier ones, so an override file only needs to list the entries it changes.
+pub fn merge_config_layers(layers: &[ConfigLayer]) -> error::Result<MergedConfig> {
+    let mut merged = MergedConfig {
+        value: toml::Value::Table(toml::value::Table::new()),
+        origins: BTreeMap::new(),
+    };
+
+    for layer in layers.iter() {
+        let layer_value: toml::Value = match toml::from_str(&layer.toml) {
+            Ok(v) => v,
+            Err(why) => {
+                eprintln!("Error parsing {}: {}", layer.origin, why);
+                return Err("Error parsing calibration configuration");
+            }
+        };
+        merge_value(
+            &mut merged.value,
+            &layer_value,
+            "",
+            &layer.origin,
+            &mut merged.origins,
+        );
+    }
+
+    Ok(merged)
+}
+
+fn read_config_layer(file_path: &str) -> ConfigLayer {
+    let mut file = match File::open(file_path) {
+        Err(why) => panic!("couldn't open {}", why),
+        Ok(file) => file,
+    };
+
+    let mut buf: Vec<u8> = Vec::default();
+    file.read_to_end(&mut buf).unwrap();
+
+    ConfigLayer {
+        origin: file_path.to_string(),
+        toml: String::from_utf8(buf).unwrap(),
+    }
+}
+
+/// Determines the configuration files in the order they are applied: the system
+/// caldata.toml, then ~/.marsdata/caldata.local.toml, then ./mru.toml.
+pub fn locate_config_layers() -> error::Result<Vec<String>> {
+    let mut layers: Vec<String> = vec![];
+
+    match locate_calibration_file(&String::from("caldata.toml")) {
+        Ok(caldata_toml) => layers.push(caldata_toml),
+        Err(e) => return Err(e),
+    }
+
+    if let Some(dir) = dirs::home_dir() {
+        let local_toml = format!("{}/.marsdata/{}", dir.to_str().unwrap(), CALDATA_LOCAL_FILE);
+        if path::file_exists(&local_toml) {
+            layers.push(local_toml);
+        }
+    }
+
+    if path::file_exists(PROJECT_CONFIG_FILE) {
+        layers.push(format!("{}/{}", path::cwd(), PROJECT_CONFIG_FILE));
+    }
+
+    Ok(layers)
+}
+
+pub fn load_merged_config() -> error::Result<MergedConfig> {
+    match locate_config_layers() {
+        Ok(layer_files) => {
+            for f in layer_files.iter() {
+                vprintln!("Applying calibration configuration layer {}", f);
+            }
+            let layers: Vec<ConfigLayer> =
+                layer_files.iter().map(|f| read_config_layer(f)).collect();
+            merge_config_layers(&layers)
         }
         Err(_) => {
             panic!("Unable to locate calibration configuration file")
@@ -98,6 +291,13 @@ pub fn load_caldata_mapping_file() -> error::Result<Config> {
     }
 }
 
+pub fn load_caldata_mapping_file() -> error::Result<Config> {
+    match load_merged_config() {
+        Ok(merged) => merged.to_config(),
+        Err(e) => Err(e),
+    }
+}
+
 // Allows the user to specify files without an extension as a shortcut. Still needs to be able
 // to guess an extension, though
 pub fn locate_calibration_file_no_extention(
@@ -187,125 +387,116 @@ pub fn get_calibration_file_for_type(
     }
 }
 
+fn get_instrument_properties(
+    config: &Config,
+    instrument: enums::Instrument,
+) -> Option<&InstrumentProperties> {
+    match instrument {
+        enums::Instrument::MslMAHLI => Some(&config.msl.mahli),
+        enums::Instrument::MslMastcamLeft => Some(&config.msl.mastcam_left),
+        enums::Instrument::MslMastcamRight => Some(&config.msl.mastcam_right),
+        enums::Instrument::MslNavCamRight => Some(&config.msl.nav_right), // Limiting to RCE-B camera for ECAM. For now.
+        enums::Instrument::MslNavCamLeft => Some(&config.msl.nav_left),
+        enums::Instrument::MslFrontHazLeft => Some(&config.msl.fhaz_left),
+        enums::Instrument::MslFrontHazRight => Some(&config.msl.fhaz_right),
+        enums::Instrument::MslRearHazLeft => Some(&config.msl.rhaz_left),
+        enums::Instrument::MslRearHazRight => Some(&config.msl.rhaz_right),
+        enums::Instrument::MslMARDI => Some(&config.msl.mardi),
+        enums::Instrument::MslChemCam => Some(&config.msl.chemcam),
+        enums::Instrument::M20MastcamZLeft => Some(&config.m20.mastcamz_left),
+        enums::Instrument::M20MastcamZRight => Some(&config.m20.mastcamz_right),
+        enums::Instrument::M20NavcamLeft => Some(&config.m20.nav_left),
+        enums::Instrument::M20NavcamRight => Some(&config.m20.nav_right),
+        enums::Instrument::M20FrontHazLeft => Some(&config.m20.fhaz_left),
+        enums::Instrument::M20FrontHazRight => Some(&config.m20.fhaz_right),
+        enums::Instrument::M20RearHazLeft => Some(&config.m20.rhaz_left),
+        enums::Instrument::M20RearHazRight => Some(&config.m20.rhaz_left),
+        enums::Instrument::M20Watson => Some(&config.m20.watson),
+        enums::Instrument::M20SuperCam => Some(&config.m20.supercam_rmi),
+        enums::Instrument::M20HeliNav => Some(&config.m20.heli_nav),
+        enums::Instrument::M20HeliRte => Some(&config.m20.heli_rte),
+        enums::Instrument::M20Pixl => Some(&config.m20.pixl_mcc),
+        enums::Instrument::M20SkyCam => Some(&config.m20.skycam),
+        enums::Instrument::NsytICC => Some(&config.nsyt.icc),
+        enums::Instrument::NsytIDC => Some(&config.nsyt.idc),
+        enums::Instrument::None => None,
+    }
+}
+
 pub fn get_calibration_base_file_for_instrument(
     instrument: enums::Instrument,
     cal_file_type: enums::CalFileType,
 ) -> error::Result<String> {
     let config = load_caldata_mapping_file().unwrap();
 
-    match instrument {
-        enums::Instrument::MslMAHLI => Ok(get_calibration_file_for_type(
-            &config.msl.mahli,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMastcamLeft => Ok(get_calibration_file_for_type(
-            &config.msl.mastcam_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMastcamRight => Ok(get_calibration_file_for_type(
-            &config.msl.mastcam_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslNavCamRight => Ok(get_calibration_file_for_type(
-            &config.msl.nav_right,
-            cal_file_type,
-        )), // Limiting to RCE-B camera for ECAM. For now.
-        enums::Instrument::MslNavCamLeft => Ok(get_calibration_file_for_type(
-            &config.msl.nav_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslFrontHazLeft => Ok(get_calibration_file_for_type(
-            &config.msl.fhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslFrontHazRight => Ok(get_calibration_file_for_type(
-            &config.msl.fhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslRearHazLeft => Ok(get_calibration_file_for_type(
-            &config.msl.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslRearHazRight => Ok(get_calibration_file_for_type(
-            &config.msl.rhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMARDI => Ok(get_calibration_file_for_type(
-            &config.msl.mardi,
-            cal_file_type,
-        )),
-        enums::Instrument::MslChemCam => Ok(get_calibration_file_for_type(
-            &config.msl.chemcam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20MastcamZLeft => Ok(get_calibration_file_for_type(
-            &config.m20.mastcamz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20MastcamZRight => Ok(get_calibration_file_for_type(
-            &config.m20.mastcamz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20NavcamLeft => Ok(get_calibration_file_for_type(
-            &config.m20.nav_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20NavcamRight => Ok(get_calibration_file_for_type(
-            &config.m20.nav_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20FrontHazLeft => Ok(get_calibration_file_for_type(
-            &config.m20.fhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20FrontHazRight => Ok(get_calibration_file_for_type(
-            &config.m20.fhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20RearHazLeft => Ok(get_calibration_file_for_type(
-            &config.m20.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20RearHazRight => Ok(get_calibration_file_for_type(
-            &config.m20.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20Watson => Ok(get_calibration_file_for_type(
-            &config.m20.watson,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SuperCam => Ok(get_calibration_file_for_type(
-            &config.m20.supercam_rmi,
-            cal_file_type,
-        )),
-        enums::Instrument::M20HeliNav => Ok(get_calibration_file_for_type(
-            &config.m20.heli_nav,
-            cal_file_type,
-        )),
-        enums::Instrument::M20HeliRte => Ok(get_calibration_file_for_type(
-            &config.m20.heli_rte,
-            cal_file_type,
-        )),
-        enums::Instrument::M20Pixl => Ok(get_calibration_file_for_type(
-            &config.m20.pixl_mcc,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SkyCam => Ok(get_calibration_file_for_type(
-            &config.m20.skycam,
-            cal_file_type,
-        )),
-        enums::Instrument::NsytICC => Ok(get_calibration_file_for_type(
-            &config.nsyt.icc,
-            cal_file_type,
-        )),
-        enums::Instrument::NsytIDC => Ok(get_calibration_file_for_type(
-            &config.nsyt.idc,
-            cal_file_type,
-        )),
-        enums::Instrument::None => Err(constants::status::UNSUPPORTED_INSTRUMENT),
+    match get_instrument_properties(&config, instrument) {
+        Some(inst_props) => Ok(get_calibration_file_for_type(inst_props, cal_file_type)),
+        None => Err(constants::status::UNSUPPORTED_INSTRUMENT),
     }
 }
 
+/// Returns the filter-specific calibration values for an instrument, if any are configured
+pub fn get_filter_properties(
+    instrument: enums::Instrument,
+    filter: &FilterPosition,
+) -> Option<FilterProperties> {
+    let config = load_caldata_mapping_file().ok()?;
+    get_instrument_properties(&config, instrument)?
+        .filters
+        .get(&filter.name())
+        .cloned()
+}
+
+/// The decompanding lookup tables configured for an instrument
+#[derive(Clone, Debug, Default)]
+pub struct IltFiles {
+    pub ilt: Option<String>,
+    pub products: Vec<IltProduct>,
+}
+
+impl IltFiles {
+    /// The table for a product: the first per-product rule matching its id, otherwise the
+    /// instrument's table
+    pub fn for_product(&self, product_id: Option<&str>) -> Option<String> {
+        if let Some(id) = product_id {
+            if let Some(p) = self
+                .products
+                .iter()
+                .find(|p| id.contains(p.pattern.as_str()))
+            {
+                return Some(p.ilt.clone());
+            }
+        }
+        self.ilt.clone()
+    }
+}
+
+/// Reads the lookup table rules for an instrument from the calibration data
+pub fn get_ilt_files(instrument: enums::Instrument) -> IltFiles {
+    // Fall back to the built in tables when there's no calibration data at all
+    if locate_config_layers().is_err() {
+        return IltFiles::default();
+    }
+    match load_caldata_mapping_file() {
+        Ok(config) => match get_instrument_properties(&config, instrument) {
+            Some(props) => IltFiles {
+                ilt: props.ilt.clone(),
+                products: props.ilt_products.clone(),
+            },
+            None => IltFiles::default(),
+        },
+        Err(_) => IltFiles::default(),
+    }
+}
+
+pub fn get_cfa_pattern(instrument: enums::Instrument) -> Option<String> {
+    locate_config_layers().ok()?;
+    let config = load_caldata_mapping_file().ok()?;
+    get_instrument_properties(&config, instrument)?
+        .cfa_pattern
+        .clone()
+}
+
 pub fn get_calibration_file_for_instrument(
     instrument: enums::Instrument,
     cal_file_type: enums::CalFileType,
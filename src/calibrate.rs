@@ -1,6 +1,9 @@
 use rayon::prelude::*;
 
-use crate::{calprofile::*, enums::Instrument, path, print::*, vprintln};
+use crate::{
+    calprofile::*, colornoise, colorspace, cosmicray, debayer, deblock, enums::Instrument,
+    hotpixel, image::MarsImage, path, print::*, vprintln, whitebalance,
+};
 
 use sciimg::error;
 
@@ -36,6 +39,109 @@ pub fn cal_fail(cal_context: &CalProfile) -> error::Result<CompleteContext> {
     Ok(CompleteContext::new(CompleteStatus::FAIL, cal_context))
 }
 
+/// Processing stages shared by the calibrators. Apart from debayering, each one only runs
+/// when enabled in the calibration profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Deblock,
+
+    /// Deblocking of a raw color filter array mosaic, before it's debayered
+    MosaicDeblock,
+    Debayer,
+    WhiteBalance,
+    HotPixels,
+
+    /// Hot pixel correction of a raw color filter array mosaic, before it's debayered
+    MosaicHotPixels,
+    CosmicRays,
+    ColorNoise,
+    OutputTransform,
+}
+
+impl Stage {
+    /// Deblocking for a raw frame that may still be a color filter array mosaic
+    pub fn deblock(mosaic: bool) -> Stage {
+        if mosaic {
+            Stage::MosaicDeblock
+        } else {
+            Stage::Deblock
+        }
+    }
+
+    /// Hot pixel correction for a raw frame that may still be a color filter array mosaic
+    pub fn hot_pixels(mosaic: bool) -> Stage {
+        if mosaic {
+            Stage::MosaicHotPixels
+        } else {
+            Stage::HotPixels
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            Stage::Deblock | Stage::MosaicDeblock => "deblock",
+            Stage::Debayer => "debayer",
+            Stage::WhiteBalance => "white balance",
+            Stage::HotPixels | Stage::MosaicHotPixels => "correct hot pixels in",
+            Stage::CosmicRays => "remove cosmic rays from",
+            Stage::ColorNoise => "reduce color noise in",
+            Stage::OutputTransform => "convert the color space of",
+        }
+    }
+}
+
+/// Runs the shared processing stages for one image and calibration profile
+pub struct StageRunner<'a> {
+    cal_context: &'a CalProfile,
+    input_file: &'a str,
+    out_file: &'a str,
+}
+
+impl<'a> StageRunner<'a> {
+    pub fn new(cal_context: &'a CalProfile, input_file: &'a str, out_file: &'a str) -> Self {
+        StageRunner {
+            cal_context,
+            input_file,
+            out_file,
+        }
+    }
+
+    /// Runs stages in order. A stage only fails when it was asked for and couldn't be done,
+    /// so the failure is reported and returned, ending the calibration of the image.
+    pub fn apply(&self, img: &mut MarsImage, stages: &[Stage]) -> error::Result<()> {
+        let (cal_context, input_file, out_file) =
+            (self.cal_context, self.input_file, self.out_file);
+        for stage in stages.iter() {
+            let result = match stage {
+                Stage::Deblock => deblock::apply_deblock(img, cal_context, input_file, false),
+                Stage::MosaicDeblock => deblock::apply_deblock(img, cal_context, input_file, true),
+                Stage::Debayer => debayer::apply_debayer(img, cal_context),
+                Stage::WhiteBalance => whitebalance::apply_white_balance(img, cal_context),
+                Stage::HotPixels => {
+                    hotpixel::apply_hot_pixel_correction(img, cal_context, out_file, false)
+                }
+                Stage::MosaicHotPixels => {
+                    hotpixel::apply_hot_pixel_correction(img, cal_context, out_file, true)
+                }
+                Stage::CosmicRays => {
+                    cosmicray::apply_cosmic_ray_removal(img, cal_context, out_file)
+                }
+                Stage::ColorNoise => colornoise::apply_color_noise_reduction(img, cal_context),
+                Stage::OutputTransform => colorspace::apply_output_transform(img, cal_context),
+            };
+            if let Err(why) = result {
+                eprintln!(
+                    "Could not {} {}",
+                    stage.description(),
+                    path::basename(input_file)
+                );
+                return Err(why);
+            }
+        }
+        Ok(())
+    }
+}
+
 pub trait Calibration: Sync {
     fn accepts_instrument(&self, instrument: Instrument) -> bool;
 
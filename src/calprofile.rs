@@ -1,4 +1,12 @@
-use crate::{calibfile, constants, vprintln};
+use crate::{
+    calibfile,
+    colorspace::ColorSpace,
+    constants,
+    debayer::{CfaPattern, DebayerMethod},
+    deblock::JpegGrid,
+    vprintln,
+    whitebalance::WhiteBalanceMethod,
+};
 
 use sciimg::error;
 
@@ -33,8 +41,74 @@ pub struct CalProfile {
     #[serde(default = "default_hpc_window_size")]
     pub hot_pixel_window_size: i32,
 
+    #[serde(default = "default_false")]
+    pub hot_pixel_map: bool,
+
+    #[serde(default = "default_none")]
+    pub hot_pixel_map_file: Option<String>,
+
+    #[serde(default = "default_false")]
+    pub deblock: bool,
+
+    #[serde(default = "default_deblock_strength")]
+    pub deblock_strength: f32,
+
+    #[serde(default = "default_deblock_grid")]
+    pub deblock_grid: JpegGrid,
+
+    #[serde(default = "default_false")]
+    pub cosmic_ray_removal: bool,
+
+    #[serde(default = "default_cosmic_ray_sigma_clip")]
+    pub cosmic_ray_sigma_clip: f32,
+
+    #[serde(default = "default_cosmic_ray_sigma_frac")]
+    pub cosmic_ray_sigma_frac: f32,
+
+    #[serde(default = "default_cosmic_ray_obj_limit")]
+    pub cosmic_ray_obj_limit: f32,
+
+    #[serde(default = "default_cosmic_ray_gain")]
+    pub cosmic_ray_gain: f32,
+
+    #[serde(default = "default_cosmic_ray_read_noise")]
+    pub cosmic_ray_read_noise: f32,
+
+    #[serde(default = "default_cosmic_ray_iterations")]
+    pub cosmic_ray_iterations: usize,
+
+    #[serde(default = "default_false")]
+    pub cosmic_ray_mask: bool,
+
     #[serde(default = "default_filename_suffix")]
     pub filename_suffix: String,
+
+    #[serde(default = "default_debayer_method")]
+    pub debayer_method: DebayerMethod,
+
+    #[serde(default = "default_none")]
+    pub cfa_pattern: Option<CfaPattern>,
+
+    #[serde(default = "default_white_balance")]
+    pub white_balance: WhiteBalanceMethod,
+
+    #[serde(default = "default_none")]
+    pub white_balance_target: Option<String>,
+
+    #[serde(default = "default_sky_fraction")]
+    pub white_balance_sky_fraction: f32,
+
+    #[serde(default = "default_sky_color")]
+    pub white_balance_sky_color: [f32; 3],
+
+    #[serde(default = "default_none")]
+    pub color_matrix_file: Option<String>,
+
+    #[serde(default = "default_none")]
+    pub color_matrix: Option<[[f32; 3]; 3]>,
+
+    #[serde(default = "default_output_color_space")]
+    pub output_color_space: ColorSpace,
 }
 
 impl CalProfile {
@@ -48,7 +122,29 @@ impl CalProfile {
             color_noise_reduction_amount: default_color_noise_reduction_amount(),
             hot_pixel_detection_threshold: default_hpc_threshold(),
             hot_pixel_window_size: default_hpc_window_size(),
+            hot_pixel_map: default_false(),
+            hot_pixel_map_file: default_none(),
+            deblock: default_false(),
+            deblock_strength: default_deblock_strength(),
+            deblock_grid: default_deblock_grid(),
+            cosmic_ray_removal: default_false(),
+            cosmic_ray_sigma_clip: default_cosmic_ray_sigma_clip(),
+            cosmic_ray_sigma_frac: default_cosmic_ray_sigma_frac(),
+            cosmic_ray_obj_limit: default_cosmic_ray_obj_limit(),
+            cosmic_ray_gain: default_cosmic_ray_gain(),
+            cosmic_ray_read_noise: default_cosmic_ray_read_noise(),
+            cosmic_ray_iterations: default_cosmic_ray_iterations(),
+            cosmic_ray_mask: default_false(),
             filename_suffix: default_filename_suffix(),
+            debayer_method: default_debayer_method(),
+            cfa_pattern: default_none(),
+            white_balance: default_white_balance(),
+            white_balance_target: default_none(),
+            white_balance_sky_fraction: default_sky_fraction(),
+            white_balance_sky_color: default_sky_color(),
+            color_matrix_file: default_none(),
+            color_matrix: default_none(),
+            output_color_space: default_output_color_space(),
         }
     }
 }
@@ -57,10 +153,46 @@ fn default_hpc_window_size() -> i32 {
     10
 }
 
+fn default_deblock_strength() -> f32 {
+    1.0
+}
+
+fn default_deblock_grid() -> JpegGrid {
+    JpegGrid::Image
+}
+
+fn default_cosmic_ray_sigma_clip() -> f32 {
+    4.5
+}
+
+fn default_cosmic_ray_sigma_frac() -> f32 {
+    0.3
+}
+
+fn default_cosmic_ray_obj_limit() -> f32 {
+    5.0
+}
+
+fn default_cosmic_ray_gain() -> f32 {
+    1.0
+}
+
+fn default_cosmic_ray_read_noise() -> f32 {
+    6.5
+}
+
+fn default_cosmic_ray_iterations() -> usize {
+    4
+}
+
 fn default_filename_suffix() -> String {
     String::from(constants::OUTPUT_FILENAME_APPEND)
 }
 
+fn default_debayer_method() -> DebayerMethod {
+    DebayerMethod::Sciimg
+}
+
 fn default_false() -> bool {
     false
 }
@@ -77,6 +209,26 @@ fn default_hpc_threshold() -> f32 {
     0.0
 }
 
+fn default_white_balance() -> WhiteBalanceMethod {
+    WhiteBalanceMethod::None
+}
+
+fn default_sky_fraction() -> f32 {
+    0.15
+}
+
+fn default_sky_color() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_output_color_space() -> ColorSpace {
+    ColorSpace::Native
+}
+
+fn default_none<T>() -> Option<T> {
+    None
+}
+
 pub fn load_calibration_profile(file_path: &String) -> error::Result<CalProfile> {
     match calibfile::locate_calibration_file_no_extention(file_path, &".toml".to_string()) {
         Ok(located_file) => {
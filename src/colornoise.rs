@@ -0,0 +1,117 @@
+use crate::{calprofile::CalProfile, colorspace, constants, image::MarsImage, vprintln};
+
+use sciimg::error;
+
+/// One dimensional gaussian kernel with a sigma of `radius / 2`
+fn gaussian_kernel(radius: usize) -> Vec<f32> {
+    let sigma = (radius as f32 / 2.0).max(0.5);
+    let kernel: Vec<f32> = (0..=radius * 2)
+        .map(|i| {
+            let d = i as f32 - radius as f32;
+            (-(d * d) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let sum: f32 = kernel.iter().sum();
+    kernel.iter().map(|k| k / sum).collect()
+}
+
+fn mirror(i: i32, len: usize) -> usize {
+    let len = len as i32;
+    let i = if i < 0 { -i } else { i };
+    let i = if i >= len { 2 * (len - 1) - i } else { i };
+    i.clamp(0, len - 1) as usize
+}
+
+/// Separable gaussian blur of a single channel stored row major
+fn blur_channel(data: &[f32], width: usize, height: usize, kernel: &[f32]) -> Vec<f32> {
+    let radius = (kernel.len() / 2) as i32;
+    let mut horiz = vec![0.0; data.len()];
+    for y in 0..height {
+        for x in 0..width {
+            horiz[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * data[y * width + mirror(x as i32 + k as i32 - radius, width)])
+                .sum();
+        }
+    }
+
+    let mut out = vec![0.0; data.len()];
+    for y in 0..height {
+        for x in 0..width {
+            out[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * horiz[mirror(y as i32 + k as i32 - radius, height) * width + x])
+                .sum();
+        }
+    }
+    out
+}
+
+/// Reduces chroma noise by low-pass filtering the a* and b* channels of the image in
+/// L*a*b* space, leaving luminance, and so fine detail, untouched. `amount` is the
+/// radius of the filter in pixels.
+pub fn reduce_color_noise(img: &mut MarsImage, amount: i32) -> error::Result<()> {
+    if img.image.num_bands() < 3 {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+    if amount <= 0 {
+        return Ok(());
+    }
+
+    let width = img.image.width;
+    let height = img.image.height;
+    let (_, max) = img.image.get_min_max_all_channel();
+    if max <= 0.0 {
+        return Ok(());
+    }
+
+    let mut l = vec![0.0; width * height];
+    let mut a = vec![0.0; width * height];
+    let mut b = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let lab = colorspace::linear_rgb_to_lab([
+                img.image.get_band(0).get(x, y).unwrap() / max,
+                img.image.get_band(1).get(x, y).unwrap() / max,
+                img.image.get_band(2).get(x, y).unwrap() / max,
+            ]);
+            let i = y * width + x;
+            l[i] = lab[0];
+            a[i] = lab[1];
+            b[i] = lab[2];
+        }
+    }
+
+    let kernel = gaussian_kernel(amount as usize);
+    let a = blur_channel(&a, width, height, &kernel);
+    let b = blur_channel(&b, width, height, &kernel);
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let rgb = colorspace::lab_to_linear_rgb([l[i], a[i], b[i]]);
+            for (band, v) in rgb.iter().enumerate() {
+                img.image.put(x, y, (v * max).max(0.0), band);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies color noise reduction when enabled in the calibration profile
+pub fn apply_color_noise_reduction(
+    img: &mut MarsImage,
+    cal_context: &CalProfile,
+) -> error::Result<()> {
+    if !cal_context.color_noise_reduction || cal_context.color_noise_reduction_amount <= 0 {
+        return Ok(());
+    }
+
+    vprintln!(
+        "Color noise reduction (amount {})...",
+        cal_context.color_noise_reduction_amount
+    );
+    reduce_color_noise(img, cal_context.color_noise_reduction_amount)
+}
@@ -0,0 +1,476 @@
+use crate::{calprofile::CalProfile, constants, image::MarsImage, path, vprintln};
+
+use sciimg::{enums::ImageMode, error, imagebuffer::ImageBuffer, rgbimage::RgbImage};
+
+use serde::{Deserialize, Serialize};
+
+use tiff::encoder::{
+    colortype::{RGB16, RGB8, RGBA16, RGBA8},
+    TiffValue,
+};
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::str::FromStr;
+
+/// Output color spaces for calibrated products
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Camera RGB, left as-is
+    Native,
+
+    /// Linear light with sRGB (Rec. 709) primaries
+    Linear,
+
+    /// sRGB primaries and transfer function
+    #[serde(rename = "srgb")]
+    SRgb,
+
+    /// Display P3 primaries with the sRGB transfer function
+    #[serde(rename = "displayp3")]
+    DisplayP3,
+}
+
+impl FromStr for ColorSpace {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ColorSpace, Self::Err> {
+        match s.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+            "native" => Ok(ColorSpace::Native),
+            "linear" => Ok(ColorSpace::Linear),
+            "srgb" => Ok(ColorSpace::SRgb),
+            "displayp3" | "p3" => Ok(ColorSpace::DisplayP3),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+// CIE XYZ (D65) to linear RGB, from the sRGB and Display P3 specifications
+const XYZ_TO_SRGB: [[f32; 3]; 3] = [
+    [3.240_454, -1.537_139, -0.498_531],
+    [-0.969_266, 1.876_011, 0.041_556],
+    [0.055_643, -0.204_026, 1.057_225],
+];
+
+const XYZ_TO_DISPLAY_P3: [[f32; 3]; 3] = [
+    [2.493_497, -0.931_384, -0.402_711],
+    [-0.829_489, 1.762_664, 0.023_625],
+    [0.035_846, -0.076_172, 0.956_885],
+];
+
+// Linear sRGB to CIE XYZ (D65), used when a profile has no camera matrix and camera RGB is
+// taken to already be white balanced sRGB.
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412_456, 0.357_576, 0.180_438],
+    [0.212_673, 0.715_152, 0.072_175],
+    [0.019_334, 0.119_192, 0.950_304],
+];
+
+// Bradford adapted (D50) colorants for the ICC profiles
+const SRGB_COLORANTS_D50: [[f32; 3]; 3] = [
+    [0.436_075, 0.222_505, 0.013_932],
+    [0.385_065, 0.716_879, 0.097_105],
+    [0.143_080, 0.060_617, 0.714_173],
+];
+
+const DISPLAY_P3_COLORANTS_D50: [[f32; 3]; 3] = [
+    [0.515_102, 0.241_182, -0.001_050],
+    [0.291_965, 0.692_236, 0.041_882],
+    [0.157_153, 0.066_582, 0.784_378],
+];
+
+const D50_WHITE: [f32; 3] = [0.9642, 1.0, 0.8249];
+
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let mut m = [[0.0; 3]; 3];
+    for (r, row) in m.iter_mut().enumerate() {
+        for (c, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[r][k] * b[k][c]).sum();
+        }
+    }
+    m
+}
+
+/// The sRGB encoding function, linear to gamma encoded, for values in 0.0 to 1.0
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// The sRGB decoding function, gamma encoded to linear, for values in 0.0 to 1.0
+pub fn srgb_decode(v: f32) -> f32 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+const D65_WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+
+fn transform(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
+/// Converts linear sRGB, nominally 0.0 to 1.0, to CIE L*a*b* (D65). Values are not clamped,
+/// so the conversion round trips for out of gamut colors.
+pub fn linear_rgb_to_lab(rgb: [f32; 3]) -> [f32; 3] {
+    let f = |t: f32| -> f32 {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let xyz = transform(&SRGB_TO_XYZ, rgb);
+    let fx = f(xyz[0] / D65_WHITE[0]);
+    let fy = f(xyz[1] / D65_WHITE[1]);
+    let fz = f(xyz[2] / D65_WHITE[2]);
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Converts CIE L*a*b* (D65) to linear sRGB
+pub fn lab_to_linear_rgb(lab: [f32; 3]) -> [f32; 3] {
+    let finv = |t: f32| -> f32 {
+        if t > 6.0 / 29.0 {
+            t * t * t
+        } else {
+            (116.0 * t - 16.0) * 27.0 / 24389.0
+        }
+    };
+    let fy = (lab[0] + 16.0) / 116.0;
+    let fx = fy + lab[1] / 500.0;
+    let fz = fy - lab[2] / 200.0;
+    let xyz = [
+        finv(fx) * D65_WHITE[0],
+        finv(fy) * D65_WHITE[1],
+        finv(fz) * D65_WHITE[2],
+    ];
+    transform(&XYZ_TO_SRGB, xyz)
+}
+
+impl ColorSpace {
+    fn xyz_to_rgb(&self) -> Option<[[f32; 3]; 3]> {
+        match self {
+            ColorSpace::Native => None,
+            ColorSpace::Linear | ColorSpace::SRgb => Some(XYZ_TO_SRGB),
+            ColorSpace::DisplayP3 => Some(XYZ_TO_DISPLAY_P3),
+        }
+    }
+
+    fn is_gamma_encoded(&self) -> bool {
+        matches!(self, ColorSpace::SRgb | ColorSpace::DisplayP3)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorSpace::Native => "native",
+            ColorSpace::Linear => "linear",
+            ColorSpace::SRgb => "srgb",
+            ColorSpace::DisplayP3 => "displayp3",
+        }
+    }
+
+    /// Builds an ICC (v2.1) display profile describing the color space, if it has one
+    pub fn icc_profile(&self) -> Option<Vec<u8>> {
+        let (description, colorants) = match self {
+            ColorSpace::Native => return None,
+            ColorSpace::Linear => ("Linear sRGB", SRGB_COLORANTS_D50),
+            ColorSpace::SRgb => ("sRGB", SRGB_COLORANTS_D50),
+            ColorSpace::DisplayP3 => ("Display P3", DISPLAY_P3_COLORANTS_D50),
+        };
+        Some(build_icc_profile(
+            description,
+            &colorants,
+            self.is_gamma_encoded(),
+        ))
+    }
+}
+
+/// The combined matrix taking linear camera RGB to linear output RGB
+pub fn output_matrix(
+    camera_to_xyz: &Option<[[f32; 3]; 3]>,
+    color_space: ColorSpace,
+) -> Option<[[f32; 3]; 3]> {
+    color_space
+        .xyz_to_rgb()
+        .map(|xyz_to_rgb| multiply(&xyz_to_rgb, &camera_to_xyz.unwrap_or(SRGB_TO_XYZ)))
+}
+
+/// Converts a normalized 16 bit calibrated image from camera RGB to the output color space of
+/// the profile, applying the camera color matrix and the output transfer function.
+pub fn apply_output_transform(img: &mut MarsImage, cal_context: &CalProfile) -> error::Result<()> {
+    let color_space = cal_context.output_color_space;
+    if img.image.num_bands() < 3 || color_space == ColorSpace::Native {
+        if cal_context.color_matrix.is_some() {
+            vprintln!("A color matrix requires an output color space other than native, ignoring");
+        }
+        return Ok(());
+    }
+
+    vprintln!("Converting to {} color...", color_space.name());
+    let m = output_matrix(&cal_context.color_matrix, color_space).unwrap();
+    let encode = color_space.is_gamma_encoded();
+
+    let bands: Vec<ImageBuffer> = (0..3).map(|b| img.image.get_band(b).clone()).collect();
+    let mut out: Vec<ImageBuffer> = bands.clone();
+    for y in 0..img.image.height {
+        for x in 0..img.image.width {
+            let rgb = [
+                bands[0].get(x, y)? / 65535.0,
+                bands[1].get(x, y)? / 65535.0,
+                bands[2].get(x, y)? / 65535.0,
+            ];
+            for (c, row) in m.iter().enumerate() {
+                let mut v = (row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]).clamp(0.0, 1.0);
+                if encode {
+                    v = srgb_encode(v);
+                }
+                out[c].put(x, y, v * 65535.0);
+            }
+        }
+    }
+    for (c, band) in out.iter().enumerate() {
+        img.image.set_band(band, c);
+    }
+
+    img.output_color_space = Some(color_space);
+    if let Some(ref mut md) = img.metadata {
+        md.color_space = Some(color_space.name().to_string());
+    }
+    Ok(())
+}
+
+fn s15_fixed16(v: f32) -> [u8; 4] {
+    ((v * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(xyz: &[f32; 3]) -> Vec<u8> {
+    let mut t = b"XYZ \0\0\0\0".to_vec();
+    for v in xyz.iter() {
+        t.extend_from_slice(&s15_fixed16(*v));
+    }
+    t
+}
+
+fn curve_tag(gamma_encoded: bool) -> Vec<u8> {
+    let mut t = b"curv\0\0\0\0".to_vec();
+    if gamma_encoded {
+        let entries = 1024;
+        t.extend_from_slice(&(entries as u32).to_be_bytes());
+        for i in 0..entries {
+            let v = srgb_decode(i as f32 / (entries - 1) as f32);
+            t.extend_from_slice(&((v * 65535.0).round() as u16).to_be_bytes());
+        }
+    } else {
+        // A single entry is a gamma in u8Fixed8, 1.0 being linear
+        t.extend_from_slice(&1_u32.to_be_bytes());
+        t.extend_from_slice(&0x0100_u16.to_be_bytes());
+    }
+    t
+}
+
+fn text_description_tag(text: &str) -> Vec<u8> {
+    let mut t = b"desc\0\0\0\0".to_vec();
+    t.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    t.extend_from_slice(text.as_bytes());
+    t.push(0);
+    // Empty unicode and scriptcode descriptions
+    t.extend_from_slice(&[0; 4 + 4 + 2 + 1 + 67]);
+    t
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+    let mut t = b"text\0\0\0\0".to_vec();
+    t.extend_from_slice(text.as_bytes());
+    t.push(0);
+    t
+}
+
+fn build_icc_profile(description: &str, colorants: &[[f32; 3]; 3], gamma_encoded: bool) -> Vec<u8> {
+    let trc = curve_tag(gamma_encoded);
+    let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", text_description_tag(description)),
+        (b"cprt", text_tag("No copyright, use freely")),
+        (b"wtpt", xyz_tag(&D50_WHITE)),
+        (b"rXYZ", xyz_tag(&colorants[0])),
+        (b"gXYZ", xyz_tag(&colorants[1])),
+        (b"bXYZ", xyz_tag(&colorants[2])),
+        (b"rTRC", trc.clone()),
+        (b"gTRC", trc.clone()),
+        (b"bTRC", trc),
+    ];
+
+    let mut table: Vec<u8> = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data: Vec<u8> = vec![];
+    let data_start = 128 + 4 + tags.len() * 12;
+    for (sig, tag) in tags.iter() {
+        table.extend_from_slice(*sig);
+        table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        data.resize(data.len().div_ceil(4) * 4, 0);
+    }
+
+    let size = data_start + data.len();
+    let mut header: Vec<u8> = vec![];
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&[0; 4]); // Preferred CMM
+    header.extend_from_slice(&0x0210_0000_u32.to_be_bytes()); // Version 2.1
+    header.extend_from_slice(b"mntr");
+    header.extend_from_slice(b"RGB ");
+    header.extend_from_slice(b"XYZ ");
+    header.extend_from_slice(&[0; 12]); // Creation date
+    header.extend_from_slice(b"acsp");
+    header.extend_from_slice(&[0; 4 + 4 + 4 + 4 + 8]); // Platform, flags, manufacturer, model, attributes
+    header.extend_from_slice(&0_u32.to_be_bytes()); // Perceptual rendering intent
+    for v in D50_WHITE.iter() {
+        header.extend_from_slice(&s15_fixed16(*v));
+    }
+    header.extend_from_slice(&[0; 4]); // Creator
+    header.extend_from_slice(&[0; 16 + 28]); // Profile ID and reserved
+
+    let mut profile = header;
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+}
+
+/// Whether outputs of a format can carry an embedded ICC profile: PNG and TIFF
+pub fn supports_icc_profile(file_path: &str) -> bool {
+    let lower = file_path.to_lowercase();
+    lower.ends_with(".png") || lower.ends_with(".tif") || lower.ends_with(".tiff")
+}
+
+/// An ICC profile, written to TIFF as the UNDEFINED type the tag calls for
+struct IccProfileTag<'a>(&'a [u8]);
+
+impl TiffValue for IccProfileTag<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: tiff::tags::Type = tiff::tags::Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+/// Samples of an image in RGB, or RGBA when it has an alpha mask. Single band images are
+/// repeated across the color channels to match the RGB profile.
+fn interleaved_samples(image: &RgbImage, max: f32) -> (Vec<f32>, usize) {
+    let channels = if image.is_using_alpha() { 4 } else { 3 };
+    let bands: Vec<&ImageBuffer> = (0..3)
+        .map(|b| image.get_band(if image.num_bands() >= 3 { b } else { 0 }))
+        .collect();
+    let mut samples = Vec::with_capacity(image.width * image.height * channels);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            for band in bands.iter() {
+                samples.push(band.get(x, y).unwrap().round().clamp(0.0, max));
+            }
+            if channels == 4 {
+                samples.push(if image.get_alpha_at(x, y) { max } else { 0.0 });
+            }
+        }
+    }
+    (samples, channels)
+}
+
+fn write_tiff<W, C>(
+    w: W,
+    width: u32,
+    height: u32,
+    icc_profile: &[u8],
+    data: &[C::Inner],
+) -> error::Result<()>
+where
+    W: Write + Seek,
+    C: tiff::encoder::colortype::ColorType,
+    [C::Inner]: TiffValue,
+{
+    let mut encoder = tiff::encoder::TiffEncoder::new(w).map_err(|_| "Error encoding TIFF")?;
+    let mut image = encoder
+        .new_image::<C>(width, height)
+        .map_err(|_| "Error encoding TIFF")?;
+    image
+        .encoder()
+        .write_tag(tiff::tags::Tag::Unknown(34675), IccProfileTag(icc_profile))
+        .map_err(|_| "Error writing TIFF ICC profile")?;
+    image.write_data(data).map_err(|_| "Error writing TIFF")
+}
+
+/// Saves an image as PNG or TIFF with an embedded ICC profile, 8 bit or 16 bit following the
+/// image mode like an untagged save
+pub fn save_with_icc_profile(
+    image: &RgbImage,
+    file_path: &str,
+    icc_profile: &[u8],
+) -> error::Result<()> {
+    if !supports_icc_profile(file_path) {
+        return Err("Format can't carry an ICC profile");
+    }
+    if !path::parent_exists_and_writable(file_path) {
+        return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+    }
+
+    let sixteen_bit = image.get_mode() != ImageMode::U8BIT;
+    let (samples, channels) = interleaved_samples(image, if sixteen_bit { 65535.0 } else { 255.0 });
+    let (width, height) = (image.width as u32, image.height as u32);
+
+    let file = File::create(file_path).map_err(|_| "Error creating image file")?;
+    let w = BufWriter::new(file);
+
+    if file_path.to_lowercase().ends_with(".png") {
+        let mut info = png::Info::with_size(width, height);
+        info.color_type = if channels == 4 {
+            png::ColorType::Rgba
+        } else {
+            png::ColorType::Rgb
+        };
+        info.icc_profile = Some(Cow::Borrowed(icc_profile));
+        let data: Vec<u8> = if sixteen_bit {
+            info.bit_depth = png::BitDepth::Sixteen;
+            samples
+                .iter()
+                .flat_map(|v| (*v as u16).to_be_bytes())
+                .collect()
+        } else {
+            info.bit_depth = png::BitDepth::Eight;
+            samples.iter().map(|v| *v as u8).collect()
+        };
+        let encoder = png::Encoder::with_info(w, info).map_err(|_| "Error encoding PNG")?;
+        let mut writer = encoder.write_header().map_err(|_| "Error encoding PNG")?;
+        writer
+            .write_image_data(&data)
+            .map_err(|_| "Error writing PNG")?;
+    } else if sixteen_bit {
+        let data: Vec<u16> = samples.iter().map(|v| *v as u16).collect();
+        if channels == 4 {
+            write_tiff::<_, RGBA16>(w, width, height, icc_profile, &data)?;
+        } else {
+            write_tiff::<_, RGB16>(w, width, height, icc_profile, &data)?;
+        }
+    } else {
+        let data: Vec<u8> = samples.iter().map(|v| *v as u8).collect();
+        if channels == 4 {
+            write_tiff::<_, RGBA8>(w, width, height, icc_profile, &data)?;
+        } else {
+            write_tiff::<_, RGB8>(w, width, height, icc_profile, &data)?;
+        }
+    }
+
+    vprintln!("Embedded ICC profile in {}", file_path);
+    Ok(())
+}
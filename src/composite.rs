@@ -1,194 +1,373 @@
-use crate::prelude::*;
-use sciimg::{max, min, prelude::*, quaternion::Quaternion, vector::Vector};
+use crate::{
+    blend::Layer,
+    frames::{FrameTransform, MosaicFrame, RoverPose},
+    prelude::*,
+    productid,
+    projection::{PlaneBounds, Projection, ProjectionSpec},
+};
+use rayon::prelude::*;
+use sciimg::{max, prelude::*, quaternion::Quaternion, vector::Vector};
+use std::collections::HashMap;
 use std::str::FromStr;
 
-pub fn get_cahvor(img: &MarsImage) -> Option<CameraModel> {
-    match &img.metadata {
-        Some(md) => {
-            if md.camera_model_component_list.is_valid() {
-                Some(md.camera_model_component_list.clone())
-            } else {
-                None
-            }
-        }
-        None => None,
-    }
-}
-
+/// Extent and size of the output map. Bounds are in the units of the map's projection.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct MapContext {
-    pub top_lat: f64,
-    pub bottom_lat: f64,
-    pub left_lon: f64,
-    pub right_lon: f64,
+    pub bounds: PlaneBounds,
     pub width: usize,
     pub height: usize,
-    pub degrees_per_pixel: f64,
+    pub units_per_pixel: f64,
 }
 
-pub struct LatLon {
-    lat: f64,
-    lon: f64,
+static SPHERE_RADIUS: f64 = 100.0;
+
+/// Where a look vector meets the sphere the mosaic is projected onto, carried into the mosaic
+/// frame and rotated
+fn lookvector_to_point(lv: &LookVector, quat: &Quaternion, transform: &FrameTransform) -> Vector {
+    let ray = transform.look_vector(lv).intersect_to_sphere(SPHERE_RADIUS);
+    quat.rotate_vector(&ray)
 }
 
-fn vector_to_cylindrical(v: &Vector) -> LatLon {
-    LatLon {
-        lat: v.z.atan2((v.x * v.x + v.y * v.y).sqrt()).to_degrees(),
-        lon: v.y.atan2(v.x).to_degrees() + 180.0,
+/// Transform into the mosaic frame for an image, from the rover pose in its metadata
+fn frame_transform(img: &MarsImage, frame: &MosaicFrame) -> FrameTransform {
+    let pose = match &img.metadata {
+        Some(md) => RoverPose::from_metadata(md),
+        None => RoverPose::default(),
+    };
+    frame.transform_for(&pose)
+}
+
+/// Map plane coordinates of an image pixel
+fn project_ls(
+    model: &CameraModel,
+    x: f64,
+    y: f64,
+    quat: &Quaternion,
+    transform: &FrameTransform,
+    projection: &dyn Projection,
+) -> Option<(f64, f64)> {
+    let lv = model
+        .ls_to_look_vector(&ImageCoordinate { line: y, sample: x })
+        .ok()?;
+    projection.project(&lookvector_to_point(&lv, quat, transform))
+}
+
+/// Samples per side of the grid of pixels used to find each image's extent on the map
+const EXTENT_SAMPLES: usize = 9;
+
+/// Map plane coordinates of an evenly spaced grid of an image's pixels, `EXTENT_SAMPLES` on a
+/// side, row by row
+fn sample_extent(
+    img: &MarsImage,
+    model: &CameraModel,
+    quat: &Quaternion,
+    transform: &FrameTransform,
+    projection: &dyn Projection,
+) -> Vec<Option<(f64, f64)>> {
+    let step_x = (img.image.width - 1) as f64 / (EXTENT_SAMPLES - 1) as f64;
+    let step_y = (img.image.height - 1) as f64 / (EXTENT_SAMPLES - 1) as f64;
+    let mut samples = Vec::with_capacity(EXTENT_SAMPLES * EXTENT_SAMPLES);
+    for j in 0..EXTENT_SAMPLES {
+        for i in 0..EXTENT_SAMPLES {
+            samples.push(project_ls(
+                model,
+                i as f64 * step_x,
+                j as f64 * step_y,
+                quat,
+                transform,
+                projection,
+            ));
+        }
     }
+    samples
 }
 
-fn lookvector_to_cylindrical(
-    lv: &LookVector,
-    quat_o: Option<&Quaternion>,
-    origin_diff: &Vector,
-) -> LatLon {
-    let ray = lv.intersect_to_sphere(SPHERE_RADIUS);
-    let ray_moved = ray.subtract(origin_diff);
-    let rotated = if let Some(quat) = quat_o {
-        quat.rotate_vector(&ray_moved)
+pub fn determine_map_context(
+    input_files: &[String],
+    quat: &Quaternion,
+    frame: &MosaicFrame,
+    projection_spec: &ProjectionSpec,
+) -> MapContext {
+    let projection = projection_spec
+        .build()
+        .expect("Unable to build map projection");
+
+    let (bounds, pixel_angle) = input_files
+        .par_iter()
+        .map_init(
+            || projection_spec.build().unwrap(),
+            |projection, input_file| {
+                let mut bounds = PlaneBounds::empty();
+                let img = MarsImage::open(input_file.to_owned(), Instrument::M20MastcamZLeft);
+                match img.camera_model() {
+                    Ok(c) => {
+                        let transform = frame_transform(&img, frame);
+                        for (px, py) in
+                            sample_extent(&img, &c, quat, &transform, projection.as_ref())
+                                .into_iter()
+                                .flatten()
+                        {
+                            bounds.include(px, py);
+                        }
+                        (bounds, c.pixel_angle_horiz())
+                    }
+                    Err(_) => (bounds, 0.0),
+                }
+            },
+        )
+        .reduce(
+            || (PlaneBounds::empty(), 0.0),
+            |(a, angle_a), (b, angle_b)| (a.union(&b), max!(angle_a, angle_b)),
+        );
+
+    let bounds = match projection.fixed_bounds() {
+        Some(b) => b,
+        None => bounds.intersect(&projection.limits()),
+    };
+    let units_per_pixel = projection.units_per_pixel(pixel_angle);
+
+    let (width, height) = if bounds.width() > 0.0 && bounds.height() > 0.0 && units_per_pixel > 0.0
+    {
+        (
+            (bounds.width() / units_per_pixel).floor() as usize,
+            (bounds.height() / units_per_pixel).floor() as usize,
+        )
     } else {
-        ray
+        (0, 0)
     };
-    vector_to_cylindrical(&rotated)
+
+    MapContext {
+        bounds,
+        width,
+        height,
+        units_per_pixel,
+    }
 }
 
-static SPHERE_RADIUS: f64 = 100.0;
+/// Output pixel coordinates of a point on the map plane
+fn plane_to_map(map_context: &MapContext, px: f64, py: f64) -> (f64, f64) {
+    let b = &map_context.bounds;
+    (
+        (px - b.min_x) / b.width() * map_context.width as f64,
+        (py - b.min_y) / b.height() * map_context.height as f64,
+    )
+}
 
-fn get_lat_lon(c: &CameraModel, x: usize, y: usize, _quat: &Quaternion) -> error::Result<LatLon> {
-    match c.ls_to_look_vector(&ImageCoordinate {
-        line: y as f64,
-        sample: x as f64,
-    }) {
-        Ok(lv) => Ok(lookvector_to_cylindrical(&lv, None, &Vector::default())),
-        Err(e) => Err(e),
-    }
+fn get_ls_from_map_xy(
+    model: &CameraModel,
+    map_context: &MapContext,
+    x: usize,
+    y: usize,
+    quat: &Quaternion,
+    transform: &FrameTransform,
+    projection: &dyn Projection,
+) -> Option<(f64, f64)> {
+    let (px, py) = project_ls(model, x as f64, y as f64, quat, transform, projection)?;
+    Some(plane_to_map(map_context, px, py))
 }
 
-pub fn determine_map_context(input_files: &[String], quat: &Quaternion) -> MapContext {
-    let mut context = MapContext {
-        top_lat: -90.0,
-        bottom_lat: 90.0,
-        left_lon: 360.0,
-        right_lon: -360.0,
-        width: 0,
-        height: 0,
-        degrees_per_pixel: 0.0,
+fn open_image(input_file: &str) -> MarsImage {
+    let mut img = MarsImage::open(String::from(input_file), Instrument::M20MastcamZLeft);
+    img.instrument = match &img.metadata {
+        Some(md) => Instrument::from_str(md.instrument.as_str()).unwrap(),
+        None => Instrument::M20MastcamZLeft,
     };
+    img
+}
 
-    input_files.iter().for_each(|input_file| {
-        let img = MarsImage::open(input_file.to_owned(), Instrument::M20MastcamZLeft);
-        if let Some(c) = get_cahvor(&img) {
-            if let Ok(ll) = get_lat_lon(&c, 0, 0, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
-            }
+fn print_model(input_model: &CameraModel) {
+    vprintln!("");
+    vprintln!("Input Model C: {:?}", input_model.c());
+    vprintln!("Input Model A: {:?}", input_model.a());
+    vprintln!("Input Model H: {:?}", input_model.h());
+    vprintln!("Input Model V: {:?}", input_model.v());
+    vprintln!("Input Model O: {:?}", input_model.o());
+    vprintln!("Input Model R: {:?}", input_model.r());
+    vprintln!("Input Model E: {:?}", input_model.e());
+    vprintln!("");
+}
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width, 0, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
-            }
+/// Map coordinates of every pixel of an image, row by row
+fn project_pixels(
+    img: &RgbImage,
+    input_model: &CameraModel,
+    map_context: &MapContext,
+    quat: &Quaternion,
+    transform: &FrameTransform,
+    projection: &dyn Projection,
+) -> Vec<Option<(f64, f64)>> {
+    let mut grid = Vec::with_capacity(img.width * img.height);
+    for y in 0..img.height {
+        for x in 0..img.width {
+            grid.push(get_ls_from_map_xy(
+                input_model,
+                map_context,
+                x,
+                y,
+                quat,
+                transform,
+                projection,
+            ));
+        }
+    }
+    grid
+}
 
-            if let Ok(ll) = get_lat_lon(&c, 0, img.image.height, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
-            }
+/// Corners of the square between pixel (x, y) and its lower right neighbor, in the order top
+/// left, top right, bottom left, bottom right. `None` if any corner isn't on the projection.
+fn square_corners(
+    grid: &[Option<(f64, f64)>],
+    width: usize,
+    x: usize,
+    y: usize,
+) -> Option<[(f64, f64); 4]> {
+    Some([
+        grid[y * width + x]?,
+        grid[y * width + x + 1]?,
+        grid[(y + 1) * width + x]?,
+        grid[(y + 1) * width + x + 1]?,
+    ])
+}
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width, img.image.height, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
-            }
+/// Paints the projected pixels of an image onto a map, shifted up and left by the offset.
+/// Squares entirely off the map are skipped.
+fn paint_projected<D: Drawable>(
+    img: &RgbImage,
+    grid: &[Option<(f64, f64)>],
+    map: &mut D,
+    offset_x: f64,
+    offset_y: f64,
+    eye: Eye,
+) {
+    let band_0 = img.get_band(0);
+    let band_1 = img.get_band(1);
+    let band_2 = img.get_band(2);
+    let width = img.width;
+    let (map_width, map_height) = (map.get_width() as f64, map.get_height() as f64);
+
+    let point = |(mx, my): (f64, f64), x: usize, y: usize| {
+        Point::create(
+            mx - offset_x,
+            my - offset_y,
+            band_0.get(x, y).unwrap() as f64,
+            band_1.get(x, y).unwrap() as f64,
+            band_2.get(x, y).unwrap() as f64,
+        )
+    };
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width / 2, 0, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
+    for x in 0..(img.width - 1) {
+        for y in 0..(img.height - 1) {
+            if !band_0.get_mask_at_point(x, y) {
+                continue;
             }
 
-            if let Ok(ll) = get_lat_lon(&c, img.image.width / 2, img.image.height, quat) {
-                context.bottom_lat = min!(context.bottom_lat, ll.lat);
-                context.top_lat = max!(context.top_lat, ll.lat);
-                context.left_lon = min!(context.left_lon, ll.lon);
-                context.right_lon = max!(context.right_lon, ll.lon);
+            let [tl, tr, bl, br] = match square_corners(grid, width, x, y) {
+                Some(corners) => corners,
+                None => continue,
+            };
+            let corners = [tl, tr, bl, br];
+            if corners.iter().all(|c| c.0 - offset_x < 0.0)
+                || corners.iter().all(|c| c.0 - offset_x >= map_width)
+                || corners.iter().all(|c| c.1 - offset_y < 0.0)
+                || corners.iter().all(|c| c.1 - offset_y >= map_height)
+            {
+                continue;
             }
+            let tl = point(tl, x, y);
+            let tr = point(tr, x + 1, y);
+            let bl = point(bl, x, y + 1);
+            let br = point(br, x + 1, y + 1);
 
-            let ang_horiz = c.pixel_angle_horiz().to_degrees();
-            context.degrees_per_pixel = max!(context.degrees_per_pixel, ang_horiz);
-        };
-    });
-
-    context.top_lat = min!(context.top_lat, 90.0);
-    context.bottom_lat = max!(context.bottom_lat, -90.0);
-    context.left_lon = max!(context.left_lon, 0.0);
-    context.right_lon = min!(context.right_lon, 360.0);
-    context.height =
-        ((context.top_lat - context.bottom_lat) / context.degrees_per_pixel).floor() as usize;
-    context.width =
-        ((context.right_lon - context.left_lon) / context.degrees_per_pixel).floor() as usize;
-
-    context
+            map.paint_square(&tl, &bl, &br, &tr, false, eye);
+        }
+    }
 }
 
-fn get_ls_from_map_xy(
-    model: &CameraModel,
+/// Largest extent of a projected square that gets painted. Larger squares are those wrapping
+/// around the map's cut-off azimuth, which `Drawable::paint_tri` skips.
+const MAX_SQUARE_EXTENT: f64 = 100.0;
+
+/// Bounding box (x, y, width, height) of the painted footprint of an image on the map
+fn projected_bounds(
+    img: &RgbImage,
+    grid: &[Option<(f64, f64)>],
     map_context: &MapContext,
-    x: usize,
-    y: usize,
-    quat: &Quaternion,
-    origin_diff: &Vector,
-) -> (f64, f64) {
-    let img_x = x as f64;
-    let img_y = y as f64;
-
-    let lv = match model.ls_to_look_vector(&ImageCoordinate {
-        line: img_y,
-        sample: img_x,
-    }) {
-        Ok(lv) => lv,
-        Err(_) => panic!("Unable to convert ls to look vector"),
-    };
+) -> Option<(usize, usize, usize, usize)> {
+    let band_0 = img.get_band(0);
+    let width = img.width;
+    let mut bounds: Option<(f64, f64, f64, f64)> = None;
+
+    for y in 0..(img.height - 1) {
+        for x in 0..(width - 1) {
+            if !band_0.get_mask_at_point(x, y) {
+                continue;
+            }
+            let corners = match square_corners(grid, width, x, y) {
+                Some(corners) => corners,
+                None => continue,
+            };
+            let x_min = corners.iter().map(|c| c.0).fold(f64::MAX, f64::min);
+            let x_max = corners.iter().map(|c| c.0).fold(f64::MIN, f64::max);
+            let y_min = corners.iter().map(|c| c.1).fold(f64::MAX, f64::min);
+            let y_max = corners.iter().map(|c| c.1).fold(f64::MIN, f64::max);
+            if x_max - x_min >= MAX_SQUARE_EXTENT || y_max - y_min >= MAX_SQUARE_EXTENT {
+                continue;
+            }
+            bounds = Some(match bounds {
+                Some((l, t, r, b)) => (l.min(x_min), t.min(y_min), r.max(x_max), b.max(y_max)),
+                None => (x_min, y_min, x_max, y_max),
+            });
+        }
+    }
 
-    let ll = lookvector_to_cylindrical(&lv, Some(quat), origin_diff);
-    let lat = ll.lat;
-    let lon = ll.lon;
+    let (l, t, r, b) = bounds?;
+    clamp_to_map(l, t, r, b, map_context)
+}
 
-    let out_y_f = (lat - map_context.bottom_lat) / (map_context.top_lat - map_context.bottom_lat)
-        * map_context.height as f64;
-    let out_x_f = (lon - map_context.left_lon) / (map_context.right_lon - map_context.left_lon)
-        * map_context.width as f64;
+/// Bounding box (x, y, width, height) of the map pixels covered by the extent from `l`, `t` to
+/// `r`, `b`. `None` if the extent is entirely off the map.
+fn clamp_to_map(
+    l: f64,
+    t: f64,
+    r: f64,
+    b: f64,
+    map_context: &MapContext,
+) -> Option<(usize, usize, usize, usize)> {
+    let left = l.floor().max(0.0) as usize;
+    let top = t.floor().max(0.0) as usize;
+    let right = (r.ceil().max(0.0) as usize).min(map_context.width.saturating_sub(1));
+    let bottom = (b.ceil().max(0.0) as usize).min(map_context.height.saturating_sub(1));
+    if right < left || bottom < top {
+        None
+    } else {
+        Some((left, top, right - left + 1, bottom - top + 1))
+    }
+}
 
-    (out_x_f, out_y_f)
+/// An input image with the map position of each of its pixels
+pub struct ProjectedImage {
+    pub image: RgbImage,
+    /// Map coordinates of each pixel, row by row, `None` where off the projection
+    pub grid: Vec<Option<(f64, f64)>>,
+    pub eye: Eye,
+    /// Bounding box (x, y, width, height) of the image's footprint on the map
+    pub footprint: (usize, usize, usize, usize),
 }
 
-pub fn process_file<D: Drawable>(
+/// Projects every pixel of an image onto the map. Returns `None` if the image doesn't land on
+/// the map.
+pub fn project_image(
     input_file: &str,
     map_context: &MapContext,
-    map: &mut D,
     anaglyph: bool,
     quat: &Quaternion,
-    initial_origin: &Vector,
-) {
-    let mut img = MarsImage::open(String::from(input_file), Instrument::M20MastcamZLeft);
-    img.instrument = match &img.metadata {
-        Some(md) => Instrument::from_str(md.instrument.as_str()).unwrap(),
-        None => Instrument::M20MastcamZLeft,
-    };
+    frame: &MosaicFrame,
+    projection: &dyn Projection,
+) -> Option<ProjectedImage> {
+    let img = open_image(input_file);
 
     let eye = if anaglyph {
-        match util::filename_char_at_pos(input_file, 1) {
-            'R' => Eye::Right,
-            'L' => Eye::Left,
-            _ => Eye::DontCare,
-        }
+        productid::eye_for_file(input_file)
     } else {
         Eye::DontCare
     };
@@ -197,84 +376,314 @@ pub fn process_file<D: Drawable>(
     //vprintln!("Rotating pitch to {}", pitch);
     //let quat = Quaternion::from_pitch_roll_yaw(0.0, -20.0f64.to_radians(), azimuth_rotation.to_radians());
 
-    match get_cahvor(&img) {
-        Some(input_model) => {
-            vprintln!("");
-            vprintln!("Input Model C: {:?}", input_model.c());
-            vprintln!("Input Model A: {:?}", input_model.a());
-            vprintln!("Input Model H: {:?}", input_model.h());
-            vprintln!("Input Model V: {:?}", input_model.v());
-            vprintln!("Input Model O: {:?}", input_model.o());
-            vprintln!("Input Model R: {:?}", input_model.r());
-            vprintln!("Input Model E: {:?}", input_model.e());
-            vprintln!("");
-
-            let band_0 = img.image.get_band(0);
-            let band_1 = img.image.get_band(1);
-            let band_2 = img.image.get_band(2);
-
-            for x in 0..(img.image.width - 1) {
-                for y in 0..(img.image.height - 1) {
-                    let origin_diff = input_model.c().subtract(initial_origin);
-
-                    let (tl_x, tl_y) =
-                        get_ls_from_map_xy(&input_model, map_context, x, y, quat, &origin_diff);
-                    let (tr_x, tr_y) =
-                        get_ls_from_map_xy(&input_model, map_context, x + 1, y, quat, &origin_diff);
-                    let (bl_x, bl_y) =
-                        get_ls_from_map_xy(&input_model, map_context, x, y + 1, quat, &origin_diff);
-                    let (br_x, br_y) = get_ls_from_map_xy(
-                        &input_model,
-                        map_context,
-                        x + 1,
-                        y + 1,
-                        quat,
-                        &origin_diff,
-                    );
-
-                    if !band_0.get_mask_at_point(x, y) {
-                        continue;
-                    }
+    match img.camera_model() {
+        Ok(input_model) => {
+            print_model(&input_model);
+            let grid = project_pixels(
+                &img.image,
+                &input_model,
+                map_context,
+                quat,
+                &frame_transform(&img, frame),
+                projection,
+            );
+            let footprint = projected_bounds(&img.image, &grid, map_context)?;
+            Some(ProjectedImage {
+                image: img.image,
+                grid,
+                eye,
+                footprint,
+            })
+        }
+        Err(_) => {
+            eprintln!("CAHVOR not found for image, cannot continue");
+            panic!("CAHVOR not found for image, cannot continue");
+        }
+    }
+}
+
+pub fn process_file<D: Drawable>(
+    input_file: &str,
+    map_context: &MapContext,
+    map: &mut D,
+    anaglyph: bool,
+    quat: &Quaternion,
+    frame: &MosaicFrame,
+    projection: &dyn Projection,
+) {
+    if let Some(p) = project_image(input_file, map_context, anaglyph, quat, frame, projection) {
+        paint_projected(&p.image, &p.grid, map, 0.0, 0.0, p.eye);
+    }
+}
+
+/// Projects an image onto its own layer covering just its footprint on the map, for blending
+/// with `blend::blend`. Returns `None` if the image doesn't land on the map.
+pub fn project_file(
+    input_file: &str,
+    map_context: &MapContext,
+    anaglyph: bool,
+    quat: &Quaternion,
+    frame: &MosaicFrame,
+    projection: &dyn Projection,
+) -> Option<Layer> {
+    let p = project_image(input_file, map_context, anaglyph, quat, frame, projection)?;
+    let (x, y, width, height) = p.footprint;
+    vprintln!("Footprint: {}x{} at {}, {}", width, height, x, y);
+
+    let mut layer = Layer::new(x, y, width, height, p.eye);
+    paint_projected(
+        &p.image,
+        &p.grid,
+        &mut layer.image,
+        x as f64,
+        y as f64,
+        Eye::DontCare,
+    );
+    Some(layer)
+}
 
-                    let tl = Point::create(
-                        tl_x,
-                        tl_y,
-                        band_0.get(x, y).unwrap() as f64,
-                        band_1.get(x, y).unwrap() as f64,
-                        band_2.get(x, y).unwrap() as f64,
-                    );
-
-                    let tr = Point::create(
-                        tr_x,
-                        tr_y,
-                        band_0.get(x + 1, y).unwrap() as f64,
-                        band_1.get(x + 1, y).unwrap() as f64,
-                        band_2.get(x + 1, y).unwrap() as f64,
-                    );
-
-                    let bl = Point::create(
-                        bl_x,
-                        bl_y,
-                        band_0.get(x, y + 1).unwrap() as f64,
-                        band_1.get(x, y + 1).unwrap() as f64,
-                        band_2.get(x, y + 1).unwrap() as f64,
-                    );
-
-                    let br = Point::create(
-                        br_x,
-                        br_y,
-                        band_0.get(x + 1, y + 1).unwrap() as f64,
-                        band_1.get(x + 1, y + 1).unwrap() as f64,
-                        band_2.get(x + 1, y + 1).unwrap() as f64,
-                    );
-
-                    map.paint_square(&tl, &bl, &br, &tr, false, eye);
+/// Settings shared by every image of a mosaic
+#[derive(Debug, Clone)]
+pub struct MosaicOptions {
+    pub anaglyph: bool,
+    pub quat: Quaternion,
+    pub frame: MosaicFrame,
+    pub projection: ProjectionSpec,
+}
+
+impl MosaicOptions {
+    /// Projection for a worker. Projections can't be shared between threads, so each rayon
+    /// worker builds one and reuses it for every image it handles.
+    fn build_projection(&self) -> Box<dyn Projection> {
+        self.projection
+            .build()
+            .expect("Unable to build map projection")
+    }
+
+    fn project(
+        &self,
+        input_file: &str,
+        map_context: &MapContext,
+        projection: &dyn Projection,
+    ) -> Option<ProjectedImage> {
+        project_image(
+            input_file,
+            map_context,
+            self.anaglyph,
+            &self.quat,
+            &self.frame,
+            projection,
+        )
+    }
+
+    /// Bounding box (x, y, width, height) of an image on the map, found from a sparse grid of
+    /// its pixels rather than by projecting all of them. The box is padded by the spacing of
+    /// the grid so edges that curve between samples stay inside it.
+    fn footprint(
+        &self,
+        input_file: &str,
+        map_context: &MapContext,
+        projection: &dyn Projection,
+    ) -> Option<(usize, usize, usize, usize)> {
+        let img = MarsImage::open(String::from(input_file), Instrument::M20MastcamZLeft);
+        let model = img.camera_model().ok()?;
+        let samples: Vec<Option<(f64, f64)>> = sample_extent(
+            &img,
+            &model,
+            &self.quat,
+            &frame_transform(&img, &self.frame),
+            projection,
+        )
+        .into_iter()
+        .map(|s| s.map(|(px, py)| plane_to_map(map_context, px, py)))
+        .collect();
+
+        let mut bounds: Option<(f64, f64, f64, f64)> = None;
+        let mut spacing: f64 = 0.0;
+        for j in 0..EXTENT_SAMPLES {
+            for i in 0..EXTENT_SAMPLES {
+                let (x, y) = match samples[j * EXTENT_SAMPLES + i] {
+                    Some(p) => p,
+                    None => continue,
+                };
+                bounds = Some(match bounds {
+                    Some((l, t, r, b)) => (l.min(x), t.min(y), r.max(x), b.max(y)),
+                    None => (x, y, x, y),
+                });
+                let right = (i + 1 < EXTENT_SAMPLES).then(|| samples[j * EXTENT_SAMPLES + i + 1]);
+                let below = (j + 1 < EXTENT_SAMPLES).then(|| samples[(j + 1) * EXTENT_SAMPLES + i]);
+                for (nx, ny) in [right, below].into_iter().flatten().flatten() {
+                    spacing = spacing.max((nx - x).abs()).max((ny - y).abs());
                 }
             }
         }
-        None => {
-            eprintln!("CAHVOR not found for image, cannot continue");
-            panic!("CAHVOR not found for image, cannot continue");
+
+        let (l, t, r, b) = bounds?;
+        clamp_to_map(
+            l - spacing,
+            t - spacing,
+            r + spacing,
+            b + spacing,
+            map_context,
+        )
+    }
+}
+
+/// Projects each input onto its own layer, in parallel. Inputs that don't land on the map are
+/// left out.
+pub fn project_layers(
+    input_files: &[String],
+    map_context: &MapContext,
+    options: &MosaicOptions,
+) -> Vec<Layer> {
+    input_files
+        .par_iter()
+        .map_init(
+            || options.build_projection(),
+            |projection, input_file| {
+                vprintln!("Processing File: {}", input_file);
+                let layer = project_file(
+                    input_file,
+                    map_context,
+                    options.anaglyph,
+                    &options.quat,
+                    &options.frame,
+                    projection.as_ref(),
+                );
+                if layer.is_none() {
+                    vprintln!("Image does not land on the map: {}", input_file);
+                }
+                layer
+            },
+        )
+        .collect::<Vec<Option<Layer>>>()
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+fn overlaps(
+    footprint: &(usize, usize, usize, usize),
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+) -> bool {
+    let (fx, fy, fw, fh) = *footprint;
+    fx < x + w && x < fx + fw && fy < y + h && y < fy + fh
+}
+
+/// Renders the mosaic in strips of `tile_size` rows, later inputs painted over earlier ones,
+/// passing each strip to `sink` with the row it starts at. Inputs are projected in parallel
+/// and kept only while they overlap the current strip, and each strip is painted in
+/// `tile_size` square tiles in parallel, so memory use is bounded by the strip and the inputs
+/// crossing it rather than by the whole mosaic.
+pub fn render_strips<F>(
+    input_files: &[String],
+    map_context: &MapContext,
+    options: &MosaicOptions,
+    tile_size: usize,
+    mut sink: F,
+) where
+    F: FnMut(usize, &RgbImage),
+{
+    let tile_size = tile_size.max(1);
+
+    // Footprints are estimated up front so each strip knows which inputs it needs, without
+    // projecting every pixel of every input twice
+    let footprints: Vec<Option<(usize, usize, usize, usize)>> = input_files
+        .par_iter()
+        .map_init(
+            || options.build_projection(),
+            |projection, input_file| {
+                vprintln!("Finding footprint of {}", input_file);
+                options.footprint(input_file, map_context, projection.as_ref())
+            },
+        )
+        .collect();
+
+    let mut cache: HashMap<usize, ProjectedImage> = HashMap::new();
+
+    for strip_y in (0..map_context.height).step_by(tile_size) {
+        let strip_height = tile_size.min(map_context.height - strip_y);
+        vprintln!(
+            "Rendering rows {} to {}",
+            strip_y,
+            strip_y + strip_height - 1
+        );
+
+        let active: Vec<usize> = footprints
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| {
+                f.as_ref()
+                    .is_some_and(|f| overlaps(f, 0, strip_y, map_context.width, strip_height))
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        cache.retain(|i, _| active.contains(i));
+        let missing: Vec<usize> = active
+            .iter()
+            .filter(|i| !cache.contains_key(i))
+            .copied()
+            .collect();
+        let projected: Vec<(usize, Option<ProjectedImage>)> = missing
+            .par_iter()
+            .map_init(
+                || options.build_projection(),
+                |projection, i| {
+                    (
+                        *i,
+                        options.project(&input_files[*i], map_context, projection.as_ref()),
+                    )
+                },
+            )
+            .collect();
+        for (i, p) in projected.into_iter() {
+            if let Some(p) = p {
+                cache.insert(i, p);
+            }
+        }
+
+        let tiles: Vec<(usize, RgbImage)> = (0..map_context.width)
+            .step_by(tile_size)
+            .collect::<Vec<usize>>()
+            .par_iter()
+            .map(|tile_x| {
+                let tile_width = tile_size.min(map_context.width - tile_x);
+                let mut tile = RgbImage::create_masked(tile_width, strip_height, false);
+                for i in active.iter() {
+                    if let Some(p) = cache.get(i) {
+                        if overlaps(&p.footprint, *tile_x, strip_y, tile_width, strip_height) {
+                            paint_projected(
+                                &p.image,
+                                &p.grid,
+                                &mut tile,
+                                *tile_x as f64,
+                                strip_y as f64,
+                                p.eye,
+                            );
+                        }
+                    }
+                }
+                (*tile_x, tile)
+            })
+            .collect();
+
+        let mut strip = RgbImage::create_masked(map_context.width, strip_height, false);
+        for (tile_x, tile) in tiles.iter() {
+            for y in 0..tile.height {
+                for x in 0..tile.width {
+                    if tile.get_alpha_at(x, y) {
+                        for b in 0..3 {
+                            strip.put(tile_x + x, y, tile.get_band(b).get(x, y).unwrap(), b);
+                        }
+                        strip.put_alpha(tile_x + x, y, true);
+                    }
+                }
+            }
         }
+        sink(strip_y, &strip);
     }
 }
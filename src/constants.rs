@@ -67,6 +67,10 @@ pub mod status {
     pub const NO: &str = "No";
     pub const DOWNLOADING: &str = "Downloading";
     pub const INVALID_CALIBRATION_FILE_ID: &str = "Invalid calibration file";
+    pub const CAMERA_MODEL_NOT_FOUND: &str = "Camera model not found";
+    pub const INDEX_OUT_OF_RANGE: &str = "Index out of range";
+    pub const ERROR_WRITING_METADATA: &str = "Error writing metadata";
+    pub const INVALID_STEREO_PAIR: &str = "Invalid stereo pair";
 }
 
 // Parameters
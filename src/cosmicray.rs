@@ -0,0 +1,381 @@
+use crate::{calprofile::CalProfile, constants, hotpixel, image::MarsImage, util, vprintln};
+
+use sciimg::{error, imagebuffer::ImageBuffer};
+
+/// Value marking a rejected pixel in a cosmic ray mask
+pub const COSMIC_RAY_MASK_VALUE: f32 = 255.0;
+
+/// Parameters of the single frame L.A.Cosmic detector (van Dokkum 2001)
+#[derive(Debug, Clone, Copy)]
+pub struct LaCosmicParams {
+    /// Detection limit in units of the noise
+    pub sigma_clip: f32,
+    /// Fraction of `sigma_clip` used when growing detections into neighboring pixels
+    pub sigma_frac: f32,
+    /// Minimum contrast between the Laplacian and the fine structure image. Raise it to keep
+    /// sharp stars or undersampled rock edges from being taken as hits.
+    pub obj_limit: f32,
+    /// Detector gain, electrons per DN
+    pub gain: f32,
+    /// Read noise, electrons
+    pub read_noise: f32,
+    /// Maximum number of detect and replace passes
+    pub iterations: usize,
+}
+
+/// The defaults of a calibration profile
+impl Default for LaCosmicParams {
+    fn default() -> Self {
+        LaCosmicParams::from_profile(&CalProfile::default())
+    }
+}
+
+impl LaCosmicParams {
+    pub fn from_profile(cal_context: &CalProfile) -> LaCosmicParams {
+        LaCosmicParams {
+            sigma_clip: cal_context.cosmic_ray_sigma_clip,
+            sigma_frac: cal_context.cosmic_ray_sigma_frac,
+            obj_limit: cal_context.cosmic_ray_obj_limit,
+            gain: cal_context.cosmic_ray_gain,
+            read_noise: cal_context.cosmic_ray_read_noise,
+            iterations: cal_context.cosmic_ray_iterations,
+        }
+    }
+}
+
+fn new_mask(width: usize, height: usize) -> ImageBuffer {
+    ImageBuffer::new_as_mode(width, height, sciimg::enums::ImageMode::U8BIT).unwrap()
+}
+
+fn clamped_get(buffer: &ImageBuffer, x: i32, y: i32) -> f32 {
+    let cx = x.clamp(0, buffer.width as i32 - 1) as usize;
+    let cy = y.clamp(0, buffer.height as i32 - 1) as usize;
+    buffer.get(cx, cy).unwrap()
+}
+
+fn median_filter(buffer: &ImageBuffer, size: i32) -> ImageBuffer {
+    let half = size / 2;
+    let mut filtered = ImageBuffer::new(buffer.width, buffer.height).unwrap();
+    let mut window: Vec<f32> = Vec::with_capacity((size * size) as usize);
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            window.clear();
+            for wy in -half..=half {
+                for wx in -half..=half {
+                    window.push(clamped_get(buffer, x as i32 + wx, y as i32 + wy));
+                }
+            }
+            filtered.put(x, y, hotpixel::median(&mut window));
+        }
+    }
+    filtered
+}
+
+/// Positive part of the Laplacian computed on a 2x subsampled grid and block averaged back.
+/// Subsampling keeps the sharp edge of a single pixel hit from being smeared over its
+/// neighbors.
+fn subsampled_laplacian(buffer: &ImageBuffer) -> ImageBuffer {
+    let w = buffer.width as i32 * 2;
+    let h = buffer.height as i32 * 2;
+    let sub = |x: i32, y: i32| clamped_get(buffer, x.clamp(0, w - 1) / 2, y.clamp(0, h - 1) / 2);
+
+    let mut lap = ImageBuffer::new(buffer.width, buffer.height).unwrap();
+    for y in 0..buffer.height as i32 {
+        for x in 0..buffer.width as i32 {
+            let mut sum = 0.0;
+            for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let px = x * 2 + sx;
+                let py = y * 2 + sy;
+                let l = 4.0 * sub(px, py)
+                    - sub(px - 1, py)
+                    - sub(px + 1, py)
+                    - sub(px, py - 1)
+                    - sub(px, py + 1);
+                sum += l.max(0.0);
+            }
+            lap.put(x as usize, y as usize, sum / 4.0);
+        }
+    }
+    lap
+}
+
+fn is_masked(mask: &ImageBuffer, x: usize, y: usize) -> bool {
+    mask.get(x, y).unwrap() > 0.0
+}
+
+/// Marks the unmasked 3x3 neighbors of masked pixels whose significance exceeds `limit`
+fn grow_mask(mask: &ImageBuffer, significance: &ImageBuffer, limit: f32) -> ImageBuffer {
+    let mut grown = mask.clone();
+    for y in 0..mask.height {
+        for x in 0..mask.width {
+            if is_masked(mask, x, y) || significance.get(x, y).unwrap() <= limit {
+                continue;
+            }
+            let near_hit = (-1..=1).any(|wy: i32| {
+                (-1..=1).any(|wx: i32| {
+                    let nx = x as i32 + wx;
+                    let ny = y as i32 + wy;
+                    nx >= 0
+                        && ny >= 0
+                        && nx < mask.width as i32
+                        && ny < mask.height as i32
+                        && is_masked(mask, nx as usize, ny as usize)
+                })
+            });
+            if near_hit {
+                grown.put(x, y, COSMIC_RAY_MASK_VALUE);
+            }
+        }
+    }
+    grown
+}
+
+/// Replaces masked pixels with the median of the unmasked pixels in the surrounding 5x5
+/// window
+fn replace_masked(buffer: &ImageBuffer, mask: &ImageBuffer) -> ImageBuffer {
+    let mut cleaned = buffer.clone();
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            if !is_masked(mask, x, y) {
+                continue;
+            }
+            let mut window: Vec<f32> = vec![];
+            for wy in -2..=2_i32 {
+                for wx in -2..=2_i32 {
+                    let nx = x as i32 + wx;
+                    let ny = y as i32 + wy;
+                    if nx >= 0
+                        && ny >= 0
+                        && nx < buffer.width as i32
+                        && ny < buffer.height as i32
+                        && !is_masked(mask, nx as usize, ny as usize)
+                    {
+                        window.push(buffer.get(nx as usize, ny as usize).unwrap());
+                    }
+                }
+            }
+            if !window.is_empty() {
+                cleaned.put(x, y, hotpixel::median(&mut window));
+            }
+        }
+    }
+    cleaned
+}
+
+/// Single detection pass. Returns the mask of newly detected hits.
+fn lacosmic_pass(buffer: &ImageBuffer, params: &LaCosmicParams) -> ImageBuffer {
+    let lap = subsampled_laplacian(buffer);
+    let med5 = median_filter(buffer, 5);
+
+    // Significance of the Laplacian against the noise model. The factor of two undoes the
+    // gain in Laplacian amplitude from subsampling.
+    let mut significance = ImageBuffer::new(buffer.width, buffer.height).unwrap();
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            let signal = med5.get(x, y).unwrap().max(0.00001) * params.gain;
+            let noise = (signal + params.read_noise.powi(2)).sqrt() / params.gain;
+            significance.put(x, y, lap.get(x, y).unwrap() / (2.0 * noise));
+        }
+    }
+
+    // Remove smooth, extended structure from the significance image
+    let sig_med = median_filter(&significance, 5);
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            let s = significance.get(x, y).unwrap() - sig_med.get(x, y).unwrap();
+            significance.put(x, y, s);
+        }
+    }
+
+    // Fine structure image, used to reject compact but resolved scene features
+    let med3 = median_filter(buffer, 3);
+    let med7 = median_filter(&med3, 7);
+
+    let mut mask = new_mask(buffer.width, buffer.height);
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            if significance.get(x, y).unwrap() <= params.sigma_clip {
+                continue;
+            }
+            let fine = (med3.get(x, y).unwrap() - med7.get(x, y).unwrap()).max(0.01);
+            if lap.get(x, y).unwrap() / fine > params.obj_limit {
+                mask.put(x, y, COSMIC_RAY_MASK_VALUE);
+            }
+        }
+    }
+
+    let mask = grow_mask(&mask, &significance, params.sigma_clip);
+    grow_mask(&mask, &significance, params.sigma_clip * params.sigma_frac)
+}
+
+/// L.A.Cosmic cosmic ray detection on a single frame. Detection and replacement are repeated
+/// until no new hits are found or the iteration limit is reached. Returns the cleaned buffer
+/// and a mask of the rejected pixels.
+pub fn lacosmic(buffer: &ImageBuffer, params: &LaCosmicParams) -> (ImageBuffer, ImageBuffer) {
+    let mut cleaned = buffer.clone();
+    let mut mask = new_mask(buffer.width, buffer.height);
+
+    for i in 0..params.iterations.max(1) {
+        let found = lacosmic_pass(&cleaned, params);
+        let mut new_hits = 0;
+        for y in 0..mask.height {
+            for x in 0..mask.width {
+                if is_masked(&found, x, y) && !is_masked(&mask, x, y) {
+                    mask.put(x, y, COSMIC_RAY_MASK_VALUE);
+                    new_hits += 1;
+                }
+            }
+        }
+        vprintln!(
+            "L.A.Cosmic pass {}: {} new pixels rejected",
+            i + 1,
+            new_hits
+        );
+        if new_hits == 0 {
+            break;
+        }
+        cleaned = replace_masked(buffer, &mask);
+    }
+
+    (cleaned, mask)
+}
+
+/// Sigma-clipped mean of a sequence of aligned frames. Radiation hits only ever add charge, so
+/// for each pixel samples more than `sigma` standard deviations above the median of the other
+/// samples are rejected, one per pass for up to `iterations` passes. Returns the clipped mean
+/// and a mask per frame of the rejected samples.
+pub fn sigma_clip_mean(
+    frames: &[&ImageBuffer],
+    sigma: f32,
+    iterations: usize,
+) -> error::Result<(ImageBuffer, Vec<ImageBuffer>)> {
+    if frames.is_empty() {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    let width = frames[0].width;
+    let height = frames[0].height;
+    if frames
+        .iter()
+        .any(|f| f.width != width || f.height != height)
+    {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+
+    let mut mean = ImageBuffer::new(width, height).unwrap();
+    let mut masks: Vec<ImageBuffer> = frames.iter().map(|_| new_mask(width, height)).collect();
+
+    for y in 0..height {
+        for x in 0..width {
+            let values: Vec<f32> = frames.iter().map(|f| f.get(x, y).unwrap()).collect();
+            let mut keep = vec![true; values.len()];
+
+            // Each pass rejects the most significant sample, measured against the median and
+            // standard deviation of the other kept samples so the hit can't inflate its own
+            // threshold. At least two other samples are needed.
+            for _ in 0..iterations {
+                let kept: Vec<usize> = (0..values.len()).filter(|i| keep[*i]).collect();
+                if kept.len() < 3 {
+                    break;
+                }
+
+                let mut worst: Option<(usize, f32)> = None;
+                for i in kept.iter() {
+                    let mut others: Vec<f32> = kept
+                        .iter()
+                        .filter(|j| *j != i)
+                        .map(|j| values[*j])
+                        .collect();
+                    let n = others.len() as f32;
+                    let m = others.iter().sum::<f32>() / n;
+                    // Floored at one DN so identical samples don't reject quantization noise
+                    let stddev = (others.iter().map(|v| (v - m).powi(2)).sum::<f32>() / (n - 1.0))
+                        .sqrt()
+                        .max(1.0);
+                    let score = (values[*i] - hotpixel::median(&mut others)) / stddev;
+                    if score > sigma && worst.is_none_or(|(_, s)| score > s) {
+                        worst = Some((*i, score));
+                    }
+                }
+
+                match worst {
+                    Some((i, _)) => keep[i] = false,
+                    None => break,
+                }
+            }
+
+            let mut sum = 0.0;
+            let mut count = 0;
+            for (i, v) in values.iter().enumerate() {
+                if keep[i] {
+                    sum += v;
+                    count += 1;
+                } else {
+                    masks[i].put(x, y, COSMIC_RAY_MASK_VALUE);
+                }
+            }
+            mean.put(x, y, sum / count as f32);
+        }
+    }
+
+    Ok((mean, masks))
+}
+
+pub fn count_rejected(mask: &ImageBuffer) -> usize {
+    hotpixel::count_hot_pixels(mask)
+}
+
+/// Combines masks into a single mask marking any pixel rejected in one of them
+pub fn merge_masks(masks: &[ImageBuffer]) -> ImageBuffer {
+    let mut merged = new_mask(masks[0].width, masks[0].height);
+    for mask in masks.iter() {
+        for y in 0..mask.height.min(merged.height) {
+            for x in 0..mask.width.min(merged.width) {
+                if is_masked(mask, x, y) {
+                    merged.put(x, y, COSMIC_RAY_MASK_VALUE);
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// Runs L.A.Cosmic on every band of an image, returning the merged mask of rejected pixels
+pub fn clean_image(img: &mut MarsImage, params: &LaCosmicParams) -> ImageBuffer {
+    let mut masks: Vec<ImageBuffer> = vec![];
+    for b in 0..img.image.num_bands() {
+        let (cleaned, mask) = lacosmic(img.image.get_band(b), params);
+        img.image.set_band(&cleaned, b);
+        masks.push(mask);
+    }
+    merge_masks(&masks)
+}
+
+/// File name of the cosmic ray mask written next to an output image
+pub fn mask_file_for(out_file: &str) -> String {
+    util::append_file_name(out_file, "crmask")
+}
+
+/// The cosmic ray removal stage shared by the calibrators
+pub fn apply_cosmic_ray_removal(
+    img: &mut MarsImage,
+    cal_context: &CalProfile,
+    out_file: &str,
+) -> error::Result<()> {
+    if !cal_context.cosmic_ray_removal {
+        return Ok(());
+    }
+
+    vprintln!(
+        "Cosmic ray removal with sigma clip {}...",
+        cal_context.cosmic_ray_sigma_clip
+    );
+    let mask = clean_image(img, &LaCosmicParams::from_profile(cal_context));
+    vprintln!("Rejected {} pixels as cosmic rays", count_rejected(&mask));
+
+    if cal_context.cosmic_ray_mask {
+        let mask_file = mask_file_for(out_file);
+        vprintln!("Writing cosmic ray mask to {}", mask_file);
+        hotpixel::save_map(&mask, &mask_file);
+    }
+    Ok(())
+}
@@ -0,0 +1,512 @@
+use crate::{calibfile, calprofile::CalProfile, constants, enums, image::MarsImage, vprintln};
+
+use sciimg::{
+    debayer::{Rb_BB_Br_RR, Rg_BR_Bg_RB, Rg_RB_Bg_BR, GR_GB},
+    error,
+    imagebuffer::ImageBuffer,
+    rgbimage::RgbImage,
+};
+
+use serde::{Deserialize, Serialize};
+
+use std::str::FromStr;
+
+const RED: usize = 0;
+const GREEN: usize = 1;
+const BLUE: usize = 2;
+
+/// Demosaicing algorithms for reconstructing color from a bayer pattern image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DebayerMethod {
+    /// The demosaic built into sciimg, which assumes its own CFA layout and ignores the
+    /// configured pattern. The default, matching the output of earlier releases.
+    Sciimg,
+
+    /// Averages the nearest samples of each color
+    Bilinear,
+
+    /// Malvar-He-Cutler gradient corrected linear interpolation
+    Malvar,
+
+    /// Threshold-based variable number of gradients. Interpolates along the smoothest
+    /// directions, reducing zipper artifacts on high frequency texture.
+    Vng,
+
+    /// Combines each 2x2 block into one pixel without interpolation, halving the resolution
+    SuperPixel,
+}
+
+impl FromStr for DebayerMethod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<DebayerMethod, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "sciimg" => Ok(DebayerMethod::Sciimg),
+            "bilinear" => Ok(DebayerMethod::Bilinear),
+            "malvar" | "mhc" => Ok(DebayerMethod::Malvar),
+            "vng" => Ok(DebayerMethod::Vng),
+            "superpixel" => Ok(DebayerMethod::SuperPixel),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Layout of the color filter array, named by the filters of the top-left 2x2 block
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CfaPattern {
+    Rggb,
+    Grbg,
+    Gbrg,
+    Bggr,
+}
+
+impl FromStr for CfaPattern {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<CfaPattern, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "RGGB" => Ok(CfaPattern::Rggb),
+            "GRBG" => Ok(CfaPattern::Grbg),
+            "GBRG" => Ok(CfaPattern::Gbrg),
+            "BGGR" => Ok(CfaPattern::Bggr),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+impl CfaPattern {
+    /// Position of the red filter within the 2x2 block
+    fn red_offset(&self) -> (i32, i32) {
+        match self {
+            CfaPattern::Rggb => (0, 0),
+            CfaPattern::Grbg => (1, 0),
+            CfaPattern::Gbrg => (0, 1),
+            CfaPattern::Bggr => (1, 1),
+        }
+    }
+
+    fn from_red_offset(x: i32, y: i32) -> CfaPattern {
+        match (x & 1, y & 1) {
+            (0, 0) => CfaPattern::Rggb,
+            (1, 0) => CfaPattern::Grbg,
+            (0, 1) => CfaPattern::Gbrg,
+            _ => CfaPattern::Bggr,
+        }
+    }
+
+    /// Color of the filter at a pixel
+    pub fn color_at(&self, x: i32, y: i32) -> usize {
+        let (rx, ry) = self.red_offset();
+        match ((x - rx) & 1, (y - ry) & 1) {
+            (0, 0) => RED,
+            (1, 1) => BLUE,
+            _ => GREEN,
+        }
+    }
+
+    /// The pattern seen by an image whose first pixel is at `(x, y)` on the sensor
+    pub fn shifted(&self, x: i32, y: i32) -> CfaPattern {
+        let (rx, ry) = self.red_offset();
+        CfaPattern::from_red_offset(rx - x, ry - y)
+    }
+
+    /// The pattern at the sensor origin for an instrument. Calibration data can override the
+    /// built in default, which is RGGB for every supported color camera.
+    pub fn for_instrument(instrument: enums::Instrument) -> CfaPattern {
+        if instrument == enums::Instrument::None {
+            return CfaPattern::Rggb;
+        }
+        if let Some(p) = calibfile::get_cfa_pattern(instrument) {
+            match CfaPattern::from_str(&p) {
+                Ok(pattern) => return pattern,
+                Err(_) => eprintln!("Invalid CFA pattern in calibration data: {}", p),
+            }
+        }
+        CfaPattern::Rggb
+    }
+
+    /// The pattern for an image, shifting the phase by the subframe origin. Subframe
+    /// coordinates are one-based. Fails when the image was downsampled on board, which
+    /// averages the mosaic away.
+    pub fn for_image(pattern: CfaPattern, img: &MarsImage) -> error::Result<CfaPattern> {
+        let md = match &img.metadata {
+            Some(md) => md,
+            None => return Ok(pattern),
+        };
+
+        if md.scale_factor > 1 {
+            return Err("Image was downsampled and no longer contains a bayer pattern");
+        }
+
+        match &md.subframe_rect {
+            Some(rect) if rect.len() >= 2 => {
+                let x = rect[0] as i32 - 1;
+                let y = rect[1] as i32 - 1;
+                Ok(pattern.shifted(x, y))
+            }
+            _ => Ok(pattern),
+        }
+    }
+}
+
+/// Value at a pixel, mirroring coordinates outside the image. Mirroring preserves the
+/// color of the filter at the reflected position.
+fn value_at(buffer: &ImageBuffer, x: i32, y: i32) -> f32 {
+    let reflect = |v: i32, len: i32| -> i32 {
+        let v = if v < 0 { -v } else { v };
+        let v = if v >= len { 2 * (len - 1) - v } else { v };
+        v.clamp(0, len - 1)
+    };
+    buffer
+        .get(
+            reflect(x, buffer.width as i32) as usize,
+            reflect(y, buffer.height as i32) as usize,
+        )
+        .unwrap()
+}
+
+fn new_band_like(buffer: &ImageBuffer) -> ImageBuffer {
+    ImageBuffer::new_with_mask(buffer.width, buffer.height, &buffer.to_mask()).unwrap()
+}
+
+fn check_size(buffer: &ImageBuffer) -> error::Result<()> {
+    if buffer.width < 2 || buffer.height < 2 {
+        Err("Image is too small to debayer")
+    } else {
+        Ok(())
+    }
+}
+
+/// Demosaics a single band bayer image into red, green, and blue bands
+pub fn debayer(
+    buffer: &ImageBuffer,
+    method: DebayerMethod,
+    pattern: CfaPattern,
+) -> error::Result<[ImageBuffer; 3]> {
+    check_size(buffer)?;
+    match method {
+        DebayerMethod::Sciimg => {
+            let img = sciimg::debayer::debayer(buffer)?;
+            Ok([
+                img.get_band(0).clone(),
+                img.get_band(1).clone(),
+                img.get_band(2).clone(),
+            ])
+        }
+        DebayerMethod::Bilinear => Ok(bilinear(buffer, pattern)),
+        DebayerMethod::Malvar => Ok(malvar(buffer, pattern)),
+        DebayerMethod::Vng => Ok(vng(buffer, pattern)),
+        DebayerMethod::SuperPixel => Ok(superpixel(buffer, pattern)),
+    }
+}
+
+/// Demosaics the first band of an image, returning a three band image of the same mode
+pub fn debayer_image(
+    image: &RgbImage,
+    method: DebayerMethod,
+    pattern: CfaPattern,
+) -> error::Result<RgbImage> {
+    if image.num_bands() == 0 {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    let [r, g, b] = debayer(image.get_band(0), method, pattern)?;
+    RgbImage::new_from_buffers_rgb(&r, &g, &b, image.get_mode())
+}
+
+/// Mean of the samples of a color in the 3x3 neighborhood of a pixel
+fn neighborhood_mean(
+    buffer: &ImageBuffer,
+    pattern: CfaPattern,
+    x: i32,
+    y: i32,
+    color: usize,
+) -> f32 {
+    let mut sum = 0.0;
+    let mut count = 0;
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            if pattern.color_at(x + dx, y + dy) == color {
+                sum += value_at(buffer, x + dx, y + dy);
+                count += 1;
+            }
+        }
+    }
+    sum / count as f32
+}
+
+fn bilinear(buffer: &ImageBuffer, pattern: CfaPattern) -> [ImageBuffer; 3] {
+    let mut bands = [
+        new_band_like(buffer),
+        new_band_like(buffer),
+        new_band_like(buffer),
+    ];
+
+    for y in 0..buffer.height as i32 {
+        for x in 0..buffer.width as i32 {
+            let site = pattern.color_at(x, y);
+            for (color, band) in bands.iter_mut().enumerate() {
+                // The measured sample is kept, only the missing colors are interpolated
+                let v = if color == site {
+                    value_at(buffer, x, y)
+                } else {
+                    neighborhood_mean(buffer, pattern, x, y, color)
+                };
+                band.put(x as usize, y as usize, v);
+            }
+        }
+    }
+    bands
+}
+
+fn apply_kernel(buffer: &ImageBuffer, x: i32, y: i32, kernel: &[f32; 25]) -> f32 {
+    let mut v = 0.0;
+    let mut s = 0.0;
+    for ny in -2..=2 {
+        for nx in -2..=2 {
+            let k = kernel[((ny + 2) * 5 + (nx + 2)) as usize];
+            if k != 0.0 {
+                v += value_at(buffer, x + nx, y + ny) * k;
+                s += k;
+            }
+        }
+    }
+    v / s
+}
+
+fn malvar(buffer: &ImageBuffer, pattern: CfaPattern) -> [ImageBuffer; 3] {
+    let mut bands = [
+        new_band_like(buffer),
+        new_band_like(buffer),
+        new_band_like(buffer),
+    ];
+
+    for y in 0..buffer.height as i32 {
+        for x in 0..buffer.width as i32 {
+            let v = value_at(buffer, x, y);
+            let (r, g, b) = match pattern.color_at(x, y) {
+                RED => (
+                    v,
+                    apply_kernel(buffer, x, y, &GR_GB),
+                    apply_kernel(buffer, x, y, &Rb_BB_Br_RR),
+                ),
+                BLUE => (
+                    apply_kernel(buffer, x, y, &Rb_BB_Br_RR),
+                    apply_kernel(buffer, x, y, &GR_GB),
+                    v,
+                ),
+                _ => {
+                    // Green in a red row has red neighbors to the left and right
+                    let red_row = pattern.color_at(x + 1, y) == RED;
+                    let horiz = apply_kernel(buffer, x, y, &Rg_RB_Bg_BR);
+                    let vert = apply_kernel(buffer, x, y, &Rg_BR_Bg_RB);
+                    if red_row {
+                        (horiz, v, vert)
+                    } else {
+                        (vert, v, horiz)
+                    }
+                }
+            };
+            bands[RED].put(x as usize, y as usize, r.max(0.0));
+            bands[GREEN].put(x as usize, y as usize, g.max(0.0));
+            bands[BLUE].put(x as usize, y as usize, b.max(0.0));
+        }
+    }
+    bands
+}
+
+const VNG_DIRECTIONS: [(i32, i32); 8] = [
+    (0, -1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+];
+
+/// Gradient along a direction, computed only from differences between samples of the same color
+fn vng_gradient(buffer: &ImageBuffer, x: i32, y: i32, dx: i32, dy: i32) -> f32 {
+    let diff = |ax: i32, ay: i32, bx: i32, by: i32| -> f32 {
+        (value_at(buffer, ax, ay) - value_at(buffer, bx, by)).abs()
+    };
+    // Perpendicular offset
+    let (px, py) = (-dy, dx);
+
+    diff(x, y, x + 2 * dx, y + 2 * dy)
+        + diff(x - dx, y - dy, x + dx, y + dy)
+        + 0.5 * diff(x + px, y + py, x + px + 2 * dx, y + py + 2 * dy)
+        + 0.5 * diff(x - px, y - py, x - px + 2 * dx, y - py + 2 * dy)
+}
+
+fn vng(buffer: &ImageBuffer, pattern: CfaPattern) -> [ImageBuffer; 3] {
+    let mut bands = [
+        new_band_like(buffer),
+        new_band_like(buffer),
+        new_band_like(buffer),
+    ];
+
+    for y in 0..buffer.height as i32 {
+        for x in 0..buffer.width as i32 {
+            let gradients: Vec<f32> = VNG_DIRECTIONS
+                .iter()
+                .map(|(dx, dy)| vng_gradient(buffer, x, y, *dx, *dy))
+                .collect();
+            let min = gradients.iter().cloned().fold(f32::MAX, f32::min);
+            let max = gradients.iter().cloned().fold(f32::MIN, f32::max);
+            let threshold = 1.5 * min + 0.5 * (max - min);
+
+            let mut sums = [0.0; 3];
+            let mut count = 0;
+            for (i, (dx, dy)) in VNG_DIRECTIONS.iter().enumerate() {
+                if gradients[i] <= threshold {
+                    for (color, sum) in sums.iter_mut().enumerate() {
+                        *sum += neighborhood_mean(buffer, pattern, x + dx, y + dy, color);
+                    }
+                    count += 1;
+                }
+            }
+
+            // Missing colors are estimated from the color differences in the selected
+            // directions, anchored to the sample at the pixel
+            let own = pattern.color_at(x, y);
+            let v = value_at(buffer, x, y);
+            for (color, band) in bands.iter_mut().enumerate() {
+                let value = if color == own {
+                    v
+                } else {
+                    v + (sums[color] - sums[own]) / count as f32
+                };
+                band.put(x as usize, y as usize, value.max(0.0));
+            }
+        }
+    }
+    bands
+}
+
+fn superpixel(buffer: &ImageBuffer, pattern: CfaPattern) -> [ImageBuffer; 3] {
+    let width = buffer.width / 2;
+    let height = buffer.height / 2;
+    let mut bands = [
+        ImageBuffer::new(width, height).unwrap(),
+        ImageBuffer::new(width, height).unwrap(),
+        ImageBuffer::new(width, height).unwrap(),
+    ];
+
+    for y in 0..height {
+        for x in 0..width {
+            let mut sums = [0.0; 3];
+            let mut counts = [0; 3];
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    let bx = (x * 2 + dx) as i32;
+                    let by = (y * 2 + dy) as i32;
+                    let color = pattern.color_at(bx, by);
+                    sums[color] += value_at(buffer, bx, by);
+                    counts[color] += 1;
+                }
+            }
+            for (color, band) in bands.iter_mut().enumerate() {
+                band.put(x, y, sums[color] / counts[color] as f32);
+            }
+        }
+    }
+    bands
+}
+
+/// Reduces a full resolution reference, such as a flat field or inpaint mask, to the size of
+/// a superpixel debayered image by averaging each 2x2 block.
+pub fn bin_2x2(buffer: &ImageBuffer) -> ImageBuffer {
+    let width = buffer.width / 2;
+    let height = buffer.height / 2;
+    let mut binned = ImageBuffer::new_as_mode(width, height, buffer.mode).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let sum = buffer.get(x * 2, y * 2).unwrap()
+                + buffer.get(x * 2 + 1, y * 2).unwrap()
+                + buffer.get(x * 2, y * 2 + 1).unwrap()
+                + buffer.get(x * 2 + 1, y * 2 + 1).unwrap();
+            binned.put(x, y, sum / 4.0);
+        }
+    }
+    binned
+}
+
+/// The (x % 2, y % 2) phase of each CFA plane: top left, top right, bottom left and bottom
+/// right of the 2x2 pattern
+pub const CFA_PLANE_PHASES: [(usize, usize); 4] = [(0, 0), (1, 0), (0, 1), (1, 1)];
+
+/// Splits a mosaic into its four CFA planes, in the order of `CFA_PLANE_PHASES`
+pub fn split_cfa_planes(buffer: &ImageBuffer) -> Vec<ImageBuffer> {
+    CFA_PLANE_PHASES
+        .iter()
+        .map(|(px, py)| {
+            let width = (buffer.width + 1 - px) / 2;
+            let height = (buffer.height + 1 - py) / 2;
+            let mut plane = ImageBuffer::new_as_mode(width, height, buffer.mode).unwrap();
+            for y in 0..height {
+                for x in 0..width {
+                    plane.put(x, y, buffer.get(x * 2 + px, y * 2 + py).unwrap());
+                }
+            }
+            plane
+        })
+        .collect()
+}
+
+/// Reassembles a mosaic from CFA planes split by `split_cfa_planes`
+pub fn merge_cfa_planes(planes: &[ImageBuffer], width: usize, height: usize) -> ImageBuffer {
+    let mut buffer = ImageBuffer::new_as_mode(width, height, planes[0].mode).unwrap();
+    for (plane, (px, py)) in planes.iter().zip(CFA_PLANE_PHASES) {
+        for y in 0..plane.height {
+            for x in 0..plane.width {
+                buffer.put(x * 2 + px, y * 2 + py, plane.get(x, y).unwrap());
+            }
+        }
+    }
+    buffer
+}
+
+/// Bins each band of an image 2x2
+pub fn bin_image_2x2(image: &RgbImage) -> RgbImage {
+    let mut binned = RgbImage::new_with_bands(
+        image.width / 2,
+        image.height / 2,
+        image.num_bands(),
+        image.get_mode(),
+    )
+    .unwrap();
+    for b in 0..image.num_bands() {
+        binned.set_band(&bin_2x2(image.get_band(b)), b);
+    }
+    binned
+}
+
+/// Debayers an image using the method and CFA pattern from the calibration profile, with the
+/// pattern phase adjusted for the subframe origin. Frames downsampled on board are left as
+/// they are unless the sciimg demosaic, which doesn't use the pattern, is selected.
+pub fn apply_debayer(img: &mut MarsImage, cal_context: &CalProfile) -> error::Result<()> {
+    if cal_context.debayer_method == DebayerMethod::Sciimg {
+        img.debayer();
+        return Ok(());
+    }
+
+    let sensor_pattern = cal_context
+        .cfa_pattern
+        .unwrap_or_else(|| CfaPattern::for_instrument(img.instrument));
+    let pattern = match CfaPattern::for_image(sensor_pattern, img) {
+        Ok(pattern) => pattern,
+        Err(why) => {
+            vprintln!("WARNING: Skipping debayer: {}", why);
+            return Ok(());
+        }
+    };
+    vprintln!(
+        "CFA pattern {:?} at sensor origin, {:?} for image",
+        sensor_pattern,
+        pattern
+    );
+    img.debayer_with_pattern(cal_context.debayer_method, pattern)
+}
@@ -0,0 +1,464 @@
+use crate::{calprofile::CalProfile, constants, debayer, image::MarsImage, path, vprintln};
+
+use sciimg::{error, imagebuffer::ImageBuffer};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+/// JPEG block size
+pub const JPEG_BLOCK_SIZE: usize = 8;
+
+/// Quantization step assumed for the DC coefficient when the file doesn't provide a table,
+/// in 8 bit DN
+pub const DEFAULT_QUANT_STEP: f32 = 8.0;
+
+/// Natural (row major) index of each coefficient in JPEG zigzag order
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Where the 8x8 block grid of the compressed product lies
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JpegGrid {
+    /// Blocks start at the origin of the image as delivered
+    Image,
+
+    /// Blocks are aligned to the full sensor frame, as for products compressed before being
+    /// subframed. The grid is shifted by the subframe origin in the metadata.
+    Sensor,
+}
+
+impl FromStr for JpegGrid {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<JpegGrid, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "image" => Ok(JpegGrid::Image),
+            "sensor" => Ok(JpegGrid::Sensor),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Block size and position of the first block boundary in image pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GridAlignment {
+    pub block_size: usize,
+    pub offset_x: usize,
+    pub offset_y: usize,
+}
+
+/// Image pixel of the first sensor aligned block boundary for a 1-based subframe origin
+fn first_sensor_boundary(origin: f64, scale: usize) -> usize {
+    let start = (origin.max(1.0) as usize - 1) / scale;
+    JPEG_BLOCK_SIZE - start % JPEG_BLOCK_SIZE
+}
+
+impl GridAlignment {
+    pub fn new(offset_x: usize, offset_y: usize) -> GridAlignment {
+        GridAlignment {
+            block_size: JPEG_BLOCK_SIZE,
+            offset_x: offset_x % JPEG_BLOCK_SIZE,
+            offset_y: offset_y % JPEG_BLOCK_SIZE,
+        }
+    }
+
+    /// Grid alignment of an image. With `JpegGrid::Sensor` the subframe origin (1-based, in
+    /// sensor pixels) and on board downsampling from the metadata place the grid. Images
+    /// already binned by superpixel debayering have half size blocks.
+    pub fn for_image(img: &MarsImage, grid: JpegGrid) -> GridAlignment {
+        let mut alignment = GridAlignment::new(0, 0);
+
+        if let (JpegGrid::Sensor, Some(md)) = (grid, &img.metadata) {
+            if let Some(rect) = md.subframe_rect.as_ref().filter(|r| r.len() >= 2) {
+                let scale = md.scale_factor.max(1) as usize;
+                alignment = GridAlignment::new(
+                    first_sensor_boundary(rect[0], scale),
+                    first_sensor_boundary(rect[1], scale),
+                );
+            }
+        }
+
+        if img.bin_factor > 1 {
+            alignment.block_size /= img.bin_factor;
+            alignment.offset_x /= img.bin_factor;
+            alignment.offset_y /= img.bin_factor;
+        }
+        alignment
+    }
+
+    /// The grid as seen by the color filter array plane of a mosaic at the given phase of the
+    /// 2x2 pattern. Blocks are half the size, starting at the first plane pixel past each
+    /// boundary.
+    pub fn for_cfa_plane(&self, phase_x: usize, phase_y: usize) -> GridAlignment {
+        GridAlignment {
+            block_size: self.block_size / 2,
+            offset_x: (self.offset_x + 1 - phase_x) / 2,
+            offset_y: (self.offset_y + 1 - phase_y) / 2,
+        }
+    }
+}
+
+/// Reads the luminance quantization table of a JPEG file, in natural order
+pub fn read_quant_table(file_path: &str) -> error::Result<[f32; 64]> {
+    if !path::file_exists(file_path) {
+        return Err(constants::status::FILE_NOT_FOUND);
+    }
+    let mut buf: Vec<u8> = Vec::default();
+    File::open(file_path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|_| "Unable to read JPEG file")?;
+    parse_quant_table(&buf)
+}
+
+/// Finds the luminance (table 0) quantization table in JPEG data
+pub fn parse_quant_table(data: &[u8]) -> error::Result<[f32; 64]> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return Err("Not a JPEG file");
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return Err("Invalid JPEG marker");
+        }
+        let marker = data[pos + 1];
+        // Start of scan, no more tables follow in a baseline file
+        if marker == 0xDA {
+            break;
+        }
+        let length = ((data[pos + 2] as usize) << 8) | data[pos + 3] as usize;
+        let end = (pos + 2 + length).min(data.len());
+
+        if marker == 0xDB {
+            let mut p = pos + 4;
+            while p < end {
+                let precision = data[p] >> 4;
+                let table_id = data[p] & 0x0F;
+                let entry_size = if precision == 0 { 1 } else { 2 };
+                p += 1;
+                if p + 64 * entry_size > end {
+                    return Err("Truncated JPEG quantization table");
+                }
+                if table_id == 0 {
+                    let mut table = [0.0; 64];
+                    for (i, natural) in ZIGZAG.iter().enumerate() {
+                        table[*natural] = if entry_size == 1 {
+                            data[p + i] as f32
+                        } else {
+                            (((data[p + i * 2] as u16) << 8) | data[p + i * 2 + 1] as u16) as f32
+                        };
+                    }
+                    return Ok(table);
+                }
+                p += 64 * entry_size;
+            }
+        }
+        pos += 2 + length;
+    }
+    Err("No quantization table found")
+}
+
+/// Orthonormal 8x8 DCT basis, which matches the coefficient scaling JPEG quantizes
+fn dct_basis() -> [[f32; 8]; 8] {
+    let mut basis = [[0.0; 8]; 8];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let a = if u == 0 {
+            (1.0_f32 / 8.0).sqrt()
+        } else {
+            (2.0_f32 / 8.0).sqrt()
+        };
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = a * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos();
+        }
+    }
+    basis
+}
+
+fn dct(block: &[f32; 64], basis: &[[f32; 8]; 8]) -> [f32; 64] {
+    let mut coefs = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for y in 0..8 {
+                for x in 0..8 {
+                    sum += basis[v][y] * basis[u][x] * block[y * 8 + x];
+                }
+            }
+            coefs[v * 8 + u] = sum;
+        }
+    }
+    coefs
+}
+
+fn idct(coefs: &[f32; 64], basis: &[[f32; 8]; 8]) -> [f32; 64] {
+    let mut block = [0.0; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let mut sum = 0.0;
+            for v in 0..8 {
+                for u in 0..8 {
+                    sum += basis[v][y] * basis[u][x] * coefs[v * 8 + u];
+                }
+            }
+            block[y * 8 + x] = sum;
+        }
+    }
+    block
+}
+
+/// Smooths the step across one block boundary. `line` holds the pixels on either side, the
+/// boundary lying between the two halves. The step is spread over the whole span as a linear
+/// ramp when it's below `alpha` and both sides are flat to within `beta`.
+fn filter_edge(line: &mut [f32], alpha: f32, beta: f32) -> bool {
+    let n = line.len() / 2;
+    let step = line[n] - line[n - 1];
+    if step == 0.0 || step.abs() >= alpha {
+        return false;
+    }
+    if line
+        .windows(2)
+        .enumerate()
+        .any(|(i, w)| i != n - 1 && (w[1] - w[0]).abs() >= beta)
+    {
+        return false;
+    }
+    for i in 0..n {
+        let c = step * (2 * (n - 1 - i) + 1) as f32 / (4 * n) as f32;
+        line[n - 1 - i] += c;
+        line[n + i] -= c;
+    }
+    true
+}
+
+fn filter_boundaries(buffer: &mut ImageBuffer, grid: &GridAlignment, alpha: f32, vertical: bool) {
+    let half = grid.block_size / 2;
+    let (length, across) = if vertical {
+        (buffer.width, buffer.height)
+    } else {
+        (buffer.height, buffer.width)
+    };
+    let offset = if vertical {
+        grid.offset_x
+    } else {
+        grid.offset_y
+    };
+
+    let mut line = vec![0.0; half * 2];
+    let mut edge = offset;
+    while edge + half <= length {
+        if edge >= half {
+            for a in 0..across {
+                for (i, v) in line.iter_mut().enumerate() {
+                    let pos = edge - half + i;
+                    *v = if vertical {
+                        buffer.get(pos, a).unwrap()
+                    } else {
+                        buffer.get(a, pos).unwrap()
+                    };
+                }
+                if filter_edge(&mut line, alpha, alpha * 0.5) {
+                    for (i, v) in line.iter().enumerate() {
+                        let pos = edge - half + i;
+                        if vertical {
+                            buffer.put(pos, a, *v);
+                        } else {
+                            buffer.put(a, pos, *v);
+                        }
+                    }
+                }
+            }
+        }
+        edge += grid.block_size;
+    }
+}
+
+/// Limits the change made to each block so that every DCT coefficient stays within half a
+/// quantization step of the decoded value, keeping the result consistent with the compressed
+/// data
+fn constrain_to_quantization(
+    filtered: &mut ImageBuffer,
+    original: &ImageBuffer,
+    grid: &GridAlignment,
+    quant: &[f32; 64],
+) {
+    let basis = dct_basis();
+    let mut by = grid.offset_y;
+    while by + JPEG_BLOCK_SIZE <= original.height {
+        let mut bx = grid.offset_x;
+        while bx + JPEG_BLOCK_SIZE <= original.width {
+            let mut diff = [0.0; 64];
+            for y in 0..8 {
+                for x in 0..8 {
+                    diff[y * 8 + x] = filtered.get(bx + x, by + y).unwrap()
+                        - original.get(bx + x, by + y).unwrap();
+                }
+            }
+            let mut coefs = dct(&diff, &basis);
+            for (c, q) in coefs.iter_mut().zip(quant.iter()) {
+                *c = c.clamp(-q / 2.0, q / 2.0);
+            }
+            let constrained = idct(&coefs, &basis);
+            for y in 0..8 {
+                for x in 0..8 {
+                    filtered.put(
+                        bx + x,
+                        by + y,
+                        original.get(bx + x, by + y).unwrap() + constrained[y * 8 + x],
+                    );
+                }
+            }
+            bx += JPEG_BLOCK_SIZE;
+        }
+        by += JPEG_BLOCK_SIZE;
+    }
+}
+
+/// Largest step across a block boundary taken to be an artifact. With the orthonormal DCT a
+/// quantization step of q in the DC coefficient moves the level of a block by q/8.
+fn edge_threshold(strength: f32, quant: Option<&[f32; 64]>) -> f32 {
+    let dc_step = match quant {
+        Some(q) => q[0],
+        None => DEFAULT_QUANT_STEP,
+    };
+    strength * dc_step / JPEG_BLOCK_SIZE as f32
+}
+
+fn constrain_if_quantized(
+    filtered: &mut ImageBuffer,
+    original: &ImageBuffer,
+    grid: &GridAlignment,
+    quant: Option<&[f32; 64]>,
+) {
+    if let Some(q) = quant {
+        if grid.block_size == JPEG_BLOCK_SIZE {
+            constrain_to_quantization(filtered, original, grid, q);
+        }
+    }
+}
+
+/// Reduces JPEG blocking in a single band. `strength` scales the largest step across a block
+/// boundary taken to be an artifact, relative to the block level change of one DC
+/// quantization step. With a quantization table the result is projected back onto the set of
+/// images that compress to the same data.
+pub fn deblock_buffer(
+    buffer: &ImageBuffer,
+    grid: &GridAlignment,
+    strength: f32,
+    quant: Option<&[f32; 64]>,
+) -> ImageBuffer {
+    let alpha = edge_threshold(strength, quant);
+
+    let mut filtered = buffer.clone();
+    if grid.block_size < 2 || alpha <= 0.0 {
+        return filtered;
+    }
+    filter_boundaries(&mut filtered, grid, alpha, true);
+    filter_boundaries(&mut filtered, grid, alpha, false);
+
+    constrain_if_quantized(&mut filtered, buffer, grid, quant);
+    filtered
+}
+
+/// Reduces JPEG blocking in a raw color filter array mosaic. Boundaries are smoothed in each
+/// CFA plane on its own, so pixels behind different color filters aren't mixed. The
+/// quantization constraint is applied to the mosaic, which is what was compressed.
+pub fn deblock_mosaic(
+    buffer: &ImageBuffer,
+    grid: &GridAlignment,
+    strength: f32,
+    quant: Option<&[f32; 64]>,
+) -> ImageBuffer {
+    let alpha = edge_threshold(strength, quant);
+    if grid.block_size < 4 || alpha <= 0.0 {
+        return buffer.clone();
+    }
+
+    let planes: Vec<ImageBuffer> = debayer::split_cfa_planes(buffer)
+        .iter()
+        .zip(debayer::CFA_PLANE_PHASES)
+        .map(|(plane, (px, py))| {
+            let plane_grid = grid.for_cfa_plane(px, py);
+            let mut filtered = plane.clone();
+            filter_boundaries(&mut filtered, &plane_grid, alpha, true);
+            filter_boundaries(&mut filtered, &plane_grid, alpha, false);
+            filtered
+        })
+        .collect();
+    let mut filtered = debayer::merge_cfa_planes(&planes, buffer.width, buffer.height);
+
+    constrain_if_quantized(&mut filtered, buffer, grid, quant);
+    filtered
+}
+
+/// Deblocks every band of an image. The quantization table only describes the luminance of
+/// color JPEGs, so it's only used for grayscale (including bayer pattern) images. Set
+/// `mosaic` for raw bayer pattern images that haven't been debayered yet.
+pub fn deblock_image(
+    img: &mut MarsImage,
+    grid: &GridAlignment,
+    strength: f32,
+    quant: Option<&[f32; 64]>,
+    mosaic: bool,
+) {
+    let quant = if img.image.is_grayscale() {
+        quant
+    } else {
+        None
+    };
+    for b in 0..img.image.num_bands() {
+        let deblocked = if mosaic {
+            deblock_mosaic(img.image.get_band(b), grid, strength, quant)
+        } else {
+            deblock_buffer(img.image.get_band(b), grid, strength, quant)
+        };
+        img.image.set_band(&deblocked, b);
+    }
+}
+
+/// The deblocking stage shared by the calibrators. Runs on the data as decoded, before
+/// decompanding or any cropping, so the grid is still where the compressor put it. Raw
+/// mosaics are deblocked one CFA plane at a time.
+pub fn apply_deblock(
+    img: &mut MarsImage,
+    cal_context: &CalProfile,
+    input_file: &str,
+    mosaic: bool,
+) -> error::Result<()> {
+    if !cal_context.deblock {
+        return Ok(());
+    }
+
+    let grid = GridAlignment::for_image(img, cal_context.deblock_grid);
+    let quant = match read_quant_table(input_file) {
+        Ok(q) => Some(q),
+        Err(why) => {
+            vprintln!(
+                "No quantization table available ({}), using default step",
+                why
+            );
+            None
+        }
+    };
+
+    vprintln!(
+        "Deblocking with strength {} and grid offset {},{}...",
+        cal_context.deblock_strength,
+        grid.offset_x,
+        grid.offset_y
+    );
+    deblock_image(
+        img,
+        &grid,
+        cal_context.deblock_strength,
+        quant.as_ref(),
+        mosaic,
+    );
+    Ok(())
+}
@@ -1,4 +1,11 @@
-use crate::enums;
+use crate::{calibfile, constants, enums, metadata::Metadata, path, vprintln};
+
+use sciimg::error;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
 
 pub const ILT: [u32; 256] = [
     0, 2, 3, 3, 4, 5, 5, 6, 7, 8, 9, 10, 11, 12, 14, 15, 16, 18, 19, 20, 22, 24, 25, 27, 29, 31,
@@ -35,7 +42,129 @@ pub const NSYT_ILT: [u32; 256] = [
     3584, 3617, 3650, 3683, 3716, 3750, 3784, 3818, 3852, 3886, 3920, 3955, 3990, 4025, 4060, 4095,
 ];
 
-pub fn get_ilt_for_instrument(instrument: enums::Instrument) -> [u32; 256] {
+/// Parses a lookup table of 256 DN values. Values may be separated by commas or whitespace,
+/// and lines starting with `#` are ignored. Tables in the two column `code, DN` layout used
+/// by the PDS ILUT files are also accepted.
+pub fn parse_ilt(text: &str) -> error::Result<[u32; 256]> {
+    let mut values: Vec<u32> = vec![];
+    let rows: Vec<Vec<&str>> = text
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            l.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|v| !v.is_empty())
+                .collect()
+        })
+        .collect();
+
+    let two_column = rows.len() == 256 && rows.iter().all(|r| r.len() == 2);
+    for row in rows.iter() {
+        let fields = if two_column { &row[1..] } else { &row[..] };
+        for v in fields.iter() {
+            match v.parse::<f64>() {
+                Ok(n) if n >= 0.0 => values.push(n.round() as u32),
+                _ => return Err(constants::status::INVALID_RAW_VALUE),
+            }
+        }
+    }
+
+    if values.len() != 256 {
+        return Err("Lookup table must contain 256 values");
+    }
+    let mut ilt = [0; 256];
+    ilt.copy_from_slice(&values);
+    Ok(ilt)
+}
+
+/// Loads a lookup table file, located the same way as the other calibration files
+pub fn load_ilt_file(file_path: &str) -> error::Result<[u32; 256]> {
+    let located = calibfile::locate_calibration_file(&file_path.to_string())?;
+    let mut file = File::open(&located).map_err(|why| {
+        vprintln!("Couldn't open {}: {}", located, why);
+        constants::status::FILE_NOT_FOUND
+    })?;
+    let mut buf: Vec<u8> = Vec::default();
+    file.read_to_end(&mut buf)
+        .map_err(|_| "Error reading lookup table")?;
+    let text = String::from_utf8(buf).map_err(|_| "Lookup table is not valid text")?;
+
+    let ilt = parse_ilt(&text)?;
+    let validation = validate_ilt(&ilt);
+    if !validation.monotonic {
+        return Err("Lookup table values must not decrease");
+    }
+    if !validation.is_invertible() {
+        vprintln!(
+            "Lookup table {} cannot be exactly inverted for codes {:?}",
+            located,
+            validation.roundtrip_errors
+        );
+    }
+    vprintln!(
+        "Loaded {} bit lookup table from {}",
+        validation.bit_depth,
+        located
+    );
+    Ok(ilt)
+}
+
+/// Number of bits needed to hold the largest value of a table
+pub fn bit_depth(ilt: &[u32; 256]) -> u32 {
+    let max = ilt.iter().max().cloned().unwrap_or(0);
+    (32 - max.leading_zeros()).max(8)
+}
+
+/// Converts a DN back to its 8 bit code: the code whose table value is nearest, or the first
+/// of several codes sharing a value.
+pub fn compand_value(value: u32, ilt: &[u32; 256]) -> u8 {
+    let idx = ilt.partition_point(|v| *v < value);
+    if idx == 0 {
+        0
+    } else if idx >= 256 {
+        255
+    } else if ilt[idx] - value < value - ilt[idx - 1] {
+        idx as u8
+    } else {
+        (idx - 1) as u8
+    }
+}
+
+/// The result of checking a companding table
+#[derive(Debug, Clone)]
+pub struct IltValidation {
+    /// Table values never decrease
+    pub monotonic: bool,
+
+    /// Bits needed for the decompanded values
+    pub bit_depth: u32,
+
+    /// Codes that don't survive decompanding followed by companding, i.e. those sharing a
+    /// value with an earlier code
+    pub roundtrip_errors: Vec<u8>,
+}
+
+impl IltValidation {
+    pub fn is_invertible(&self) -> bool {
+        self.monotonic && self.roundtrip_errors.is_empty()
+    }
+}
+
+/// Checks that a table can be inverted, that companding each of its values returns the code
+/// it came from.
+pub fn validate_ilt(ilt: &[u32; 256]) -> IltValidation {
+    let monotonic = ilt.windows(2).all(|w| w[0] <= w[1]);
+    let roundtrip_errors: Vec<u8> = (0..=255_u8)
+        .filter(|i| compand_value(ilt[*i as usize], ilt) != *i)
+        .collect();
+    IltValidation {
+        monotonic,
+        bit_depth: bit_depth(ilt),
+        roundtrip_errors,
+    }
+}
+
+fn builtin_ilt_for_instrument(instrument: enums::Instrument) -> [u32; 256] {
     match instrument {
         enums::Instrument::NsytICC => NSYT_ILT,
         enums::Instrument::NsytIDC => NSYT_ILT,
@@ -43,7 +172,85 @@ pub fn get_ilt_for_instrument(instrument: enums::Instrument) -> [u32; 256] {
     }
 }
 
-pub fn get_max_for_instrument(instrument: enums::Instrument) -> u32 {
-    let ilt = get_ilt_for_instrument(instrument);
+lazy_static! {
+    /// Lookup table rules read from the calibration data, by instrument
+    static ref ILT_FILES: Mutex<HashMap<enums::Instrument, calibfile::IltFiles>> =
+        Mutex::new(HashMap::new());
+
+    /// Lookup table files loaded so far, including those that failed to load
+    static ref ILT_TABLES: Mutex<HashMap<String, error::Result<[u32; 256]>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Lookup table rules for an instrument, read from the calibration data once per run
+fn ilt_files_for_instrument(instrument: enums::Instrument) -> calibfile::IltFiles {
+    ILT_FILES
+        .lock()
+        .unwrap()
+        .entry(instrument)
+        .or_insert_with(|| calibfile::get_ilt_files(instrument))
+        .clone()
+}
+
+/// Loads a lookup table file once per run. A table that fails to load is reported the
+/// first time only.
+fn load_ilt_file_cached(file_path: &str) -> error::Result<[u32; 256]> {
+    let mut tables = ILT_TABLES.lock().unwrap();
+    *tables.entry(file_path.to_string()).or_insert_with(|| {
+        let result = load_ilt_file(file_path);
+        if let Err(why) = result {
+            eprintln!("Error loading lookup table {}: {}", file_path, why);
+        }
+        result
+    })
+}
+
+fn load_ilt_or_builtin(ilt_file: Option<String>, instrument: enums::Instrument) -> [u32; 256] {
+    match ilt_file {
+        Some(f) => {
+            load_ilt_file_cached(&f).unwrap_or_else(|_| builtin_ilt_for_instrument(instrument))
+        }
+        None => builtin_ilt_for_instrument(instrument),
+    }
+}
+
+/// The instrument's lookup table from the calibration data, or the built in table when the
+/// calibration data doesn't name one.
+pub fn get_ilt_for_instrument(instrument: enums::Instrument) -> [u32; 256] {
+    load_ilt_or_builtin(
+        ilt_files_for_instrument(instrument).for_product(None),
+        instrument,
+    )
+}
+
+/// Product identifier used to select a per-product lookup table: the image id from the
+/// metadata when available, otherwise the file name.
+fn product_id(metadata: &Option<Metadata>, input_file: &str) -> String {
+    match metadata {
+        Some(md) if !md.imageid.is_empty() => md.imageid.clone(),
+        _ => path::basename(input_file),
+    }
+}
+
+/// The lookup table for a specific product, honoring any per-product rules in the
+/// calibration data before falling back to the instrument's table.
+pub fn get_ilt_for_product(
+    instrument: enums::Instrument,
+    metadata: &Option<Metadata>,
+    input_file: &str,
+) -> [u32; 256] {
+    let id = product_id(metadata, input_file);
+    load_ilt_or_builtin(
+        ilt_files_for_instrument(instrument).for_product(Some(&id)),
+        instrument,
+    )
+}
+
+/// Largest decompanded value of a table
+pub fn get_max_for_ilt(ilt: &[u32; 256]) -> u32 {
     ilt[255]
 }
+
+pub fn get_max_for_instrument(instrument: enums::Instrument) -> u32 {
+    get_max_for_ilt(&get_ilt_for_instrument(instrument))
+}
@@ -54,7 +54,7 @@ fn imagebuffer_to_vec_v8(
     f
 }
 
-fn rgbimage_to_vec_v8(img3band: &rgbimage::RgbImage) -> Vec<u8> {
+pub(crate) fn rgbimage_to_vec_v8(img3band: &rgbimage::RgbImage) -> Vec<u8> {
     let b0 = img3band.get_band(0);
     let b1 = img3band.get_band(1);
     let b2 = img3band.get_band(2);
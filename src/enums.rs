@@ -18,7 +18,7 @@ pub enum Eye {
 }
 
 // Supported instruments
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Instrument {
     MslMAHLI,
     MslMastcamLeft,
@@ -0,0 +1,360 @@
+use rayon::prelude::*;
+use sciimg::prelude::*;
+
+/// A corner found in an image, oriented by the intensity centroid of the patch around it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keypoint {
+    pub x: f32,
+    pub y: f32,
+    pub score: f32,
+    pub angle: f32,
+}
+
+/// A 256 bit binary descriptor of the patch around a keypoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Descriptor(pub [u64; 4]);
+
+impl Descriptor {
+    pub fn distance(&self, other: &Descriptor) -> u32 {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Feature {
+    pub keypoint: Keypoint,
+    pub descriptor: Descriptor,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DetectorOptions {
+    /// Most features kept per image
+    pub max_features: usize,
+    /// How much brighter or darker than the center a circle pixel must be to count towards a
+    /// corner, as a fraction of the image's range
+    pub threshold: f32,
+}
+
+impl Default for DetectorOptions {
+    fn default() -> Self {
+        DetectorOptions {
+            max_features: 1000,
+            threshold: 0.04,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MatchOptions {
+    /// Largest descriptor distance, in bits, of an accepted match
+    pub max_distance: u32,
+    /// Largest ratio between the best and second best distance of an accepted match
+    pub ratio: f32,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        MatchOptions {
+            max_distance: 64,
+            ratio: 0.8,
+        }
+    }
+}
+
+/// The 16 pixel Bresenham circle of radius 3 used by the FAST corner test
+const CIRCLE: [(i32, i32); 16] = [
+    (0, -3),
+    (1, -3),
+    (2, -2),
+    (3, -1),
+    (3, 0),
+    (3, 1),
+    (2, 2),
+    (1, 3),
+    (0, 3),
+    (-1, 3),
+    (-2, 2),
+    (-3, 1),
+    (-3, 0),
+    (-3, -1),
+    (-2, -2),
+    (-1, -3),
+];
+
+/// Contiguous circle pixels needed for a corner
+const FAST_ARC: usize = 9;
+
+/// Radius of the patch used for orientation
+const ORIENTATION_RADIUS: i32 = 15;
+
+/// Largest offset of a descriptor sample from the keypoint, before rotation
+const PATTERN_RADIUS: i32 = 13;
+
+/// Distance from the image edge inside which keypoints aren't detected, enough for the
+/// orientation patch and rotated descriptor samples plus the smoothing window
+const BORDER: usize = 21;
+
+/// Side of the square cells keypoints are spread over
+const CELL_SIZE: usize = 32;
+
+/// Mean of the bands of an image, scaled to 0-1 over its range
+pub fn luminance(img: &RgbImage) -> ImageBuffer {
+    let mut lum = ImageBuffer::new(img.width, img.height).unwrap();
+    let bands = img.num_bands();
+    for y in 0..img.height {
+        for x in 0..img.width {
+            let sum: f32 = (0..bands).map(|b| img.get_band(b).get(x, y).unwrap()).sum();
+            lum.put(x, y, sum / bands as f32);
+        }
+    }
+    img.get_band(0).copy_mask_to(&mut lum);
+    let mm = lum.get_min_max();
+    let (min, max) = (mm.min, mm.max);
+    if max > min {
+        for y in 0..img.height {
+            for x in 0..img.width {
+                lum.put(x, y, (lum.get(x, y).unwrap() - min) / (max - min));
+            }
+        }
+    }
+    lum
+}
+
+fn at(buffer: &ImageBuffer, x: i32, y: i32) -> f32 {
+    buffer.get(x as usize, y as usize).unwrap()
+}
+
+/// FAST corner score: how far the circle pixels stand out from the center beyond the
+/// threshold, if enough of them do so contiguously
+fn fast_score(buffer: &ImageBuffer, x: i32, y: i32, threshold: f32) -> Option<f32> {
+    let center = at(buffer, x, y);
+    let diffs: Vec<f32> = CIRCLE
+        .iter()
+        .map(|(dx, dy)| at(buffer, x + dx, y + dy) - center)
+        .collect();
+
+    let mut is_corner = false;
+    for sign in [1.0, -1.0] {
+        let mut run = 0;
+        for i in 0..(CIRCLE.len() + FAST_ARC) {
+            if diffs[i % CIRCLE.len()] * sign > threshold {
+                run += 1;
+                if run >= FAST_ARC {
+                    is_corner = true;
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+    }
+
+    if is_corner {
+        Some(diffs.iter().map(|d| (d.abs() - threshold).max(0.0)).sum())
+    } else {
+        None
+    }
+}
+
+fn orientation(buffer: &ImageBuffer, x: i32, y: i32) -> f32 {
+    let (mut m10, mut m01) = (0.0, 0.0);
+    for dy in -ORIENTATION_RADIUS..=ORIENTATION_RADIUS {
+        for dx in -ORIENTATION_RADIUS..=ORIENTATION_RADIUS {
+            if dx * dx + dy * dy <= ORIENTATION_RADIUS * ORIENTATION_RADIUS {
+                let v = at(buffer, x + dx, y + dy);
+                m10 += dx as f32 * v;
+                m01 += dy as f32 * v;
+            }
+        }
+    }
+    m01.atan2(m10)
+}
+
+/// Mean over a 5x5 window, which makes the descriptor's point comparisons robust to noise
+fn smooth(buffer: &ImageBuffer) -> ImageBuffer {
+    let mut out = ImageBuffer::new(buffer.width, buffer.height).unwrap();
+    for y in 2..buffer.height.saturating_sub(2) {
+        for x in 2..buffer.width.saturating_sub(2) {
+            let mut sum = 0.0;
+            for wy in (y - 2)..=(y + 2) {
+                for wx in (x - 2)..=(x + 2) {
+                    sum += buffer.get(wx, wy).unwrap();
+                }
+            }
+            out.put(x, y, sum / 25.0);
+        }
+    }
+    out
+}
+
+lazy_static! {
+    /// Pairs of points compared by the descriptor, drawn once from an approximately Gaussian
+    /// distribution around the keypoint with a fixed seed
+    static ref PATTERN: Vec<[(f32, f32); 2]> = {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as f32 / (1u64 << 31) as f32
+        };
+        let mut offset = || {
+            let v = (next() + next() + next() - 1.5) / 1.5;
+            v * PATTERN_RADIUS as f32
+        };
+        (0..256)
+            .map(|_| [(offset(), offset()), (offset(), offset())])
+            .collect()
+    };
+}
+
+fn describe(smoothed: &ImageBuffer, keypoint: &Keypoint) -> Descriptor {
+    let (sin, cos) = keypoint.angle.sin_cos();
+    let sample = |(px, py): (f32, f32)| {
+        let x = keypoint.x + px * cos - py * sin;
+        let y = keypoint.y + px * sin + py * cos;
+        smoothed
+            .get(x.round() as usize, y.round() as usize)
+            .unwrap()
+    };
+    let mut bits = [0u64; 4];
+    for (i, [p, q]) in PATTERN.iter().enumerate() {
+        if sample(*p) < sample(*q) {
+            bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+    Descriptor(bits)
+}
+
+/// Finds oriented FAST corners spread over the image and describes each with a rotated BRIEF
+/// descriptor. Masked pixels are skipped.
+pub fn detect_features(buffer: &ImageBuffer, options: &DetectorOptions) -> Vec<Feature> {
+    if buffer.width <= BORDER * 2 || buffer.height <= BORDER * 2 {
+        return vec![];
+    }
+
+    let scores: Vec<Vec<f32>> = (0..buffer.height)
+        .into_par_iter()
+        .map(|y| {
+            (0..buffer.width)
+                .map(|x| {
+                    if x < BORDER
+                        || y < BORDER
+                        || x >= buffer.width - BORDER
+                        || y >= buffer.height - BORDER
+                        || !buffer.get_mask_at_point(x, y)
+                    {
+                        0.0
+                    } else {
+                        fast_score(buffer, x as i32, y as i32, options.threshold).unwrap_or(0.0)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    // Local maxima only, ties going to the first in scan order
+    let mut corners: Vec<Keypoint> = vec![];
+    for y in BORDER..(buffer.height - BORDER) {
+        for x in BORDER..(buffer.width - BORDER) {
+            let s = scores[y][x];
+            if s <= 0.0 {
+                continue;
+            }
+            let is_max = (-1..=1).all(|dy: i32| {
+                (-1..=1).all(|dx: i32| {
+                    let (nx, ny) = ((x as i32 + dx) as usize, (y as i32 + dy) as usize);
+                    let n = scores[ny][nx];
+                    (dx, dy) == (0, 0) || n < s || (n == s && (ny, nx) > (y, x))
+                })
+            });
+            if is_max {
+                corners.push(Keypoint {
+                    x: x as f32,
+                    y: y as f32,
+                    score: s,
+                    angle: 0.0,
+                });
+            }
+        }
+    }
+
+    // Strongest first, with each cell limited to its share so features cover the whole image
+    corners.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let cells_x = buffer.width.div_ceil(CELL_SIZE);
+    let cells_y = buffer.height.div_ceil(CELL_SIZE);
+    let per_cell = (options.max_features * 2 / (cells_x * cells_y)).max(1);
+    let mut cell_counts = vec![0; cells_x * cells_y];
+    let keypoints: Vec<Keypoint> = corners
+        .into_iter()
+        .filter(|k| {
+            let cell = (k.y as usize / CELL_SIZE) * cells_x + k.x as usize / CELL_SIZE;
+            cell_counts[cell] += 1;
+            cell_counts[cell] <= per_cell
+        })
+        .take(options.max_features)
+        .collect();
+
+    let smoothed = smooth(buffer);
+    keypoints
+        .par_iter()
+        .map(|k| {
+            let keypoint = Keypoint {
+                angle: orientation(buffer, k.x as i32, k.y as i32),
+                ..*k
+            };
+            Feature {
+                keypoint,
+                descriptor: describe(&smoothed, &keypoint),
+            }
+        })
+        .collect()
+}
+
+/// Best and second best descriptor distance from a feature to a set of features, with the
+/// index of the best
+fn nearest(feature: &Feature, to: &[Feature]) -> Option<(usize, u32, u32)> {
+    let mut best: Option<(usize, u32)> = None;
+    let mut second = u32::MAX;
+    for (i, f) in to.iter().enumerate() {
+        let d = feature.descriptor.distance(&f.descriptor);
+        match best {
+            Some((_, b)) if d >= b => second = second.min(d),
+            _ => {
+                if let Some((_, b)) = best {
+                    second = b;
+                }
+                best = Some((i, d));
+            }
+        }
+    }
+    best.map(|(i, d)| (i, d, second))
+}
+
+/// Matches features between two images, returning index pairs into each. Matches must be close,
+/// clearly better than the next best candidate and each other's best match.
+pub fn match_features(a: &[Feature], b: &[Feature], options: &MatchOptions) -> Vec<(usize, usize)> {
+    let b_to_a: Vec<Option<usize>> = b
+        .par_iter()
+        .map(|f| nearest(f, a).map(|(i, _, _)| i))
+        .collect();
+
+    a.par_iter()
+        .enumerate()
+        .filter_map(|(ia, f)| {
+            let (ib, best, second) = nearest(f, b)?;
+            if best <= options.max_distance
+                && (best as f32) < options.ratio * second as f32
+                && b_to_a[ib] == Some(ia)
+            {
+                Some((ia, ib))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,169 @@
+use crate::{calibfile, enums::Eye, enums::Instrument, metadata::Metadata, path};
+
+/// A filter wheel position on one of the multispectral mast cameras. Position zero is
+/// the clear/Bayer (RGB) filter; positions one through six are the narrowband geology
+/// filters.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FilterPosition {
+    pub eye: Eye,
+    pub position: u8,
+}
+
+impl FilterPosition {
+    pub fn new(eye: Eye, position: u8) -> Self {
+        FilterPosition { eye, position }
+    }
+
+    /// The conventional filter name, e.g. "L0" or "R6"
+    pub fn name(&self) -> String {
+        format!(
+            "{}{}",
+            match self.eye {
+                Eye::Left => 'L',
+                Eye::Right => 'R',
+                Eye::DontCare => '?',
+            },
+            self.position
+        )
+    }
+
+    pub fn is_narrowband(&self) -> bool {
+        self.position > 0
+    }
+
+    /// Parses a filter from a string such as "L3", "R0", or "ZCAM_L3". The first
+    /// two-character token made of an eye and a position digit is used.
+    pub fn from_name(name: &str) -> Option<FilterPosition> {
+        name.to_uppercase()
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|t| t.len() == 2)
+            .find_map(|t| {
+                let mut chars = t.chars();
+                FilterPosition::from_chars(chars.next().unwrap(), chars.next().unwrap())
+            })
+    }
+
+    fn from_chars(eye: char, position: char) -> Option<FilterPosition> {
+        let eye = match eye {
+            'L' => Eye::Left,
+            'R' => Eye::Right,
+            _ => return None,
+        };
+        match position.to_digit(10) {
+            Some(p) if p <= 7 => Some(FilterPosition::new(eye, p as u8)),
+            _ => None,
+        }
+    }
+
+    /// Determines the filter from a Mastcam-Z product file name, e.g. "ZL3_...".
+    pub fn from_zcam_file_name(file_name: &str) -> Option<FilterPosition> {
+        let bn = path::basename(file_name);
+        let mut chars = bn.chars();
+        if chars.next()? != 'Z' {
+            return None;
+        }
+        FilterPosition::from_chars(chars.next()?, chars.next()?)
+    }
+
+    /// Determines the filter for an image, preferring the metadata and falling back to
+    /// the product file name. MSL Mastcam file names don't carry the filter, so those
+    /// images need metadata.
+    pub fn for_image(
+        instrument: Instrument,
+        metadata: &Option<Metadata>,
+        file_name: &str,
+    ) -> Option<FilterPosition> {
+        if let Some(md) = metadata {
+            if let Some(filter_name) = &md.filter_name {
+                if let Some(f) = FilterPosition::from_name(filter_name) {
+                    return Some(f);
+                }
+            }
+        }
+
+        match instrument {
+            Instrument::M20MastcamZLeft | Instrument::M20MastcamZRight => {
+                FilterPosition::from_zcam_file_name(file_name)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Center wavelengths, in nanometers, from Bell et al. (2021), "The Mars 2020 Perseverance
+// Rover Mast Camera Zoom (Mastcam-Z) Multispectral, Stereoscopic Imaging Investigation".
+// Position zero lists the red, green, and blue Bayer bands.
+const MCZ_LEFT_WAVELENGTHS: [&[f32]; 7] = [
+    &[630.0, 544.0, 480.0],
+    &[800.0],
+    &[754.0],
+    &[677.0],
+    &[605.0],
+    &[528.0],
+    &[442.0],
+];
+
+const MCZ_RIGHT_WAVELENGTHS: [&[f32]; 7] = [
+    &[631.0, 544.0, 480.0],
+    &[800.0],
+    &[866.0],
+    &[910.0],
+    &[939.0],
+    &[978.0],
+    &[1022.0],
+];
+
+// Center wavelengths, in nanometers, from Bell et al. (2017), "The Mars Science Laboratory
+// Curiosity rover Mastcam instruments: Preflight and in-flight calibration, validation, and
+// data archiving".
+const MSL_MCAM_LEFT_WAVELENGTHS: [&[f32]; 7] = [
+    &[640.0, 554.0, 495.0],
+    &[527.0],
+    &[445.0],
+    &[751.0],
+    &[676.0],
+    &[867.0],
+    &[1012.0],
+];
+
+const MSL_MCAM_RIGHT_WAVELENGTHS: [&[f32]; 7] = [
+    &[638.0, 551.0, 493.0],
+    &[527.0],
+    &[447.0],
+    &[805.0],
+    &[908.0],
+    &[937.0],
+    &[1013.0],
+];
+
+/// The default center wavelengths, in nanometers, of each band recorded through a filter.
+/// Narrowband filters have a single band; the Bayer filter lists red, green, and blue.
+pub fn default_band_wavelengths(
+    instrument: Instrument,
+    filter: &FilterPosition,
+) -> Option<Vec<f32>> {
+    let table = match instrument {
+        Instrument::M20MastcamZLeft | Instrument::M20MastcamZRight => match filter.eye {
+            Eye::Right => &MCZ_RIGHT_WAVELENGTHS,
+            _ => &MCZ_LEFT_WAVELENGTHS,
+        },
+        Instrument::MslMastcamLeft => &MSL_MCAM_LEFT_WAVELENGTHS,
+        Instrument::MslMastcamRight => &MSL_MCAM_RIGHT_WAVELENGTHS,
+        _ => return None,
+    };
+
+    table
+        .get(filter.position as usize)
+        .map(|wavelengths| wavelengths.to_vec())
+}
+
+/// The center wavelengths of each band recorded through a filter, taking any
+/// `wavelengths` override in the calibration configuration over the built-in values.
+pub fn band_wavelengths(instrument: Instrument, filter: &FilterPosition) -> Option<Vec<f32>> {
+    if let Some(props) = calibfile::get_filter_properties(instrument, filter) {
+        if let Some(wavelengths) = props.wavelengths {
+            return Some(wavelengths);
+        }
+    }
+    default_band_wavelengths(instrument, filter)
+}
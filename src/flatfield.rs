@@ -1,4 +1,4 @@
-use crate::{calibfile, enums, image::MarsImage};
+use crate::{calibfile, enums, filters::FilterPosition, image::MarsImage, vprintln};
 
 use sciimg::error;
 
@@ -9,3 +9,33 @@ pub fn load_flat(instrument: enums::Instrument) -> error::Result<MarsImage> {
         Err(e) => Err(e),
     }
 }
+
+/// Returns the path of the flat to use for an image taken through a filter. A flat configured
+/// for the filter in the calibration data takes precedence over the instrument's default flat.
+pub fn flat_file_for_filter(
+    instrument: enums::Instrument,
+    filter: &Option<FilterPosition>,
+) -> error::Result<String> {
+    if let Some(f) = filter {
+        if let Some(flat) = calibfile::get_filter_properties(instrument, f).and_then(|p| p.flat) {
+            vprintln!("Using flat for filter {}: {}", f.name(), flat);
+            return calibfile::locate_calibration_file(&flat);
+        } else if f.is_narrowband() {
+            vprintln!(
+                "No flat configured for filter {}, using the default flat",
+                f.name()
+            );
+        }
+    }
+    calibfile::get_calibration_file_for_instrument(instrument, enums::CalFileType::FlatField)
+}
+
+pub fn load_flat_for_filter(
+    instrument: enums::Instrument,
+    filter: &Option<FilterPosition>,
+) -> error::Result<MarsImage> {
+    match flat_file_for_filter(instrument, filter) {
+        Ok(cal_file) => Ok(MarsImage::open(cal_file, instrument)),
+        Err(e) => Err(e),
+    }
+}
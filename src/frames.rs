@@ -0,0 +1,201 @@
+use crate::{constants, metadata::Metadata};
+
+use sciimg::{prelude::*, quaternion::Quaternion, vector::Vector};
+
+use std::str::FromStr;
+
+/// Coordinate frame a mosaic is assembled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame {
+    /// The rover navigation frame at the reference image's position: x forward, y right,
+    /// z down, tilted with the rover deck
+    RoverNav,
+
+    /// North, east and down axes centered on the rover at the reference image's position
+    LocalLevel,
+
+    /// North, east and down axes centered on the site origin
+    Site,
+}
+
+impl FromStr for Frame {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Frame, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "rover" | "rovernav" => Ok(Frame::RoverNav),
+            "locallevel" | "level" => Ok(Frame::LocalLevel),
+            "site" => Ok(Frame::Site),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Quaternion from an attitude as published by the mission APIs, scalar first
+pub fn attitude_to_quaternion(attitude: &[f64]) -> Option<Quaternion> {
+    match attitude {
+        [q0, q1, q2, q3] => {
+            let mut q = Quaternion::default();
+            q.set_q(0, *q0);
+            q.set_q(1, *q1);
+            q.set_q(2, *q2);
+            q.set_q(3, *q3);
+            if q.length() > 0.0 {
+                Some(q.normalized())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Where the rover was and how it was oriented when an image was taken. The attitude rotates
+/// rover nav frame vectors into the local level frame and the position is the rover nav origin
+/// in the site frame.
+#[derive(Debug, Clone, Copy)]
+pub struct RoverPose {
+    pub site: Option<u32>,
+    pub drive: Option<u32>,
+    pub attitude: Quaternion,
+    pub position: Vector,
+}
+
+/// A level rover at the site origin
+impl Default for RoverPose {
+    fn default() -> Self {
+        RoverPose {
+            site: None,
+            drive: None,
+            attitude: Quaternion::default(),
+            position: Vector::new(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+impl RoverPose {
+    /// Pose from image metadata. A missing attitude is taken as level and a missing position
+    /// as the site origin.
+    pub fn from_metadata(md: &Metadata) -> Self {
+        RoverPose {
+            site: md.site,
+            drive: md.drive,
+            attitude: md
+                .attitude
+                .as_ref()
+                .and_then(|a| attitude_to_quaternion(a))
+                .unwrap_or_else(Quaternion::default),
+            position: match md.xyz.as_deref() {
+                Some([x, y, z]) => Vector::new(*x, *y, *z),
+                _ => Vector::new(0.0, 0.0, 0.0),
+            },
+        }
+    }
+
+    /// Transforms a rover nav frame direction into the local level or site frame
+    pub fn direction_to_frame(&self, direction: &Vector, frame: Frame) -> Vector {
+        match frame {
+            Frame::RoverNav => *direction,
+            Frame::LocalLevel | Frame::Site => self.attitude.rotate_vector(direction),
+        }
+    }
+
+    /// Transforms a rover nav frame point into the local level or site frame
+    pub fn point_to_frame(&self, point: &Vector, frame: Frame) -> Vector {
+        match frame {
+            Frame::RoverNav | Frame::LocalLevel => self.direction_to_frame(point, frame),
+            Frame::Site => self.direction_to_frame(point, frame).add(&self.position),
+        }
+    }
+
+    /// Transforms a site frame point into this pose's local level or rover nav frame
+    pub fn point_from_site(&self, point: &Vector, frame: Frame) -> Vector {
+        match frame {
+            Frame::Site => *point,
+            Frame::LocalLevel => point.subtract(&self.position),
+            Frame::RoverNav => self
+                .attitude
+                .invert()
+                .rotate_vector(&point.subtract(&self.position)),
+        }
+    }
+
+    /// Transforms a site frame direction into this pose's local level or rover nav frame
+    pub fn direction_from_site(&self, direction: &Vector, frame: Frame) -> Vector {
+        match frame {
+            Frame::Site | Frame::LocalLevel => *direction,
+            Frame::RoverNav => self.attitude.invert().rotate_vector(direction),
+        }
+    }
+}
+
+/// The frame a mosaic is assembled in, anchored to the pose and camera of its reference image
+#[derive(Debug, Clone, Copy)]
+pub struct MosaicFrame {
+    pub frame: Frame,
+    pub pose: RoverPose,
+    /// Camera center of the reference image, in its rover nav frame
+    pub camera_center: Vector,
+}
+
+impl MosaicFrame {
+    pub fn new(frame: Frame, md: &Metadata) -> Self {
+        MosaicFrame {
+            frame,
+            pose: RoverPose::from_metadata(md),
+            camera_center: md.camera_model_component_list.c(),
+        }
+    }
+
+    /// Moves a rover nav frame point of the given pose into the mosaic frame
+    fn point_to_mosaic(&self, pose: &RoverPose, point: &Vector) -> Vector {
+        self.pose
+            .point_from_site(&pose.point_to_frame(point, Frame::Site), self.frame)
+    }
+
+    /// Transform placing the look vectors of an image taken from the given pose in the mosaic
+    /// frame. As with a single position, every camera of the mosaic shares the reference
+    /// camera center, offset only by how far the rover moved between images.
+    pub fn transform_for(&self, pose: &RoverPose) -> FrameTransform {
+        let zero = Vector::new(0.0, 0.0, 0.0);
+        let rover_offset = self
+            .point_to_mosaic(pose, &zero)
+            .subtract(&self.point_to_mosaic(&self.pose, &zero));
+        let rotation = match self.frame {
+            Frame::RoverNav => self.pose.attitude.invert().times(&pose.attitude),
+            Frame::LocalLevel | Frame::Site => pose.attitude,
+        };
+        FrameTransform {
+            rotation: rotation.normalized(),
+            origin: self
+                .point_to_mosaic(&self.pose, &self.camera_center)
+                .add(&rover_offset),
+        }
+    }
+}
+
+/// Carries look vectors of one image into a mosaic frame
+#[derive(Debug, Clone, Copy)]
+pub struct FrameTransform {
+    /// Rotation from the image's rover nav frame to the mosaic frame
+    pub rotation: Quaternion,
+    /// Origin of the image's look vectors in the mosaic frame
+    pub origin: Vector,
+}
+
+impl FrameTransform {
+    /// Leaves look vectors in the rover nav frame, moved to the given origin
+    pub fn identity(origin: &Vector) -> Self {
+        FrameTransform {
+            rotation: Quaternion::default(),
+            origin: *origin,
+        }
+    }
+
+    pub fn look_vector(&self, lv: &LookVector) -> LookVector {
+        LookVector {
+            origin: self.origin,
+            look_direction: self.rotation.rotate_vector(&lv.look_direction),
+        }
+    }
+}
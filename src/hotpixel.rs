@@ -0,0 +1,343 @@
+use crate::{
+    calprofile::CalProfile, constants, debayer, enums::Instrument, image::MarsImage, path, util,
+    vprintln,
+};
+
+use sciimg::{enums::ImageMode, error, imagebuffer::ImageBuffer, rgbimage::RgbImage};
+
+/// Value marking a replaced pixel in a hot pixel map
+pub const HOT_PIXEL_MAP_VALUE: f32 = 255.0;
+
+pub(crate) fn median(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let mid = values.len() / 2;
+    if values.len() & 1 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+/// Values in a square window around a pixel, including the pixel itself when `include_center`
+fn window_values(
+    buffer: &ImageBuffer,
+    window_size: i32,
+    x: usize,
+    y: usize,
+    include_center: bool,
+) -> Vec<f32> {
+    let half = (window_size / 2).max(1);
+    let mut v: Vec<f32> = Vec::with_capacity(((half * 2 + 1) * (half * 2 + 1)) as usize);
+    for wy in -half..=half {
+        for wx in -half..=half {
+            let bx = x as i32 + wx;
+            let by = y as i32 + wy;
+            if (include_center || wx != 0 || wy != 0)
+                && bx >= 0
+                && by >= 0
+                && bx < buffer.width as i32
+                && by < buffer.height as i32
+            {
+                v.push(buffer.get(bx as usize, by as usize).unwrap());
+            }
+        }
+    }
+    v
+}
+
+fn new_map(width: usize, height: usize) -> ImageBuffer {
+    ImageBuffer::new_as_mode(width, height, ImageMode::U8BIT).unwrap()
+}
+
+/// Replaces the pixels marked in a map with the median of their neighbors
+pub fn replace_with_map(buffer: &ImageBuffer, map: &ImageBuffer, window_size: i32) -> ImageBuffer {
+    let mut corrected = buffer.clone();
+    for y in 0..buffer.height.min(map.height) {
+        for x in 0..buffer.width.min(map.width) {
+            if map.get(x, y).unwrap() > 0.0 {
+                let mut window = window_values(buffer, window_size, x, y, false);
+                corrected.put(x, y, median(&mut window));
+            }
+        }
+    }
+    corrected
+}
+
+/// Detects hot pixels in a single frame. A pixel is hot when its z-score against the
+/// surrounding window, the pixel included, exceeds `threshold`. Returns the corrected buffer,
+/// with hot pixels replaced by the mean of the window, and a map of the replaced pixels. This
+/// is the statistic of sciimg's `hot_pixel_detection`, so a 3x3 window caps the z-score at
+/// about 2.67.
+pub fn spatial_correction(
+    buffer: &ImageBuffer,
+    window_size: i32,
+    threshold: f32,
+) -> (ImageBuffer, ImageBuffer) {
+    let mut corrected = buffer.clone();
+    let mut map = new_map(buffer.width, buffer.height);
+    for y in 0..buffer.height {
+        for x in 0..buffer.width {
+            let window = window_values(buffer, window_size, x, y, true);
+            let n = window.len() as f32;
+            let mean = window.iter().sum::<f32>() / n;
+            let stddev = (window.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n).sqrt();
+            if stddev > 0.0 && (buffer.get(x, y).unwrap() - mean) / stddev > threshold {
+                corrected.put(x, y, mean);
+                map.put(x, y, HOT_PIXEL_MAP_VALUE);
+            }
+        }
+    }
+    (corrected, map)
+}
+
+/// Detects hot pixels that persist across a sequence of frames. The per-pixel temporal
+/// median suppresses scene content that moves between frames, such as clouds, dust devils
+/// or a changing pointing, leaving the fixed pattern of the detector. Pixels whose median
+/// exceeds the median of their neighbors by more than `threshold` robust standard
+/// deviations are marked.
+pub fn temporal_map(
+    frames: &[&ImageBuffer],
+    window_size: i32,
+    threshold: f32,
+) -> error::Result<ImageBuffer> {
+    if frames.is_empty() {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    let width = frames[0].width;
+    let height = frames[0].height;
+    if frames
+        .iter()
+        .any(|f| f.width != width || f.height != height)
+    {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+
+    let mut temporal = ImageBuffer::new(width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let mut values: Vec<f32> = frames.iter().map(|f| f.get(x, y).unwrap()).collect();
+            temporal.put(x, y, median(&mut values));
+        }
+    }
+
+    let mut residuals: Vec<f32> = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let mut window = window_values(&temporal, window_size, x, y, false);
+            residuals.push(temporal.get(x, y).unwrap() - median(&mut window));
+        }
+    }
+
+    // Median absolute deviation, scaled to a standard deviation for normally distributed noise
+    let mut deviations: Vec<f32> = residuals.iter().map(|r| r.abs()).collect();
+    let sigma = (median(&mut deviations) * 1.4826).max(f32::EPSILON);
+
+    let mut map = new_map(width, height);
+    for (i, r) in residuals.iter().enumerate() {
+        if *r / sigma > threshold {
+            map.put(i % width, i / width, HOT_PIXEL_MAP_VALUE);
+        }
+    }
+    Ok(map)
+}
+
+/// Spatial hot pixel correction of a raw color filter array mosaic. Each CFA plane is corrected
+/// on its own, so pixels are only compared with neighbors behind the same color filter.
+pub fn spatial_correction_mosaic(
+    buffer: &ImageBuffer,
+    window_size: i32,
+    threshold: f32,
+) -> (ImageBuffer, ImageBuffer) {
+    let (corrected, maps): (Vec<ImageBuffer>, Vec<ImageBuffer>) = debayer::split_cfa_planes(buffer)
+        .iter()
+        .map(|plane| spatial_correction(plane, window_size, threshold))
+        .unzip();
+    (
+        debayer::merge_cfa_planes(&corrected, buffer.width, buffer.height),
+        debayer::merge_cfa_planes(&maps, buffer.width, buffer.height),
+    )
+}
+
+/// Replaces the pixels marked in a map on a raw mosaic with the median of their neighbors
+/// behind the same color filter
+pub fn replace_with_map_mosaic(
+    buffer: &ImageBuffer,
+    map: &ImageBuffer,
+    window_size: i32,
+) -> ImageBuffer {
+    let corrected: Vec<ImageBuffer> = debayer::split_cfa_planes(buffer)
+        .iter()
+        .zip(debayer::split_cfa_planes(map).iter())
+        .map(|(plane, map)| replace_with_map(plane, map, window_size))
+        .collect();
+    debayer::merge_cfa_planes(&corrected, buffer.width, buffer.height)
+}
+
+/// Temporal hot pixel detection over a sequence of raw mosaics, run on each CFA plane
+pub fn temporal_map_mosaic(
+    frames: &[&ImageBuffer],
+    window_size: i32,
+    threshold: f32,
+) -> error::Result<ImageBuffer> {
+    if frames.is_empty() {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    let (width, height) = (frames[0].width, frames[0].height);
+    if frames
+        .iter()
+        .any(|f| f.width != width || f.height != height)
+    {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+    let planes: Vec<Vec<ImageBuffer>> = frames
+        .iter()
+        .map(|f| debayer::split_cfa_planes(f))
+        .collect();
+    let mut maps = vec![];
+    for p in 0..4 {
+        let plane_frames: Vec<&ImageBuffer> = planes.iter().map(|f| &f[p]).collect();
+        maps.push(temporal_map(&plane_frames, window_size, threshold)?);
+    }
+    Ok(debayer::merge_cfa_planes(&maps, width, height))
+}
+
+/// Combines the maps of each band into a single map
+fn merge_maps(maps: &[ImageBuffer]) -> ImageBuffer {
+    let mut merged = new_map(maps[0].width, maps[0].height);
+    for map in maps.iter() {
+        for y in 0..map.height {
+            for x in 0..map.width {
+                if map.get(x, y).unwrap() > 0.0 {
+                    merged.put(x, y, HOT_PIXEL_MAP_VALUE);
+                }
+            }
+        }
+    }
+    merged
+}
+
+pub fn count_hot_pixels(map: &ImageBuffer) -> usize {
+    map.to_vector().iter().filter(|v| **v > 0.0).count()
+}
+
+/// Runs spatial hot pixel correction on every band of an image, returning the map of
+/// replaced pixels. A `mosaic` is corrected one CFA plane at a time.
+pub fn correct_image(
+    img: &mut MarsImage,
+    window_size: i32,
+    threshold: f32,
+    mosaic: bool,
+) -> ImageBuffer {
+    let mut maps: Vec<ImageBuffer> = vec![];
+    for b in 0..img.image.num_bands() {
+        let band = img.image.get_band(b);
+        let (corrected, map) = if mosaic {
+            spatial_correction_mosaic(band, window_size, threshold)
+        } else {
+            spatial_correction(band, window_size, threshold)
+        };
+        img.image.set_band(&corrected, b);
+        maps.push(map);
+    }
+    merge_maps(&maps)
+}
+
+/// Replaces the pixels marked in a map, such as one from `temporal_map`, on every band
+pub fn correct_image_with_map(
+    img: &mut MarsImage,
+    map: &ImageBuffer,
+    window_size: i32,
+    mosaic: bool,
+) {
+    for b in 0..img.image.num_bands() {
+        let band = img.image.get_band(b);
+        let corrected = if mosaic {
+            replace_with_map_mosaic(band, map, window_size)
+        } else {
+            replace_with_map(band, map, window_size)
+        };
+        img.image.set_band(&corrected, b);
+    }
+}
+
+pub fn load_map(file_path: &str) -> error::Result<ImageBuffer> {
+    if !path::file_exists(file_path) {
+        return Err(constants::status::FILE_NOT_FOUND);
+    }
+    Ok(RgbImage::open_str(file_path)?.get_band(0).clone())
+}
+
+pub fn save_map(map: &ImageBuffer, to_file: &str) {
+    let mut img = MarsImage::new(map.width, map.height, Instrument::None);
+    img.image.set_mode(ImageMode::U8BIT);
+    for b in 0..3 {
+        img.image.set_band(map, b);
+    }
+    img.save(to_file);
+}
+
+/// File name of the hot pixel map written next to an output image
+pub fn map_file_for(out_file: &str) -> String {
+    util::append_file_name(out_file, "hpcmap")
+}
+
+/// The hot pixel correction stage shared by the calibrators, run on the raw frame before it's
+/// debayered or resampled. Pixels from a known map, e.g. one built from a sequence of raw frames
+/// by `mru hpcfilter --temporal`, are replaced first, followed by spatial detection when a
+/// threshold is set. The map must be the size of the raw frame. The map of replaced pixels is
+/// written next to the output when the profile asks for it.
+pub fn apply_hot_pixel_correction(
+    img: &mut MarsImage,
+    cal_context: &CalProfile,
+    out_file: &str,
+    mosaic: bool,
+) -> error::Result<()> {
+    let mut maps: Vec<ImageBuffer> = vec![];
+
+    if let Some(map_file) = &cal_context.hot_pixel_map_file {
+        vprintln!("Hot pixel correction using map {}...", map_file);
+        let map = load_map(map_file)?;
+        if map.width != img.image.width || map.height != img.image.height {
+            vprintln!(
+                "Hot pixel map {} is {}x{}, but the raw image is {}x{}",
+                map_file,
+                map.width,
+                map.height,
+                img.image.width,
+                img.image.height
+            );
+            return Err(constants::status::ARRAY_SIZE_MISMATCH);
+        }
+        correct_image_with_map(img, &map, cal_context.hot_pixel_window_size, mosaic);
+        maps.push(map);
+    }
+
+    if cal_context.hot_pixel_detection_threshold > 0.0 {
+        vprintln!(
+            "Hot pixel correction with variance threshold {}...",
+            cal_context.hot_pixel_detection_threshold
+        );
+        maps.push(correct_image(
+            img,
+            cal_context.hot_pixel_window_size,
+            cal_context.hot_pixel_detection_threshold,
+            mosaic,
+        ));
+    }
+
+    if maps.is_empty() {
+        return Ok(());
+    }
+
+    let map = merge_maps(&maps);
+    vprintln!("Replaced {} hot pixels", count_hot_pixels(&map));
+    if cal_context.hot_pixel_map {
+        let map_file = map_file_for(out_file);
+        vprintln!("Writing hot pixel map to {}", map_file);
+        save_map(&map, &map_file);
+    }
+    Ok(())
+}
@@ -1,12 +1,28 @@
-use crate::{drawable::Drawable, enums, flatfield, inpaintmask, metadata::*, path, util, vprintln};
-
-use sciimg::{enums::ImageMode, imagebuffer::ImageBuffer, inpaint, rgbimage::RgbImage};
+use crate::{
+    colorspace,
+    colorspace::ColorSpace,
+    constants, debayer,
+    debayer::{CfaPattern, DebayerMethod},
+    drawable::Drawable,
+    enums, flatfield, hotpixel, inpaintmask,
+    metadata::*,
+    path, util, vprintln,
+};
+
+use sciimg::{
+    camera::model::CameraModel, enums::ImageMode, error, imagebuffer::ImageBuffer, inpaint,
+    rgbimage::RgbImage,
+};
 
 #[derive(Clone)]
 pub struct MarsImage {
     pub image: RgbImage,
     pub instrument: enums::Instrument,
     pub metadata: Option<Metadata>,
+    pub output_color_space: Option<ColorSpace>,
+
+    /// Binning applied to the sensor pixels, 2 after superpixel debayering
+    pub bin_factor: usize,
 }
 
 impl MarsImage {
@@ -15,6 +31,8 @@ impl MarsImage {
             image: RgbImage::new_with_bands(width, height, 3, ImageMode::U8BIT).unwrap(),
             instrument,
             metadata: None,
+            output_color_space: None,
+            bin_factor: 1,
         }
     }
 
@@ -29,6 +47,24 @@ impl MarsImage {
             image: RgbImage::open(&file_path).unwrap(),
             instrument,
             metadata: MarsImage::load_image_metadata(&file_path),
+            output_color_space: None,
+            bin_factor: 1,
+        }
+    }
+
+    pub fn open16(file_path: String, instrument: enums::Instrument) -> Self {
+        if !path::file_exists(file_path.as_str()) {
+            panic!("File not found: {}", file_path);
+        }
+
+        vprintln!("Loading 16 bit image from {}", file_path);
+
+        MarsImage {
+            image: RgbImage::open16(&file_path).unwrap(),
+            instrument,
+            metadata: MarsImage::load_image_metadata(&file_path),
+            output_color_space: None,
+            bin_factor: 1,
         }
     }
 
@@ -48,7 +84,15 @@ impl MarsImage {
     }
 
     pub fn save(&self, to_file: &str) {
-        self.image.save(to_file);
+        match self.output_color_space.and_then(|cs| cs.icc_profile()) {
+            Some(icc) if colorspace::supports_icc_profile(to_file) => {
+                if let Err(why) = colorspace::save_with_icc_profile(&self.image, to_file, &icc) {
+                    eprintln!("Could not embed ICC profile in {}: {}", to_file, why);
+                    self.image.save(to_file);
+                }
+            }
+            _ => self.image.save(to_file),
+        }
 
         vprintln!("Writing image buffer to file at {}", to_file);
         if path::parent_exists_and_writable(to_file) {
@@ -85,6 +129,36 @@ impl MarsImage {
         }
     }
 
+    /// Debayers using the instrument's CFA pattern, adjusted for the subframe origin
+    pub fn debayer_with_method(&mut self, method: DebayerMethod) -> error::Result<()> {
+        let pattern = CfaPattern::for_image(CfaPattern::for_instrument(self.instrument), self)?;
+        self.debayer_with_pattern(method, pattern)
+    }
+
+    /// Debayers with a CFA pattern given for the first pixel of the image
+    pub fn debayer_with_pattern(
+        &mut self,
+        method: DebayerMethod,
+        pattern: CfaPattern,
+    ) -> error::Result<()> {
+        vprintln!("Debayering with method {:?}, pattern {:?}", method, pattern);
+        self.image = debayer::debayer_image(&self.image, method, pattern)?;
+
+        if let Some(ref mut md) = self.metadata {
+            md.debayer = true;
+
+            // Superpixel output is half the resolution of the sensor readout
+            if method == DebayerMethod::SuperPixel {
+                md.scale_factor = md.scale_factor.max(1) * 2;
+            }
+        }
+
+        if method == DebayerMethod::SuperPixel {
+            self.bin_factor *= 2;
+        }
+        Ok(())
+    }
+
     pub fn decompand(&mut self, ilt: &[u32; 256]) {
         self.image.decompand(ilt);
 
@@ -110,21 +184,34 @@ impl MarsImage {
     }
 
     pub fn flatfield_with_flat(&mut self, flat: &MarsImage) {
-        self.apply_flat(&flat.image);
+        let flat = self.fit_flat(&flat.image);
+        self.apply_flat(&flat);
+    }
+
+    /// Records which flat field file(s) were used, and how they were weighted, in the image metadata
+    pub fn set_flat_references(&mut self, flats: Vec<FlatReference>) {
+        if let Some(ref mut md) = self.metadata {
+            md.flat_files = Some(flats);
+        }
     }
 
     pub fn crop(&mut self, x: usize, y: usize, width: usize, height: usize) {
         self.image.crop(x, y, width, height);
     }
 
-    pub fn flatfield(&mut self) {
-        let mut flat = flatfield::load_flat(self.instrument).unwrap();
+    /// Matches a flat field to the size of the image, binning a full resolution flat for a
+    /// superpixel debayered image and cropping the flat if it's larger than the image.
+    fn fit_flat(&self, flat: &RgbImage) -> RgbImage {
+        let mut flat = if self.bin_factor == 2 && flat.width / 2 >= self.image.width {
+            vprintln!("Binning flat to match half resolution image");
+            debayer::bin_image_2x2(flat)
+        } else {
+            flat.clone()
+        };
 
-        // Crop the flatfield image if it's larger than the input image.
-        // Sizes need to match
-        if flat.image.width > self.image.width {
-            let x = (flat.image.width - self.image.width) / 2;
-            let y = (flat.image.height - self.image.height) / 2;
+        if flat.width > self.image.width {
+            let x = (flat.width - self.image.width) / 2;
+            let y = (flat.height - self.image.height) / 2;
             vprintln!(
                 "Cropping flat with x/y/width/height: {},{} {}x{}",
                 x,
@@ -132,15 +219,21 @@ impl MarsImage {
                 self.image.width,
                 self.image.height
             );
-            flat.image.crop(x, y, self.image.width, self.image.height);
+            flat.crop(x, y, self.image.width, self.image.height);
         }
+        flat
+    }
+
+    pub fn flatfield(&mut self) {
+        let flat = flatfield::load_flat(self.instrument).unwrap();
 
         // if inpaint::inpaint_supported_for_instrument(self.instrument) {
         //     flat.apply_inpaint_fix().unwrap();
         // } else {
         //     vprintln!("No inpaint available for flatfield image on {:?}", self.instrument);
         // }
-        self.apply_flat(&flat.image);
+        let flat = self.fit_flat(&flat.image);
+        self.apply_flat(&flat);
     }
 
     pub fn apply_alpha(&mut self, mask: &ImageBuffer) {
@@ -161,7 +254,13 @@ impl MarsImage {
     }
 
     pub fn apply_inpaint_fix_with_mask(&mut self, mask: &ImageBuffer) {
-        let mut fixed = inpaint::apply_inpaint_to_buffer(&self.image, mask).unwrap();
+        let mask = if self.bin_factor == 2 && mask.width / 2 >= self.image.width {
+            vprintln!("Binning inpaint mask to match half resolution image");
+            debayer::bin_2x2(mask)
+        } else {
+            mask.clone()
+        };
+        let mut fixed = inpaint::apply_inpaint_to_buffer(&self.image, &mask).unwrap();
         fixed.set_mode(self.image.get_mode());
         self.image = fixed;
 
@@ -174,7 +273,28 @@ impl MarsImage {
         self.image.hot_pixel_correction(window_size, threshold);
     }
 
+    /// Replaces hot pixels in every band, returning a map of the pixels replaced. A raw
+    /// `mosaic` is corrected one CFA plane at a time.
+    pub fn hot_pixel_correction_with_map(
+        &mut self,
+        window_size: i32,
+        threshold: f32,
+        mosaic: bool,
+    ) -> ImageBuffer {
+        hotpixel::correct_image(self, window_size, threshold, mosaic)
+    }
+
     pub fn to_mono(&mut self) {
         self.image.to_mono();
     }
+
+    /// The camera model from the image's metadata, if it has a valid one
+    pub fn camera_model(&self) -> error::Result<CameraModel> {
+        match &self.metadata {
+            Some(md) if md.camera_model_component_list.is_valid() => {
+                Ok(md.camera_model_component_list.clone())
+            }
+            _ => Err(constants::status::CAMERA_MODEL_NOT_FOUND),
+        }
+    }
 }
@@ -67,10 +67,28 @@ fn str_to_vec(s: &str) -> error::Result<Vec<f64>> {
     Ok(tuple_vec)
 }
 
+/// Parses a tuple string such as `(1,2,3)`, returning `None` if it isn't one
+pub fn parse_tuple(s: &str) -> Option<Vec<f64>> {
+    let s = s.trim();
+    if s.len() >= 2 && s.starts_with('(') && s.ends_with(')') {
+        str_to_vec(s).ok()
+    } else {
+        None
+    }
+}
+
 pub fn default_vec_f64_none() -> Option<Vec<f64>> {
     None
 }
 
+pub fn default_none<T>() -> Option<T> {
+    None
+}
+
+pub fn default_camera_model() -> CameraModel {
+    CameraModel::default()
+}
+
 pub fn default_false() -> bool {
     false
 }
@@ -107,135 +125,137 @@ pub mod cahvor_format {
         let r: Result<&str, D::Error> = Deserialize::deserialize(deserializer);
         match r {
             Err(_) => Ok(CameraModel::default()),
-            Ok(s) => {
-                let s0 = String::from(s);
-
-                let split = s0.split(';');
-                let mut parts: Vec<Vec<f64>> = Vec::new();
-
-                for n in split {
-                    match n.find('(') {
-                        None => {
-                            if string_is_valid_f64(n) {
-                                parts.push(vec![n.parse::<f64>().unwrap()]);
-                            }
-                        }
-                        Some(_i) => {
-                            parts.push(str_to_vec(n).unwrap());
-                        }
-                    }
-                }
+            Ok(s) => Ok(parse(s)),
+        }
+    }
 
-                match parts.len() {
-                    4 => {
-                        // CAHV
-                        Ok(CameraModel::new(Box::new(Cahv {
-                            c: if !parts.is_empty() {
-                                Vector::from_vec(&parts[0]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            a: if parts.len() >= 2 {
-                                Vector::from_vec(&parts[1]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            h: if parts.len() >= 3 {
-                                Vector::from_vec(&parts[2]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            v: if parts.len() >= 4 {
-                                Vector::from_vec(&parts[3]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                        })))
-                    }
-                    6 => {
-                        // CAHVOR
-                        Ok(CameraModel::new(Box::new(Cahvor {
-                            c: if !parts.is_empty() {
-                                Vector::from_vec(&parts[0]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            a: if parts.len() >= 2 {
-                                Vector::from_vec(&parts[1]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            h: if parts.len() >= 3 {
-                                Vector::from_vec(&parts[2]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            v: if parts.len() >= 4 {
-                                Vector::from_vec(&parts[3]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            o: if parts.len() >= 5 {
-                                Vector::from_vec(&parts[4]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            r: if parts.len() >= 6 {
-                                Vector::from_vec(&parts[5]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                        })))
-                    }
-                    9 => {
-                        // CAHVORE
-                        Ok(CameraModel::new(Box::new(Cahvore {
-                            c: if !parts.is_empty() {
-                                Vector::from_vec(&parts[0]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            a: if parts.len() >= 2 {
-                                Vector::from_vec(&parts[1]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            h: if parts.len() >= 3 {
-                                Vector::from_vec(&parts[2]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            v: if parts.len() >= 4 {
-                                Vector::from_vec(&parts[3]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            o: if parts.len() >= 5 {
-                                Vector::from_vec(&parts[4]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            r: if parts.len() >= 6 {
-                                Vector::from_vec(&parts[5]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            e: if parts.len() >= 7 {
-                                Vector::from_vec(&parts[6]).unwrap()
-                            } else {
-                                Vector::default()
-                            },
-                            linearity: if parts.len() >= 8 {
-                                parts[7][0]
-                            } else {
-                                LINEARITY_PERSPECTIVE
-                            },
-                            pupil_type: PupilType::General,
-                        })))
+    /// Camera model from its serialized form, as written to metadata. The model is invalid if
+    /// the form isn't recognized.
+    pub fn parse(s: &str) -> CameraModel {
+        let split = s.split(';');
+        let mut parts: Vec<Vec<f64>> = Vec::new();
+
+        for n in split {
+            match n.find('(') {
+                None => {
+                    if string_is_valid_f64(n) {
+                        parts.push(vec![n.parse::<f64>().unwrap()]);
                     }
-                    _ => Ok(CameraModel::default()),
                 }
+                Some(_i) => {
+                    parts.push(str_to_vec(n).unwrap());
+                }
+            }
+        }
+
+        match parts.len() {
+            4 => {
+                // CAHV
+                CameraModel::new(Box::new(Cahv {
+                    c: if !parts.is_empty() {
+                        Vector::from_vec(&parts[0]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    a: if parts.len() >= 2 {
+                        Vector::from_vec(&parts[1]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    h: if parts.len() >= 3 {
+                        Vector::from_vec(&parts[2]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    v: if parts.len() >= 4 {
+                        Vector::from_vec(&parts[3]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                }))
+            }
+            6 => {
+                // CAHVOR
+                CameraModel::new(Box::new(Cahvor {
+                    c: if !parts.is_empty() {
+                        Vector::from_vec(&parts[0]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    a: if parts.len() >= 2 {
+                        Vector::from_vec(&parts[1]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    h: if parts.len() >= 3 {
+                        Vector::from_vec(&parts[2]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    v: if parts.len() >= 4 {
+                        Vector::from_vec(&parts[3]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    o: if parts.len() >= 5 {
+                        Vector::from_vec(&parts[4]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    r: if parts.len() >= 6 {
+                        Vector::from_vec(&parts[5]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                }))
+            }
+            9 => {
+                // CAHVORE
+                CameraModel::new(Box::new(Cahvore {
+                    c: if !parts.is_empty() {
+                        Vector::from_vec(&parts[0]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    a: if parts.len() >= 2 {
+                        Vector::from_vec(&parts[1]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    h: if parts.len() >= 3 {
+                        Vector::from_vec(&parts[2]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    v: if parts.len() >= 4 {
+                        Vector::from_vec(&parts[3]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    o: if parts.len() >= 5 {
+                        Vector::from_vec(&parts[4]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    r: if parts.len() >= 6 {
+                        Vector::from_vec(&parts[5]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    e: if parts.len() >= 7 {
+                        Vector::from_vec(&parts[6]).unwrap()
+                    } else {
+                        Vector::default()
+                    },
+                    linearity: if parts.len() >= 8 {
+                        parts[7][0]
+                    } else {
+                        LINEARITY_PERSPECTIVE
+                    },
+                    pupil_type: PupilType::General,
+                }))
             }
+            _ => CameraModel::default(),
         }
     }
 }
@@ -13,28 +13,49 @@ lazy_static! {
 extern crate lazy_static;
 
 pub mod anaglyph;
+pub mod blend;
+pub mod bundleadjust;
 pub mod calibfile;
 pub mod calibrate;
 pub mod calprofile;
+pub mod colornoise;
+pub mod colorspace;
 pub mod composite;
 pub mod constants;
+pub mod cosmicray;
+pub mod debayer;
+pub mod deblock;
 pub mod decompanding;
 pub mod diffgif;
 pub mod drawable;
 pub mod enums;
+pub mod features;
+pub mod filters;
 pub mod flatfield;
 pub mod focusmerge;
+pub mod frames;
+pub mod hotpixel;
 pub mod httpfetch;
 pub mod image;
 pub mod inpaintmask;
 pub mod jsonfetch;
 pub mod m20;
 pub mod mer;
+pub mod mesh;
 pub mod metadata;
 pub mod msl;
 pub mod nsyt;
 pub mod path;
+pub mod pngstream;
 pub mod prelude;
 pub mod print;
+pub mod productid;
+pub mod projection;
+pub mod radiometry;
+pub mod spectral;
+pub mod stereo;
+pub mod stereopairs;
+pub mod stereorender;
 pub mod time;
 pub mod util;
+pub mod whitebalance;
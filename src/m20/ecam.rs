@@ -1,6 +1,6 @@
 use crate::{
-    calibrate::*, calprofile::CalProfile, enums, enums::Instrument, image::MarsImage, path, util,
-    vprintln,
+    calibrate::*, calprofile::CalProfile, enums, enums::Instrument, image::MarsImage, path,
+    productid, util, vprintln,
 };
 
 use sciimg::error;
@@ -33,39 +33,17 @@ impl Calibration for M20EECam {
             return cal_warn(cal_context);
         }
 
-        let mut instrument = enums::Instrument::M20NavcamRight;
-
-        // Attempt to figure out camera from file name
-        if util::filename_char_at_pos(input_file, 0) == 'N' {
-            // NAVCAMS
-            if util::filename_char_at_pos(input_file, 1) == 'L' {
-                // Left
-                instrument = enums::Instrument::M20NavcamLeft;
-            } else {
-                // Assume Right
-                instrument = enums::Instrument::M20NavcamRight;
-            }
-        } else if util::filename_char_at_pos(input_file, 0) == 'F' {
-            // FHAZ
-            if util::filename_char_at_pos(input_file, 1) == 'L' {
-                // Left
-                instrument = enums::Instrument::M20FrontHazLeft;
-            } else {
-                // Assume Right
-                instrument = enums::Instrument::M20FrontHazRight;
-            }
-        } else if util::filename_char_at_pos(input_file, 0) == 'R' {
-            // RHAZ
-            if util::filename_char_at_pos(input_file, 1) == 'L' {
-                // Left
-                instrument = enums::Instrument::M20RearHazLeft;
-            } else {
-                // Assume Right
-                instrument = enums::Instrument::M20RearHazRight;
-            }
-        }
+        // Figure out the camera from the product id, assuming right when it can't be decoded
+        let instrument = productid::instrument_for_file(input_file)
+            .filter(|i| self.accepts_instrument(*i))
+            .unwrap_or(enums::Instrument::M20NavcamRight);
 
         let mut raw = MarsImage::open(String::from(input_file), instrument);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        // Looks like 'ECM' in the name seems to indicate that it still have the bayer pattern
+        let mosaic = raw.image.is_grayscale();
+        stages.apply(&mut raw, &[Stage::deblock(mosaic)])?;
 
         let data_max = 255.0;
 
@@ -75,10 +53,11 @@ impl Calibration for M20EECam {
         //     data_max = decompanding::get_max_for_instrument(instrument) as f32;
         // }
 
-        // Looks like 'ECM' in the name seems to indicate that it still have the bayer pattern
-        if raw.image.is_grayscale() {
+        stages.apply(&mut raw, &[Stage::hot_pixels(mosaic)])?;
+
+        if mosaic {
             vprintln!("Debayering...");
-            raw.debayer();
+            stages.apply(&mut raw, &[Stage::Debayer])?;
         }
 
         // We're going to need a reliable way of figuring out what part of the sensor
@@ -86,6 +65,8 @@ impl Calibration for M20EECam {
         //vprintln!("Inpainting...");
         //raw.apply_inpaint_fix().unwrap();
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -93,9 +74,13 @@ impl Calibration for M20EECam {
             cal_context.blue_scalar,
         );
 
+        stages.apply(&mut raw, &[Stage::CosmicRays, Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         // Trim off border pixels
         //let crop_to_width = raw.image.width - 4;
         //let crop_to_height = raw.image.height - 4;
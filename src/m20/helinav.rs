@@ -26,6 +26,9 @@ impl Calibration for M20HeliNav {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20HeliNav);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock])?;
 
         let data_max = 255.0;
 
@@ -38,6 +41,8 @@ impl Calibration for M20HeliNav {
         vprintln!("Flatfielding...");
         raw.flatfield();
 
+        stages.apply(&mut raw, &[Stage::HotPixels, Stage::CosmicRays])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
@@ -26,6 +26,9 @@ impl Calibration for M20HeliRte {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20HeliRte);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock])?;
 
         let data_max = 255.0;
 
@@ -38,6 +41,8 @@ impl Calibration for M20HeliRte {
         vprintln!("Flatfielding...");
         raw.flatfield();
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -45,9 +50,16 @@ impl Calibration for M20HeliRte {
             cal_context.blue_scalar,
         );
 
+        stages.apply(
+            &mut raw,
+            &[Stage::HotPixels, Stage::CosmicRays, Stage::ColorNoise],
+        )?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         vprintln!("Writing to disk...");
         raw.save(&out_file);
 
@@ -97,6 +97,10 @@ impl ImageMetadata for Image {
         self.extended.xyz.as_ref().cloned()
     }
 
+    fn get_attitude(&self) -> Option<Vec<f64>> {
+        crate::jsonfetch::parse_tuple(&self.attitude)
+    }
+
     fn get_dimension(&self) -> Option<Vec<f64>> {
         self.extended.dimension.as_ref().cloned()
     }
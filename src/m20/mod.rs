@@ -6,6 +6,7 @@ pub mod latest;
 pub mod metadata;
 pub mod missiontime;
 pub mod pixlmcc;
+pub mod productid;
 pub mod remote;
 pub mod scam;
 pub mod skycam;
@@ -26,10 +26,15 @@ impl Calibration for M20Pixl {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20Pixl);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock])?;
 
         vprintln!("Flatfielding...");
         raw.flatfield();
 
+        stages.apply(&mut raw, &[Stage::HotPixels, Stage::CosmicRays])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(255.0);
 
@@ -0,0 +1,96 @@
+use crate::{
+    enums::{Eye, Instrument, Mission},
+    productid::{char_at, eye_from_char, field, numeric_field, ProductId},
+};
+
+use sciimg::error;
+
+/// Shortest id: version characters are missing from some products
+const MIN_ID_LENGTH: usize = 52;
+
+fn instrument_for_camera(camera: &str) -> Instrument {
+    match camera {
+        "ZL" => Instrument::M20MastcamZLeft,
+        "ZR" => Instrument::M20MastcamZRight,
+        "NL" => Instrument::M20NavcamLeft,
+        "NR" => Instrument::M20NavcamRight,
+        "FL" => Instrument::M20FrontHazLeft,
+        "FR" => Instrument::M20FrontHazRight,
+        "RL" => Instrument::M20RearHazLeft,
+        "RR" => Instrument::M20RearHazRight,
+        "SI" => Instrument::M20Watson,
+        "LR" => Instrument::M20SuperCam,
+        "PC" => Instrument::M20Pixl,
+        "WS" => Instrument::M20SkyCam,
+        "HN" => Instrument::M20HeliNav,
+        "HS" => Instrument::M20HeliRte,
+        _ => Instrument::None,
+    }
+}
+
+/// Parses a Mars 2020 product id, e.g. `ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01`:
+///  * 0-1: Camera, with the eye second for stereo cameras
+///  * 2: Filter position (Mastcam-Z) or camera configuration
+///  * 4-7: Sol
+///  * 9-18, 20-22: Spacecraft clock seconds and milliseconds
+///  * 23-25: Product type
+///  * 27: Venue, `T` for thumbnails
+///  * 28-43: Site, drive and sequence id
+///  * 45-48: Camera specific, the focal length in tenths of a millimeter for Mastcam-Z
+///  * 49-50: Compression
+///  * 51: Producer
+///  * 52-53: Version
+pub fn parse(id: &str) -> error::Result<ProductId> {
+    if id.len() < MIN_ID_LENGTH
+        || [3, 8, 19, 26, 44]
+            .iter()
+            .any(|p| char_at(id, *p) != Some('_'))
+    {
+        return Err("Not a Mars 2020 product id");
+    }
+
+    let sol = numeric_field(id, 4..8).ok_or("Invalid sol in product id")?;
+    let sclk = numeric_field(id, 9..19).ok_or("Invalid sclk in product id")?;
+    let millis = numeric_field(id, 20..23).unwrap_or(0);
+
+    let camera = field(id, 0..2).unwrap_or_default();
+    let instrument = instrument_for_camera(camera);
+    let is_zcam = matches!(
+        instrument,
+        Instrument::M20MastcamZLeft | Instrument::M20MastcamZRight
+    );
+
+    let eye = match instrument {
+        Instrument::M20Watson
+        | Instrument::M20SuperCam
+        | Instrument::M20Pixl
+        | Instrument::M20SkyCam
+        | Instrument::M20HeliNav
+        | Instrument::M20HeliRte => Eye::DontCare,
+        _ => eye_from_char(char_at(id, 1)),
+    };
+
+    Ok(ProductId {
+        id: String::from(id),
+        mission: Mission::MARS2020,
+        camera: String::from(camera),
+        instrument,
+        eye,
+        sol: Some(sol as u32),
+        sclk: Some(sclk as f64 + millis as f64 / 1000.0),
+        product_type: String::from(field(id, 23..26).unwrap_or_default()),
+        sequence: field(id, 28..44).map(String::from),
+        filter: if is_zcam {
+            field(id, 2..3).map(String::from)
+        } else {
+            None
+        },
+        focal_length: if is_zcam {
+            numeric_field(id, 45..49).map(|f| f as f32 / 10.0)
+        } else {
+            None
+        },
+        thumbnail: char_at(id, 27) == Some('T'),
+        version: field(id, 52..54).map(String::from),
+    })
+}
@@ -26,6 +26,10 @@ impl Calibration for M20SuperCam {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20SuperCam);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        let mosaic = input_file.contains("ECM") && raw.image.is_grayscale();
+        stages.apply(&mut raw, &[Stage::deblock(mosaic)])?;
 
         vprintln!("Loading image mask");
         let mask = imagebuffer::ImageBuffer::from_file(
@@ -41,9 +45,11 @@ impl Calibration for M20SuperCam {
 
         let data_max = 255.0;
 
-        if input_file.contains("ECM") && raw.image.is_grayscale() {
+        stages.apply(&mut raw, &[Stage::hot_pixels(mosaic)])?;
+
+        if mosaic {
             vprintln!("Image appears to be grayscale, applying debayering...");
-            raw.debayer();
+            stages.apply(&mut raw, &[Stage::Debayer])?;
         }
 
         // Gonna start with standard rectangular flat field, but should really
@@ -51,6 +57,8 @@ impl Calibration for M20SuperCam {
         vprintln!("Flatfielding...");
         raw.flatfield();
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -58,9 +66,13 @@ impl Calibration for M20SuperCam {
             cal_context.blue_scalar,
         );
 
+        stages.apply(&mut raw, &[Stage::CosmicRays, Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         vprintln!("Writing to disk...");
         raw.save(&out_file);
 
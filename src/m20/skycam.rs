@@ -26,20 +26,14 @@ impl Calibration for M20SkyCam {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20SkyCam);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock])?;
 
         vprintln!("Flatfielding...");
         raw.flatfield();
 
-        if cal_context.hot_pixel_detection_threshold > 0.0 {
-            vprintln!(
-                "Hot pixel correction with variance threshold {}...",
-                cal_context.hot_pixel_detection_threshold
-            );
-            raw.hot_pixel_correction(
-                cal_context.hot_pixel_window_size,
-                cal_context.hot_pixel_detection_threshold,
-            );
-        }
+        stages.apply(&mut raw, &[Stage::HotPixels, Stage::CosmicRays])?;
 
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(255.0);
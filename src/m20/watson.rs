@@ -26,18 +26,29 @@ impl Calibration for M20Watson {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20Watson);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        let mosaic = input_file.contains("ECM") && raw.image.is_grayscale();
+        stages.apply(&mut raw, &[Stage::deblock(mosaic)])?;
 
         let mut data_max = 255.0;
 
         if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            raw.decompand(&decompanding::ILT);
-            data_max = decompanding::get_max_for_instrument(enums::Instrument::M20Watson) as f32;
+            let ilt = decompanding::get_ilt_for_product(
+                enums::Instrument::M20Watson,
+                &raw.metadata,
+                input_file,
+            );
+            raw.decompand(&ilt);
+            data_max = decompanding::get_max_for_ilt(&ilt) as f32;
         }
 
-        if input_file.contains("ECM") && raw.image.is_grayscale() {
+        stages.apply(&mut raw, &[Stage::hot_pixels(mosaic)])?;
+
+        if mosaic {
             vprintln!("Image appears to be grayscale, applying debayering...");
-            raw.debayer();
+            stages.apply(&mut raw, &[Stage::Debayer])?;
         }
 
         vprintln!("Flatfielding...");
@@ -54,6 +65,8 @@ impl Calibration for M20Watson {
         }
         raw.apply_inpaint_fix_with_mask(&inpaint_mask);
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -61,9 +74,13 @@ impl Calibration for M20Watson {
             cal_context.blue_scalar,
         );
 
+        stages.apply(&mut raw, &[Stage::CosmicRays, Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         if raw.image.width == 1648 {
             vprintln!("Cropping...");
             raw.image.crop(24, 4, 1600, 1192);
@@ -1,6 +1,7 @@
 use crate::{
-    calibfile, calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Instrument,
-    image::MarsImage, inpaintmask, path, util, vprintln,
+    calibfile, calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Eye,
+    enums::Instrument, filters::FilterPosition, image::MarsImage, inpaintmask,
+    metadata::FlatReference, path, productid, util, vprintln,
 };
 
 use sciimg::prelude::*;
@@ -33,17 +34,75 @@ fn focal_length_from_cahvor(cahvor: &CameraModel) -> error::Result<f32> {
     }
 }
 
-fn close_enough(a: f32, b: f32) -> bool {
-    (a - b).abs() < 5.0
-}
+// Weights this close to either end snap to a single flat rather than blending two.
+const FLAT_WEIGHT_SNAP: f32 = 0.01;
+
+fn bracket_stops(value: f32, stops: &[f32]) -> (usize, usize, f32) {
+    let last = stops.len() - 1;
+    if value <= stops[0] {
+        return (0, 0, 0.0);
+    } else if value >= stops[last] {
+        return (last, last, 0.0);
+    }
 
-fn motor_stop_from_focal_length(fl: f32) -> u16 {
-    for i in 1..FOCAL_STOPS.len() {
-        if close_enough(fl, FOCAL_STOPS[i]) {
-            return MOTOR_COUNT_STOPS[i];
+    for i in 0..last {
+        if value >= stops[i] && value <= stops[i + 1] {
+            let w = (value - stops[i]) / (stops[i + 1] - stops[i]);
+            return if w < FLAT_WEIGHT_SNAP {
+                (i, i, 0.0)
+            } else if w > 1.0 - FLAT_WEIGHT_SNAP {
+                (i + 1, i + 1, 0.0)
+            } else {
+                (i, i + 1, w)
+            };
         }
     }
-    MOTOR_COUNT_STOPS[0]
+    (0, 0, 0.0)
+}
+
+/// Converts a focal length to its zoom motor count by interpolating between the known stops
+pub fn motor_count_from_focal_length(fl: f32) -> f32 {
+    let (lo, hi, w) = bracket_stops(fl, &FOCAL_STOPS);
+    MOTOR_COUNT_STOPS[lo] as f32 * (1.0 - w) + MOTOR_COUNT_STOPS[hi] as f32 * w
+}
+
+/// Determines the indices of the two flat field stops bracketing a zoom motor count and the
+/// weight given to the upper one. When the motor count sits on (or beyond) a stop, both
+/// indices are the same and the weight is zero.
+pub fn flat_stops_for_motor_count(motor_count: f32) -> (usize, usize, f32) {
+    let stops: Vec<f32> = MOTOR_COUNT_STOPS.iter().map(|m| *m as f32).collect();
+    bracket_stops(motor_count, &stops)
+}
+
+/// Same as `flat_stops_for_motor_count`, but for a focal length in millimeters
+pub fn flat_stops_for_focal_length(fl: f32) -> (usize, usize, f32) {
+    flat_stops_for_motor_count(motor_count_from_focal_length(fl))
+}
+
+fn flat_file_for_stop(calfile: &str, stop: usize) -> String {
+    let motor_stop_str = format!("{:04}", MOTOR_COUNT_STOPS[stop]);
+    let file_name = calfile.replace("-motorcount-", motor_stop_str.as_str());
+    calibfile::locate_calibration_file(&file_name).unwrap_or(file_name)
+}
+
+fn open_flat_file(file_path: &str, instrument: Instrument) -> MarsImage {
+    if path::file_exists(file_path) {
+        MarsImage::open(String::from(file_path), instrument)
+    } else {
+        eprintln!("Flat file not found: {}", file_path);
+        panic!("Flat file not found!");
+    }
+}
+
+/// Blends two flats as `(1 - weight) * lower + weight * upper`
+fn blend_flats(lower: &MarsImage, upper: &MarsImage, weight: f32) -> MarsImage {
+    let mut blended = lower.clone();
+    for b in 0..blended.image.num_bands() {
+        let lo = lower.image.get_band(b).scale(1.0 - weight).unwrap();
+        let hi = upper.image.get_band(b).scale(weight).unwrap();
+        blended.image.set_band(&lo.add(&hi).unwrap(), b);
+    }
+    blended
 }
 
 #[derive(Copy, Clone)]
@@ -70,32 +129,51 @@ impl Calibration for M20MastcamZ {
         }
 
         let mut warn = false;
-        let mut instrument = Instrument::M20MastcamZLeft;
-
-        let bn = path::basename(input_file);
-        if bn.chars().nth(1).unwrap() == 'R' {
-            instrument = Instrument::M20MastcamZRight;
-            vprintln!("Processing for Mastcam-Z Right");
-        } else {
-            vprintln!("Processing for Mastcam-Z Left");
-        }
+        let instrument = match productid::eye_for_file(input_file) {
+            Eye::Right => {
+                vprintln!("Processing for Mastcam-Z Right");
+                Instrument::M20MastcamZRight
+            }
+            _ => {
+                vprintln!("Processing for Mastcam-Z Left");
+                Instrument::M20MastcamZLeft
+            }
+        };
 
         let mut raw = MarsImage::open(String::from(input_file), instrument);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        // Looks like 'ECM' in the name seems to indicate that it still have the bayer pattern
+        // Update: Not always. Added a check to determine whether or not is is grayscale.
+        // It's not perfect so please validate results. Gonna keep the 'ECM' check for now.
+        let mosaic = input_file.contains("ECM") && raw.image.is_grayscale();
+        stages.apply(&mut raw, &[Stage::deblock(mosaic)])?;
 
         let mut data_max = 255.0;
 
         if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            raw.decompand(&decompanding::get_ilt_for_instrument(instrument));
-            data_max = decompanding::get_max_for_instrument(instrument) as f32;
+            let ilt = decompanding::get_ilt_for_product(instrument, &raw.metadata, input_file);
+            raw.decompand(&ilt);
+            data_max = decompanding::get_max_for_ilt(&ilt) as f32;
         }
 
-        // Looks like 'ECM' in the name seems to indicate that it still have the bayer pattern
-        // Update: Not always. Added a check to determine whether or not is is grayscale.
-        // It's not perfect so please validate results. Gonna keep the 'ECM' check for now.
-        if input_file.contains("ECM") && raw.image.is_grayscale() {
+        let filter = FilterPosition::for_image(instrument, &raw.metadata, input_file);
+        let filter_props = match &filter {
+            Some(f) => calibfile::get_filter_properties(instrument, f),
+            None => None,
+        };
+        let narrowband = filter.map(|f| f.is_narrowband()).unwrap_or(false);
+        if let Some(f) = &filter {
+            vprintln!("Filter: {}", f.name());
+        }
+
+        stages.apply(&mut raw, &[Stage::hot_pixels(mosaic)])?;
+
+        // The narrowband filters are normalized across the bayer pattern by their flats instead.
+        if mosaic && !narrowband {
             vprintln!("Image appears to be grayscale, applying debayering...");
-            raw.debayer();
+            stages.apply(&mut raw, &[Stage::Debayer])?;
         }
 
         // I'm not wild about this
@@ -126,38 +204,75 @@ impl Calibration for M20MastcamZ {
                 vprintln!("Flatfielding...");
                 vprintln!("Determined camera focal length at {}mm", fl);
 
-                let calfile = calibfile::get_calibration_file_for_instrument(
-                    instrument,
-                    enums::CalFileType::FlatField,
-                )
-                .unwrap();
-
-                let motor_stop = motor_stop_from_focal_length(fl);
-                let motor_stop_str = format!("{:04}", motor_stop);
-                let file_path = calfile.replace("-motorcount-", motor_stop_str.as_str());
-
-                vprintln!("Using flat file: {}", file_path);
-
-                if path::file_exists(&file_path) {
-                    let mut flat = MarsImage::open(file_path, instrument);
-
-                    if let Some(md) = &raw.metadata {
-                        if let Some(rect) = &md.subframe_rect {
-                            flat.crop(
-                                rect[0] as usize - 1,
-                                rect[1] as usize - 1,
-                                rect[2] as usize,
-                                rect[3] as usize,
-                            );
+                let calfile = match filter_props.as_ref().and_then(|p| p.flat.clone()) {
+                    Some(f) => f,
+                    None => {
+                        if narrowband {
+                            vprintln!("No flat configured for this filter, using the bayer flat");
                         }
+                        calibfile::get_calibration_base_file_for_instrument(
+                            instrument,
+                            enums::CalFileType::FlatField,
+                        )
+                        .unwrap()
                     }
-
-                    raw.flatfield_with_flat(&flat);
+                };
+
+                let (lo, hi, weight) = flat_stops_for_focal_length(fl);
+                let lo_file = flat_file_for_stop(&calfile, lo);
+                let hi_file = flat_file_for_stop(&calfile, hi);
+
+                let (mut flat, flat_refs) = if lo == hi {
+                    vprintln!("Using flat file: {}", lo_file);
+                    (
+                        open_flat_file(&lo_file, instrument),
+                        vec![FlatReference {
+                            file: path::basename(&lo_file),
+                            weight: 1.0,
+                        }],
+                    )
                 } else {
-                    eprintln!("Flat file not found: {}", file_path);
-                    // print_fail(&format!("{} ({})", path::basename(input_file), filename_suffix));
-                    panic!("Flat file not found!");
+                    vprintln!(
+                        "Interpolating flat files: {} ({}), {} ({})",
+                        lo_file,
+                        1.0 - weight,
+                        hi_file,
+                        weight
+                    );
+                    let lo_flat = open_flat_file(&lo_file, instrument);
+                    let hi_flat = open_flat_file(&hi_file, instrument);
+                    (
+                        blend_flats(&lo_flat, &hi_flat, weight),
+                        vec![
+                            FlatReference {
+                                file: path::basename(&lo_file),
+                                weight: 1.0 - weight,
+                            },
+                            FlatReference {
+                                file: path::basename(&hi_file),
+                                weight,
+                            },
+                        ],
+                    )
+                };
+
+                if let Some(md) = &raw.metadata {
+                    if let Some(rect) = &md.subframe_rect {
+                        flat.crop(
+                            rect[0] as usize - 1,
+                            rect[1] as usize - 1,
+                            rect[2] as usize,
+                            rect[3] as usize,
+                        );
+                    }
+                }
+
+                if narrowband && !flat.image.is_grayscale() {
+                    flat.to_mono();
                 }
+
+                raw.flatfield_with_flat(&flat);
+                raw.set_flat_references(flat_refs);
             }
             Err(e) => {
                 warn = true;
@@ -181,16 +296,31 @@ impl Calibration for M20MastcamZ {
         }
         raw.apply_inpaint_fix_with_mask(&inpaint_mask);
 
-        vprintln!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
+        if let Some(scalar) = filter_props.as_ref().and_then(|p| p.scalar) {
+            vprintln!("Applying filter scalar {}...", scalar);
+            raw.apply_weight(scalar, scalar, scalar);
+        }
+
+        if !narrowband {
+            stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
+            vprintln!("Applying color weights...");
+            raw.apply_weight(
+                cal_context.red_scalar,
+                cal_context.green_scalar,
+                cal_context.blue_scalar,
+            );
+        }
+
+        stages.apply(&mut raw, &[Stage::CosmicRays, Stage::ColorNoise])?;
 
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        if !narrowband {
+            stages.apply(&mut raw, &[Stage::OutputTransform])?;
+        }
+
         if raw.image.width == 1648 && raw.image.height == 1200 {
             vprintln!("Cropping...");
             raw.image.crop(29, 9, 1590, 1182);
@@ -0,0 +1,231 @@
+use crate::{
+    constants,
+    frames::{Frame, RoverPose},
+    path,
+    stereo::StereoProducts,
+    vprintln,
+};
+
+use sciimg::{prelude::*, vector::Vector};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+/// Coordinate frame point clouds and meshes are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFrame {
+    /// Centered on the left camera: x right, y down and z forward along its pointing
+    Camera,
+
+    /// A rover nav, local level or site frame at the pose the image was taken from
+    Rover(Frame),
+}
+
+impl FromStr for ExportFrame {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ExportFrame, Self::Err> {
+        if s.eq_ignore_ascii_case("camera") {
+            Ok(ExportFrame::Camera)
+        } else {
+            Ok(ExportFrame::Rover(Frame::from_str(s)?))
+        }
+    }
+}
+
+/// Triangles whose longest edge is more than this fraction of their distance from the camera
+/// are taken to span a depth discontinuity and left out of meshes
+const MAX_EDGE_RATIO: f64 = 0.05;
+
+/// Points triangulated on the pixel grid of an image, with the color of each pixel
+pub struct GridMesh {
+    pub width: usize,
+    pub height: usize,
+    pub points: Vec<Option<Vector>>,
+    pub colors: Vec<[u8; 3]>,
+
+    /// Camera center, in the same frame as the points
+    pub origin: Vector,
+}
+
+fn to_8bit(img: &RgbImage, x: usize, y: usize) -> [u8; 3] {
+    let max = ImageMode::maxvalue(img.get_mode());
+    let value = |b: usize| {
+        let band = b.min(img.num_bands() - 1);
+        (img.get_band(band).get(x, y).unwrap() / max * 255.0).clamp(0.0, 255.0) as u8
+    };
+    [value(0), value(1), value(2)]
+}
+
+impl GridMesh {
+    /// Points of stereo range data, colored from the rectified left image and transformed into
+    /// the given frame using the rover pose in the image's metadata
+    pub fn from_stereo(products: &StereoProducts, frame: ExportFrame) -> GridMesh {
+        let range = &products.range;
+        let (width, height) = (range.range.width, range.range.height);
+        let model = &products.model;
+        let pose = match &products.rectified.metadata {
+            Some(md) => RoverPose::from_metadata(md),
+            None => RoverPose::default(),
+        };
+
+        let right = model
+            .h
+            .subtract(&model.a.scale(model.h.dot_product(&model.a)));
+        let down = model.a.cross_product(&right.normalized());
+        let transform = |p: &Vector| match frame {
+            ExportFrame::Camera => {
+                let d = p.subtract(&model.c);
+                Vector::new(
+                    d.dot_product(&right.normalized()),
+                    d.dot_product(&down),
+                    d.dot_product(&model.a),
+                )
+            }
+            ExportFrame::Rover(f) => pose.point_to_frame(p, f),
+        };
+
+        let mut points = Vec::with_capacity(width * height);
+        let mut colors = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                points.push(range.point(x, y).map(|p| transform(&p)));
+                colors.push(to_8bit(&products.rectified.image, x, y));
+            }
+        }
+
+        GridMesh {
+            width,
+            height,
+            points,
+            colors,
+            origin: transform(&model.c),
+        }
+    }
+
+    pub fn num_points(&self) -> usize {
+        self.points.iter().filter(|p| p.is_some()).count()
+    }
+
+    /// Two triangles for each square of neighboring pixels that all have points, as indexes into
+    /// the pixel grid, wound counterclockwise as seen from the camera
+    pub fn triangles(&self) -> Vec<[usize; 3]> {
+        let mut triangles = vec![];
+        let keep = |t: [usize; 3]| {
+            let p: Vec<Vector> = t.iter().filter_map(|i| self.points[*i]).collect();
+            if p.len() < 3 {
+                return false;
+            }
+            let longest = p[0]
+                .subtract(&p[1])
+                .len()
+                .max(p[1].subtract(&p[2]).len())
+                .max(p[2].subtract(&p[0]).len());
+            longest < p[0].subtract(&self.origin).len() * MAX_EDGE_RATIO
+        };
+        for y in 0..self.height.saturating_sub(1) {
+            for x in 0..self.width.saturating_sub(1) {
+                let i = y * self.width + x;
+                let (tl, tr, bl, br) = (i, i + 1, i + self.width, i + self.width + 1);
+                for t in [[tl, bl, tr], [tr, bl, br]] {
+                    if keep(t) {
+                        triangles.push(t);
+                    }
+                }
+            }
+        }
+        triangles
+    }
+
+    /// Writes the points as a binary PLY with per-vertex colors
+    pub fn save_ply(&self, to_file: &str) -> error::Result<()> {
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+        }
+        vprintln!("Writing point cloud to {}", to_file);
+
+        let write = || -> std::io::Result<()> {
+            let mut out = BufWriter::new(File::create(to_file)?);
+            write!(
+                out,
+                "ply\nformat binary_little_endian 1.0\ncomment Mars Raw Utils stereo\n\
+                 element vertex {}\n\
+                 property float x\nproperty float y\nproperty float z\n\
+                 property uchar red\nproperty uchar green\nproperty uchar blue\n\
+                 end_header\n",
+                self.num_points()
+            )?;
+            for (p, c) in self.points.iter().zip(self.colors.iter()) {
+                if let Some(p) = p {
+                    for v in [p.x, p.y, p.z] {
+                        out.write_all(&(v as f32).to_le_bytes())?;
+                    }
+                    out.write_all(c)?;
+                }
+            }
+            out.flush()
+        };
+        write().map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)
+    }
+
+    /// Writes the mesh as an OBJ with a material (`.mtl` alongside it) textured by the given
+    /// image, which must cover the same pixel grid as the points
+    pub fn save_obj(&self, to_file: &str, texture_file: &str) -> error::Result<()> {
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+        }
+        let mtl_file = format!("{}.mtl", to_file.strip_suffix(".obj").unwrap_or(to_file));
+        let file_name = |f: &str| {
+            std::path::Path::new(f)
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        vprintln!("Writing mesh to {}", to_file);
+
+        let triangles = self.triangles();
+        let write = || -> std::io::Result<()> {
+            let mut mtl = BufWriter::new(File::create(&mtl_file)?);
+            writeln!(mtl, "newmtl texture")?;
+            writeln!(mtl, "Ka 1 1 1\nKd 1 1 1\nKs 0 0 0\nillum 1")?;
+            writeln!(mtl, "map_Kd {}", file_name(texture_file))?;
+            mtl.flush()?;
+
+            let mut out = BufWriter::new(File::create(to_file)?);
+            writeln!(out, "# Mars Raw Utils stereo")?;
+            writeln!(out, "mtllib {}", file_name(&mtl_file))?;
+
+            // Only points used by a triangle are written, so vertex numbers are remapped
+            let mut used = vec![false; self.points.len()];
+            for t in triangles.iter() {
+                for i in t {
+                    used[*i] = true;
+                }
+            }
+            let mut index = vec![0; self.points.len()];
+            let mut n = 0;
+            for (i, p) in self.points.iter().enumerate() {
+                if let (true, Some(p)) = (used[i], p) {
+                    n += 1;
+                    index[i] = n;
+                    writeln!(out, "v {} {} {}", p.x, p.y, p.z)?;
+                    writeln!(
+                        out,
+                        "vt {} {}",
+                        ((i % self.width) as f64 + 0.5) / self.width as f64,
+                        1.0 - ((i / self.width) as f64 + 0.5) / self.height as f64
+                    )?;
+                }
+            }
+
+            writeln!(out, "usemtl texture")?;
+            for [a, b, c] in triangles.iter() {
+                let (a, b, c) = (index[*a], index[*b], index[*c]);
+                writeln!(out, "f {}/{} {}/{} {}/{}", a, a, b, b, c, c)?;
+            }
+            out.flush()
+        };
+        write().map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)
+    }
+}
@@ -31,10 +31,27 @@ pub trait ImageMetadata {
     fn get_sclk(&self) -> Option<f64>;
     fn get_date_received(&self) -> String;
     fn get_xyz(&self) -> Option<Vec<f64>>;
+    fn get_attitude(&self) -> Option<Vec<f64>>;
     fn get_dimension(&self) -> Option<Vec<f64>>;
     fn get_sample_type(&self) -> String;
 }
 
+/// A flat field image used in calibration along with its contribution to the applied flat
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FlatReference {
+    pub file: String,
+    pub weight: f32,
+}
+
+/// Per-band gains converting calibrated DN to I/F, derived from a calibration target
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RadiometricCoefficients {
+    pub reference_image: String,
+    pub reference_sclk: Option<f64>,
+    pub gains: Vec<f32>,
+    pub iof_scale: f32,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Metadata {
     pub link: String,
@@ -74,6 +91,12 @@ pub struct Metadata {
     )]
     pub xyz: Option<Vec<f64>>,
 
+    #[serde(
+        with = "crate::jsonfetch::tuple_format",
+        default = "crate::jsonfetch::default_vec_f64_none"
+    )]
+    pub attitude: Option<Vec<f64>>,
+
     pub camera_model_type: Option<String>,
     pub site: Option<u32>,
     pub drive: Option<u32>,
@@ -98,6 +121,29 @@ pub struct Metadata {
 
     #[serde(default = "crate::jsonfetch::default_false")]
     pub cropped: bool,
+
+    #[serde(default = "crate::jsonfetch::default_false")]
+    pub pointing_adjusted: bool,
+
+    /// The published camera model, kept when `camera_model_component_list` is replaced by a
+    /// pointing adjustment
+    #[serde(
+        with = "crate::jsonfetch::cahvor_format",
+        default = "crate::jsonfetch::default_camera_model"
+    )]
+    pub original_camera_model: CameraModel,
+
+    #[serde(default = "crate::jsonfetch::default_none")]
+    pub flat_files: Option<Vec<FlatReference>>,
+
+    #[serde(default = "crate::jsonfetch::default_none")]
+    pub band_wavelengths: Option<Vec<f32>>,
+
+    #[serde(default = "crate::jsonfetch::default_none")]
+    pub radiometric_coefficients: Option<RadiometricCoefficients>,
+
+    #[serde(default = "crate::jsonfetch::default_none")]
+    pub color_space: Option<String>,
 }
 
 pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
@@ -119,6 +165,12 @@ pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
         radiometric: jsonfetch::default_false(),
         inpaint: jsonfetch::default_false(),
         cropped: jsonfetch::default_false(),
+        pointing_adjusted: jsonfetch::default_false(),
+        original_camera_model: jsonfetch::default_camera_model(),
+        flat_files: jsonfetch::default_none(),
+        band_wavelengths: jsonfetch::default_none(),
+        radiometric_coefficients: jsonfetch::default_none(),
+        color_space: jsonfetch::default_none(),
         camera_vector: im.get_camera_vector(),
         camera_model_component_list: im.get_camera_model_component_list(),
         camera_position: im.get_camera_position(),
@@ -130,6 +182,7 @@ pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
         sclk: im.get_sclk(),
         dimension: im.get_dimension(),
         xyz: im.get_xyz(),
+        attitude: im.get_attitude(),
         date_received: im.get_date_received(),
         sample_type: im.get_sample_type(),
     }
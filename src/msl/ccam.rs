@@ -26,6 +26,9 @@ impl Calibration for MslChemCam {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::MslChemCam);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock])?;
 
         vprintln!("Loading image mask");
         let mask = imagebuffer::ImageBuffer::from_file(
@@ -52,6 +55,8 @@ impl Calibration for MslChemCam {
             // ... Do something about that
         }
 
+        stages.apply(&mut raw, &[Stage::HotPixels, Stage::CosmicRays])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
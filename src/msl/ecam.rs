@@ -1,6 +1,6 @@
 use crate::{
     calibfile, calibrate::*, calprofile::CalProfile, enums, enums::Instrument, image::MarsImage,
-    inpaintmask, path, util, vprintln,
+    inpaintmask, path, productid, util, vprintln,
 };
 
 use sciimg::error;
@@ -42,39 +42,15 @@ impl Calibration for MslEcam {
             return cal_warn(cal_context);
         }
 
-        let mut instrument = enums::Instrument::MslNavCamRight;
-
-        // Attempt to figure out camera from file name
-        if util::filename_char_at_pos(input_file, 0) == 'N' {
-            // NAVCAMS
-            if util::filename_char_at_pos(input_file, 1) == 'L' {
-                // Left
-                instrument = enums::Instrument::MslNavCamLeft;
-            } else {
-                // Assume Right
-                instrument = enums::Instrument::MslNavCamRight;
-            }
-        } else if util::filename_char_at_pos(input_file, 0) == 'F' {
-            // FHAZ
-            if util::filename_char_at_pos(input_file, 1) == 'L' {
-                // Left
-                instrument = enums::Instrument::MslFrontHazLeft;
-            } else {
-                // Assume Right
-                instrument = enums::Instrument::MslFrontHazRight;
-            }
-        } else if util::filename_char_at_pos(input_file, 0) == 'R' {
-            // RHAZ
-            if util::filename_char_at_pos(input_file, 1) == 'L' {
-                // Left
-                instrument = enums::Instrument::MslRearHazLeft;
-            } else {
-                // Assume Right
-                instrument = enums::Instrument::MslRearHazRight;
-            }
-        }
+        // Figure out the camera from the product id, assuming right when it can't be decoded
+        let instrument = productid::instrument_for_file(input_file)
+            .filter(|i| self.accepts_instrument(*i))
+            .unwrap_or(enums::Instrument::MslNavCamRight);
 
         let mut raw = MarsImage::open(String::from(input_file), instrument);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock])?;
 
         // Exclude subframed images for now...
         if inpaintmask::inpaint_supported_for_instrument(instrument) && raw.image.height >= 1022 {
@@ -84,16 +60,7 @@ impl Calibration for MslEcam {
             vprintln!("Inpainting not supported for instrument {:?}", instrument);
         }
 
-        if cal_context.hot_pixel_detection_threshold > 0.0 {
-            vprintln!(
-                "Hot pixel correction with variance threshold {}...",
-                cal_context.hot_pixel_detection_threshold
-            );
-            raw.hot_pixel_correction(
-                cal_context.hot_pixel_window_size,
-                cal_context.hot_pixel_detection_threshold,
-            );
-        }
+        stages.apply(&mut raw, &[Stage::HotPixels, Stage::CosmicRays])?;
 
         let data_max = 255.0;
 
@@ -124,6 +91,8 @@ impl Calibration for MslEcam {
             panic!("Flat file not found!");
         }
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -131,9 +100,13 @@ impl Calibration for MslEcam {
             cal_context.blue_scalar,
         );
 
+        stages.apply(&mut raw, &[Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         // Trim off border pixels
         let crop_to_width = raw.image.width - 2;
         let crop_to_height = raw.image.height - 2;
@@ -26,6 +26,9 @@ impl Calibration for MslMahli {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::MslMAHLI);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock, Stage::HotPixels])?;
 
         if raw.image.width == 1632 && raw.image.height == 1200 {
             vprintln!("Cropping...");
@@ -48,10 +51,13 @@ impl Calibration for MslMahli {
 
         if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            raw.decompand(&decompanding::get_ilt_for_instrument(
+            let ilt = decompanding::get_ilt_for_product(
                 enums::Instrument::MslMAHLI,
-            ));
-            data_max = decompanding::get_max_for_instrument(enums::Instrument::MslMAHLI) as f32;
+                &raw.metadata,
+                input_file,
+            );
+            raw.decompand(&ilt);
+            data_max = decompanding::get_max_for_ilt(&ilt) as f32;
         }
 
         vprintln!("Flatfielding...");
@@ -76,20 +82,13 @@ impl Calibration for MslMahli {
 
         raw.flatfield_with_flat(&flat);
 
-        if cal_context.hot_pixel_detection_threshold > 0.0 {
-            vprintln!(
-                "Hot pixel correction with variance threshold {}...",
-                cal_context.hot_pixel_detection_threshold
-            );
-            raw.hot_pixel_correction(
-                cal_context.hot_pixel_window_size,
-                cal_context.hot_pixel_detection_threshold,
-            );
-        }
+        stages.apply(&mut raw, &[Stage::CosmicRays])?;
 
         vprintln!("Cropping...");
         raw.image.crop(2, 3, 1580, 1180);
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -97,9 +96,13 @@ impl Calibration for MslMahli {
             cal_context.blue_scalar,
         );
 
+        stages.apply(&mut raw, &[Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         vprintln!("Writing to disk...");
         raw.save(&out_file);
 
@@ -1,6 +1,7 @@
 use crate::{
-    calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Instrument, flatfield,
-    image::MarsImage, inpaintmask, path, util, vprintln,
+    calibfile, calibrate::*, calprofile::CalProfile, decompanding, enums, enums::Eye,
+    enums::Instrument, filters::FilterPosition, flatfield, image::MarsImage, inpaintmask,
+    metadata::FlatReference, path, productid, util, vprintln,
 };
 
 use sciimg::{enums::ImageMode, error};
@@ -28,34 +29,59 @@ impl Calibration for MslMastcam {
             return cal_warn(cal_context);
         }
 
-        let mut instrument = enums::Instrument::MslMastcamLeft;
-
-        if util::filename_char_at_pos(input_file, 5) == 'R' {
-            instrument = enums::Instrument::MslMastcamRight;
-            vprintln!("Processing for Mastcam Right");
-        } else {
-            vprintln!("Processing for Mastcam Left");
-        }
+        let instrument = match productid::eye_for_file(input_file) {
+            Eye::Right => {
+                vprintln!("Processing for Mastcam Right");
+                enums::Instrument::MslMastcamRight
+            }
+            _ => {
+                vprintln!("Processing for Mastcam Left");
+                enums::Instrument::MslMastcamLeft
+            }
+        };
 
         let mut raw = MarsImage::open(String::from(input_file), instrument);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        let mosaic = raw.image.is_grayscale();
+        stages.apply(&mut raw, &[Stage::deblock(mosaic)])?;
 
         let mut data_max = 255.0;
 
         if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            raw.decompand(&decompanding::get_ilt_for_instrument(instrument));
-            data_max = decompanding::get_max_for_instrument(instrument) as f32;
+            let ilt = decompanding::get_ilt_for_product(instrument, &raw.metadata, input_file);
+            raw.decompand(&ilt);
+            data_max = decompanding::get_max_for_ilt(&ilt) as f32;
+        }
+
+        let filter = FilterPosition::for_image(instrument, &raw.metadata, input_file);
+        let filter_props = match &filter {
+            Some(f) => calibfile::get_filter_properties(instrument, f),
+            None => None,
+        };
+        let narrowband = filter.map(|f| f.is_narrowband()).unwrap_or(false);
+        if let Some(f) = &filter {
+            vprintln!("Filter: {}", f.name());
         }
 
+        stages.apply(&mut raw, &[Stage::hot_pixels(mosaic)])?;
+
+        // The science filters pass a single band through the bayer pattern, so those
+        // frames are left as-is and flatfielded in mono.
         if
         /*util::filename_char_at_pos(&input_file, 22) == 'E' &&*/
-        raw.image.is_grayscale() {
+        mosaic && !narrowband {
             vprintln!("Image appears to be grayscale, applying debayering...");
-            raw.debayer();
+            stages.apply(&mut raw, &[Stage::Debayer])?;
         }
 
         let mut inpaint_mask = inpaintmask::load_mask(instrument).unwrap();
-        let mut flat = flatfield::load_flat(instrument).unwrap();
+        let flat_file = flatfield::flat_file_for_filter(instrument, &filter).unwrap();
+        let mut flat = MarsImage::open(flat_file.clone(), instrument);
+        if narrowband && !flat.image.is_grayscale() {
+            flat.to_mono();
+        }
 
         if raw.image.width == 1536 {
             raw.image.crop(161, 0, 1328, raw.image.height);
@@ -83,11 +109,10 @@ impl Calibration for MslMastcam {
             }
 
             if raw.image.get_mode() == ImageMode::U8BIT {
-                flat.image.normalize_to_12bit_with_max(
-                    decompanding::get_max_for_instrument(instrument) as f32,
-                    255.0,
-                );
-                flat.compand(&decompanding::get_ilt_for_instrument(instrument));
+                let ilt = decompanding::get_ilt_for_product(instrument, &raw.metadata, input_file);
+                flat.image
+                    .normalize_to_12bit_with_max(ilt[255] as f32, 255.0);
+                flat.compand(&ilt);
             }
         }
 
@@ -108,11 +133,10 @@ impl Calibration for MslMastcam {
             }
 
             if raw.image.get_mode() == ImageMode::U8BIT {
-                flat.image.normalize_to_12bit_with_max(
-                    decompanding::get_max_for_instrument(instrument) as f32,
-                    255.0,
-                );
-                flat.compand(&decompanding::get_ilt_for_instrument(instrument));
+                let ilt = decompanding::get_ilt_for_product(instrument, &raw.metadata, input_file);
+                flat.image
+                    .normalize_to_12bit_with_max(ilt[255] as f32, 255.0);
+                flat.compand(&ilt);
             }
         }
 
@@ -152,6 +176,10 @@ impl Calibration for MslMastcam {
         );
 
         raw.flatfield_with_flat(&flat);
+        raw.set_flat_references(vec![FlatReference {
+            file: path::basename(&flat_file),
+            weight: 1.0,
+        }]);
 
         // Only inpaint with the same size as the mask until we can reliably determine
         // subframing sensor location.
@@ -160,22 +188,31 @@ impl Calibration for MslMastcam {
         raw.apply_inpaint_fix_with_mask(&inpaint_mask);
         //}
 
-        vprintln!("Applying color weights...");
-        raw.apply_weight(
-            cal_context.red_scalar,
-            cal_context.green_scalar,
-            cal_context.blue_scalar,
-        );
+        if let Some(scalar) = filter_props.as_ref().and_then(|p| p.scalar) {
+            vprintln!("Applying filter responsivity scalar {}...", scalar);
+            raw.apply_weight(scalar, scalar, scalar);
+        }
+
+        if !narrowband {
+            stages.apply(&mut raw, &[Stage::WhiteBalance])?;
 
-        if cal_context.color_noise_reduction && cal_context.color_noise_reduction_amount > 0 {
-            vprintln!("Color noise reduction...");
-            raw.image
-                .reduce_color_noise(cal_context.color_noise_reduction_amount);
+            vprintln!("Applying color weights...");
+            raw.apply_weight(
+                cal_context.red_scalar,
+                cal_context.green_scalar,
+                cal_context.blue_scalar,
+            );
         }
 
+        stages.apply(&mut raw, &[Stage::CosmicRays, Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        if !narrowband {
+            stages.apply(&mut raw, &[Stage::OutputTransform])?;
+        }
+
         vprintln!("Cropping...");
         raw.image
             .crop(3, 3, raw.image.width - 6, raw.image.height - 6);
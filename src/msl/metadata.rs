@@ -83,6 +83,10 @@ impl ImageMetadata for Image {
         self.xyz.as_ref().cloned()
     }
 
+    fn get_attitude(&self) -> Option<Vec<f64>> {
+        self.attitude.as_ref().cloned()
+    }
+
     fn get_dimension(&self) -> Option<Vec<f64>> {
         None
     }
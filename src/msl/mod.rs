@@ -6,4 +6,5 @@ pub mod mardi;
 pub mod mcam;
 pub mod metadata;
 pub mod missiontime;
+pub mod productid;
 pub mod remote;
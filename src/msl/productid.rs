@@ -0,0 +1,111 @@
+use crate::{
+    enums::{Eye, Instrument, Mission},
+    productid::{char_at, eye_from_char, field, numeric_field, ProductId},
+};
+
+use sciimg::error;
+
+/// Parses a Mars Science Laboratory product id, in either the Malin camera (Mastcam, MAHLI,
+/// MARDI) or the engineering camera (Navcam, Hazcam, ChemCam RMI) layout
+pub fn parse(id: &str) -> error::Result<ProductId> {
+    parse_mmm(id).or_else(|_| parse_ecam(id))
+}
+
+/// Malin camera ids, e.g. `0450MR0018470000301669E01`:
+///  * 0-3: Sol
+///  * 4-5: Camera (`ML`, `MR`, `MH`, `MD`)
+///  * 6-21: Sequence (6-11) and image counters
+///  * 22: Product type, `I` for thumbnails
+///  * 23-24: Version
+pub fn parse_mmm(id: &str) -> error::Result<ProductId> {
+    if id.len() < 25 || numeric_field(id, 6..22).is_none() {
+        return Err("Not an MSL Malin camera product id");
+    }
+    let sol = numeric_field(id, 0..4).ok_or("Invalid sol in product id")?;
+
+    let camera = field(id, 4..6).unwrap_or_default();
+    let (instrument, eye) = match camera {
+        "ML" => (Instrument::MslMastcamLeft, Eye::Left),
+        "MR" => (Instrument::MslMastcamRight, Eye::Right),
+        "MH" => (Instrument::MslMAHLI, Eye::DontCare),
+        "MD" => (Instrument::MslMARDI, Eye::DontCare),
+        _ => return Err("Not an MSL Malin camera product id"),
+    };
+
+    let product_type = field(id, 22..23).unwrap_or_default();
+    Ok(ProductId {
+        id: String::from(id),
+        mission: Mission::MSL,
+        camera: String::from(camera),
+        instrument,
+        eye,
+        sol: Some(sol as u32),
+        sclk: None,
+        product_type: String::from(product_type),
+        sequence: field(id, 6..12).map(String::from),
+        filter: None,
+        focal_length: None,
+        thumbnail: product_type == "I",
+        version: field(id, 23..25).map(String::from),
+    })
+}
+
+/// Engineering camera ids, e.g. `NRB_670586006EDR_S0871444NCAM00545M_`:
+///  * 0: Camera (`N`avcam, `F`ront or `R`ear Hazcam, `C`hemCam)
+///  * 1: Eye, or `R` for the ChemCam RMI
+///  * 2: Rover compute element (`A`, `B`)
+///  * 4-12: Spacecraft clock
+///  * 13-15: Product type, `ILT` for thumbnails
+///  * 17-33: Site, drive and sequence id
+///  * 34: Producer
+///  * 35-: Version
+pub fn parse_ecam(id: &str) -> error::Result<ProductId> {
+    if id.len() < 34 || char_at(id, 3) != Some('_') || char_at(id, 16) != Some('_') {
+        return Err("Not an MSL engineering camera product id");
+    }
+    let sclk = numeric_field(id, 4..13).ok_or("Invalid sclk in product id")?;
+
+    let camera = field(id, 0..2).unwrap_or_default();
+    let eye = eye_from_char(char_at(id, 1));
+    let instrument = match (char_at(id, 0), eye) {
+        (Some('N'), Eye::Left) => Instrument::MslNavCamLeft,
+        (Some('N'), Eye::Right) => Instrument::MslNavCamRight,
+        (Some('F'), Eye::Left) => Instrument::MslFrontHazLeft,
+        (Some('F'), Eye::Right) => Instrument::MslFrontHazRight,
+        (Some('R'), Eye::Left) => Instrument::MslRearHazLeft,
+        (Some('R'), Eye::Right) => Instrument::MslRearHazRight,
+        (Some('C'), Eye::Right) => Instrument::MslChemCam,
+        _ => return Err("Not an MSL engineering camera product id"),
+    };
+
+    let product_type = field(id, 13..16).unwrap_or_default();
+    let version: String = id
+        .chars()
+        .skip(35)
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+
+    Ok(ProductId {
+        id: String::from(id),
+        mission: Mission::MSL,
+        camera: String::from(camera),
+        instrument,
+        eye: if instrument == Instrument::MslChemCam {
+            Eye::DontCare
+        } else {
+            eye
+        },
+        sol: None,
+        sclk: Some(sclk as f64),
+        product_type: String::from(product_type),
+        sequence: field(id, 17..34).map(String::from),
+        filter: None,
+        focal_length: None,
+        thumbnail: product_type == "ILT",
+        version: if version.is_empty() {
+            None
+        } else {
+            Some(version)
+        },
+    })
+}
@@ -25,20 +25,28 @@ impl Calibration for NsytIcc {
         }
 
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::NsytICC);
+        let stages = StageRunner::new(cal_context, input_file, &out_file);
+
+        stages.apply(&mut raw, &[Stage::Deblock, Stage::HotPixels])?;
 
         let mut data_max = 255.0;
 
         if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            raw.decompand(&decompanding::get_ilt_for_instrument(
+            let ilt = decompanding::get_ilt_for_product(
                 enums::Instrument::NsytICC,
-            ));
-            data_max = decompanding::get_max_for_instrument(enums::Instrument::NsytICC) as f32;
+                &raw.metadata,
+                input_file,
+            );
+            raw.decompand(&ilt);
+            data_max = decompanding::get_max_for_ilt(&ilt) as f32;
         }
 
         vprintln!("Flatfielding...");
         raw.flatfield();
 
+        stages.apply(&mut raw, &[Stage::WhiteBalance])?;
+
         vprintln!("Applying color weights...");
         raw.apply_weight(
             cal_context.red_scalar,
@@ -49,9 +57,13 @@ impl Calibration for NsytIcc {
         vprintln!("Cropping...");
         raw.image.crop(3, 3, 1018, 1018);
 
+        stages.apply(&mut raw, &[Stage::CosmicRays, Stage::ColorNoise])?;
+
         vprintln!("Normalizing...");
         raw.image.normalize_to_16bit_with_max(data_max);
 
+        stages.apply(&mut raw, &[Stage::OutputTransform])?;
+
         vprintln!("Writing to disk...");
         raw.save(&out_file);
 
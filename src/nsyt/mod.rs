@@ -3,4 +3,5 @@ pub mod idc;
 pub mod latest;
 pub mod metadata;
 pub mod missiontime;
+pub mod productid;
 pub mod remote;
@@ -0,0 +1,56 @@
+use crate::{
+    enums::{Eye, Instrument, Mission},
+    productid::{char_at, field, numeric_field, ProductId},
+};
+
+use sciimg::error;
+
+/// Parses an InSight product id, e.g. `D001R0007_598999612EDR_F0000_0900M_`:
+///  * 0: Camera (`C` for the ICC, `D` for the IDC)
+///  * 5-8: Sol
+///  * 10-18: Spacecraft clock
+///  * 19-21: Product type, `ILT` for thumbnails
+///  * 23-27: Filter
+///  * 29-32: Sequence
+///  * 33: Producer
+///  * 34-: Version
+pub fn parse(id: &str) -> error::Result<ProductId> {
+    if id.len() < 33 || char_at(id, 9) != Some('_') || char_at(id, 22) != Some('_') {
+        return Err("Not an InSight product id");
+    }
+
+    let instrument = match char_at(id, 0) {
+        Some('C') => Instrument::NsytICC,
+        Some('D') => Instrument::NsytIDC,
+        _ => return Err("Not an InSight product id"),
+    };
+    let sol = numeric_field(id, 5..9).ok_or("Invalid sol in product id")?;
+    let sclk = numeric_field(id, 10..19).ok_or("Invalid sclk in product id")?;
+
+    let product_type = field(id, 19..22).unwrap_or_default();
+    let version: String = id
+        .chars()
+        .skip(34)
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+
+    Ok(ProductId {
+        id: String::from(id),
+        mission: Mission::INSIGHT,
+        camera: String::from(field(id, 0..1).unwrap_or_default()),
+        instrument,
+        eye: Eye::DontCare,
+        sol: Some(sol as u32),
+        sclk: Some(sclk as f64),
+        product_type: String::from(product_type),
+        sequence: field(id, 29..33).map(String::from),
+        filter: field(id, 23..28).map(String::from),
+        focal_length: None,
+        thumbnail: product_type == "ILT",
+        version: if version.is_empty() {
+            None
+        } else {
+            Some(version)
+        },
+    })
+}
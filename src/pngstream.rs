@@ -0,0 +1,84 @@
+use crate::constants;
+
+use sciimg::{error, path, prelude::*};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Writes a 16 bit RGBA PNG a band of rows at a time, for images too large to hold in memory
+pub struct PngStreamWriter {
+    writer: Option<png::StreamWriter<'static, BufWriter<File>>>,
+    width: usize,
+    height: usize,
+    rows_written: usize,
+    /// Input value written as full scale
+    max_value: f32,
+}
+
+impl PngStreamWriter {
+    /// Starts a PNG of `width` x `height`. Values from 0 to `max_value` are scaled to the full
+    /// 16 bit range.
+    pub fn create(
+        to_file: &str,
+        width: usize,
+        height: usize,
+        max_value: f32,
+    ) -> error::Result<PngStreamWriter> {
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+        }
+        let file = File::create(to_file).map_err(|_| "Error creating image file")?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let writer = encoder
+            .write_header()
+            .map_err(|_| "Error encoding PNG")?
+            .into_stream_writer()
+            .map_err(|_| "Error encoding PNG")?;
+        Ok(PngStreamWriter {
+            writer: Some(writer),
+            width,
+            height,
+            rows_written: 0,
+            max_value,
+        })
+    }
+
+    /// Appends the rows of `rows`, which must be as wide as the output. Pixels without alpha
+    /// are written transparent.
+    pub fn write_rows(&mut self, rows: &RgbImage) -> error::Result<()> {
+        if rows.width != self.width || self.rows_written + rows.height > self.height {
+            return Err(constants::status::ARRAY_SIZE_MISMATCH);
+        }
+        let writer = self.writer.as_mut().ok_or("PNG already finished")?;
+        let scale = 65535.0 / self.max_value;
+        let mut line: Vec<u8> = Vec::with_capacity(self.width * 8);
+        for y in 0..rows.height {
+            line.clear();
+            for x in 0..rows.width {
+                for b in 0..3 {
+                    let band = b.min(rows.num_bands() - 1);
+                    let v = (rows.get_band(band).get(x, y).unwrap() * scale).clamp(0.0, 65535.0);
+                    line.extend_from_slice(&(v.round() as u16).to_be_bytes());
+                }
+                let a: u16 = if rows.get_alpha_at(x, y) { 65535 } else { 0 };
+                line.extend_from_slice(&a.to_be_bytes());
+            }
+            writer.write_all(&line).map_err(|_| "Error writing PNG")?;
+        }
+        self.rows_written += rows.height;
+        Ok(())
+    }
+
+    /// Completes the file once every row has been written
+    pub fn finish(&mut self) -> error::Result<()> {
+        if self.rows_written != self.height {
+            return Err("Not all rows of the PNG were written");
+        }
+        match self.writer.take() {
+            Some(writer) => writer.finish().map_err(|_| "Error writing PNG"),
+            None => Err("PNG already finished"),
+        }
+    }
+}
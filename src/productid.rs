@@ -0,0 +1,93 @@
+use crate::{enums::Eye, enums::Instrument, enums::Mission, m20, msl, nsyt, path};
+
+use sciimg::error;
+
+use std::ops::Range;
+
+/// Fields decoded from a raw product id, the file name of a raw image. Each mission encodes the
+/// camera, timing and product details at fixed positions, decoded by `m20::productid`,
+/// `msl::productid` and `nsyt::productid`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductId {
+    /// The id as parsed, without directory or extension
+    pub id: String,
+    pub mission: Mission,
+    /// Camera code from the id
+    pub camera: String,
+    pub instrument: Instrument,
+    pub eye: Eye,
+    pub sol: Option<u32>,
+    /// Spacecraft clock, including fractional seconds where the id carries them
+    pub sclk: Option<f64>,
+    pub product_type: String,
+    /// Sequence id, with the site and drive where the id carries them
+    pub sequence: Option<String>,
+    pub filter: Option<String>,
+    /// Focal length in millimeters, for zoom cameras
+    pub focal_length: Option<f32>,
+    pub thumbnail: bool,
+    pub version: Option<String>,
+}
+
+/// Characters of the id in `range`, if the id is long enough
+pub(crate) fn field(id: &str, range: Range<usize>) -> Option<&str> {
+    id.get(range)
+}
+
+/// Characters of the id in `range` parsed as a number, if they're all digits
+pub(crate) fn numeric_field(id: &str, range: Range<usize>) -> Option<u64> {
+    field(id, range)
+        .filter(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_digit()))
+        .and_then(|f| f.parse::<u64>().ok())
+}
+
+pub(crate) fn char_at(id: &str, pos: usize) -> Option<char> {
+    id.chars().nth(pos)
+}
+
+pub(crate) fn eye_from_char(c: Option<char>) -> Eye {
+    match c {
+        Some('L') => Eye::Left,
+        Some('R') => Eye::Right,
+        _ => Eye::DontCare,
+    }
+}
+
+/// The product id part of a file name: the base name without extension and without any
+/// suffix appended by processing (e.g. `-rjcal`)
+pub fn product_id_from_file(file_path: &str) -> String {
+    let bn = path::basename(file_path);
+    let stem = match bn.find('.') {
+        Some(i) => &bn[..i],
+        None => bn.as_str(),
+    };
+    match stem.find('-') {
+        Some(i) => String::from(&stem[..i]),
+        None => String::from(stem),
+    }
+}
+
+/// Parses the product id of a raw image file name, trying each mission's format
+pub fn parse(file_path: &str) -> error::Result<ProductId> {
+    let id = product_id_from_file(file_path);
+    m20::productid::parse(&id)
+        .or_else(|_| msl::productid::parse(&id))
+        .or_else(|_| nsyt::productid::parse(&id))
+        .map_err(|_| "Unrecognized product id")
+}
+
+/// The instrument that took an image, determined from its file name
+pub fn instrument_for_file(file_path: &str) -> Option<Instrument> {
+    match parse(file_path) {
+        Ok(pid) if pid.instrument != Instrument::None => Some(pid.instrument),
+        _ => None,
+    }
+}
+
+/// The stereo eye of an image, determined from its file name
+pub fn eye_for_file(file_path: &str) -> Eye {
+    match parse(file_path) {
+        Ok(pid) => pid.eye,
+        Err(_) => Eye::DontCare,
+    }
+}
@@ -0,0 +1,501 @@
+use crate::{constants, jsonfetch::cahvor_format, prelude::*};
+
+use sciimg::{prelude::*, vector::Vector};
+
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// Largest angle from the center of an azimuthal projection that is mapped. Beyond it the
+/// projected scale grows without bound.
+const MAX_AZIMUTHAL_ANGLE: f64 = 175.0;
+
+/// Largest distance from the center of the map plane kept when fitting stereographic and
+/// perspective maps to their inputs. These projections stretch toward the edge of their range,
+/// so the sky would otherwise make a map enormous.
+const MAX_AZIMUTHAL_RADIUS: f64 = 4.0;
+
+/// Largest angle from the horizon mapped by the cylindrical perspective projection
+const MAX_CYLINDRICAL_ELEVATION: f64 = 80.0;
+
+/// Rectangle on the map plane. `y` runs down the output image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlaneBounds {
+    pub min_x: f64,
+    pub max_x: f64,
+    pub min_y: f64,
+    pub max_y: f64,
+}
+
+impl PlaneBounds {
+    /// Bounds no projected point falls outside of
+    pub fn unlimited() -> PlaneBounds {
+        PlaneBounds {
+            min_x: f64::MIN,
+            max_x: f64::MAX,
+            min_y: f64::MIN,
+            max_y: f64::MAX,
+        }
+    }
+
+    /// Bounds that grow to fit the first point included
+    pub fn empty() -> PlaneBounds {
+        PlaneBounds {
+            min_x: f64::MAX,
+            max_x: f64::MIN,
+            min_y: f64::MAX,
+            max_y: f64::MIN,
+        }
+    }
+
+    pub fn include(&mut self, x: f64, y: f64) {
+        self.min_x = self.min_x.min(x);
+        self.max_x = self.max_x.max(x);
+        self.min_y = self.min_y.min(y);
+        self.max_y = self.max_y.max(y);
+    }
+
+    /// Smallest bounds containing both
+    pub fn union(&self, other: &PlaneBounds) -> PlaneBounds {
+        PlaneBounds {
+            min_x: self.min_x.min(other.min_x),
+            max_x: self.max_x.max(other.max_x),
+            min_y: self.min_y.min(other.min_y),
+            max_y: self.max_y.max(other.max_y),
+        }
+    }
+
+    pub fn intersect(&self, other: &PlaneBounds) -> PlaneBounds {
+        PlaneBounds {
+            min_x: self.min_x.max(other.min_x),
+            max_x: self.max_x.min(other.max_x),
+            min_y: self.min_y.max(other.min_y),
+            max_y: self.max_y.min(other.max_y),
+        }
+    }
+
+    pub fn width(&self) -> f64 {
+        self.max_x - self.min_x
+    }
+
+    pub fn height(&self) -> f64 {
+        self.max_y - self.min_y
+    }
+}
+
+/// Azimuth and elevation limits of a mosaic, in degrees. Azimuth runs clockwise from the rover's
+/// forward direction (-180 to 180), elevation is positive above the horizon.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FieldOfView {
+    pub azimuth_min: f64,
+    pub azimuth_max: f64,
+    pub elevation_min: f64,
+    pub elevation_max: f64,
+}
+
+impl FromStr for FieldOfView {
+    type Err = &'static str;
+
+    /// Parses `azimuth_min,azimuth_max,elevation_min,elevation_max`
+    fn from_str(s: &str) -> Result<FieldOfView, Self::Err> {
+        let values: Vec<f64> = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| "Invalid field of view value")?;
+        if values.len() != 4 {
+            return Err("Field of view requires four values");
+        }
+        if values[0] >= values[1] || values[2] >= values[3] {
+            return Err("Field of view minimums must be less than maximums");
+        }
+        Ok(FieldOfView {
+            azimuth_min: values[0],
+            azimuth_max: values[1],
+            elevation_min: values[2],
+            elevation_max: values[3],
+        })
+    }
+}
+
+/// Maps points around the mosaic origin, in the rover frame (x forward, y right, z down), onto
+/// the plane of the output image
+pub trait Projection {
+    /// Map plane coordinates of a point, or `None` where the projection doesn't cover it
+    fn project(&self, point: &Vector) -> Option<(f64, f64)>;
+
+    /// Size on the map plane of a pixel subtending `pixel_angle` radians at the projection's
+    /// center of least distortion
+    fn units_per_pixel(&self, pixel_angle: f64) -> f64 {
+        pixel_angle
+    }
+
+    /// Bounds of the map when they're fixed rather than fit to the input images
+    fn fixed_bounds(&self) -> Option<PlaneBounds> {
+        None
+    }
+
+    /// Bounds fit map extents are clamped to
+    fn limits(&self) -> PlaneBounds {
+        PlaneBounds::unlimited()
+    }
+}
+
+/// The available projections, as selected from the command line
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ProjectionType {
+    Equirectangular,
+    Cylindrical,
+    Polar,
+    Stereographic,
+    Perspective,
+    Camera,
+}
+
+impl FromStr for ProjectionType {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<ProjectionType, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "equirectangular" | "equirect" => Ok(ProjectionType::Equirectangular),
+            "cylindrical" | "cylindrical-perspective" => Ok(ProjectionType::Cylindrical),
+            "polar" => Ok(ProjectionType::Polar),
+            "stereographic" | "tinyplanet" | "tiny-planet" => Ok(ProjectionType::Stereographic),
+            "perspective" | "vertical-perspective" => Ok(ProjectionType::Perspective),
+            "camera" | "cahv" => Ok(ProjectionType::Camera),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Azimuth of a point in radians, as used on the horizontal axis of the cylindrical maps. The
+/// rover's forward direction is at the middle of the axis.
+fn azimuth(point: &Vector) -> f64 {
+    point.y.atan2(point.x) + PI
+}
+
+/// Angle below the horizon of a point, in radians
+fn depression(point: &Vector) -> f64 {
+    point
+        .z
+        .atan2((point.x * point.x + point.y * point.y).sqrt())
+}
+
+fn fov_bounds(fov: &FieldOfView, y_for_depression: impl Fn(f64) -> f64) -> PlaneBounds {
+    PlaneBounds {
+        min_x: fov.azimuth_min.to_radians() + PI,
+        max_x: fov.azimuth_max.to_radians() + PI,
+        min_y: y_for_depression(-fov.elevation_max.to_radians()),
+        max_y: y_for_depression(-fov.elevation_min.to_radians()),
+    }
+}
+
+/// Azimuth and elevation mapped linearly onto the plane
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Equirectangular {
+    pub fov: Option<FieldOfView>,
+}
+
+impl Projection for Equirectangular {
+    fn project(&self, point: &Vector) -> Option<(f64, f64)> {
+        Some((azimuth(point), depression(point)))
+    }
+
+    fn fixed_bounds(&self) -> Option<PlaneBounds> {
+        self.fov.as_ref().map(|fov| fov_bounds(fov, |d| d))
+    }
+
+    fn limits(&self) -> PlaneBounds {
+        PlaneBounds {
+            min_x: 0.0,
+            max_x: PI * 2.0,
+            min_y: -PI / 2.0,
+            max_y: PI / 2.0,
+        }
+    }
+}
+
+/// Azimuth mapped linearly and elevation projected from the center of the cylinder, which keeps
+/// vertical and horizontal lines straight near the horizon
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CylindricalPerspective {
+    pub fov: Option<FieldOfView>,
+}
+
+impl Projection for CylindricalPerspective {
+    fn project(&self, point: &Vector) -> Option<(f64, f64)> {
+        let d = depression(point);
+        if d.abs() > MAX_CYLINDRICAL_ELEVATION.to_radians() {
+            None
+        } else {
+            Some((azimuth(point), d.tan()))
+        }
+    }
+
+    fn fixed_bounds(&self) -> Option<PlaneBounds> {
+        self.fov.as_ref().map(|fov| fov_bounds(fov, |d| d.tan()))
+    }
+
+    fn limits(&self) -> PlaneBounds {
+        PlaneBounds {
+            min_x: 0.0,
+            max_x: PI * 2.0,
+            min_y: -MAX_CYLINDRICAL_ELEVATION.to_radians().tan(),
+            max_y: MAX_CYLINDRICAL_ELEVATION.to_radians().tan(),
+        }
+    }
+}
+
+/// Orientation of an azimuthal projection: the direction at the center of the map and the
+/// directions of the map's right and down axes
+#[derive(Debug, Copy, Clone)]
+pub struct AzimuthalCenter {
+    center: Vector,
+    right: Vector,
+    down: Vector,
+}
+
+impl AzimuthalCenter {
+    /// Centers the map on an azimuth (clockwise from forward) and elevation (positive up), in
+    /// degrees. Looking straight down, the top of the map faces the rover's forward direction.
+    pub fn new(azimuth: f64, elevation: f64) -> AzimuthalCenter {
+        let (az, el) = (azimuth.to_radians(), elevation.to_radians());
+        let center = Vector::new(el.cos() * az.cos(), el.cos() * az.sin(), -el.sin());
+        let right = Vector::new(-az.sin(), az.cos(), 0.0);
+        let down = center.cross_product(&right);
+        AzimuthalCenter {
+            center,
+            right,
+            down,
+        }
+    }
+
+    /// Straight down, for top-down views of the ground around the rover
+    pub fn nadir() -> AzimuthalCenter {
+        AzimuthalCenter::new(0.0, -90.0)
+    }
+
+    /// Projects a point given the distance on the map from the center for its angle from the
+    /// center
+    fn project(
+        &self,
+        point: &Vector,
+        radius_for_angle: impl Fn(f64) -> Option<f64>,
+    ) -> Option<(f64, f64)> {
+        let p = point.normalized();
+        let u = p.dot_product(&self.right);
+        let w = p.dot_product(&self.down);
+        let angle = p.dot_product(&self.center).clamp(-1.0, 1.0).acos();
+        if angle > MAX_AZIMUTHAL_ANGLE.to_radians() {
+            return None;
+        }
+        let lateral = (u * u + w * w).sqrt();
+        if lateral < f64::EPSILON {
+            return Some((0.0, 0.0));
+        }
+        let r = radius_for_angle(angle)?;
+        Some((r * u / lateral, r * w / lateral))
+    }
+}
+
+fn azimuthal_limits() -> PlaneBounds {
+    PlaneBounds {
+        min_x: -MAX_AZIMUTHAL_RADIUS,
+        max_x: MAX_AZIMUTHAL_RADIUS,
+        min_y: -MAX_AZIMUTHAL_RADIUS,
+        max_y: MAX_AZIMUTHAL_RADIUS,
+    }
+}
+
+impl Default for AzimuthalCenter {
+    fn default() -> Self {
+        AzimuthalCenter::nadir()
+    }
+}
+
+/// Azimuthal equidistant projection, distance from the map center proportional to the angle
+/// from the center direction. Centered at the nadir it's a polar view of the ground.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Polar {
+    pub center: AzimuthalCenter,
+}
+
+impl Projection for Polar {
+    fn project(&self, point: &Vector) -> Option<(f64, f64)> {
+        self.center.project(point, Some)
+    }
+}
+
+/// Conformal azimuthal projection. Centered at the nadir the horizon wraps into a circle, the
+/// "tiny planet" view.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Stereographic {
+    pub center: AzimuthalCenter,
+}
+
+impl Projection for Stereographic {
+    fn project(&self, point: &Vector) -> Option<(f64, f64)> {
+        self.center
+            .project(point, |angle| Some(2.0 * (angle / 2.0).tan()))
+    }
+
+    fn limits(&self) -> PlaneBounds {
+        azimuthal_limits()
+    }
+}
+
+/// Azimuthal projection from a viewpoint `distance` sphere radii behind the center of the
+/// sphere, onto the plane touching it at the map center. A distance of zero is the rectilinear
+/// (gnomonic) view of a pinhole camera, one is stereographic, and larger distances approach an
+/// orthographic view.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct VerticalPerspective {
+    pub center: AzimuthalCenter,
+    pub distance: f64,
+}
+
+impl Projection for VerticalPerspective {
+    fn project(&self, point: &Vector) -> Option<(f64, f64)> {
+        let p = self.distance.max(0.0);
+        self.center.project(point, |angle| {
+            let denom = p + angle.cos();
+            // Behind the viewpoint, or hidden over the horizon of the sphere
+            if denom <= 0.01 || (p > 1.0 && angle.cos() < -1.0 / p) {
+                None
+            } else {
+                Some((p + 1.0) * angle.sin() / denom)
+            }
+        })
+    }
+
+    fn limits(&self) -> PlaneBounds {
+        azimuthal_limits()
+    }
+}
+
+/// Projection into the image plane of a camera model, for rendering inputs as seen by another
+/// camera. Map plane units are the target camera's pixels.
+pub struct CameraProjection {
+    pub model: CameraModel,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Projection for CameraProjection {
+    fn project(&self, point: &Vector) -> Option<(f64, f64)> {
+        if point.subtract(&self.model.c()).dot_product(&self.model.a()) <= 0.0 {
+            return None;
+        }
+        let ic = self.model.xyz_to_ls(point, false);
+        Some((ic.sample, ic.line))
+    }
+
+    fn units_per_pixel(&self, _pixel_angle: f64) -> f64 {
+        1.0
+    }
+
+    fn fixed_bounds(&self) -> Option<PlaneBounds> {
+        Some(PlaneBounds {
+            min_x: 0.0,
+            max_x: self.width as f64,
+            min_y: 0.0,
+            max_y: self.height as f64,
+        })
+    }
+}
+
+/// Camera model and size of the image a camera projection renders into, read from the target
+/// image once. The model is held in its serialized form, since camera models can't be shared
+/// between threads.
+#[derive(Debug, Clone)]
+pub struct CameraTarget {
+    model: String,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl CameraTarget {
+    pub fn new(model: &CameraModel, width: usize, height: usize) -> CameraTarget {
+        CameraTarget {
+            model: model.serialize(),
+            width,
+            height,
+        }
+    }
+
+    pub fn open(target: &str) -> error::Result<CameraTarget> {
+        if !path::file_exists(target) {
+            return Err(constants::status::FILE_NOT_FOUND);
+        }
+        let img = MarsImage::open(target.to_owned(), Instrument::M20MastcamZLeft);
+        Ok(CameraTarget::new(
+            &img.camera_model()?,
+            img.image.width,
+            img.image.height,
+        ))
+    }
+
+    pub fn model(&self) -> CameraModel {
+        cahvor_format::parse(&self.model)
+    }
+}
+
+/// Everything needed to build a projection. Camera models can't be shared between threads, so
+/// parallel mosaicking builds a projection from this in each worker.
+#[derive(Debug, Clone)]
+pub struct ProjectionSpec {
+    pub projection_type: ProjectionType,
+    /// Extent of the equirectangular and cylindrical projections
+    pub fov: Option<FieldOfView>,
+    /// Center of the azimuthal projections
+    pub center: AzimuthalCenter,
+    /// Viewpoint distance of the vertical perspective projection
+    pub distance: f64,
+    /// Camera the camera projection renders as
+    pub target: Option<CameraTarget>,
+}
+
+impl Default for ProjectionSpec {
+    fn default() -> Self {
+        ProjectionSpec {
+            projection_type: ProjectionType::Equirectangular,
+            fov: None,
+            center: AzimuthalCenter::nadir(),
+            distance: 0.0,
+            target: None,
+        }
+    }
+}
+
+impl ProjectionSpec {
+    pub fn build(&self) -> error::Result<Box<dyn Projection>> {
+        Ok(match self.projection_type {
+            ProjectionType::Equirectangular => Box::new(Equirectangular { fov: self.fov }),
+            ProjectionType::Cylindrical => Box::new(CylindricalPerspective { fov: self.fov }),
+            ProjectionType::Polar => Box::new(Polar {
+                center: self.center,
+            }),
+            ProjectionType::Stereographic => Box::new(Stereographic {
+                center: self.center,
+            }),
+            ProjectionType::Perspective => Box::new(VerticalPerspective {
+                center: self.center,
+                distance: self.distance,
+            }),
+            ProjectionType::Camera => {
+                let target = self
+                    .target
+                    .as_ref()
+                    .ok_or("Camera projection requires a target image")?;
+                let model = target.model();
+                if !model.is_valid() {
+                    return Err(constants::status::CAMERA_MODEL_NOT_FOUND);
+                }
+                Box::new(CameraProjection {
+                    model,
+                    width: target.width,
+                    height: target.height,
+                })
+            }
+        })
+    }
+}
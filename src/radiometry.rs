@@ -0,0 +1,173 @@
+use crate::{
+    constants, image::MarsImage, metadata::RadiometricCoefficients, path, spectral, spectral::Roi,
+    vprintln,
+};
+
+use sciimg::error;
+
+use serde::Deserialize;
+
+use std::fs::File;
+use std::io::Read;
+
+/// Calibrated I/F is stored in 16 bit outputs as `I/F * IOF_SCALE`, leaving headroom
+/// for surfaces brighter than the reference.
+pub const IOF_SCALE: f32 = 32768.0;
+
+/// A patch of known reflectance on the calibration target, located in the reference frame
+#[derive(Deserialize, Debug, Clone)]
+pub struct CalTargetPatch {
+    pub name: String,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+
+    /// Reflectance of the patch per image band. A single value applies to every band.
+    pub reflectance: Vec<f32>,
+}
+
+impl CalTargetPatch {
+    pub fn roi(&self) -> Roi {
+        Roi {
+            name: self.name.clone(),
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    pub fn reflectance_for_band(&self, band: usize) -> f32 {
+        if self.reflectance.len() == 1 {
+            self.reflectance[0]
+        } else {
+            self.reflectance[band.min(self.reflectance.len() - 1)]
+        }
+    }
+}
+
+/// The calibration target as seen in a reference frame
+#[derive(Deserialize, Debug, Clone)]
+pub struct CalTarget {
+    /// Solar incidence angle on the target, in degrees
+    #[serde(default)]
+    pub incidence_angle: f32,
+
+    pub patch: Vec<CalTargetPatch>,
+}
+
+pub fn load_caltarget(file_path: &str) -> error::Result<CalTarget> {
+    if !path::file_exists(file_path) {
+        return Err(constants::status::FILE_NOT_FOUND);
+    }
+
+    let mut file = match File::open(file_path) {
+        Err(why) => panic!("couldn't open {}", why),
+        Ok(file) => file,
+    };
+
+    let mut buf: Vec<u8> = Vec::default();
+    file.read_to_end(&mut buf).unwrap();
+    let text = String::from_utf8(buf).unwrap();
+
+    match toml::from_str::<CalTarget>(&text) {
+        Ok(t) => {
+            if t.patch.is_empty() || t.patch.iter().any(|p| p.reflectance.is_empty()) {
+                Err("Calibration target requires patches with reflectance values")
+            } else {
+                Ok(t)
+            }
+        }
+        Err(why) => {
+            eprintln!("Error parsing calibration target file: {:?}", why);
+            Err("Error parsing calibration target file")
+        }
+    }
+}
+
+/// Derives the per-band gains converting DN to I/F from the calibration target patches in a
+/// reference frame. Each gain is the least-squares fit, through the origin, of the expected
+/// patch I/F (reflectance times the cosine of the incidence angle) to the mean patch DN.
+pub fn derive_coefficients(
+    reference: &MarsImage,
+    reference_file: &str,
+    target: &CalTarget,
+) -> error::Result<RadiometricCoefficients> {
+    let cos_i = target.incidence_angle.to_radians().cos();
+    if cos_i <= 0.0 {
+        return Err("Calibration target incidence angle must be less than 90 degrees");
+    }
+
+    let mut gains: Vec<f32> = vec![];
+    for b in 0..reference.image.num_bands() {
+        let mut sum_rd = 0.0;
+        let mut sum_dd = 0.0;
+        for patch in target.patch.iter() {
+            let (dn, _) = spectral::roi_stats(reference.image.get_band(b), &patch.roi())?;
+            let iof = patch.reflectance_for_band(b) * cos_i;
+            vprintln!(
+                "Band {}, patch {}: mean DN {}, expected I/F {}",
+                b,
+                patch.name,
+                dn,
+                iof
+            );
+            sum_rd += iof * dn;
+            sum_dd += dn * dn;
+        }
+        if sum_dd == 0.0 {
+            return Err("Calibration target patches have no signal");
+        }
+        gains.push(sum_rd / sum_dd);
+    }
+
+    Ok(RadiometricCoefficients {
+        reference_image: path::basename(reference_file),
+        reference_sclk: reference.metadata.as_ref().and_then(|md| md.sclk),
+        gains,
+        iof_scale: IOF_SCALE,
+    })
+}
+
+/// Whether a frame was taken within `max_seconds` of the calibration reference frame,
+/// as judged by the spacecraft clocks.
+pub fn is_close_in_time(
+    coefficients: &RadiometricCoefficients,
+    img: &MarsImage,
+    max_seconds: f64,
+) -> bool {
+    match (
+        coefficients.reference_sclk,
+        img.metadata.as_ref().and_then(|md| md.sclk),
+    ) {
+        (Some(r), Some(s)) => (r - s).abs() <= max_seconds,
+        _ => false,
+    }
+}
+
+/// Converts a calibrated frame to approximate I/F using the derived gains, recording the
+/// coefficients in the image metadata.
+pub fn apply_coefficients(
+    img: &mut MarsImage,
+    coefficients: &RadiometricCoefficients,
+) -> error::Result<()> {
+    if coefficients.gains.len() != img.image.num_bands() {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+
+    for (b, gain) in coefficients.gains.iter().enumerate() {
+        let band = img
+            .image
+            .get_band(b)
+            .scale(gain * coefficients.iof_scale)?
+            .clip(0.0, 65535.0)?;
+        img.image.set_band(&band, b);
+    }
+
+    if let Some(ref mut md) = img.metadata {
+        md.radiometric = true;
+        md.radiometric_coefficients = Some(coefficients.clone());
+    }
+    Ok(())
+}
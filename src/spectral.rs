@@ -0,0 +1,484 @@
+use crate::{
+    constants, filters::FilterPosition, image::MarsImage, metadata::Metadata, path, vprintln,
+};
+
+use sciimg::{error, imagebuffer::ImageBuffer};
+
+use serde::Deserialize;
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// A single band of a multispectral cube
+#[derive(Clone)]
+pub struct SpectralBand {
+    pub name: String,
+    pub wavelength: f32,
+    pub buffer: ImageBuffer,
+}
+
+/// A stack of co-registered bands of the same target, ordered by wavelength
+pub struct SpectralCube {
+    pub width: usize,
+    pub height: usize,
+    pub bands: Vec<SpectralBand>,
+
+    /// Bands hold I/F, from frames converted with calibration target gains, rather than
+    /// calibrated DN
+    pub iof: bool,
+}
+
+/// A rectangular region of interest within the cube
+#[derive(Deserialize, Debug, Clone)]
+pub struct Roi {
+    pub name: String,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Deserialize)]
+struct RoiFile {
+    roi: Vec<Roi>,
+}
+
+impl Roi {
+    /// Parses a region of interest from a string formatted as `x,y,width,height`
+    pub fn from_str_with_name(s: &str, name: &str) -> error::Result<Roi> {
+        let parts: Vec<usize> = s
+            .split(',')
+            .map(|p| p.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| "Invalid region of interest")?;
+        if parts.len() != 4 || parts[2] == 0 || parts[3] == 0 {
+            return Err("Invalid region of interest");
+        }
+        Ok(Roi {
+            name: name.to_string(),
+            x: parts[0],
+            y: parts[1],
+            width: parts[2],
+            height: parts[3],
+        })
+    }
+}
+
+/// Loads regions of interest from a toml file containing a list of `[[roi]]` tables
+pub fn load_rois(file_path: &str) -> error::Result<Vec<Roi>> {
+    if !path::file_exists(file_path) {
+        return Err(constants::status::FILE_NOT_FOUND);
+    }
+
+    let mut file = match File::open(file_path) {
+        Err(why) => panic!("couldn't open {}", why),
+        Ok(file) => file,
+    };
+
+    let mut buf: Vec<u8> = Vec::default();
+    file.read_to_end(&mut buf).unwrap();
+    let text = String::from_utf8(buf).unwrap();
+
+    match toml::from_str::<RoiFile>(&text) {
+        Ok(f) => Ok(f.roi),
+        Err(why) => {
+            eprintln!("Error parsing ROI file: {:?}", why);
+            Err("Error parsing ROI file")
+        }
+    }
+}
+
+/// Computes the mean and standard deviation of the unmasked pixels of a buffer within a
+/// region of interest
+pub fn roi_stats(buffer: &ImageBuffer, roi: &Roi) -> error::Result<(f32, f32)> {
+    if roi.x + roi.width > buffer.width || roi.y + roi.height > buffer.height {
+        return Err(constants::status::INVALID_PIXEL_COORDINATES);
+    }
+
+    let mut values: Vec<f32> = Vec::with_capacity(roi.width * roi.height);
+    for y in roi.y..(roi.y + roi.height) {
+        for x in roi.x..(roi.x + roi.width) {
+            if buffer.get_mask_at_point(x, y) {
+                values.push(buffer.get(x, y).unwrap());
+            }
+        }
+    }
+    let n = values.len().max(1) as f32;
+    let mean = values.iter().sum::<f32>() / n;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    Ok((mean, variance.sqrt()))
+}
+
+/// A point in a region-of-interest spectrum
+#[derive(Debug, Clone)]
+pub struct SpectrumSample {
+    pub band_name: String,
+    pub wavelength: f32,
+    pub mean: f32,
+    pub stddev: f32,
+}
+
+impl SpectralCube {
+    pub fn new() -> Self {
+        SpectralCube {
+            width: 0,
+            height: 0,
+            bands: vec![],
+            iof: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bands.is_empty()
+    }
+
+    /// Adds a band, keeping the cube sorted by wavelength
+    pub fn add_band(&mut self, band: SpectralBand) -> error::Result<()> {
+        if self.is_empty() {
+            self.width = band.buffer.width;
+            self.height = band.buffer.height;
+        } else if band.buffer.width != self.width || band.buffer.height != self.height {
+            return Err(constants::status::ARRAY_SIZE_MISMATCH);
+        }
+
+        let idx = self
+            .bands
+            .iter()
+            .position(|b| b.wavelength > band.wavelength)
+            .unwrap_or(self.bands.len());
+        self.bands.insert(idx, band);
+        Ok(())
+    }
+
+    /// Adds the bands of a calibrated frame taken through a filter. Narrowband frames contribute a
+    /// single band (the mean of the image channels); bayer frames contribute red, green, and blue.
+    /// Frames converted to I/F by `mru iof` are added as I/F, and can't be mixed with frames
+    /// left in calibrated DN.
+    pub fn add_frame(
+        &mut self,
+        img: &MarsImage,
+        filter: &FilterPosition,
+        wavelengths: &[f32],
+    ) -> error::Result<()> {
+        let coefficients = img
+            .metadata
+            .as_ref()
+            .and_then(|md| md.radiometric_coefficients.as_ref());
+        if !self.is_empty() && self.iof != coefficients.is_some() {
+            return Err("Cannot mix I/F and calibrated DN frames");
+        }
+        self.iof = coefficients.is_some();
+        let to_value = |buffer: ImageBuffer| match coefficients {
+            Some(c) => buffer.scale(1.0 / c.iof_scale),
+            None => Ok(buffer),
+        };
+
+        if wavelengths.len() == 1 {
+            let mut sum = img.image.get_band(0).clone();
+            for b in 1..img.image.num_bands() {
+                sum = sum.add(img.image.get_band(b)).unwrap();
+            }
+            let mean = sum.scale(1.0 / img.image.num_bands() as f32).unwrap();
+            self.add_band(SpectralBand {
+                name: filter.name(),
+                wavelength: wavelengths[0],
+                buffer: to_value(mean)?,
+            })
+        } else {
+            for (b, wavelength) in wavelengths.iter().enumerate().take(img.image.num_bands()) {
+                self.add_band(SpectralBand {
+                    name: format!("{}{}", filter.name(), ["R", "G", "B"][b % 3]),
+                    wavelength: *wavelength,
+                    buffer: to_value(img.image.get_band(b).clone())?,
+                })?;
+            }
+            Ok(())
+        }
+    }
+
+    /// What the band values are: `iof`, or `dn` for calibrated DN
+    pub fn value_units(&self) -> &'static str {
+        if self.iof {
+            "iof"
+        } else {
+            "dn"
+        }
+    }
+
+    /// Co-registers the bands of the cube by shifting each band onto the band in the middle of
+    /// the wavelength range. Shifts are whole pixels and limited to `max_shift` in either axis.
+    /// Returns the (horizontal, vertical) shift applied to each band.
+    pub fn register(&mut self, max_shift: usize) -> error::Result<Vec<(i32, i32)>> {
+        if self.is_empty() {
+            return Err(constants::status::STRUCT_IS_EMPTY);
+        }
+
+        let reference = self.bands[self.bands.len() / 2].buffer.clone();
+        let mut shifts: Vec<(i32, i32)> = Vec::with_capacity(self.bands.len());
+        for band in self.bands.iter_mut() {
+            let (dx, dy) = find_translation(&reference, &band.buffer, max_shift);
+            vprintln!(
+                "Band {} ({}nm) offset: {}, {}",
+                band.name,
+                band.wavelength,
+                dx,
+                dy
+            );
+            if dx != 0 || dy != 0 {
+                band.buffer = band.buffer.shift(-dx, -dy)?;
+            }
+            shifts.push((dx, dy));
+        }
+        Ok(shifts)
+    }
+
+    pub fn wavelengths(&self) -> Vec<f32> {
+        self.bands.iter().map(|b| b.wavelength).collect()
+    }
+
+    /// Computes the mean and standard deviation of each band within a region of interest
+    pub fn roi_spectrum(&self, roi: &Roi) -> error::Result<Vec<SpectrumSample>> {
+        self.bands
+            .iter()
+            .map(|band| {
+                let (mean, stddev) = roi_stats(&band.buffer, roi)?;
+                Ok(SpectrumSample {
+                    band_name: band.name.clone(),
+                    wavelength: band.wavelength,
+                    mean,
+                    stddev,
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the cube as an ENVI standard image: band-sequential 32 bit floats (`<base>.img`)
+    /// along with a header listing the band names and wavelengths (`<base>.hdr`).
+    pub fn save_envi(&self, base_path: &str) -> error::Result<()> {
+        if self.is_empty() {
+            return Err(constants::status::STRUCT_IS_EMPTY);
+        }
+
+        let bands: Vec<(&str, &ImageBuffer)> = self
+            .bands
+            .iter()
+            .map(|b| (b.name.as_str(), &b.buffer))
+            .collect();
+        let wavelengths: Vec<String> = self
+            .bands
+            .iter()
+            .map(|b| format!("{:.1}", b.wavelength))
+            .collect();
+
+        save_envi_bands(
+            base_path,
+            &bands,
+            if self.iof {
+                "Mars Raw Utils multispectral cube, I/F"
+            } else {
+                "Mars Raw Utils multispectral cube, calibrated DN"
+            },
+            &format!(
+                "wavelength units = Nanometers\nwavelength = {{{}}}\n",
+                wavelengths.join(", ")
+            ),
+        )
+    }
+
+    /// Writes the metadata of the cube to `<base>-metadata.json`, recording the band wavelengths
+    pub fn save_metadata(&self, base_path: &str, metadata: &Metadata) -> error::Result<()> {
+        let mut md = metadata.clone();
+        md.band_wavelengths = Some(self.wavelengths());
+
+        let md_path = format!("{}-metadata.json", base_path);
+        vprintln!("Writing cube metadata to {}", md_path);
+        let md_str = serde_json::to_string_pretty(&md).map_err(|_| "Error serializing metadata")?;
+        let mut file = File::create(&md_path).map_err(|_| "Error creating metadata file")?;
+        file.write_all(md_str.as_bytes())
+            .map_err(|_| "Error writing metadata file")?;
+        Ok(())
+    }
+}
+
+impl Default for SpectralCube {
+    fn default() -> Self {
+        SpectralCube::new()
+    }
+}
+
+// Bands are matched on their gradients rather than their values since surface features can
+// change brightness, or even contrast, between wavelengths.
+fn normalized_gradient(buffer: &ImageBuffer, factor: usize) -> (Vec<f32>, usize, usize) {
+    let w = buffer.width / factor;
+    let h = buffer.height / factor;
+    let mut pooled = vec![0.0; w * h];
+    for y in 0..h * factor {
+        for x in 0..w * factor {
+            pooled[(y / factor) * w + (x / factor)] += buffer.get(x, y).unwrap();
+        }
+    }
+
+    let mut grad = vec![0.0; w * h];
+    for y in 1..h.max(1) - 1 {
+        for x in 1..w.max(1) - 1 {
+            let gx = pooled[y * w + x + 1] - pooled[y * w + x - 1];
+            let gy = pooled[(y + 1) * w + x] - pooled[(y - 1) * w + x];
+            grad[y * w + x] = (gx * gx + gy * gy).sqrt();
+        }
+    }
+
+    let n = grad.len().max(1) as f32;
+    let mean = grad.iter().sum::<f32>() / n;
+    let stddev = (grad.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n).sqrt();
+    let stddev = if stddev > 0.0 { stddev } else { 1.0 };
+    (grad.iter().map(|v| (v - mean) / stddev).collect(), w, h)
+}
+
+fn correlation_at(a: &[f32], b: &[f32], w: usize, h: usize, dx: i32, dy: i32) -> f32 {
+    let mut sum = 0.0;
+    let mut count = 0;
+    for y in 0..h as i32 {
+        let by = y + dy;
+        if by < 0 || by >= h as i32 {
+            continue;
+        }
+        for x in 0..w as i32 {
+            let bx = x + dx;
+            if bx < 0 || bx >= w as i32 {
+                continue;
+            }
+            sum += a[y as usize * w + x as usize] * b[by as usize * w + bx as usize];
+            count += 1;
+        }
+    }
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f32
+    }
+}
+
+fn best_shift(
+    a: &[f32],
+    b: &[f32],
+    w: usize,
+    h: usize,
+    center: (i32, i32),
+    radius: i32,
+) -> (i32, i32) {
+    let mut best = center;
+    let mut best_score = f32::MIN;
+    for dy in (center.1 - radius)..=(center.1 + radius) {
+        for dx in (center.0 - radius)..=(center.0 + radius) {
+            let score = correlation_at(a, b, w, h, dx, dy);
+            if score > best_score {
+                best_score = score;
+                best = (dx, dy);
+            }
+        }
+    }
+    best
+}
+
+/// Finds the whole-pixel translation of `target` relative to `reference`, such that
+/// `target(x + dx, y + dy)` best matches `reference(x, y)`. The search is done coarsely on a
+/// reduced image, then refined at full resolution.
+pub fn find_translation(
+    reference: &ImageBuffer,
+    target: &ImageBuffer,
+    max_shift: usize,
+) -> (i32, i32) {
+    if reference.width != target.width || reference.height != target.height || max_shift == 0 {
+        return (0, 0);
+    }
+
+    let factor = if max_shift >= 8 && reference.width >= 128 && reference.height >= 128 {
+        4
+    } else {
+        1
+    };
+
+    let mut center = (0, 0);
+    let mut radius = max_shift as i32;
+    if factor > 1 {
+        let (a, w, h) = normalized_gradient(reference, factor);
+        let (b, _, _) = normalized_gradient(target, factor);
+        let coarse = best_shift(&a, &b, w, h, (0, 0), radius / factor as i32 + 1);
+        center = (coarse.0 * factor as i32, coarse.1 * factor as i32);
+        radius = factor as i32;
+    }
+
+    let (a, w, h) = normalized_gradient(reference, 1);
+    let (b, _, _) = normalized_gradient(target, 1);
+    let (dx, dy) = best_shift(&a, &b, w, h, center, radius);
+    let m = max_shift as i32;
+    (dx.clamp(-m, m), dy.clamp(-m, m))
+}
+
+/// Writes bands of equal size as an ENVI standard image: band-sequential 32 bit floats
+/// (`<base>.img`) and a header naming the bands (`<base>.hdr`), followed by any extra header
+/// lines.
+pub fn save_envi_bands(
+    base_path: &str,
+    bands: &[(&str, &ImageBuffer)],
+    description: &str,
+    extra_header: &str,
+) -> error::Result<()> {
+    if bands.is_empty() {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    let (width, height) = (bands[0].1.width, bands[0].1.height);
+    if bands
+        .iter()
+        .any(|(_, b)| b.width != width || b.height != height)
+    {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+
+    let img_path = format!("{}.img", base_path);
+    let hdr_path = format!("{}.hdr", base_path);
+
+    if !path::parent_exists_and_writable(&img_path) {
+        return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+    }
+
+    vprintln!("Writing ENVI data to {}", img_path);
+    let mut data: Vec<u8> = Vec::with_capacity(width * height * bands.len() * 4);
+    for (_, band) in bands.iter() {
+        for v in band.to_vector().iter() {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    let mut file = File::create(&img_path).map_err(|_| "Error creating ENVI file")?;
+    file.write_all(&data)
+        .map_err(|_| "Error writing ENVI file")?;
+
+    let band_names: Vec<&str> = bands.iter().map(|(n, _)| *n).collect();
+
+    vprintln!("Writing ENVI header to {}", hdr_path);
+    let header = format!(
+        "ENVI\n\
+         description = {{{}}}\n\
+         samples = {}\n\
+         lines = {}\n\
+         bands = {}\n\
+         header offset = 0\n\
+         file type = ENVI Standard\n\
+         data type = 4\n\
+         interleave = bsq\n\
+         byte order = 0\n\
+         band names = {{{}}}\n\
+         {}",
+        description,
+        width,
+        height,
+        bands.len(),
+        band_names.join(", "),
+        extra_header
+    );
+    let mut file = File::create(&hdr_path).map_err(|_| "Error creating ENVI header")?;
+    file.write_all(header.as_bytes())
+        .map_err(|_| "Error writing ENVI header")?;
+
+    Ok(())
+}
@@ -0,0 +1,779 @@
+use crate::{constants, enums::Instrument, features, image::MarsImage, path, spectral, vprintln};
+
+use rayon::prelude::*;
+use sciimg::{prelude::*, vector::Vector};
+
+#[derive(Debug, Clone, Copy)]
+pub struct StereoOptions {
+    /// Number of disparities searched, starting from zero
+    pub num_disparities: usize,
+    /// Penalty for a one pixel change in disparity between neighbors
+    pub p1: u16,
+    /// Penalty for a larger change in disparity between neighbors
+    pub p2: u16,
+    /// How much lower than any other the best matching cost must be, as a fraction
+    pub uniqueness: f32,
+    /// Largest difference, in pixels, between the left and right disparities of a match
+    pub max_lr_difference: f32,
+}
+
+impl Default for StereoOptions {
+    fn default() -> Self {
+        StereoOptions {
+            num_disparities: 64,
+            p1: 3,
+            p2: 20,
+            uniqueness: 0.05,
+            max_lr_difference: 1.0,
+        }
+    }
+}
+
+fn vector_parts(v: &Vector, a: &Vector) -> (f64, f64) {
+    let center = v.dot_product(a);
+    (center, v.subtract(&a.scale(center)).len())
+}
+
+/// Epipolar-aligned CAHV models for a stereo pair. Both share a pointing, with the horizontal
+/// axis along the baseline, so a point appears on the same line in each and is shifted only
+/// horizontally. The left camera must be to the left of the right one.
+pub fn rectify_models(
+    left: &CameraModel,
+    right: &CameraModel,
+    width: usize,
+    height: usize,
+) -> error::Result<(Cahv, Cahv)> {
+    let linear_left = left.linearize(width, height, width, height)?;
+    let linear_right = right.linearize(width, height, width, height)?;
+
+    let baseline = right.c().subtract(&left.c());
+    if baseline.len() == 0.0 {
+        return Err(constants::status::INVALID_STEREO_PAIR);
+    }
+    let h_axis = baseline.normalized();
+    let (_, hs_left) = vector_parts(&linear_left.h, &linear_left.a);
+    let left_h_axis = linear_left
+        .h
+        .subtract(
+            &linear_left
+                .a
+                .scale(linear_left.h.dot_product(&linear_left.a)),
+        )
+        .scale(1.0 / hs_left);
+    if h_axis.dot_product(&left_h_axis) <= 0.0 {
+        return Err(constants::status::INVALID_STEREO_PAIR);
+    }
+
+    let mean_a = linear_left.a.add(&linear_right.a).normalized();
+    let a = mean_a
+        .subtract(&h_axis.scale(mean_a.dot_product(&h_axis)))
+        .normalized();
+    let v_axis = a.cross_product(&h_axis);
+
+    let (hc_l, hs_l) = vector_parts(&linear_left.h, &linear_left.a);
+    let (hc_r, hs_r) = vector_parts(&linear_right.h, &linear_right.a);
+    let (vc_l, vs_l) = vector_parts(&linear_left.v, &linear_left.a);
+    let (vc_r, vs_r) = vector_parts(&linear_right.v, &linear_right.a);
+    let (hc, hs) = ((hc_l + hc_r) / 2.0, (hs_l + hs_r) / 2.0);
+    let (vc, vs) = ((vc_l + vc_r) / 2.0, (vs_l + vs_r) / 2.0);
+
+    let h = a.scale(hc).add(&h_axis.scale(hs));
+    let v = a.scale(vc).add(&v_axis.scale(vs));
+
+    Ok((
+        Cahv {
+            c: left.c(),
+            a,
+            h,
+            v,
+        },
+        Cahv {
+            c: right.c(),
+            a,
+            h,
+            v,
+        },
+    ))
+}
+
+/// Bilinear sample of an image band, `None` outside the image or where masked
+fn sample(buffer: &ImageBuffer, x: f64, y: f64) -> Option<f32> {
+    if x < 0.0 || y < 0.0 || x > (buffer.width - 1) as f64 || y > (buffer.height - 1) as f64 {
+        return None;
+    }
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = (
+        (x0 + 1).min(buffer.width - 1),
+        (y0 + 1).min(buffer.height - 1),
+    );
+    if !buffer.get_mask_at_point(x0, y0) {
+        return None;
+    }
+    let (fx, fy) = ((x - x0 as f64) as f32, (y - y0 as f64) as f32);
+    let top = buffer.get(x0, y0).unwrap() * (1.0 - fx) + buffer.get(x1, y0).unwrap() * fx;
+    let bottom = buffer.get(x0, y1).unwrap() * (1.0 - fx) + buffer.get(x1, y1).unwrap() * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+/// Resamples an image into a linear model sharing its camera center. Pixels with nothing to
+/// sample are masked.
+pub fn rectify_image(img: &RgbImage, model: &CameraModel, rectified: &Cahv) -> RgbImage {
+    let mut bands: Vec<ImageBuffer> = (0..img.num_bands())
+        .map(|_| ImageBuffer::new_with_mask_as(img.width, img.height, false).unwrap())
+        .collect();
+    for y in 0..img.height {
+        for x in 0..img.width {
+            let direction = match rectified.ls_to_look_vector(&ImageCoordinate {
+                line: y as f64,
+                sample: x as f64,
+            }) {
+                Ok(lv) => lv.look_direction,
+                Err(_) => continue,
+            };
+            if direction.dot_product(&model.a()) <= 0.0 {
+                continue;
+            }
+            let ls = model.xyz_to_ls(&direction, true);
+            let values: Option<Vec<f32>> = (0..img.num_bands())
+                .map(|b| sample(img.get_band(b), ls.sample, ls.line))
+                .collect();
+            if let Some(values) = values {
+                for (band, v) in bands.iter_mut().zip(values) {
+                    band.put_mask(x, y, true);
+                    band.put(x, y, v);
+                }
+            }
+        }
+    }
+
+    let mut out = RgbImage::new_with_bands_masked(
+        img.width,
+        img.height,
+        img.num_bands(),
+        img.get_mode(),
+        false,
+    )
+    .unwrap();
+    for (b, band) in bands.iter().enumerate() {
+        out.set_band(band, b);
+    }
+    for y in 0..img.height {
+        for x in 0..img.width {
+            out.put_alpha(x, y, bands[0].get_mask_at_point(x, y));
+        }
+    }
+    out
+}
+
+/// Camera geometry of a stereo pair, as recorded in an MPO. `None` where it isn't known.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StereoGeometry {
+    /// Distance between the camera centers, in meters
+    pub baseline: Option<f64>,
+    /// Angle between the camera axes in degrees, positive where they converge
+    pub convergence: Option<f64>,
+}
+
+impl StereoGeometry {
+    pub fn from_models(left: &CameraModel, right: &CameraModel) -> StereoGeometry {
+        let baseline = right.c().subtract(&left.c());
+        let (left_a, right_a) = (left.a().normalized(), right.a().normalized());
+        let angle = left_a
+            .dot_product(&right_a)
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        let converging = left_a.subtract(&right_a).dot_product(&baseline) > 0.0;
+        StereoGeometry {
+            baseline: Some(baseline.len()),
+            convergence: Some(if converging { angle } else { -angle }),
+        }
+    }
+}
+
+/// A stereo pair resampled into the epipolar-aligned models from `rectify_models`
+pub struct RectifiedPair {
+    pub left: RgbImage,
+    pub right: RgbImage,
+    pub left_model: Cahv,
+    pub right_model: Cahv,
+}
+
+/// Rectifies a stereo pair using the camera models in their metadata
+pub fn rectify_pair(left: &MarsImage, right: &MarsImage) -> error::Result<RectifiedPair> {
+    if left.image.width != right.image.width || left.image.height != right.image.height {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+    let left_model = left.camera_model()?;
+    let right_model = right.camera_model()?;
+    let (left_rect, right_rect) = rectify_models(
+        &left_model,
+        &right_model,
+        left.image.width,
+        left.image.height,
+    )?;
+
+    vprintln!("Rectifying images");
+    Ok(RectifiedPair {
+        left: rectify_image(&left.image, &left_model, &left_rect),
+        right: rectify_image(&right.image, &right_model, &right_rect),
+        left_model: left_rect,
+        right_model: right_rect,
+    })
+}
+
+/// Opens a stereo pair for viewing, rectified where both images have camera models that allow
+/// it and as they are otherwise, with the geometry of the cameras the pair is seen from
+pub fn load_rectified_pair(
+    left_image_path: &str,
+    right_image_path: &str,
+) -> error::Result<(RgbImage, RgbImage, StereoGeometry)> {
+    vprintln!("Left image: {}", left_image_path);
+    let left_img = MarsImage::open(String::from(left_image_path), Instrument::M20MastcamZLeft);
+
+    vprintln!("Right image: {}", right_image_path);
+    let right_img = MarsImage::open(String::from(right_image_path), Instrument::M20MastcamZRight);
+
+    if left_img.image.width != right_img.image.width
+        || left_img.image.height != right_img.image.height
+    {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+
+    match rectify_pair(&left_img, &right_img) {
+        Ok(pair) => {
+            let geometry = StereoGeometry::from_models(
+                &CameraModel::new(Box::new(pair.left_model)),
+                &CameraModel::new(Box::new(pair.right_model)),
+            );
+            Ok((pair.left, pair.right, geometry))
+        }
+        Err(why) => {
+            vprintln!(
+                "Unable to rectify the pair ({}). Doing simple assembly",
+                why
+            );
+            let geometry = match (left_img.camera_model(), right_img.camera_model()) {
+                (Ok(left), Ok(right)) => StereoGeometry::from_models(&left, &right),
+                _ => StereoGeometry::default(),
+            };
+            Ok((left_img.image, right_img.image, geometry))
+        }
+    }
+}
+
+/// Radius of the census transform window
+const CENSUS_RADIUS: i32 = 2;
+
+/// Matching cost of pixels outside the other image, the most a census comparison can cost
+const MAX_COST: u8 = 24;
+
+/// Census transform over a 5x5 window, one bit per neighbor set where it's darker than the
+/// center. Pixels near the edge are left zero.
+fn census(buffer: &ImageBuffer) -> Vec<u32> {
+    let (w, h) = (buffer.width as i32, buffer.height as i32);
+    (0..h)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (0..w).map(move |x| {
+                if x < CENSUS_RADIUS
+                    || y < CENSUS_RADIUS
+                    || x >= w - CENSUS_RADIUS
+                    || y >= h - CENSUS_RADIUS
+                {
+                    return 0;
+                }
+                let center = buffer.get(x as usize, y as usize).unwrap();
+                let mut bits = 0u32;
+                for dy in -CENSUS_RADIUS..=CENSUS_RADIUS {
+                    for dx in -CENSUS_RADIUS..=CENSUS_RADIUS {
+                        if dx == 0 && dy == 0 {
+                            continue;
+                        }
+                        let v = buffer.get((x + dx) as usize, (y + dy) as usize).unwrap();
+                        bits = (bits << 1) | (v < center) as u32;
+                    }
+                }
+                bits
+            })
+        })
+        .collect()
+}
+
+/// Aggregated cost along one path at one pixel, from the costs at the pixel and the aggregated
+/// costs at the previous pixel of the path
+fn path_cost(cost: &[u8], prev: Option<&[u32]>, p1: u32, p2: u32, out: &mut [u32]) {
+    let prev = match prev {
+        Some(p) => p,
+        None => {
+            for (o, c) in out.iter_mut().zip(cost.iter()) {
+                *o = *c as u32;
+            }
+            return;
+        }
+    };
+    let min_prev = *prev.iter().min().unwrap();
+    let n = cost.len();
+    for d in 0..n {
+        let mut best = prev[d].min(min_prev + p2);
+        if d > 0 {
+            best = best.min(prev[d - 1] + p1);
+        }
+        if d + 1 < n {
+            best = best.min(prev[d + 1] + p1);
+        }
+        out[d] = cost[d] as u32 + best - min_prev;
+    }
+}
+
+fn add_to(sum: &mut [u32], values: &[u32]) {
+    for (s, v) in sum.iter_mut().zip(values.iter()) {
+        *s += *v;
+    }
+}
+
+/// Sums the costs aggregated along the vertical and diagonal paths entering each row from the
+/// previous one, going down the image or up it
+fn aggregate_vertical(
+    cost: &[u8],
+    sum: &mut [u32],
+    width: usize,
+    height: usize,
+    nd: usize,
+    options: &StereoOptions,
+    downwards: bool,
+) {
+    let row_len = width * nd;
+    let mut prev: Vec<Option<Vec<u32>>> = vec![None, None, None];
+    let rows: Vec<usize> = if downwards {
+        (0..height).collect()
+    } else {
+        (0..height).rev().collect()
+    };
+
+    for y in rows {
+        let row_cost = &cost[y * row_len..(y + 1) * row_len];
+        let mut current = vec![];
+        // Paths from straight above (or below), and from either side
+        for (k, dx) in [0i32, -1, 1].iter().enumerate() {
+            let mut cur = vec![0u32; row_len];
+            cur.par_chunks_mut(nd).enumerate().for_each(|(x, out)| {
+                let px = x as i32 + dx;
+                let p = match &prev[k] {
+                    Some(p) if px >= 0 && (px as usize) < width => {
+                        Some(&p[px as usize * nd..(px as usize + 1) * nd])
+                    }
+                    _ => None,
+                };
+                path_cost(
+                    &row_cost[x * nd..(x + 1) * nd],
+                    p,
+                    options.p1 as u32,
+                    options.p2 as u32,
+                    out,
+                );
+            });
+            current.push(cur);
+        }
+        let row_sum = &mut sum[y * row_len..(y + 1) * row_len];
+        for cur in current.iter() {
+            add_to(row_sum, cur);
+        }
+        prev = current.into_iter().map(Some).collect();
+    }
+}
+
+/// Rows of disparities computed at a time. Costs are only held for one band and its margins,
+/// rather than for every pixel and disparity of the image.
+const BAND_ROWS: usize = 64;
+
+/// Rows above and below a band that the vertical and diagonal paths are aggregated over before
+/// they enter it
+const BAND_MARGIN: usize = 32;
+
+/// Census matching costs of the rows `y0..y1`, for each pixel and disparity
+fn matching_costs(
+    left: &ImageBuffer,
+    right: &ImageBuffer,
+    census_left: &[u32],
+    census_right: &[u32],
+    y0: usize,
+    y1: usize,
+    nd: usize,
+) -> Vec<u8> {
+    let width = left.width;
+    (y0 * width..y1 * width)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let (x, y) = (i % width, i / width);
+            let cl = census_left[i];
+            let masked = !left.get_mask_at_point(x, y);
+            (0..nd).map(move |d| {
+                if masked || d > x || !right.get_mask_at_point(x - d, y) {
+                    MAX_COST
+                } else {
+                    (cl ^ census_right[y * width + x - d]).count_ones() as u8
+                }
+            })
+        })
+        .collect()
+}
+
+/// Sums the costs aggregated along all eight paths, over `height` rows of costs. A path's cost
+/// can reach `MAX_COST + p2`, so the sums are kept in 32 bits.
+fn aggregate_costs(
+    cost: &[u8],
+    width: usize,
+    height: usize,
+    nd: usize,
+    options: &StereoOptions,
+) -> Vec<u32> {
+    let mut sum = vec![0u32; width * height * nd];
+    sum.par_chunks_mut(width * nd)
+        .enumerate()
+        .for_each(|(y, row_sum)| {
+            let row_cost = &cost[y * width * nd..(y + 1) * width * nd];
+            let mut prev = vec![0u32; nd];
+            let mut cur = vec![0u32; nd];
+            for reverse in [false, true] {
+                for i in 0..width {
+                    let x = if reverse { width - 1 - i } else { i };
+                    path_cost(
+                        &row_cost[x * nd..(x + 1) * nd],
+                        if i == 0 { None } else { Some(&prev) },
+                        options.p1 as u32,
+                        options.p2 as u32,
+                        &mut cur,
+                    );
+                    add_to(&mut row_sum[x * nd..(x + 1) * nd], &cur);
+                    std::mem::swap(&mut prev, &mut cur);
+                }
+            }
+        });
+    aggregate_vertical(cost, &mut sum, width, height, nd, options, true);
+    aggregate_vertical(cost, &mut sum, width, height, nd, options, false);
+    sum
+}
+
+/// Disparities of the rows `y0..y1` of the left image from the aggregated costs of the rows
+/// from `sum_y0` on, `None` where no reliable match was found
+fn select_disparities(
+    left: &ImageBuffer,
+    sum: &[u32],
+    sum_y0: usize,
+    y0: usize,
+    y1: usize,
+    nd: usize,
+    options: &StereoOptions,
+) -> Vec<Option<f32>> {
+    let width = left.width;
+    let costs_at = |x: usize, y: usize| {
+        let i = (y - sum_y0) * width + x;
+        &sum[i * nd..(i + 1) * nd]
+    };
+    let best = |costs: &[u32]| -> (usize, u32) {
+        costs
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, c)| **c)
+            .map(|(d, c)| (d, *c))
+            .unwrap()
+    };
+
+    // Disparity of each right image pixel, for the consistency check
+    let right_disparity: Vec<usize> = (y0 * width..y1 * width)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            (0..nd)
+                .filter(|d| x + d < width)
+                .min_by_key(|d| costs_at(x + d, y)[*d])
+                .unwrap_or(0)
+        })
+        .collect();
+
+    (y0 * width..y1 * width)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            if !left.get_mask_at_point(x, y) {
+                return None;
+            }
+            let costs = costs_at(x, y);
+            let (d, c) = best(costs);
+            if d > x {
+                return None;
+            }
+
+            let second = costs
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| (*k as i64 - d as i64).abs() > 1)
+                .map(|(_, c)| *c)
+                .min()
+                .unwrap_or(u32::MAX);
+            if c as f32 * (1.0 + options.uniqueness) >= second as f32 {
+                return None;
+            }
+
+            let offset = if d > 0 && d + 1 < nd {
+                let (l, r) = (costs[d - 1] as f32, costs[d + 1] as f32);
+                let denom = l - 2.0 * c as f32 + r;
+                if denom > 0.0 {
+                    (l - r) / (2.0 * denom)
+                } else {
+                    0.0
+                }
+            } else {
+                0.0
+            };
+
+            let rd = right_disparity[i - y0 * width - d];
+            if (rd as f32 - d as f32).abs() > options.max_lr_difference {
+                return None;
+            }
+            Some(d as f32 + offset)
+        })
+        .collect()
+}
+
+/// Semi-global matching of a rectified pair over eight paths. Returns the disparity of each
+/// left image pixel, masked where no reliable match was found. A pixel at (x, y) in the left
+/// image is matched with (x - disparity, y) in the right.
+///
+/// The image is matched in bands of `BAND_ROWS` rows, each aggregated with a margin of
+/// `BAND_MARGIN` rows above and below it, so memory use doesn't grow with the image height.
+pub fn disparity(left: &ImageBuffer, right: &ImageBuffer, options: &StereoOptions) -> ImageBuffer {
+    let (width, height) = (left.width, left.height);
+    let nd = options.num_disparities.max(1);
+    let census_left = census(left);
+    let census_right = census(right);
+
+    let mut out = ImageBuffer::new_with_mask_as(width, height, false).unwrap();
+    for y0 in (0..height).step_by(BAND_ROWS) {
+        let y1 = (y0 + BAND_ROWS).min(height);
+        let (top, bottom) = (
+            y0.saturating_sub(BAND_MARGIN),
+            (y1 + BAND_MARGIN).min(height),
+        );
+        vprintln!("Matching rows {} to {} over {} disparities", y0, y1 - 1, nd);
+
+        let cost = matching_costs(left, right, &census_left, &census_right, top, bottom, nd);
+        let sum = aggregate_costs(&cost, width, bottom - top, nd, options);
+        let values = select_disparities(left, &sum, top, y0, y1, nd, options);
+
+        for (i, v) in values.into_iter().enumerate() {
+            if let Some(v) = v {
+                let (x, y) = (i % width, y0 + i / width);
+                out.put_mask(x, y, true);
+                out.put(x, y, v);
+            }
+        }
+    }
+    out
+}
+
+/// Point nearest both rays, `None` if they're parallel or meet behind either camera
+pub fn triangulate(left: &LookVector, right: &LookVector) -> Option<Vector> {
+    let (u, v) = (&left.look_direction, &right.look_direction);
+    let w0 = left.origin.subtract(&right.origin);
+    let (a, b, c) = (u.dot_product(u), u.dot_product(v), v.dot_product(v));
+    let (d, e) = (u.dot_product(&w0), v.dot_product(&w0));
+    let denom = a * c - b * b;
+    if denom.abs() < 1e-12 {
+        return None;
+    }
+    let t_left = (b * e - c * d) / denom;
+    let t_right = (a * e - b * d) / denom;
+    if t_left <= 0.0 || t_right <= 0.0 {
+        return None;
+    }
+    let p = left.origin.add(&u.scale(t_left));
+    let q = right.origin.add(&v.scale(t_right));
+    Some(p.add(&q).scale(0.5))
+}
+
+/// Range and XYZ of each pixel of the left image of a rectified pair, in the frame of the
+/// camera models
+pub struct RangeData {
+    pub range: ImageBuffer,
+    pub x: ImageBuffer,
+    pub y: ImageBuffer,
+    pub z: ImageBuffer,
+}
+
+/// Triangulates every pixel with a disparity
+pub fn range_from_disparity(disparity: &ImageBuffer, left: &Cahv, right: &Cahv) -> RangeData {
+    let (width, height) = (disparity.width, disparity.height);
+    let points: Vec<Option<Vector>> = (0..width * height)
+        .into_par_iter()
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            if !disparity.get_mask_at_point(x, y) {
+                return None;
+            }
+            let d = disparity.get(x, y).unwrap() as f64;
+            let lv = left
+                .ls_to_look_vector(&ImageCoordinate {
+                    line: y as f64,
+                    sample: x as f64,
+                })
+                .ok()?;
+            let rv = right
+                .ls_to_look_vector(&ImageCoordinate {
+                    line: y as f64,
+                    sample: x as f64 - d,
+                })
+                .ok()?;
+            triangulate(&lv, &rv)
+        })
+        .collect();
+
+    let new = || ImageBuffer::new_with_mask_as(width, height, false).unwrap();
+    let mut data = RangeData {
+        range: new(),
+        x: new(),
+        y: new(),
+        z: new(),
+    };
+    for (i, p) in points.into_iter().enumerate() {
+        if let Some(p) = p {
+            let (x, y) = (i % width, i / width);
+            let values = [
+                (&mut data.range, p.subtract(&left.c).len()),
+                (&mut data.x, p.x),
+                (&mut data.y, p.y),
+                (&mut data.z, p.z),
+            ];
+            for (buffer, v) in values {
+                buffer.put_mask(x, y, true);
+                buffer.put(x, y, v as f32);
+            }
+        }
+    }
+    data
+}
+
+/// Writes a disparity map as a 16 bit PNG of the disparity times 256, zero where there is none
+pub fn save_disparity_png(disparity: &ImageBuffer, to_file: &str) -> error::Result<()> {
+    let mut out = image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::new(
+        disparity.width as u32,
+        disparity.height as u32,
+    );
+    for y in 0..disparity.height {
+        for x in 0..disparity.width {
+            if disparity.get_mask_at_point(x, y) {
+                let v = (disparity.get(x, y).unwrap() * 256.0).round();
+                out.put_pixel(
+                    x as u32,
+                    y as u32,
+                    image::Luma([v.clamp(1.0, 65535.0) as u16]),
+                );
+            }
+        }
+    }
+    if !path::parent_exists_and_writable(to_file) {
+        return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+    }
+    vprintln!("Writing disparity map to {}", to_file);
+    out.save(to_file)
+        .map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)
+}
+
+impl RangeData {
+    /// XYZ of a pixel, `None` where it has no range
+    pub fn point(&self, x: usize, y: usize) -> Option<Vector> {
+        if self.range.get_mask_at_point(x, y) {
+            Some(Vector::new(
+                self.x.get(x, y).unwrap() as f64,
+                self.y.get(x, y).unwrap() as f64,
+                self.z.get(x, y).unwrap() as f64,
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Number of pixels with a range
+    pub fn num_points(&self) -> usize {
+        let (width, height) = (self.range.width, self.range.height);
+        (0..width * height)
+            .filter(|i| self.range.get_mask_at_point(i % width, i / width))
+            .count()
+    }
+
+    /// Writes the range map as a single band ENVI image at `<base>-range` and the XYZ image as
+    /// a three band one at `<base>-xyz`. Pixels without a range are zero.
+    pub fn save(&self, base_path: &str) -> error::Result<()> {
+        let zeroed = |b: &ImageBuffer| {
+            let mut out = ImageBuffer::new(b.width, b.height).unwrap();
+            for y in 0..b.height {
+                for x in 0..b.width {
+                    if b.get_mask_at_point(x, y) {
+                        out.put(x, y, b.get(x, y).unwrap());
+                    }
+                }
+            }
+            out
+        };
+        let range = zeroed(&self.range);
+        spectral::save_envi_bands(
+            &format!("{}-range", base_path),
+            &[("Range", &range)],
+            "Mars Raw Utils stereo range",
+            "",
+        )?;
+        let (x, y, z) = (zeroed(&self.x), zeroed(&self.y), zeroed(&self.z));
+        spectral::save_envi_bands(
+            &format!("{}-xyz", base_path),
+            &[("X", &x), ("Y", &y), ("Z", &z)],
+            "Mars Raw Utils stereo XYZ",
+            "",
+        )
+    }
+}
+
+/// What's computed from a stereo pair: the range data over the rectified left image, which
+/// carries the rectified left camera model in its metadata
+pub struct StereoProducts {
+    pub range: RangeData,
+    pub rectified: MarsImage,
+    pub model: Cahv,
+}
+
+/// Computes disparity and range for a stereo pair, writing `<base>-disparity.png`, the range
+/// and XYZ images, and the left image rectified as `<base>-rectified.png` with its rectified
+/// camera model in the metadata sidecar.
+pub fn process_files(
+    left_file: &str,
+    right_file: &str,
+    base_path: &str,
+    options: &StereoOptions,
+) -> error::Result<StereoProducts> {
+    let mut left_img = MarsImage::open(String::from(left_file), Instrument::None);
+    let right_img = MarsImage::open(String::from(right_file), Instrument::None);
+    let pair = rectify_pair(&left_img, &right_img)?;
+
+    let disparity = disparity(
+        &features::luminance(&pair.left),
+        &features::luminance(&pair.right),
+        options,
+    );
+    save_disparity_png(&disparity, &format!("{}-disparity.png", base_path))?;
+
+    vprintln!("Triangulating");
+    let range = range_from_disparity(&disparity, &pair.left_model, &pair.right_model);
+    range.save(base_path)?;
+
+    left_img.image = pair.left;
+    if let Some(md) = &mut left_img.metadata {
+        md.camera_model_component_list = CameraModel::new(Box::new(pair.left_model.clone()));
+    }
+    left_img.save(&format!("{}-rectified.png", base_path));
+
+    Ok(StereoProducts {
+        range,
+        rectified: left_img,
+        model: pair.left_model,
+    })
+}
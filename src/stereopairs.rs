@@ -0,0 +1,115 @@
+use crate::{constants, enums::Eye, path, productid, vprintln};
+
+use sciimg::error;
+
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Left and right eye images of the same scene
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StereoPair {
+    pub left: String,
+    pub right: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PairMatches {
+    pub pairs: Vec<StereoPair>,
+
+    /// Left or right eye images without a matching image from the other eye
+    pub unpaired: Vec<String>,
+}
+
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "tif", "tiff"];
+
+/// Image files in a directory, sorted by name
+pub fn image_files_in(dir: &str) -> error::Result<Vec<String>> {
+    if !path::is_dir(dir) {
+        return Err(constants::status::FILE_NOT_FOUND);
+    }
+    let mut files: Vec<String> = fs::read_dir(dir)
+        .map_err(|_| constants::status::FILE_NOT_FOUND)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.is_file()
+                && p.extension()
+                    .map(|e| {
+                        IMAGE_EXTENSIONS.contains(&e.to_string_lossy().to_lowercase().as_str())
+                    })
+                    .unwrap_or(false)
+        })
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// What two images must share to be a pair: everything in their product ids but the eye,
+/// the spacecraft clock's fractional seconds and the product's compression, producer and
+/// version
+fn pair_key(pid: &productid::ProductId) -> String {
+    let camera: String = pid
+        .camera
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .map(|(_, c)| c)
+        .collect();
+    format!(
+        "{:?}/{}/{:?}/{:?}/{}/{:?}/{:?}/{:?}/{}",
+        pid.mission,
+        camera,
+        pid.sol,
+        pid.sclk.map(|s| s.floor() as u64),
+        pid.product_type,
+        pid.sequence,
+        pid.filter,
+        pid.focal_length.map(|f| (f * 10.0).round() as u32),
+        pid.thumbnail
+    )
+}
+
+/// Pairs left and right eye images by their product ids. Images from the same camera, taken
+/// at the same spacecraft clock in the same sequence and, for zoom cameras, at the same focal
+/// length are paired, in file name order where there are several of each eye. Images whose
+/// ids don't carry the spacecraft clock, such as MSL Mastcam's, can't be matched reliably and
+/// are reported as unpaired. Files that aren't from a stereo camera are skipped.
+pub fn find_pairs(files: &[String]) -> PairMatches {
+    let mut matches = PairMatches::default();
+    let mut groups: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+    for f in files.iter() {
+        match productid::parse(f) {
+            Ok(pid) if pid.eye != Eye::DontCare && pid.sclk.is_none() => {
+                vprintln!("No spacecraft clock in the product id to pair by: {}", f);
+                matches.unpaired.push(f.to_owned());
+            }
+            Ok(pid) if pid.eye != Eye::DontCare => {
+                let group = groups.entry(pair_key(&pid)).or_default();
+                if pid.eye == Eye::Left {
+                    group.0.push(f.to_owned());
+                } else {
+                    group.1.push(f.to_owned());
+                }
+            }
+            _ => vprintln!("Not a stereo camera image, skipping: {}", f),
+        }
+    }
+
+    for (_, (mut lefts, mut rights)) in groups.into_iter() {
+        lefts.sort();
+        rights.sort();
+        let n = lefts.len().min(rights.len());
+        for (left, right) in lefts.iter().zip(rights.iter()) {
+            matches.pairs.push(StereoPair {
+                left: left.to_owned(),
+                right: right.to_owned(),
+            });
+        }
+        matches.unpaired.extend(lefts.into_iter().skip(n));
+        matches.unpaired.extend(rights.into_iter().skip(n));
+    }
+    matches.pairs.sort_by(|a, b| a.left.cmp(&b.left));
+    matches.unpaired.sort();
+    matches
+}
@@ -0,0 +1,360 @@
+use crate::{constants, diffgif, drawable::Drawable, path, stereo::StereoGeometry, vprintln};
+
+use image::codecs::jpeg::JpegEncoder;
+use sciimg::prelude::*;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+/// How a stereo pair is laid out for viewing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoRenderMode {
+    /// Animated GIF alternating between the eyes
+    WiggleGif,
+
+    /// Animated PNG alternating between the eyes
+    WiggleApng,
+
+    /// Left eye on the left, for parallel viewing
+    SideBySide,
+
+    /// Left eye on top
+    OverUnder,
+
+    /// Multi-picture JPEG for 3D displays
+    Mpo,
+}
+
+impl FromStr for StereoRenderMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<StereoRenderMode, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wiggle-gif" | "gif" => Ok(StereoRenderMode::WiggleGif),
+            "wiggle-apng" | "apng" => Ok(StereoRenderMode::WiggleApng),
+            "side-by-side" | "sbs" => Ok(StereoRenderMode::SideBySide),
+            "over-under" | "ou" => Ok(StereoRenderMode::OverUnder),
+            "mpo" => Ok(StereoRenderMode::Mpo),
+            _ => Err("Invalid stereo render mode"),
+        }
+    }
+}
+
+/// The image moved `dx` pixels to the right, with the uncovered edge left black
+pub fn shift_horizontal(img: &RgbImage, dx: i32) -> RgbImage {
+    let mut out = RgbImage::create(img.width, img.height);
+    for y in 0..img.height {
+        for x in 0..img.width {
+            let sx = x as i32 - dx;
+            if sx >= 0 && (sx as usize) < img.width {
+                for b in 0..3 {
+                    out.put(x, y, img.get_band(b).get(sx as usize, y).unwrap(), b);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Left and right eyes next to each other, for parallel viewing
+pub fn side_by_side(left: &RgbImage, right: &RgbImage) -> RgbImage {
+    let mut out = RgbImage::create(left.width * 2, left.height);
+    out.paste(left, 0, 0);
+    out.paste(right, left.width, 0);
+    out
+}
+
+/// The left eye above the right
+pub fn over_under(left: &RgbImage, right: &RgbImage) -> RgbImage {
+    let mut out = RgbImage::create(left.width, left.height * 2);
+    out.paste(left, 0, 0);
+    out.paste(right, 0, left.height);
+    out
+}
+
+/// Writes an animated GIF alternating between the frames, each shown for `delay` milliseconds.
+/// Frames are 8 bit, 0 - 255.
+pub fn save_wiggle_gif(frames: &[&RgbImage], delay: u16, to_file: &str) -> error::Result<()> {
+    if frames.is_empty() {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    if !path::parent_exists_and_writable(to_file) {
+        return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+    }
+    vprintln!("Writing wiggle GIF to {}", to_file);
+
+    let (width, height) = (frames[0].width as u16, frames[0].height as u16);
+    let mut file =
+        File::create(to_file).map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)?;
+    let mut encoder = gif::Encoder::new(&mut file, width, height, &[])
+        .map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)?;
+    encoder
+        .set_repeat(gif::Repeat::Infinite)
+        .map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)?;
+    for img in frames.iter() {
+        let pixels = diffgif::rgbimage_to_vec_v8(img);
+        let mut frame = gif::Frame::from_rgb(width, height, &pixels);
+        frame.delay = delay.div_ceil(10);
+        encoder
+            .write_frame(&frame)
+            .map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)?;
+    }
+    Ok(())
+}
+
+/// Writes an animated PNG alternating between the frames, each shown for `delay` milliseconds.
+/// Unlike GIF, colors aren't reduced to a palette.
+pub fn save_wiggle_apng(frames: &[&RgbImage], delay: u16, to_file: &str) -> error::Result<()> {
+    if frames.is_empty() {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+    if !path::parent_exists_and_writable(to_file) {
+        return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+    }
+    vprintln!("Writing wiggle APNG to {}", to_file);
+
+    let write = || -> Result<(), png::EncodingError> {
+        let file = File::create(to_file)?;
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            frames[0].width as u32,
+            frames[0].height as u32,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frames.len() as u32, 0)?;
+        encoder.set_frame_delay(delay, 1000)?;
+        let mut writer = encoder.write_header()?;
+        for img in frames.iter() {
+            writer.write_image_data(&diffgif::rgbimage_to_vec_v8(img))?;
+        }
+        writer.finish()
+    };
+    write().map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)
+}
+
+fn encode_jpeg(img: &RgbImage, quality: u8) -> error::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    JpegEncoder::new_with_quality(&mut bytes, quality)
+        .encode(
+            &diffgif::rgbimage_to_vec_v8(img),
+            img.width as u32,
+            img.height as u32,
+            image::ColorType::Rgb8,
+        )
+        .map_err(|_| "Error encoding JPEG")?;
+    Ok(bytes)
+}
+
+const MPF_VERSION: &[u8; 4] = b"0100";
+
+/// Individual image attribute of a multi-frame disparity image, and the flag marking the
+/// representative image
+const MP_TYPE_DISPARITY: u32 = 0x0002_0002;
+const MP_REPRESENTATIVE: u32 = 0x2000_0000;
+
+fn ifd_entry(out: &mut Vec<u8>, tag: u16, field_type: u16, count: u32, value: &[u8; 4]) {
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&field_type.to_le_bytes());
+    out.extend_from_slice(&count.to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Viewpoint the disparity images of a pair are relative to, the left eye
+const MP_BASE_VIEWPOINT: u32 = 1;
+
+/// Numerator and denominator written for a rational MP attribute that isn't known
+const MP_UNKNOWN: u32 = 0xffff_ffff;
+
+/// Denominator of the rational MP attributes written
+const MP_RATIONAL_SCALE: f64 = 10000.0;
+
+fn rational_bytes(value: Option<f64>, signed: bool) -> [u8; 8] {
+    let (numerator, denominator) = match value {
+        Some(v) if signed => (
+            ((v * MP_RATIONAL_SCALE).round() as i32) as u32,
+            MP_RATIONAL_SCALE as u32,
+        ),
+        Some(v) => (
+            (v * MP_RATIONAL_SCALE).round().max(0.0) as u32,
+            MP_RATIONAL_SCALE as u32,
+        ),
+        None => (MP_UNKNOWN, MP_UNKNOWN),
+    };
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&numerator.to_le_bytes());
+    bytes[4..].copy_from_slice(&denominator.to_le_bytes());
+    bytes
+}
+
+/// An APP2 segment carrying a multi-picture format header: the little endian TIFF style header,
+/// then the MP index IFD (first image only) and an MP attribute IFD with the disparity image
+/// attributes. Offsets are from the start of the TIFF header.
+fn mpf_segment(
+    image_number: u32,
+    entries: Option<&[(u32, u32, u32)]>,
+    geometry: &StereoGeometry,
+) -> Vec<u8> {
+    const UNDEFINED: u16 = 7;
+    const LONG: u16 = 4;
+    const RATIONAL: u16 = 5;
+    const SRATIONAL: u16 = 10;
+
+    let mut tiff: Vec<u8> = vec![0x49, 0x49, 0x2a, 0x00];
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+
+    if let Some(entries) = entries {
+        // Three entries, then the offsets of the attribute IFD and the MP entries
+        let index_len = 2 + 3 * 12 + 4;
+        let mp_entry_offset = 8 + index_len as u32;
+        let attribute_offset = mp_entry_offset + 16 * entries.len() as u32;
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        ifd_entry(&mut tiff, 0xb000, UNDEFINED, 4, MPF_VERSION);
+        ifd_entry(
+            &mut tiff,
+            0xb001,
+            LONG,
+            1,
+            &(entries.len() as u32).to_le_bytes(),
+        );
+        ifd_entry(
+            &mut tiff,
+            0xb002,
+            UNDEFINED,
+            16 * entries.len() as u32,
+            &mp_entry_offset.to_le_bytes(),
+        );
+        tiff.extend_from_slice(&attribute_offset.to_le_bytes());
+        for (attribute, size, offset) in entries.iter() {
+            tiff.extend_from_slice(&attribute.to_le_bytes());
+            tiff.extend_from_slice(&size.to_le_bytes());
+            tiff.extend_from_slice(&offset.to_le_bytes());
+            tiff.extend_from_slice(&[0; 4]);
+        }
+    }
+
+    // Five entries, then the convergence angle and baseline length they point to
+    let values_offset = (tiff.len() + 2 + 5 * 12 + 4) as u32;
+    tiff.extend_from_slice(&5u16.to_le_bytes());
+    ifd_entry(&mut tiff, 0xb000, UNDEFINED, 4, MPF_VERSION);
+    ifd_entry(&mut tiff, 0xb101, LONG, 1, &image_number.to_le_bytes());
+    ifd_entry(&mut tiff, 0xb204, LONG, 1, &MP_BASE_VIEWPOINT.to_le_bytes());
+    ifd_entry(
+        &mut tiff,
+        0xb205,
+        SRATIONAL,
+        1,
+        &values_offset.to_le_bytes(),
+    );
+    ifd_entry(
+        &mut tiff,
+        0xb206,
+        RATIONAL,
+        1,
+        &(values_offset + 8).to_le_bytes(),
+    );
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff.extend_from_slice(&rational_bytes(geometry.convergence, true));
+    tiff.extend_from_slice(&rational_bytes(geometry.baseline, false));
+
+    let mut segment = vec![0xff, 0xe2];
+    segment.extend_from_slice(&((2 + 4 + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(b"MPF\0");
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+/// Where a segment can be inserted into a JPEG: after the start of image marker and the JFIF
+/// header if there is one
+fn insert_position(jpeg: &[u8]) -> usize {
+    if jpeg.len() > 6 && jpeg[2] == 0xff && jpeg[3] == 0xe0 {
+        4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize
+    } else {
+        2
+    }
+}
+
+fn with_segment(jpeg: &[u8], segment: &[u8]) -> Vec<u8> {
+    let at = insert_position(jpeg);
+    [&jpeg[..at], segment, &jpeg[at..]].concat()
+}
+
+/// Writes a stereo pair as an MPO, the multi-picture JPEG read by 3D cameras, displays and
+/// viewers: the left eye's JPEG, flagged as the representative image, followed by the right's.
+/// Both carry the baseline and convergence of the cameras.
+pub fn save_mpo(
+    left: &RgbImage,
+    right: &RgbImage,
+    quality: u8,
+    geometry: &StereoGeometry,
+    to_file: &str,
+) -> error::Result<()> {
+    if !path::parent_exists_and_writable(to_file) {
+        return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+    }
+    vprintln!("Writing MPO to {}", to_file);
+
+    let left_jpeg = encode_jpeg(left, quality)?;
+    let right_jpeg = with_segment(
+        &encode_jpeg(right, quality)?,
+        &mpf_segment(2, None, geometry),
+    );
+
+    // The index's size doesn't depend on the values in it, so it's laid out once to find the
+    // sizes and offsets, which are relative to the start of the TIFF header
+    let placeholder = [(0, 0, 0), (0, 0, 0)];
+    let segment_len = mpf_segment(1, Some(&placeholder), geometry).len();
+    let left_len = left_jpeg.len() + segment_len;
+    let header_start = insert_position(&left_jpeg) + 8;
+    let entries = [
+        (MP_REPRESENTATIVE | MP_TYPE_DISPARITY, left_len as u32, 0),
+        (
+            MP_TYPE_DISPARITY,
+            right_jpeg.len() as u32,
+            (left_len - header_start) as u32,
+        ),
+    ];
+    let left_jpeg = with_segment(&left_jpeg, &mpf_segment(1, Some(&entries), geometry));
+
+    let write = || -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(to_file)?);
+        out.write_all(&left_jpeg)?;
+        out.write_all(&right_jpeg)?;
+        out.flush()
+    };
+    write().map_err(|_| constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE)
+}
+
+const MPO_QUALITY: u8 = 95;
+
+/// Writes a stereo pair, 8 bit, 0 - 255, in the given layout. Wiggle frames are each shown
+/// for `delay` milliseconds, and MPOs record the camera `geometry`.
+pub fn save(
+    left: &RgbImage,
+    right: &RgbImage,
+    mode: StereoRenderMode,
+    delay: u16,
+    geometry: &StereoGeometry,
+    to_file: &str,
+) -> error::Result<()> {
+    if left.width != right.width || left.height != right.height {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+    let save_png = |mut img: RgbImage| {
+        if !path::parent_exists_and_writable(to_file) {
+            return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+        }
+        vprintln!("Writing {}", to_file);
+        img.normalize_to_16bit_with_max(255.0);
+        img.save(to_file);
+        Ok(())
+    };
+    match mode {
+        StereoRenderMode::WiggleGif => save_wiggle_gif(&[left, right], delay, to_file),
+        StereoRenderMode::WiggleApng => save_wiggle_apng(&[left, right], delay, to_file),
+        StereoRenderMode::SideBySide => save_png(side_by_side(left, right)),
+        StereoRenderMode::OverUnder => save_png(over_under(left, right)),
+        StereoRenderMode::Mpo => save_mpo(left, right, MPO_QUALITY, geometry, to_file),
+    }
+}
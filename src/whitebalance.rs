@@ -0,0 +1,320 @@
+use crate::{
+    calprofile::CalProfile, constants, enums::Instrument, image::MarsImage, path, radiometry,
+    vprintln,
+};
+
+use sciimg::{error, imagebuffer::ImageBuffer};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// Automatic white balance algorithms
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WhiteBalanceMethod {
+    None,
+
+    /// Assumes the scene averages to a neutral gray
+    GrayWorld,
+
+    /// Assumes the brightest parts of the scene are white
+    WhitePatch,
+
+    /// Balances the sky, taken as the top rows of the frame, to a reference color
+    Sky,
+
+    /// Balances the neutral patches of the calibration target
+    CalTarget,
+}
+
+impl FromStr for WhiteBalanceMethod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<WhiteBalanceMethod, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "none" => Ok(WhiteBalanceMethod::None),
+            "grayworld" | "greyworld" => Ok(WhiteBalanceMethod::GrayWorld),
+            "whitepatch" => Ok(WhiteBalanceMethod::WhitePatch),
+            "sky" => Ok(WhiteBalanceMethod::Sky),
+            "caltarget" => Ok(WhiteBalanceMethod::CalTarget),
+            _ => Err(constants::status::INVALID_ENUM_VALUE),
+        }
+    }
+}
+
+/// Percentile of each band taken as white by the white patch method
+const WHITE_PATCH_PERCENTILE: f32 = 0.99;
+
+/// A 3x3 color matrix applied to linear RGB, typically saved once per sol and instrument and
+/// reused across an image set. White balance gains are stored on the diagonal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColorMatrix {
+    #[serde(default)]
+    pub sol: Option<u32>,
+
+    #[serde(default)]
+    pub instrument: Option<String>,
+
+    #[serde(default)]
+    pub method: Option<WhiteBalanceMethod>,
+
+    pub matrix: [[f32; 3]; 3],
+}
+
+impl ColorMatrix {
+    pub fn from_gains(gains: [f32; 3]) -> Self {
+        ColorMatrix {
+            sol: None,
+            instrument: None,
+            method: None,
+            matrix: [
+                [gains[0], 0.0, 0.0],
+                [0.0, gains[1], 0.0],
+                [0.0, 0.0, gains[2]],
+            ],
+        }
+    }
+
+    pub fn load(file_path: &str) -> error::Result<ColorMatrix> {
+        if !path::file_exists(file_path) {
+            return Err(constants::status::FILE_NOT_FOUND);
+        }
+
+        let mut file = match File::open(file_path) {
+            Err(why) => panic!("couldn't open {}", why),
+            Ok(file) => file,
+        };
+
+        let mut buf: Vec<u8> = Vec::default();
+        file.read_to_end(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+
+        match toml::from_str(&text) {
+            Ok(m) => Ok(m),
+            Err(why) => {
+                eprintln!("Error parsing color matrix file: {:?}", why);
+                Err("Error parsing color matrix file")
+            }
+        }
+    }
+
+    pub fn save(&self, file_path: &str) -> error::Result<()> {
+        if !path::parent_exists_and_writable(file_path) {
+            return Err(constants::status::PARENT_NOT_EXISTS_OR_UNWRITABLE);
+        }
+        let text = toml::to_string(self).map_err(|_| "Error serializing color matrix")?;
+        let mut file = File::create(file_path).map_err(|_| "Error creating color matrix file")?;
+        file.write_all(text.as_bytes())
+            .map_err(|_| "Error writing color matrix file")?;
+        Ok(())
+    }
+
+    /// Applies the matrix to the first three bands of an image
+    pub fn apply(&self, img: &mut MarsImage) -> error::Result<()> {
+        if img.image.num_bands() < 3 {
+            return Err(constants::status::ARRAY_SIZE_MISMATCH);
+        }
+
+        let bands: Vec<ImageBuffer> = (0..3).map(|b| img.image.get_band(b).clone()).collect();
+        for (c, row) in self.matrix.iter().enumerate() {
+            let mut out = bands[0].scale(row[0])?;
+            out = out.add(&bands[1].scale(row[1])?)?;
+            out = out.add(&bands[2].scale(row[2])?)?;
+            img.image.set_band(&out.clip(0.0, f32::MAX)?, c);
+        }
+        Ok(())
+    }
+}
+
+/// Expands `{sol}` and `{instrument}` in a color matrix path using the image metadata, allowing
+/// a profile to refer to a different matrix per sol.
+pub fn expand_color_matrix_path(file_path: &str, img: &MarsImage) -> String {
+    match &img.metadata {
+        Some(md) => file_path
+            .replace("{sol}", &format!("{}", md.sol))
+            .replace("{instrument}", &md.instrument),
+        None => file_path.to_string(),
+    }
+}
+
+fn normalize_to_green(values: [f32; 3]) -> error::Result<[f32; 3]> {
+    if values.iter().any(|v| *v <= 0.0) {
+        return Err("Cannot white balance an image without signal in every band");
+    }
+    Ok([values[1] / values[0], 1.0, values[1] / values[2]])
+}
+
+fn band_mean(buffer: &ImageBuffer, top_rows: usize) -> f32 {
+    let mut sum = 0.0;
+    let mut count = 0;
+    for y in 0..top_rows.min(buffer.height) {
+        for x in 0..buffer.width {
+            if buffer.get_mask_at_point(x, y) {
+                sum += buffer.get(x, y).unwrap();
+                count += 1;
+            }
+        }
+    }
+    if count > 0 {
+        sum / count as f32
+    } else {
+        0.0
+    }
+}
+
+fn band_percentile(buffer: &ImageBuffer, percentile: f32) -> f32 {
+    let mut values = buffer.to_vector();
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values[((values.len() - 1) as f32 * percentile).round() as usize]
+}
+
+pub fn gray_world_gains(img: &MarsImage) -> error::Result<[f32; 3]> {
+    let h = img.image.height;
+    normalize_to_green([
+        band_mean(img.image.get_band(0), h),
+        band_mean(img.image.get_band(1), h),
+        band_mean(img.image.get_band(2), h),
+    ])
+}
+
+pub fn white_patch_gains(img: &MarsImage) -> error::Result<[f32; 3]> {
+    normalize_to_green([
+        band_percentile(img.image.get_band(0), WHITE_PATCH_PERCENTILE),
+        band_percentile(img.image.get_band(1), WHITE_PATCH_PERCENTILE),
+        band_percentile(img.image.get_band(2), WHITE_PATCH_PERCENTILE),
+    ])
+}
+
+/// Gains that bring the mean color of the top `sky_fraction` of the frame to `sky_color`
+pub fn sky_gains(
+    img: &MarsImage,
+    sky_fraction: f32,
+    sky_color: [f32; 3],
+) -> error::Result<[f32; 3]> {
+    let rows = ((img.image.height as f32 * sky_fraction).round() as usize).max(1);
+    let sky = normalize_to_green([
+        band_mean(img.image.get_band(0), rows),
+        band_mean(img.image.get_band(1), rows),
+        band_mean(img.image.get_band(2), rows),
+    ])?;
+    let reference = normalize_to_green(sky_color)?;
+    Ok([sky[0] / reference[0], 1.0, sky[2] / reference[2]])
+}
+
+/// Gains derived from the calibration target patches visible in the image
+pub fn caltarget_gains(img: &MarsImage, target_file: &str) -> error::Result<[f32; 3]> {
+    let target = radiometry::load_caltarget(target_file)?;
+    let coefficients = radiometry::derive_coefficients(img, target_file, &target)?;
+    if coefficients.gains.len() < 3 {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+    let g = coefficients.gains[1];
+    if g <= 0.0 {
+        return Err("Cannot white balance an image without signal in every band");
+    }
+    Ok([coefficients.gains[0] / g, 1.0, coefficients.gains[2] / g])
+}
+
+/// Computes white balance gains for an image using the given method
+pub fn compute_gains(
+    img: &MarsImage,
+    method: WhiteBalanceMethod,
+    cal_context: &CalProfile,
+) -> error::Result<[f32; 3]> {
+    if img.image.num_bands() < 3 {
+        return Err(constants::status::ARRAY_SIZE_MISMATCH);
+    }
+
+    match method {
+        WhiteBalanceMethod::None => Ok([1.0, 1.0, 1.0]),
+        WhiteBalanceMethod::GrayWorld => gray_world_gains(img),
+        WhiteBalanceMethod::WhitePatch => white_patch_gains(img),
+        WhiteBalanceMethod::Sky => sky_gains(
+            img,
+            cal_context.white_balance_sky_fraction,
+            cal_context.white_balance_sky_color,
+        ),
+        WhiteBalanceMethod::CalTarget => match &cal_context.white_balance_target {
+            Some(target_file) => caltarget_gains(img, target_file),
+            None => Err("The caltarget white balance method requires a calibration target file"),
+        },
+    }
+}
+
+/// Applies the white balance selected in the calibration profile. A per-sol color matrix, when
+/// configured and present, takes precedence over computing gains from the image itself.
+pub fn apply_white_balance(img: &mut MarsImage, cal_context: &CalProfile) -> error::Result<()> {
+    if img.image.num_bands() < 3 {
+        return Ok(());
+    }
+
+    if let Some(matrix_path) = &cal_context.color_matrix_file {
+        let matrix_path = expand_color_matrix_path(matrix_path, img);
+        if path::file_exists(&matrix_path) {
+            vprintln!("Applying color matrix from {}", matrix_path);
+            return ColorMatrix::load(&matrix_path)?.apply(img);
+        }
+        vprintln!("Color matrix {} not found", matrix_path);
+    }
+
+    if cal_context.white_balance == WhiteBalanceMethod::None {
+        return Ok(());
+    }
+
+    vprintln!(
+        "Automatic white balance ({:?})...",
+        cal_context.white_balance
+    );
+    let gains = compute_gains(img, cal_context.white_balance, cal_context)?;
+    vprintln!("White balance gains: {:?}", gains);
+    img.apply_weight(gains[0], gains[1], gains[2]);
+    Ok(())
+}
+
+/// Builds a color matrix from the mean white balance gains of a set of images, e.g. the frames
+/// of a sol, recording the sol and instrument of the first image.
+pub fn color_matrix_for_images(
+    input_files: &[String],
+    method: WhiteBalanceMethod,
+    cal_context: &CalProfile,
+) -> error::Result<ColorMatrix> {
+    let mut sum = [0.0; 3];
+    let mut count = 0;
+    let mut sol: Option<u32> = None;
+    let mut instrument: Option<String> = None;
+
+    for in_file in input_files.iter() {
+        let img = MarsImage::open16(in_file.clone(), Instrument::None);
+        let gains = compute_gains(&img, method, cal_context)?;
+        vprintln!("{}: {:?}", path::basename(in_file), gains);
+        for b in 0..3 {
+            sum[b] += gains[b];
+        }
+        count += 1;
+
+        if let Some(md) = &img.metadata {
+            sol = sol.or(Some(md.sol));
+            instrument = instrument.or_else(|| Some(md.instrument.clone()));
+        }
+    }
+
+    if count == 0 {
+        return Err(constants::status::STRUCT_IS_EMPTY);
+    }
+
+    let mut matrix = ColorMatrix::from_gains([
+        sum[0] / count as f32,
+        sum[1] / count as f32,
+        sum[2] / count as f32,
+    ]);
+    matrix.sol = sol;
+    matrix.instrument = instrument;
+    matrix.method = Some(method);
+    Ok(matrix)
+}
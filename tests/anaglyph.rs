@@ -0,0 +1,114 @@
+mod common;
+
+use mars_raw_utils::{
+    anaglyph::{self, AnaglyphMode, AnaglyphOptions},
+    drawable::Drawable,
+};
+use sciimg::prelude::*;
+use std::str::FromStr;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 48;
+
+/// Random blocks of color, the right eye seeing them `disparity` pixels further left
+fn eye(disparity: usize) -> RgbImage {
+    let mut img = RgbImage::create_masked(WIDTH, HEIGHT, false);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH - disparity {
+            let h = common::block_hash(((x + disparity) / 3) as i64, (y / 3) as i64);
+            img.put_alpha(x, y, true);
+            for b in 0..3 {
+                img.put(x, y, ((h >> (16 + b * 8)) & 0xff) as f32, b);
+            }
+        }
+    }
+    img
+}
+
+fn pixel(img: &RgbImage) -> [f32; 3] {
+    [0, 1, 2].map(|b| img.get_band(b).get(1, 1).unwrap())
+}
+
+#[test]
+fn test_modes() {
+    assert_eq!(
+        AnaglyphMode::from_str("Dubois").unwrap(),
+        AnaglyphMode::DuboisRedCyan
+    );
+    assert_eq!(
+        AnaglyphMode::from_str("half_color").unwrap(),
+        AnaglyphMode::HalfColor
+    );
+    assert!(AnaglyphMode::from_str("purple").is_err());
+
+    let m = anaglyph::parse_matrix("1,0,0,0,0,0, 0,0,0,0,1,0, 0,0,0,0,0,1").unwrap();
+    assert_eq!(m, AnaglyphMode::Color.matrix());
+    assert!(anaglyph::parse_matrix("1,0,0").is_err());
+    assert!(anaglyph::parse_matrix("1,0,0,0,0,0,0,0,0,0,1,0,0,0,0,0,0,x").is_err());
+
+    // Red from the left, green and blue from the right
+    let left = common::solid(4, 4, [200.0, 100.0, 50.0]);
+    let right = common::solid(4, 4, [10.0, 20.0, 30.0]);
+    let color = anaglyph::combine(&left, &right, &AnaglyphOptions::default(), 255.0);
+    assert_eq!(pixel(&color), [200.0, 20.0, 30.0]);
+
+    // Flattened for saving, pixels neither eye sees are black rather than transparent
+    let mut partial = color.clone();
+    partial.put_alpha(0, 0, false);
+    let flat = anaglyph::flatten(&partial);
+    assert!(!flat.is_using_alpha());
+    assert_eq!(pixel(&flat), [200.0, 20.0, 30.0]);
+    assert_eq!(flat.get_band(0).get(0, 0).unwrap(), 0.0);
+
+    // White stays close to white through the Dubois projections
+    let white = common::solid(4, 4, [255.0, 255.0, 255.0]);
+    for mode in [
+        AnaglyphMode::DuboisRedCyan,
+        AnaglyphMode::DuboisGreenMagenta,
+        AnaglyphMode::DuboisAmberBlue,
+    ] {
+        let options = AnaglyphOptions {
+            mode,
+            ..Default::default()
+        };
+        let out = anaglyph::combine(&white, &white, &options, 255.0);
+        for v in pixel(&out) {
+            assert!(v > 250.0, "{:?} {}", mode, v);
+        }
+    }
+}
+
+#[test]
+fn test_convergence() {
+    let left = eye(0);
+    let right = eye(5);
+    assert_eq!(anaglyph::center_parallax(&left, &right), 5);
+
+    // Converged, the center of a gray anaglyph lines up
+    let options = AnaglyphOptions {
+        mode: AnaglyphMode::Custom([
+            [1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            [0.0; 6],
+        ]),
+        auto_converge: true,
+        ..Default::default()
+    };
+    let out = anaglyph::combine(&left, &right, &options, 255.0);
+    for x in 20..40 {
+        assert_eq!(
+            out.get_band(0).get(x, 24).unwrap(),
+            out.get_band(1).get(x, 24).unwrap()
+        );
+    }
+
+    // Moved the other way, pixels past the edge of the right eye only have the left
+    let options = AnaglyphOptions {
+        parallax: -3,
+        auto_converge: false,
+        ..options
+    };
+    let out = anaglyph::combine(&left, &right, &options, 255.0);
+    assert!(out.get_alpha_at(WIDTH - 2, 0));
+    assert_eq!(out.get_band(1).get(WIDTH - 2, 0).unwrap(), 0.0);
+}
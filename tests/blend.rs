@@ -0,0 +1,107 @@
+use mars_raw_utils::{
+    blend,
+    blend::{BlendMode, ExposureCompensation, Layer},
+    enums::Eye,
+};
+use std::str::FromStr;
+
+/// A layer covering its whole bounding box, filled with a horizontal gradient
+fn gradient_layer(x: usize, width: usize, height: usize, scale: f32) -> Layer {
+    let mut layer = Layer::new(x, 0, width, height, Eye::DontCare);
+    for ly in 0..height {
+        for lx in 0..width {
+            let v = (50.0 + (x + lx) as f32) * scale;
+            for b in 0..3 {
+                layer.image.put(lx, ly, v, b);
+            }
+            layer.image.put_alpha(lx, ly, true);
+        }
+    }
+    layer
+}
+
+#[test]
+fn test_parse_modes() {
+    assert_eq!(BlendMode::from_str("Feather").unwrap(), BlendMode::Feather);
+    assert_eq!(
+        BlendMode::from_str("multiband").unwrap(),
+        BlendMode::Multiband
+    );
+    assert!(BlendMode::from_str("smudge").is_err());
+    assert_eq!(
+        ExposureCompensation::from_str("gain-offset").unwrap(),
+        ExposureCompensation::GainOffset
+    );
+    assert!(ExposureCompensation::from_str("auto").is_err());
+}
+
+#[test]
+fn test_exposure_compensation() {
+    // The second image is 20% brighter than the first where they overlap
+    let mut layers = vec![
+        gradient_layer(0, 40, 16, 1.0),
+        gradient_layer(24, 40, 16, 1.2),
+    ];
+
+    let compensation = blend::estimate_exposure(&layers, ExposureCompensation::Gain);
+    let ratio = compensation[1].0 / compensation[0].0;
+    assert!((ratio - 1.0 / 1.2).abs() < 0.02, "Gain ratio {}", ratio);
+
+    blend::apply_exposure(&mut layers, &compensation);
+    let a = layers[0].image.get_band(0).get(30, 8).unwrap();
+    let b = layers[1].image.get_band(0).get(6, 8).unwrap();
+    assert!((a - b).abs() / a < 0.02, "{} vs {}", a, b);
+
+    let none = blend::estimate_exposure(&layers, ExposureCompensation::None);
+    assert_eq!(none, vec![(1.0, 0.0), (1.0, 0.0)]);
+}
+
+#[test]
+fn test_blend_consistent_layers() {
+    // Layers that agree should blend back to the same gradient, without seams
+    let layers = vec![
+        gradient_layer(0, 40, 16, 1.0),
+        gradient_layer(24, 40, 16, 1.0),
+    ];
+
+    for mode in [
+        BlendMode::Overwrite,
+        BlendMode::Feather,
+        BlendMode::Multiband,
+    ] {
+        let map = blend::blend(&layers, 72, 16, mode, 3);
+        for x in 0..64 {
+            assert!(map.get_alpha_at(x, 8));
+            let v = map.get_band(1).get(x, 8).unwrap();
+            assert!(
+                (v - (50.0 + x as f32)).abs() < 1.5,
+                "{:?} at {}: {}",
+                mode,
+                x,
+                v
+            );
+        }
+        assert!(!map.get_alpha_at(68, 8));
+    }
+}
+
+#[test]
+fn test_feather_transition() {
+    // Feathering fades between differing layers rather than stepping at the seam
+    let layers = vec![
+        gradient_layer(0, 40, 16, 1.0),
+        gradient_layer(24, 40, 16, 2.0),
+    ];
+    let map = blend::blend(&layers, 64, 16, BlendMode::Feather, 1);
+
+    let ratio = |x: usize| map.get_band(0).get(x, 8).unwrap() / (50.0 + x as f32);
+    assert!((ratio(10) - 1.0).abs() < 0.01);
+    assert!((ratio(50) - 2.0).abs() < 0.01);
+    let mut last = ratio(24);
+    for x in 25..40 {
+        let r = ratio(x);
+        assert!(r >= last - 0.01);
+        last = r;
+    }
+    assert!(ratio(32) > 1.2 && ratio(32) < 1.8);
+}
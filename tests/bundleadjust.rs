@@ -0,0 +1,211 @@
+mod common;
+
+use mars_raw_utils::{
+    bundleadjust::{self, AdjustOptions, TiePoint},
+    enums::Instrument,
+    features::{self, DetectorOptions, MatchOptions},
+    image::MarsImage,
+};
+use sciimg::{prelude::*, quaternion::Quaternion, vector::Vector};
+
+const WIDTH: usize = 240;
+const HEIGHT: usize = 200;
+
+/// A camera at the origin looking level at the given azimuth, with a 500 pixel focal length
+fn camera(azimuth: f64) -> CameraModel {
+    let az = azimuth.to_radians();
+    let a = Vector::new(az.cos(), az.sin(), 0.0);
+    let right = Vector::new(-az.sin(), az.cos(), 0.0);
+    let down = Vector::new(0.0, 0.0, 1.0);
+    CameraModel::new(Box::new(Cahv {
+        c: Vector::new(0.0, 0.0, 0.0),
+        a,
+        h: a.scale(WIDTH as f64 / 2.0).add(&right.scale(500.0)),
+        v: a.scale(HEIGHT as f64 / 2.0).add(&down.scale(500.0)),
+    }))
+}
+
+/// Gray level of the scene in a direction: blocks of random brightness, small enough to give
+/// plenty of corners
+fn scene(direction: &Vector) -> f32 {
+    let az = direction.y.atan2(direction.x);
+    let el = direction.z.atan2(direction.x.hypot(direction.y));
+    common::block_level((az / 0.02).floor() as i64, (el / 0.02).floor() as i64)
+}
+
+fn render(model: &CameraModel) -> ImageBuffer {
+    let mut buffer = ImageBuffer::new(WIDTH, HEIGHT).unwrap();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let lv = model
+                .ls_to_look_vector(&ImageCoordinate {
+                    line: y as f64,
+                    sample: x as f64,
+                })
+                .unwrap();
+            buffer.put(x, y, scene(&lv.look_direction));
+        }
+    }
+    buffer
+}
+
+#[test]
+fn test_match_shifted() {
+    let img = render(&camera(0.0));
+    let mut shifted = ImageBuffer::new(WIDTH, HEIGHT).unwrap();
+    for y in 0..HEIGHT - 4 {
+        for x in 0..WIDTH - 7 {
+            shifted.put(x + 7, y + 4, img.get(x, y).unwrap());
+        }
+    }
+
+    let a = features::detect_features(&img, &DetectorOptions::default());
+    let b = features::detect_features(&shifted, &DetectorOptions::default());
+    assert!(a.len() > 50, "{} features", a.len());
+
+    let matches = features::match_features(&a, &b, &MatchOptions::default());
+    assert!(matches.len() > 30, "{} matches", matches.len());
+    let correct = matches
+        .iter()
+        .filter(|(ia, ib)| {
+            let (ka, kb) = (a[*ia].keypoint, b[*ib].keypoint);
+            kb.x - ka.x == 7.0 && kb.y - ka.y == 4.0
+        })
+        .count();
+    assert!(
+        correct as f32 > matches.len() as f32 * 0.9,
+        "{} of {} correct",
+        correct,
+        matches.len()
+    );
+}
+
+#[test]
+fn test_rotate_model() {
+    let model = camera(0.0);
+    let q = Quaternion::from_axis_and_angle(&Vector::new(0.0, 0.0, 1.0), 1f64.to_radians());
+    let rotated = bundleadjust::rotate_model(&model, &q);
+    assert!(rotated.model_type() == ModelType::CAHV);
+    assert!(rotated.c().subtract(&model.c()).len() < 1e-12);
+
+    // Turning right moves the scene left in the image
+    let ahead = rotated.xyz_to_ls(&Vector::new(100.0, 0.0, 0.0), false);
+    assert!((ahead.sample - (WIDTH as f64 / 2.0 - 500.0 * 1f64.to_radians().tan())).abs() < 1e-6);
+}
+
+#[test]
+fn test_solve_rotations() {
+    let truth = [camera(0.0), camera(10.0)];
+    let error = Quaternion::from_pitch_roll_yaw(0.001, -0.002, 0.003);
+    let published = [
+        truth[0].clone(),
+        bundleadjust::rotate_model(&truth[1], &error),
+    ];
+
+    let mut ties = vec![];
+    for y in (10..HEIGHT).step_by(30) {
+        for x in (140..WIDTH).step_by(20) {
+            let a = (x as f64, y as f64);
+            let b = bundleadjust::predict(&truth[0], &truth[1], a).unwrap();
+            ties.push(TiePoint {
+                image_a: 0,
+                image_b: 1,
+                a,
+                b,
+            });
+        }
+    }
+
+    let rotations = bundleadjust::solve_rotations(&published, &ties, 0, 10);
+    let adjusted = bundleadjust::rotate_model(&published[1], &rotations[1]);
+    for t in ties.iter() {
+        let (x, y) = bundleadjust::predict(&published[0], &adjusted, t.a).unwrap();
+        assert!((x - t.b.0).hypot(y - t.b.1) < 0.05);
+    }
+}
+
+#[test]
+fn test_adjust() {
+    let truth = [camera(0.0), camera(12.0)];
+    let images = [render(&truth[0]), render(&truth[1])];
+    let error = Quaternion::from_pitch_roll_yaw(0.0, 0.004, -0.006);
+    let published = [
+        truth[0].clone(),
+        bundleadjust::rotate_model(&truth[1], &error),
+    ];
+
+    let (adjusted, ties) = bundleadjust::adjust(&images, &published, &AdjustOptions::default());
+    assert!(ties.len() >= 8, "{} tie points", ties.len());
+
+    // Pixel of the second image against where each model says it is
+    let error_px = |model: &CameraModel| {
+        let p = bundleadjust::predict(&truth[0], model, (200.0, 100.0)).unwrap();
+        let q = bundleadjust::predict(&truth[0], &truth[1], (200.0, 100.0)).unwrap();
+        (p.0 - q.0).hypot(p.1 - q.1)
+    };
+    assert!(error_px(&published[1]) > 2.0);
+    assert!(error_px(&adjusted[1]) < 0.5, "{}", error_px(&adjusted[1]));
+}
+
+/// Saves an image of the scene from the true camera, with the published one in its sidecar
+fn save_with_metadata(
+    dir: &std::path::Path,
+    name: &str,
+    truth: &CameraModel,
+    model: &CameraModel,
+) -> String {
+    let buffer = render(truth).scale(255.0).unwrap();
+    let file = dir.join(name).to_str().unwrap().to_string();
+    RgbImage::new_from_buffers_rgb(&buffer, &buffer, &buffer, ImageMode::U8BIT)
+        .unwrap()
+        .save(&file);
+    let md = serde_json::json!({
+        "link": "", "credit": "", "sol": 0, "imageid": name, "caption": "",
+        "date_taken_utc": "", "date_taken_mars": null, "subframe_rect": null,
+        "scale_factor": 1, "instrument": "", "filter_name": null, "camera_vector": null,
+        "mast_az": null, "mast_el": null, "sclk": null, "camera_position": null,
+        "camera_model_type": null, "site": null, "drive": null,
+        "camera_model_component_list": model.serialize(),
+    });
+    std::fs::write(file.replace(".png", "-metadata.json"), md.to_string()).unwrap();
+    file
+}
+
+#[test]
+fn test_adjust_files_from_published_models() {
+    let dir = std::env::temp_dir().join("mru_test_bundleadjust");
+    std::fs::create_dir_all(&dir).unwrap();
+    let error = Quaternion::from_pitch_roll_yaw(0.0, 0.004, -0.006);
+    let published = bundleadjust::rotate_model(&camera(12.0), &error);
+    let files = vec![
+        save_with_metadata(&dir, "a.png", &camera(0.0), &camera(0.0)),
+        save_with_metadata(&dir, "b.png", &camera(12.0), &published),
+    ];
+
+    let first = bundleadjust::adjust_files(&files, &AdjustOptions::default()).unwrap();
+    let adjusted = MarsImage::open(files[1].clone(), Instrument::None)
+        .metadata
+        .unwrap();
+    let error_px = |model: &CameraModel| {
+        let p = bundleadjust::predict(&camera(0.0), model, (200.0, 100.0)).unwrap();
+        let q = bundleadjust::predict(&camera(0.0), &camera(12.0), (200.0, 100.0)).unwrap();
+        (p.0 - q.0).hypot(p.1 - q.1)
+    };
+    assert!(error_px(&adjusted.camera_model_component_list) < 0.5);
+    assert!(adjusted.pointing_adjusted);
+    assert_eq!(
+        adjusted.original_camera_model.serialize(),
+        published.serialize()
+    );
+
+    // A second run starts again from the published models rather than stacking corrections
+    let second = bundleadjust::adjust_files(&files, &AdjustOptions::default()).unwrap();
+    assert!((first[1] - second[1]).abs() < 1e-9);
+    let readjusted = MarsImage::open(files[1].clone(), Instrument::None)
+        .metadata
+        .unwrap();
+    assert_eq!(
+        readjusted.original_camera_model.serialize(),
+        published.serialize()
+    );
+}
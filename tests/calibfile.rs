@@ -23,3 +23,71 @@ fn test_locate_without_extention() {
     .unwrap();
     assert_eq!(caldata_toml, "mars-raw-utils-data/caldata/caldata.toml");
 }
+
+#[test]
+fn test_merge_config_layers() {
+    let layers = vec![
+        calibfile::ConfigLayer {
+            origin: String::from("system"),
+            toml: String::from(
+                r#"
+                [m20.watson]
+                flat = "M20_WATSON_FLAT_V0.png"
+                inpaint_mask = "M20_WATSON_INPAINT_MASK_V1.png"
+                mask = ""
+                "#,
+            ),
+        },
+        calibfile::ConfigLayer {
+            origin: String::from("local"),
+            toml: String::from(
+                r#"
+                [m20.watson]
+                flat = "MY_WATSON_FLAT.png"
+                "#,
+            ),
+        },
+    ];
+
+    let merged = calibfile::merge_config_layers(&layers).unwrap();
+    assert_eq!(
+        merged.value_at("m20.watson.flat").unwrap().as_str(),
+        Some("MY_WATSON_FLAT.png")
+    );
+    assert_eq!(
+        merged.value_at("m20.watson.inpaint_mask").unwrap().as_str(),
+        Some("M20_WATSON_INPAINT_MASK_V1.png")
+    );
+    assert_eq!(merged.origin_of("m20.watson.flat").unwrap(), "local");
+    assert_eq!(
+        merged.origin_of("m20.watson.inpaint_mask").unwrap(),
+        "system"
+    );
+}
+
+#[test]
+fn test_merge_config_layers_replacing_tables() {
+    let layer = |origin: &str, toml: &str| calibfile::ConfigLayer {
+        origin: String::from(origin),
+        toml: String::from(toml),
+    };
+
+    // A value replacing a table leaves no origins for the table's old entries
+    let layers = vec![
+        layer("system", "[m20.watson]\nflat = \"FLAT.png\"\nmask = \"\""),
+        layer("local", "[m20]\nwatson = \"none\""),
+    ];
+    let merged = calibfile::merge_config_layers(&layers).unwrap();
+    assert_eq!(merged.origin_of("m20.watson").unwrap(), "local");
+    assert!(merged.origin_of("m20.watson.flat").is_none());
+    assert!(merged.origin_of("m20.watson.mask").is_none());
+
+    // A table replacing a value leaves no origin for the value
+    let layers = vec![
+        layer("system", "[m20]\nwatson = \"none\""),
+        layer("local", "[m20.watson]\nflat = \"FLAT.png\""),
+    ];
+    let merged = calibfile::merge_config_layers(&layers).unwrap();
+    assert!(merged.origin_of("m20.watson").is_none());
+    assert_eq!(merged.origin_of("m20.watson.flat").unwrap(), "local");
+}
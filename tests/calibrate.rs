@@ -0,0 +1,36 @@
+use mars_raw_utils::{
+    calibrate::{Stage, StageRunner},
+    calprofile::CalProfile,
+    enums::Instrument,
+    image::MarsImage,
+};
+
+#[test]
+fn test_stages_report_failures() {
+    let mut img = MarsImage::new(16, 16, Instrument::None);
+
+    // Nothing is enabled in the default profile, so every stage passes over the image
+    let cal_context = CalProfile::default();
+    let stages = StageRunner::new(&cal_context, "in.png", "out.png");
+    assert!(stages
+        .apply(
+            &mut img,
+            &[
+                Stage::Deblock,
+                Stage::WhiteBalance,
+                Stage::HotPixels,
+                Stage::CosmicRays,
+                Stage::ColorNoise,
+                Stage::OutputTransform,
+            ],
+        )
+        .is_ok());
+
+    // A stage the profile asks for that can't run fails the calibration
+    let cal_context = CalProfile {
+        hot_pixel_map_file: Some("/nonexistent/hpcmap.png".to_string()),
+        ..CalProfile::default()
+    };
+    let stages = StageRunner::new(&cal_context, "in.png", "out.png");
+    assert!(stages.apply(&mut img, &[Stage::HotPixels]).is_err());
+}
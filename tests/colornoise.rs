@@ -0,0 +1,51 @@
+use mars_raw_utils::{colornoise, colorspace, enums::Instrument, image::MarsImage};
+
+#[test]
+fn test_lab_roundtrip() {
+    for rgb in [
+        [0.5, 0.5, 0.5],
+        [0.9, 0.2, 0.1],
+        [0.05, 0.3, 0.8],
+        [0.001, 0.0, 0.002],
+    ] {
+        let back = colorspace::lab_to_linear_rgb(colorspace::linear_rgb_to_lab(rgb));
+        for c in 0..3 {
+            assert!((back[c] - rgb[c]).abs() < 1e-4);
+        }
+    }
+    let white = colorspace::linear_rgb_to_lab([1.0, 1.0, 1.0]);
+    assert!((white[0] - 100.0).abs() < 0.01);
+    assert!(white[1].abs() < 0.01 && white[2].abs() < 0.01);
+}
+
+#[test]
+fn test_reduce_color_noise() {
+    // Gray scene with alternating red and blue tinted pixels
+    let mut img = MarsImage::new(32, 32, Instrument::None);
+    for y in 0..32 {
+        for x in 0..32 {
+            let tint = if (x + y) % 2 == 0 { 200.0 } else { -200.0 };
+            img.image.put(x, y, 1000.0 + tint, 0);
+            img.image.put(x, y, 1000.0, 1);
+            img.image.put(x, y, 1000.0 - tint, 2);
+        }
+    }
+
+    let chroma = |img: &MarsImage| -> f32 {
+        (0..32)
+            .map(|x| {
+                let r = img.image.get_band(0).get(x, 16).unwrap();
+                let b = img.image.get_band(2).get(x, 16).unwrap();
+                (r - b).abs()
+            })
+            .sum::<f32>()
+    };
+
+    let before = chroma(&img);
+    colornoise::reduce_color_noise(&mut img, 2).unwrap();
+    assert!(chroma(&img) < before * 0.2);
+
+    // Green carries most of the luminance and should be nearly unchanged
+    let g = img.image.get_band(1).get(16, 16).unwrap();
+    assert!((g - 1000.0).abs() < 50.0);
+}
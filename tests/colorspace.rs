@@ -0,0 +1,98 @@
+use mars_raw_utils::{
+    calprofile::CalProfile, colorspace, colorspace::ColorSpace, enums::Instrument, image::MarsImage,
+};
+use sciimg::enums::ImageMode;
+use std::str::FromStr;
+
+#[test]
+fn test_color_space_from_str() {
+    assert_eq!(ColorSpace::from_str("sRGB"), Ok(ColorSpace::SRgb));
+    assert_eq!(
+        ColorSpace::from_str("display-p3"),
+        Ok(ColorSpace::DisplayP3)
+    );
+    assert_eq!(ColorSpace::from_str("linear"), Ok(ColorSpace::Linear));
+    assert!(ColorSpace::from_str("adobergb").is_err());
+}
+
+#[test]
+fn test_srgb_transfer_roundtrip() {
+    for i in 0..=20 {
+        let v = i as f32 / 20.0;
+        assert!((colorspace::srgb_decode(colorspace::srgb_encode(v)) - v).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_icc_profile_structure() {
+    assert!(ColorSpace::Native.icc_profile().is_none());
+    for cs in [ColorSpace::Linear, ColorSpace::SRgb, ColorSpace::DisplayP3] {
+        let icc = cs.icc_profile().unwrap();
+        let size = u32::from_be_bytes([icc[0], icc[1], icc[2], icc[3]]) as usize;
+        assert_eq!(size, icc.len());
+        assert_eq!(&icc[36..40], b"acsp");
+        assert_eq!(&icc[12..16], b"mntr");
+        let tag_count = u32::from_be_bytes([icc[128], icc[129], icc[130], icc[131]]);
+        assert_eq!(tag_count, 9);
+    }
+}
+
+#[test]
+fn test_output_transform_and_tagging() {
+    let mut img = MarsImage::new(4, 4, Instrument::None);
+    img.image.set_mode(ImageMode::U16BIT);
+    for y in 0..4 {
+        for x in 0..4 {
+            for b in 0..3 {
+                img.image.put(x, y, 65535.0 * 0.2, b);
+            }
+        }
+    }
+
+    let cal_context = CalProfile {
+        output_color_space: ColorSpace::SRgb,
+        ..CalProfile::default()
+    };
+    colorspace::apply_output_transform(&mut img, &cal_context).unwrap();
+
+    // Neutral stays neutral, and is gamma encoded
+    let expected = colorspace::srgb_encode(0.2) * 65535.0;
+    for b in 0..3 {
+        assert!((img.image.get_band(b).get(0, 0).unwrap() - expected).abs() < 20.0);
+    }
+
+    let dir = std::env::temp_dir().join("mru_test_colorspace");
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("tagged.png");
+    let out = out.to_str().unwrap();
+    img.save(out);
+
+    let decoder = png::Decoder::new(std::fs::File::open(out).unwrap());
+    let reader = decoder.read_info().unwrap();
+    let icc = reader.info().icc_profile.as_ref().unwrap();
+    assert_eq!(icc.to_vec(), ColorSpace::SRgb.icc_profile().unwrap());
+    // Written once, in the image's own channel layout
+    assert_eq!(reader.info().color_type, png::ColorType::Rgb);
+    assert_eq!(reader.info().bit_depth, png::BitDepth::Sixteen);
+
+    let out = dir.join("tagged.tif");
+    let out = out.to_str().unwrap();
+    img.save(out);
+    let data = std::fs::read(out).unwrap();
+    let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    assert_eq!(&data[0..2], b"II");
+    let ifd = u32_at(4) as usize;
+    let entry = (0..u16_at(ifd) as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|e| u16_at(*e) == 34675)
+        .expect("No ICC profile tag");
+    // UNDEFINED, with one count per byte of the profile
+    assert_eq!(u16_at(entry + 2), 7);
+    let profile = ColorSpace::SRgb.icc_profile().unwrap();
+    assert_eq!(u32_at(entry + 4) as usize, profile.len());
+    let offset = u32_at(entry + 8) as usize;
+    assert_eq!(&data[offset..offset + profile.len()], &profile[..]);
+    let tiff = image::open(out).unwrap();
+    assert_eq!(tiff.color(), image::ColorType::Rgb16);
+}
@@ -0,0 +1,54 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+
+use mars_raw_utils::{drawable::Drawable, metadata::Metadata};
+use sciimg::{imagebuffer::ImageBuffer, prelude::*};
+
+/// Metadata with only the subframe and downsampling set
+pub fn metadata(subframe_rect: &str, scale_factor: u32) -> Metadata {
+    serde_json::from_str(&format!(
+        r#"{{"link": "", "credit": "", "sol": 1, "imageid": "", "caption": "",
+        "date_taken_utc": "", "date_taken_mars": null, "subframe_rect": {},
+        "scale_factor": {}, "instrument": "MAST_LEFT", "filter_name": null,
+        "camera_vector": null, "mast_az": null, "mast_el": null, "sclk": null,
+        "camera_position": "UNK", "camera_model_type": null, "site": null, "drive": null,
+        "camera_model_component_list": "UNK"}}"#,
+        subframe_rect, scale_factor
+    ))
+    .unwrap()
+}
+
+/// A square frame with gentle texture, so the local variance isn't zero
+pub fn textured_frame(size: usize, seed: usize) -> ImageBuffer {
+    let mut buffer = ImageBuffer::new(size, size).unwrap();
+    for y in 0..size {
+        for x in 0..size {
+            buffer.put(x, y, 100.0 + ((x * 7 + y * 13 + seed * 5) % 11) as f32);
+        }
+    }
+    buffer
+}
+
+/// Pseudo-random bits for the block at (i, j), for scenes of randomly bright blocks
+pub fn block_hash(i: i64, j: i64) -> u64 {
+    ((i.wrapping_mul(73_856_093) ^ j.wrapping_mul(19_349_663)) as u64)
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+}
+
+/// Brightness, from 0 to 1, of the block at (i, j)
+pub fn block_level(i: i64, j: i64) -> f32 {
+    (block_hash(i, j) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// An image filled with one color
+pub fn solid(width: usize, height: usize, color: [f32; 3]) -> RgbImage {
+    let mut img = RgbImage::create(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            for (b, v) in color.iter().enumerate() {
+                img.put(x, y, *v, b);
+            }
+        }
+    }
+    img
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use mars_raw_utils::{
+    composite::{self, MosaicOptions},
+    drawable::Drawable,
+    enums::Instrument,
+    frames::{Frame, MosaicFrame},
+    image::MarsImage,
+    projection::ProjectionSpec,
+};
+use sciimg::{camera::cahv::Cahv, prelude::*, quaternion::Quaternion, vector::Vector};
+
+/// Camera at the origin looking along the horizon at the given azimuth, 40x30 pixels with a 60
+/// pixel focal length
+fn camera(azimuth: f64) -> CameraModel {
+    let (s, c) = azimuth.to_radians().sin_cos();
+    let a = Vector::new(c, s, 0.0);
+    let h = Vector::new(-s, c, 0.0);
+    let v = Vector::new(0.0, 0.0, 1.0);
+    CameraModel::new(Box::new(Cahv {
+        c: Vector::new(0.0, 0.0, 0.0),
+        a,
+        h: h.scale(60.0).add(&a.scale(20.0)),
+        v: v.scale(60.0).add(&a.scale(15.0)),
+    }))
+}
+
+fn save_input(dir: &std::path::Path, name: &str, azimuth: f64) -> String {
+    let mut img = MarsImage::new(40, 30, Instrument::None);
+    for y in 0..30 {
+        for x in 0..40 {
+            img.image.put(x, y, (x * 5 + y) as f32, 0);
+            img.image.put(x, y, (y * 7) as f32, 1);
+            img.image.put(x, y, (azimuth + 100.0) as f32, 2);
+        }
+    }
+    let mut md = common::metadata("null", 1);
+    md.camera_model_component_list = camera(azimuth);
+    img.metadata = Some(md);
+
+    let file = dir.join(name).to_str().unwrap().to_string();
+    img.save(&file);
+    file
+}
+
+#[test]
+fn test_render_strips_matches_full() {
+    let dir = std::env::temp_dir().join("mru_test_composite");
+    std::fs::create_dir_all(&dir).unwrap();
+    let in_files = vec![
+        save_input(&dir, "left.png", -15.0),
+        save_input(&dir, "right.png", 15.0),
+    ];
+
+    let reference = MarsImage::open(in_files[0].clone(), Instrument::None);
+    let options = MosaicOptions {
+        anaglyph: false,
+        quat: Quaternion::default(),
+        frame: MosaicFrame::new(Frame::RoverNav, reference.metadata.as_ref().unwrap()),
+        projection: ProjectionSpec::default(),
+    };
+    let map_context = composite::determine_map_context(
+        &in_files,
+        &options.quat,
+        &options.frame,
+        &options.projection,
+    );
+    assert!(map_context.width > 40 && map_context.height > 7);
+
+    let projection = options.projection.build().unwrap();
+    let mut full = RgbImage::create_masked(map_context.width, map_context.height, false);
+    for f in in_files.iter() {
+        composite::process_file(
+            f,
+            &map_context,
+            &mut full,
+            false,
+            &options.quat,
+            &options.frame,
+            projection.as_ref(),
+        );
+    }
+
+    let mut strips = RgbImage::create_masked(map_context.width, map_context.height, false);
+    composite::render_strips(&in_files, &map_context, &options, 7, |strip_y, strip| {
+        for y in 0..strip.height {
+            for x in 0..strip.width {
+                if strip.get_alpha_at(x, y) {
+                    for b in 0..3 {
+                        strips.put(x, strip_y + y, strip.get_band(b).get(x, y).unwrap(), b);
+                    }
+                    strips.put_alpha(x, strip_y + y, true);
+                }
+            }
+        }
+    });
+
+    let mut painted = 0;
+    for y in 0..map_context.height {
+        for x in 0..map_context.width {
+            assert_eq!(
+                full.get_alpha_at(x, y),
+                strips.get_alpha_at(x, y),
+                "coverage at {}, {}",
+                x,
+                y
+            );
+            if full.get_alpha_at(x, y) {
+                painted += 1;
+                for b in 0..3 {
+                    let (f, s) = (
+                        full.get_band(b).get(x, y).unwrap(),
+                        strips.get_band(b).get(x, y).unwrap(),
+                    );
+                    assert!((f - s).abs() < 0.01, "band {} at {}, {}", b, x, y);
+                }
+            }
+        }
+    }
+    assert!(painted > 40 * 30);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use mars_raw_utils::{cosmicray, cosmicray::COSMIC_RAY_MASK_VALUE};
+use sciimg::imagebuffer::ImageBuffer;
+
+#[test]
+fn test_lacosmic() {
+    let mut buffer = common::textured_frame(32, 0);
+
+    // A single pixel hit and a two pixel streak
+    buffer.put(8, 9, 2000.0);
+    buffer.put(20, 22, 1500.0);
+    buffer.put(21, 22, 1500.0);
+
+    // A resolved, smooth feature that must survive
+    for y in 0..32_usize {
+        for x in 0..32_usize {
+            let d2 = (x as f32 - 22.0).powi(2) + (y as f32 - 8.0).powi(2);
+            let v = buffer.get(x, y).unwrap() + 800.0 * (-d2 / 8.0).exp();
+            buffer.put(x, y, v);
+        }
+    }
+
+    let (cleaned, mask) = cosmicray::lacosmic(&buffer, &cosmicray::LaCosmicParams::default());
+    assert_eq!(mask.get(8, 9).unwrap(), COSMIC_RAY_MASK_VALUE);
+    assert_eq!(mask.get(20, 22).unwrap(), COSMIC_RAY_MASK_VALUE);
+    assert_eq!(mask.get(21, 22).unwrap(), COSMIC_RAY_MASK_VALUE);
+    assert_eq!(mask.get(22, 8).unwrap(), 0.0);
+    assert!(cleaned.get(8, 9).unwrap() < 120.0);
+    assert!(cleaned.get(21, 22).unwrap() < 120.0);
+    assert_eq!(cleaned.get(3, 3).unwrap(), buffer.get(3, 3).unwrap());
+}
+
+#[test]
+fn test_sigma_clip_mean() {
+    let frames: Vec<ImageBuffer> = (0..5)
+        .map(|i| {
+            let mut f = common::textured_frame(32, i);
+            if i == 2 {
+                f.put(7, 11, 3000.0);
+            }
+            f
+        })
+        .collect();
+    let refs: Vec<&ImageBuffer> = frames.iter().collect();
+
+    let (mean, masks) = cosmicray::sigma_clip_mean(&refs, 3.0, 5).unwrap();
+    assert_eq!(masks.len(), 5);
+    assert_eq!(masks[2].get(7, 11).unwrap(), COSMIC_RAY_MASK_VALUE);
+    assert_eq!(
+        cosmicray::count_rejected(&cosmicray::merge_masks(&masks)),
+        1
+    );
+    assert!(mean.get(7, 11).unwrap() < 120.0);
+
+    let short = ImageBuffer::new(4, 4).unwrap();
+    assert!(cosmicray::sigma_clip_mean(&[&frames[0], &short], 3.0, 5).is_err());
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use mars_raw_utils::{
+    calprofile::CalProfile,
+    debayer,
+    debayer::{CfaPattern, DebayerMethod},
+    enums::Instrument,
+    image::MarsImage,
+};
+use sciimg::imagebuffer::ImageBuffer;
+use std::str::FromStr;
+
+const COLOR: [f32; 3] = [1200.0, 800.0, 400.0];
+
+/// A mosaic of a scene of uniform color
+fn uniform_mosaic(width: usize, height: usize, pattern: CfaPattern) -> ImageBuffer {
+    let mut buffer = ImageBuffer::new(width, height).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            buffer.put(x, y, COLOR[pattern.color_at(x as i32, y as i32)]);
+        }
+    }
+    buffer
+}
+
+#[test]
+fn test_debayer_method_from_str() {
+    assert_eq!(DebayerMethod::from_str("VNG"), Ok(DebayerMethod::Vng));
+    assert_eq!(
+        DebayerMethod::from_str("super-pixel"),
+        Ok(DebayerMethod::SuperPixel)
+    );
+    assert_eq!(DebayerMethod::from_str("mhc"), Ok(DebayerMethod::Malvar));
+    assert_eq!(DebayerMethod::from_str("sciimg"), Ok(DebayerMethod::Sciimg));
+    assert!(DebayerMethod::from_str("nearest").is_err());
+}
+
+#[test]
+fn test_debayer_uniform_color() {
+    let mosaic = uniform_mosaic(16, 12, CfaPattern::Rggb);
+    for method in [
+        DebayerMethod::Bilinear,
+        DebayerMethod::Malvar,
+        DebayerMethod::Vng,
+    ] {
+        let bands = debayer::debayer(&mosaic, method, CfaPattern::Rggb).unwrap();
+        for (b, band) in bands.iter().enumerate() {
+            assert_eq!(band.width, 16);
+            assert_eq!(band.height, 12);
+            for y in 0..12 {
+                for x in 0..16 {
+                    assert!(
+                        (band.get(x, y).unwrap() - COLOR[b]).abs() < 0.01,
+                        "{:?} band {} at {},{}",
+                        method,
+                        b,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_debayer_bilinear_keeps_samples() {
+    let mosaic = common::textured_frame(12, 0);
+    let bands = debayer::debayer(&mosaic, DebayerMethod::Bilinear, CfaPattern::Rggb).unwrap();
+    for y in 0..12 {
+        for x in 0..12 {
+            let color = CfaPattern::Rggb.color_at(x as i32, y as i32);
+            assert_eq!(bands[color].get(x, y).unwrap(), mosaic.get(x, y).unwrap());
+        }
+    }
+    // Green at a red site is the mean of the four green neighbors
+    let green = (mosaic.get(3, 4).unwrap()
+        + mosaic.get(5, 4).unwrap()
+        + mosaic.get(4, 3).unwrap()
+        + mosaic.get(4, 5).unwrap())
+        / 4.0;
+    assert!((bands[1].get(4, 4).unwrap() - green).abs() < 0.01);
+}
+
+#[test]
+fn test_debayer_superpixel() {
+    let bands = debayer::debayer(
+        &uniform_mosaic(16, 12, CfaPattern::Rggb),
+        DebayerMethod::SuperPixel,
+        CfaPattern::Rggb,
+    )
+    .unwrap();
+    for (b, band) in bands.iter().enumerate() {
+        assert_eq!(band.width, 8);
+        assert_eq!(band.height, 6);
+        assert_eq!(band.get(3, 2).unwrap(), COLOR[b]);
+    }
+}
+
+#[test]
+fn test_cfa_pattern_phase() {
+    assert_eq!(CfaPattern::from_str("grbg"), Ok(CfaPattern::Grbg));
+    assert_eq!(CfaPattern::Rggb.shifted(0, 0), CfaPattern::Rggb);
+    assert_eq!(CfaPattern::Rggb.shifted(1, 0), CfaPattern::Grbg);
+    assert_eq!(CfaPattern::Rggb.shifted(0, 1), CfaPattern::Gbrg);
+    assert_eq!(CfaPattern::Rggb.shifted(1, 1), CfaPattern::Bggr);
+    assert_eq!(CfaPattern::Grbg.shifted(3, 2), CfaPattern::Rggb);
+}
+
+#[test]
+fn test_debayer_other_patterns() {
+    for pattern in [CfaPattern::Grbg, CfaPattern::Gbrg, CfaPattern::Bggr] {
+        let mosaic = uniform_mosaic(10, 10, pattern);
+        for method in [DebayerMethod::Malvar, DebayerMethod::SuperPixel] {
+            let bands = debayer::debayer(&mosaic, method, pattern).unwrap();
+            for (b, band) in bands.iter().enumerate() {
+                assert!((band.get(2, 3).unwrap() - COLOR[b]).abs() < 0.01);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cfa_pattern_for_image() {
+    let mut img = MarsImage::new(8, 8, Instrument::None);
+    img.metadata = Some(common::metadata("[1.0, 1.0, 8.0, 8.0]", 1));
+    assert_eq!(
+        CfaPattern::for_image(CfaPattern::Rggb, &img),
+        Ok(CfaPattern::Rggb)
+    );
+
+    // Subframe starting on the second row of the sensor
+    img.metadata = Some(common::metadata("[1.0, 2.0, 8.0, 8.0]", 1));
+    assert_eq!(
+        CfaPattern::for_image(CfaPattern::Rggb, &img),
+        Ok(CfaPattern::Gbrg)
+    );
+
+    img.metadata = Some(common::metadata("[1.0, 1.0, 16.0, 16.0]", 2));
+    assert!(CfaPattern::for_image(CfaPattern::Rggb, &img).is_err());
+    assert!(img.debayer_with_method(DebayerMethod::Malvar).is_err());
+
+    // The calibration stage skips downsampled frames rather than failing
+    let cal_context = CalProfile {
+        debayer_method: DebayerMethod::Malvar,
+        ..CalProfile::default()
+    };
+    assert!(debayer::apply_debayer(&mut img, &cal_context).is_ok());
+    assert!(!img.metadata.as_ref().unwrap().debayer);
+}
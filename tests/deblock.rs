@@ -0,0 +1,141 @@
+mod common;
+
+use mars_raw_utils::{
+    deblock,
+    deblock::{GridAlignment, JpegGrid},
+    enums::Instrument,
+    image::MarsImage,
+};
+use sciimg::imagebuffer::ImageBuffer;
+
+/// Flat 8x8 blocks whose levels differ slightly, as left by coarse DC quantization
+fn blocky_frame(offset: usize) -> ImageBuffer {
+    let mut buffer = ImageBuffer::new(32, 32).unwrap();
+    for y in 0..32 {
+        for x in 0..32 {
+            let bx = (x + 8 - offset) / 8;
+            let by = (y + 8 - offset) / 8;
+            buffer.put(x, y, 100.0 + ((bx + by) % 2) as f32 * 4.0);
+        }
+    }
+    buffer
+}
+
+#[test]
+fn test_parse_quant_table() {
+    let img = image::GrayImage::from_fn(16, 16, |x, y| image::Luma([(x * 8 + y) as u8]));
+    let mut data: Vec<u8> = vec![];
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut data, 50)
+        .encode_image(&img)
+        .unwrap();
+
+    // The standard luminance table, unscaled at quality 50
+    let table = deblock::parse_quant_table(&data).unwrap();
+    assert_eq!(table[0], 16.0);
+    assert_eq!(table[1], 11.0);
+    assert_eq!(table[8], 12.0);
+
+    assert!(deblock::parse_quant_table(&[0, 1, 2, 3]).is_err());
+}
+
+#[test]
+fn test_deblock_buffer() {
+    let buffer = blocky_frame(0);
+    let grid = GridAlignment::new(0, 0);
+    let deblocked = deblock::deblock_buffer(&buffer, &grid, 8.0, None);
+
+    let step = |b: &ImageBuffer| (b.get(8, 4).unwrap() - b.get(7, 4).unwrap()).abs();
+    assert_eq!(step(&buffer), 4.0);
+    assert!(step(&deblocked) < 1.0);
+
+    // Block interiors away from the boundaries are left alone
+    assert_eq!(deblocked.get(3, 3).unwrap(), buffer.get(3, 3).unwrap());
+
+    // A DC step of 8 only shifts a block by 1 DN, so a strength of 1 leaves 4 DN steps alone
+    assert_eq!(
+        step(&deblock::deblock_buffer(&buffer, &grid, 1.0, None)),
+        4.0
+    );
+
+    // The change to each block stays within what the quantization allows
+    let constrained = deblock::deblock_buffer(&buffer, &grid, 16.0, Some(&[4.0; 64]));
+    assert!(step(&constrained) < step(&buffer));
+    assert!(step(&constrained) > step(&deblocked));
+
+    // A misaligned grid finds no flat boundaries to smooth
+    let misaligned = deblock::deblock_buffer(&buffer, &GridAlignment::new(4, 4), 8.0, None);
+    assert_eq!(step(&misaligned), 4.0);
+
+    // Steps larger than the quantization step are real edges
+    let mut edge = buffer.clone();
+    for y in 0..32 {
+        for x in 8..32 {
+            edge.put(x, y, 200.0);
+        }
+    }
+    let deblocked = deblock::deblock_buffer(&edge, &grid, 8.0, None);
+    assert_eq!(deblocked.get(8, 4).unwrap(), 200.0);
+}
+
+#[test]
+fn test_deblock_shifted_grid() {
+    let buffer = blocky_frame(3);
+    let deblocked = deblock::deblock_buffer(&buffer, &GridAlignment::new(3, 3), 8.0, None);
+    assert!((deblocked.get(11, 6).unwrap() - deblocked.get(10, 6).unwrap()).abs() < 1.0);
+}
+
+#[test]
+fn test_deblock_mosaic() {
+    // Blocky planes behind a bayer pattern whose red sites are much brighter
+    let mut buffer = blocky_frame(0);
+    for y in (0..32).step_by(2) {
+        for x in (0..32).step_by(2) {
+            buffer.put(x, y, buffer.get(x, y).unwrap() + 800.0);
+        }
+    }
+    let grid = GridAlignment::new(0, 0);
+    let step = |b: &ImageBuffer, y: usize| (b.get(9, y).unwrap() - b.get(7, y).unwrap()).abs();
+
+    // Filtering across the colors finds no flat boundaries and leaves the blocks alone
+    let mixed = deblock::deblock_buffer(&buffer, &grid, 8.0, None);
+    assert_eq!(step(&mixed, 2), 4.0);
+
+    let deblocked = deblock::deblock_mosaic(&buffer, &grid, 8.0, None);
+    assert!(step(&deblocked, 2) < 2.0);
+    assert!(step(&deblocked, 3) < 2.0);
+    assert!(deblocked.get(8, 2).unwrap() > 800.0);
+    assert!(deblocked.get(9, 2).unwrap() < 110.0);
+
+    assert_eq!(
+        grid.for_cfa_plane(1, 0),
+        GridAlignment {
+            block_size: 4,
+            offset_x: 0,
+            offset_y: 0
+        }
+    );
+    assert_eq!(GridAlignment::new(3, 3).for_cfa_plane(0, 1).offset_x, 2);
+    assert_eq!(GridAlignment::new(3, 3).for_cfa_plane(0, 1).offset_y, 1);
+}
+
+#[test]
+fn test_grid_alignment_for_image() {
+    let mut img = MarsImage::new(32, 32, Instrument::None);
+    img.metadata = Some(common::metadata("[5.0, 9.0, 32.0, 32.0]", 1));
+    assert_eq!(
+        GridAlignment::for_image(&img, JpegGrid::Image),
+        GridAlignment::new(0, 0)
+    );
+
+    // Sensor column 8 and row 8 are the first boundaries inside the subframe
+    assert_eq!(
+        GridAlignment::for_image(&img, JpegGrid::Sensor),
+        GridAlignment::new(4, 0)
+    );
+
+    img.metadata = Some(common::metadata("[9.0, 1.0, 64.0, 64.0]", 2));
+    assert_eq!(
+        GridAlignment::for_image(&img, JpegGrid::Sensor),
+        GridAlignment::new(4, 0)
+    );
+}
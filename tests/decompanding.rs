@@ -0,0 +1,73 @@
+use mars_raw_utils::decompanding;
+
+#[test]
+fn test_parse_ilt() {
+    let values: Vec<String> = (0..256).map(|i| format!("{}", i * 8)).collect();
+
+    let single = format!("# 11 bit test table\n{}\n", values.join(", "));
+    let ilt = decompanding::parse_ilt(&single).unwrap();
+    assert_eq!(ilt[0], 0);
+    assert_eq!(ilt[255], 2040);
+
+    let two_column: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("{},{}", i, v))
+        .collect();
+    assert_eq!(
+        decompanding::parse_ilt(&two_column.join("\n")).unwrap(),
+        ilt
+    );
+
+    assert!(decompanding::parse_ilt("0 1 2 3").is_err());
+    assert!(decompanding::parse_ilt(&values.join(" ").replace("16", "x")).is_err());
+}
+
+#[test]
+fn test_ilt_bit_depth() {
+    assert_eq!(decompanding::bit_depth(&decompanding::ILT), 11);
+    assert_eq!(decompanding::get_max_for_ilt(&decompanding::ILT), 2033);
+    assert_eq!(decompanding::bit_depth(&decompanding::NSYT_ILT), 12);
+}
+
+#[test]
+fn test_validate_ilt() {
+    let mut ilt = [0_u32; 256];
+    for (i, v) in ilt.iter_mut().enumerate() {
+        *v = (i * i / 16 + i) as u32;
+    }
+    let v = decompanding::validate_ilt(&ilt);
+    assert!(v.monotonic);
+    assert!(v.is_invertible());
+    assert_eq!(decompanding::compand_value(ilt[100], &ilt), 100);
+    assert_eq!(decompanding::compand_value(ilt[100] + 1, &ilt), 100);
+    assert_eq!(decompanding::compand_value(100000, &ilt), 255);
+
+    // Codes sharing a value can't be recovered
+    let v = decompanding::validate_ilt(&decompanding::NSYT_ILT);
+    assert!(v.monotonic);
+    assert!(!v.is_invertible());
+    assert_eq!(v.roundtrip_errors[..4], [1, 2, 3, 4]);
+
+    ilt[50] = 0;
+    assert!(!decompanding::validate_ilt(&ilt).monotonic);
+}
+
+#[test]
+fn test_load_ilt_file() {
+    let dir = std::env::temp_dir().join("mru_test_decompanding");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let file = dir.join("ilt.txt");
+    let values: Vec<String> = (0..256).map(|i| format!("{}", i * 4)).collect();
+    std::fs::write(&file, values.join("\n")).unwrap();
+    let ilt = decompanding::load_ilt_file(file.to_str().unwrap()).unwrap();
+    assert_eq!(ilt[255], 1020);
+
+    // Errors are returned rather than panicking
+    let missing = dir.join("missing.txt");
+    assert!(decompanding::load_ilt_file(missing.to_str().unwrap()).is_err());
+    let binary = dir.join("binary.txt");
+    std::fs::write(&binary, [0xff_u8, 0xfe, 0x00]).unwrap();
+    assert!(decompanding::load_ilt_file(binary.to_str().unwrap()).is_err());
+}
@@ -0,0 +1,89 @@
+use mars_raw_utils::{enums::Eye, enums::Instrument, filters, filters::FilterPosition, spectral};
+use sciimg::imagebuffer::ImageBuffer;
+
+#[test]
+fn test_filter_from_name() {
+    assert_eq!(
+        FilterPosition::from_name("ZCAM_L3"),
+        Some(FilterPosition::new(Eye::Left, 3))
+    );
+    assert_eq!(
+        FilterPosition::from_name("r0"),
+        Some(FilterPosition::new(Eye::Right, 0))
+    );
+    assert_eq!(FilterPosition::from_name("CLEAR"), None);
+    assert_eq!(
+        FilterPosition::from_zcam_file_name(
+            "/data/M20/0395/ZCAM/ZR6_0395_0702017827_081EBY_N0171064ZCAM08419_1100LMJ01.png"
+        ),
+        Some(FilterPosition::new(Eye::Right, 6))
+    );
+    assert_eq!(FilterPosition::new(Eye::Left, 5).name(), "L5");
+}
+
+#[test]
+fn test_default_band_wavelengths() {
+    assert_eq!(
+        filters::default_band_wavelengths(
+            Instrument::M20MastcamZLeft,
+            &FilterPosition::new(Eye::Left, 0)
+        ),
+        Some(vec![630.0, 544.0, 480.0])
+    );
+    assert_eq!(
+        filters::default_band_wavelengths(
+            Instrument::M20MastcamZRight,
+            &FilterPosition::new(Eye::Right, 6)
+        ),
+        Some(vec![1022.0])
+    );
+    assert_eq!(
+        filters::default_band_wavelengths(
+            Instrument::M20NavcamLeft,
+            &FilterPosition::new(Eye::Left, 1)
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_roi_from_str() {
+    let roi = spectral::Roi::from_str_with_name("10, 20,30,40", "a").unwrap();
+    assert_eq!((roi.x, roi.y, roi.width, roi.height), (10, 20, 30, 40));
+    assert!(spectral::Roi::from_str_with_name("10,20,30", "a").is_err());
+    assert!(spectral::Roi::from_str_with_name("10,20,0,40", "a").is_err());
+}
+
+#[test]
+fn test_msl_mcam_filters() {
+    // The filter isn't in the file name, the characters after the camera are the sequence
+    assert_eq!(
+        FilterPosition::for_image(
+            Instrument::MslMastcamRight,
+            &None,
+            "3372MR0176230030702484C00_DXXX.jpg"
+        ),
+        None
+    );
+    assert_eq!(
+        filters::default_band_wavelengths(
+            Instrument::MslMastcamRight,
+            &FilterPosition::new(Eye::Right, 6)
+        ),
+        Some(vec![1013.0])
+    );
+}
+
+#[test]
+fn test_find_translation() {
+    let mut reference = ImageBuffer::new(160, 160).unwrap();
+    for y in 0..160 {
+        for x in 0..160 {
+            let v =
+                ((x * 7 + y * 13) % 31) as f32 + if (40..80).contains(&x) { 100.0 } else { 0.0 };
+            reference.put(x, y, v);
+        }
+    }
+    let target = reference.shift(5, -3).unwrap();
+    assert_eq!(spectral::find_translation(&reference, &target, 10), (5, -3));
+}
@@ -0,0 +1,119 @@
+use mars_raw_utils::{
+    frames::{self, Frame, MosaicFrame, RoverPose},
+    jsonfetch,
+    metadata::Metadata,
+};
+use sciimg::{prelude::*, vector::Vector};
+use std::str::FromStr;
+
+fn close(a: &Vector, b: &Vector) -> bool {
+    a.subtract(b).len() < 1e-9
+}
+
+/// Attitude of a level rover turned clockwise from north by the given angle in degrees
+fn yawed(degrees: f64) -> Vec<f64> {
+    let half = degrees.to_radians() / 2.0;
+    vec![half.cos(), 0.0, 0.0, half.sin()]
+}
+
+fn metadata(attitude: &[f64], xyz: &[f64], site: u32) -> Metadata {
+    let tuple = |v: &[f64]| {
+        format!(
+            "({})",
+            v.iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        )
+    };
+    serde_json::from_str(&format!(
+        r#"{{"link": "", "credit": "", "sol": 1, "imageid": "", "caption": "",
+        "date_taken_utc": "", "date_taken_mars": null, "subframe_rect": null,
+        "scale_factor": 1, "instrument": "NAV_LEFT_B", "filter_name": null,
+        "camera_vector": null, "mast_az": null, "mast_el": null, "sclk": null,
+        "camera_position": "UNK", "camera_model_type": null, "site": {}, "drive": 0,
+        "attitude": "{}", "xyz": "{}",
+        "camera_model_component_list": "(1,0.5,-2);(1,0,0);(0,1000,0);(0,0,1000)"}}"#,
+        site,
+        tuple(attitude),
+        tuple(xyz)
+    ))
+    .unwrap()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(Frame::from_str("rover").unwrap(), Frame::RoverNav);
+    assert_eq!(Frame::from_str("Local_Level").unwrap(), Frame::LocalLevel);
+    assert_eq!(Frame::from_str("site").unwrap(), Frame::Site);
+    assert!(Frame::from_str("camera").is_err());
+
+    assert_eq!(
+        jsonfetch::parse_tuple("(0.5,0.5,-0.5,0.5)"),
+        Some(vec![0.5, 0.5, -0.5, 0.5])
+    );
+    assert!(jsonfetch::parse_tuple("").is_none());
+    assert!(frames::attitude_to_quaternion(&[0.0, 0.0, 0.0]).is_none());
+}
+
+#[test]
+fn test_rover_pose() {
+    let md = metadata(&yawed(90.0), &[10.0, 20.0, -1.0], 3);
+    assert_eq!(md.attitude.as_ref().unwrap().len(), 4);
+    let pose = RoverPose::from_metadata(&md);
+    assert_eq!(pose.site, Some(3));
+
+    // Facing east, forward in the rover frame is east in the local level frame
+    let forward = Vector::new(1.0, 0.0, 0.0);
+    assert!(close(
+        &pose.direction_to_frame(&forward, Frame::LocalLevel),
+        &Vector::new(0.0, 1.0, 0.0)
+    ));
+    assert!(close(
+        &pose.point_to_frame(&forward, Frame::Site),
+        &Vector::new(10.0, 21.0, -1.0)
+    ));
+    assert!(close(
+        &pose.point_to_frame(&forward, Frame::RoverNav),
+        &forward
+    ));
+
+    let p = Vector::new(3.0, -4.0, 5.0);
+    for frame in [Frame::RoverNav, Frame::LocalLevel] {
+        let site = pose.point_to_frame(&p, Frame::Site);
+        let back = pose.point_from_site(&site, frame);
+        assert!(close(&back, &pose.point_to_frame(&p, frame)));
+    }
+}
+
+#[test]
+fn test_mosaic_transform() {
+    let reference = metadata(&yawed(0.0), &[0.0, 0.0, 0.0], 1);
+    let moved = RoverPose::from_metadata(&metadata(&yawed(90.0), &[10.0, 0.0, 0.0], 1));
+    let forward = LookVector {
+        origin: Vector::new(1.0, 0.5, -2.0),
+        look_direction: Vector::new(1.0, 0.0, 0.0),
+    };
+
+    // The reference image stays where it was in any frame
+    for frame in [Frame::RoverNav, Frame::LocalLevel, Frame::Site] {
+        let mosaic = MosaicFrame::new(frame, &reference);
+        let lv = mosaic.transform_for(&mosaic.pose).look_vector(&forward);
+        assert!(close(&lv.origin, &forward.origin));
+        assert!(close(&lv.look_direction, &forward.look_direction));
+    }
+
+    // An image taken after driving ten meters north and turning east
+    let mosaic = MosaicFrame::new(Frame::LocalLevel, &reference);
+    let lv = mosaic.transform_for(&moved).look_vector(&forward);
+    assert!(close(&lv.origin, &Vector::new(11.0, 0.5, -2.0)));
+    assert!(close(&lv.look_direction, &Vector::new(0.0, 1.0, 0.0)));
+
+    // Seen from a reference rover facing east, the same image is ten meters to the left
+    // and looking straight ahead
+    let east = metadata(&yawed(90.0), &[0.0, 0.0, 0.0], 1);
+    let mosaic = MosaicFrame::new(Frame::RoverNav, &east);
+    let lv = mosaic.transform_for(&moved).look_vector(&forward);
+    assert!(close(&lv.origin, &Vector::new(1.0, -9.5, -2.0)));
+    assert!(close(&lv.look_direction, &forward.look_direction));
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use mars_raw_utils::{debayer, hotpixel, hotpixel::HOT_PIXEL_MAP_VALUE};
+use sciimg::imagebuffer::ImageBuffer;
+
+#[test]
+fn test_spatial_correction() {
+    let mut buffer = common::textured_frame(24, 0);
+    buffer.put(10, 12, 4000.0);
+
+    let (corrected, map) = hotpixel::spatial_correction(&buffer, 3, 2.5);
+    assert_eq!(map.get(10, 12).unwrap(), HOT_PIXEL_MAP_VALUE);
+    assert_eq!(hotpixel::count_hot_pixels(&map), 1);
+    // Replaced with the mean of the 3x3 window, the hot pixel included
+    let mut window = 4000.0;
+    for (x, y) in [
+        (9, 11),
+        (10, 11),
+        (11, 11),
+        (9, 12),
+        (11, 12),
+        (9, 13),
+        (10, 13),
+        (11, 13),
+    ] {
+        window += buffer.get(x, y).unwrap();
+    }
+    assert!((corrected.get(10, 12).unwrap() - window / 9.0).abs() < 0.01);
+    assert_eq!(corrected.get(3, 3).unwrap(), buffer.get(3, 3).unwrap());
+}
+
+#[test]
+fn test_temporal_map() {
+    // A stuck pixel at (5, 6) in every frame and a bright feature that moves between frames
+    let frames: Vec<ImageBuffer> = (0..5)
+        .map(|i| {
+            let mut f = common::textured_frame(24, i);
+            f.put(5, 6, 3000.0);
+            f.put(12 + i, 15, 3000.0);
+            f
+        })
+        .collect();
+    let refs: Vec<&ImageBuffer> = frames.iter().collect();
+
+    let map = hotpixel::temporal_map(&refs, 3, 5.0).unwrap();
+    assert_eq!(map.get(5, 6).unwrap(), HOT_PIXEL_MAP_VALUE);
+    assert_eq!(map.get(14, 15).unwrap(), 0.0);
+    assert_eq!(hotpixel::count_hot_pixels(&map), 1);
+
+    let corrected = hotpixel::replace_with_map(&frames[0], &map, 3);
+    assert!(corrected.get(5, 6).unwrap() < 120.0);
+}
+
+/// A textured raw mosaic whose red sites are much brighter than the rest
+fn textured_mosaic(seed: usize) -> ImageBuffer {
+    let mut buffer = common::textured_frame(24, seed);
+    for y in (0..24).step_by(2) {
+        for x in (0..24).step_by(2) {
+            buffer.put(x, y, buffer.get(x, y).unwrap() + 800.0);
+        }
+    }
+    buffer
+}
+
+#[test]
+fn test_cfa_planes_roundtrip() {
+    let buffer = textured_mosaic(0);
+    let planes = debayer::split_cfa_planes(&buffer);
+    assert_eq!(planes.len(), 4);
+    assert_eq!((planes[0].width, planes[0].height), (12, 12));
+    assert_eq!(planes[1].get(0, 0).unwrap(), buffer.get(1, 0).unwrap());
+    let merged = debayer::merge_cfa_planes(&planes, buffer.width, buffer.height);
+    assert_eq!(merged.to_vector(), buffer.to_vector());
+}
+
+#[test]
+fn test_mosaic_correction() {
+    let mut buffer = textured_mosaic(0);
+    buffer.put(11, 12, 4000.0);
+
+    let (corrected, map) = hotpixel::spatial_correction_mosaic(&buffer, 3, 2.5);
+    assert_eq!(map.get(11, 12).unwrap(), HOT_PIXEL_MAP_VALUE);
+    assert_eq!(hotpixel::count_hot_pixels(&map), 1);
+    // Replaced from the green neighbors, not the bright red ones around it
+    assert!(corrected.get(11, 12).unwrap() < 600.0);
+    assert_eq!(corrected.get(10, 12).unwrap(), buffer.get(10, 12).unwrap());
+
+    let frames: Vec<ImageBuffer> = (0..5)
+        .map(|i| {
+            let mut f = textured_mosaic(i);
+            f.put(5, 6, 3000.0);
+            f
+        })
+        .collect();
+    let refs: Vec<&ImageBuffer> = frames.iter().collect();
+    let map = hotpixel::temporal_map_mosaic(&refs, 3, 5.0).unwrap();
+    assert_eq!(map.get(5, 6).unwrap(), HOT_PIXEL_MAP_VALUE);
+    assert_eq!(hotpixel::count_hot_pixels(&map), 1);
+
+    let corrected = hotpixel::replace_with_map_mosaic(&frames[0], &map, 3);
+    assert!(corrected.get(5, 6).unwrap() < 120.0);
+}
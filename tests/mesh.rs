@@ -0,0 +1,109 @@
+use mars_raw_utils::{
+    enums::Instrument,
+    frames::Frame,
+    image::MarsImage,
+    mesh::{ExportFrame, GridMesh},
+    stereo::{RangeData, StereoProducts},
+};
+use sciimg::{prelude::*, vector::Vector};
+use std::str::FromStr;
+
+const WIDTH: usize = 4;
+const HEIGHT: usize = 3;
+
+/// Range data of a wall five meters ahead, except for one pixel without a point and one much
+/// further away
+fn products() -> StereoProducts {
+    let new = || ImageBuffer::new_with_mask_as(WIDTH, HEIGHT, false).unwrap();
+    let mut range = RangeData {
+        range: new(),
+        x: new(),
+        y: new(),
+        z: new(),
+    };
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            if (x, y) == (3, 2) {
+                continue;
+            }
+            let scale = if (x, y) == (0, 0) { 1.6 } else { 1.0 };
+            let p = Vector::new(5.0, (x as f64 - 1.5) * 0.05, (y as f64 - 1.0) * 0.05).scale(scale);
+            for (buffer, v) in [
+                (&mut range.range, p.len()),
+                (&mut range.x, p.x),
+                (&mut range.y, p.y),
+                (&mut range.z, p.z),
+            ] {
+                buffer.put_mask(x, y, true);
+                buffer.put(x, y, v as f32);
+            }
+        }
+    }
+
+    let a = Vector::new(1.0, 0.0, 0.0);
+    StereoProducts {
+        range,
+        rectified: MarsImage::new(WIDTH, HEIGHT, Instrument::None),
+        model: Cahv {
+            c: Vector::new(0.0, 0.0, 0.0),
+            a,
+            h: a.scale(1.5).add(&Vector::new(0.0, 100.0, 0.0)),
+            v: a.scale(1.0).add(&Vector::new(0.0, 0.0, 100.0)),
+        },
+    }
+}
+
+#[test]
+fn test_frames() {
+    assert_eq!(
+        ExportFrame::from_str("Camera").unwrap(),
+        ExportFrame::Camera
+    );
+    assert_eq!(
+        ExportFrame::from_str("site").unwrap(),
+        ExportFrame::Rover(Frame::Site)
+    );
+    assert!(ExportFrame::from_str("sky").is_err());
+
+    let products = products();
+    let camera = GridMesh::from_stereo(&products, ExportFrame::Camera);
+    let rover = GridMesh::from_stereo(&products, ExportFrame::Rover(Frame::RoverNav));
+    assert_eq!(camera.num_points(), WIDTH * HEIGHT - 1);
+
+    // Right of center and above it in the image
+    let p = camera.points[2].unwrap();
+    assert!(p.subtract(&Vector::new(0.025, -0.05, 5.0)).len() < 1e-6);
+    let p = rover.points[2].unwrap();
+    assert!(p.subtract(&Vector::new(5.0, 0.025, -0.05)).len() < 1e-6);
+}
+
+#[test]
+fn test_save() {
+    let mesh = GridMesh::from_stereo(&products(), ExportFrame::Camera);
+
+    // Triangles touching the missing pixel or spanning the jump in range are left out
+    let triangles = mesh.triangles();
+    assert_eq!(triangles.len(), 10);
+    assert!(triangles
+        .iter()
+        .all(|t| !t.contains(&0) && !t.contains(&11)));
+
+    let dir = std::env::temp_dir().join("mru_test_mesh");
+    std::fs::create_dir_all(&dir).unwrap();
+    let ply = dir.join("points.ply");
+    mesh.save_ply(ply.to_str().unwrap()).unwrap();
+    let data = std::fs::read(&ply).unwrap();
+    let header = String::from_utf8_lossy(&data[..data.len() - 11 * 15]);
+    assert!(header.contains("element vertex 11\n"));
+    assert!(header.ends_with("end_header\n"));
+
+    let obj = dir.join("mesh.obj");
+    mesh.save_obj(obj.to_str().unwrap(), "/elsewhere/texture.png")
+        .unwrap();
+    let text = std::fs::read_to_string(&obj).unwrap();
+    assert!(text.contains("mtllib mesh.mtl"));
+    assert_eq!(text.lines().filter(|l| l.starts_with("v ")).count(), 10);
+    assert_eq!(text.lines().filter(|l| l.starts_with("f ")).count(), 10);
+    let mtl = std::fs::read_to_string(dir.join("mesh.mtl")).unwrap();
+    assert!(mtl.contains("map_Kd texture.png"));
+}
@@ -0,0 +1,42 @@
+use mars_raw_utils::{drawable::Drawable, pngstream::PngStreamWriter};
+use sciimg::prelude::*;
+
+fn strip(width: usize, height: usize, start_y: usize) -> RgbImage {
+    let mut rows = RgbImage::create_masked(width, height, false);
+    for y in 0..height {
+        for x in 0..width {
+            if x != 0 {
+                rows.put(x, y, (start_y + y) as f32 * 10.0, 0);
+                rows.put(x, y, x as f32 * 10.0, 1);
+                rows.put(x, y, 255.0, 2);
+                rows.put_alpha(x, y, true);
+            }
+        }
+    }
+    rows
+}
+
+#[test]
+fn test_stream_rows() {
+    let dir = std::env::temp_dir().join("mru_test_pngstream");
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("stream.png");
+    let out = out.to_str().unwrap();
+
+    let mut writer = PngStreamWriter::create(out, 6, 5, 255.0).unwrap();
+    writer.write_rows(&strip(6, 3, 0)).unwrap();
+    assert!(writer.finish().is_err());
+    assert!(writer.write_rows(&strip(5, 2, 3)).is_err());
+    writer.write_rows(&strip(6, 2, 3)).unwrap();
+    assert!(writer.write_rows(&strip(6, 1, 5)).is_err());
+    writer.finish().unwrap();
+
+    let img = image::open(out).unwrap().to_rgba16();
+    assert_eq!(img.dimensions(), (6, 5));
+    let p = img.get_pixel(2, 4);
+    assert_eq!(p[0], (40.0_f32 * 65535.0 / 255.0).round() as u16);
+    assert_eq!(p[1], (20.0_f32 * 65535.0 / 255.0).round() as u16);
+    assert_eq!(p[2], 65535);
+    assert_eq!(p[3], 65535);
+    assert_eq!(img.get_pixel(0, 1)[3], 0);
+}
@@ -0,0 +1,89 @@
+use mars_raw_utils::{
+    enums::{Eye, Instrument, Mission},
+    productid,
+};
+
+#[test]
+fn test_m20_product_id() {
+    let pid = productid::parse(
+        "tests/testdata/ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png",
+    )
+    .unwrap();
+    assert_eq!(pid.mission, Mission::MARS2020);
+    assert_eq!(pid.camera, "ZL");
+    assert_eq!(pid.instrument, Instrument::M20MastcamZLeft);
+    assert_eq!(pid.eye, Eye::Left);
+    assert_eq!(pid.sol, Some(53));
+    assert_eq!(pid.sclk, Some(671642352.402));
+    assert_eq!(pid.product_type, "ECM");
+    assert_eq!(pid.sequence, Some(String::from("0032046ZCAM05025")));
+    assert_eq!(pid.filter, Some(String::from("0")));
+    assert_eq!(pid.focal_length, Some(110.0));
+    assert!(!pid.thumbnail);
+    assert_eq!(pid.version, Some(String::from("01")));
+
+    // No version, with a calibration suffix
+    let pid =
+        productid::parse("ZR0_0038_0670307360_057ECM_N0031392ZCAM08007_0340LUJ-rjcal.png").unwrap();
+    assert_eq!(pid.instrument, Instrument::M20MastcamZRight);
+    assert_eq!(pid.focal_length, Some(34.0));
+    assert_eq!(pid.version, None);
+
+    let pid =
+        productid::parse("NLF_0001_0667022389_000ECM_T0010052AUT_04096_00_0LLJ01.png").unwrap();
+    assert_eq!(pid.instrument, Instrument::M20NavcamLeft);
+    assert!(pid.thumbnail);
+    assert_eq!(pid.filter, None);
+    assert_eq!(pid.focal_length, None);
+}
+
+#[test]
+fn test_msl_product_id() {
+    let pid = productid::parse("tests/testdata/NRB_670586006EDR_S0871444NCAM00545M_.jpg").unwrap();
+    assert_eq!(pid.mission, Mission::MSL);
+    assert_eq!(pid.instrument, Instrument::MslNavCamRight);
+    assert_eq!(pid.eye, Eye::Right);
+    assert_eq!(pid.sclk, Some(670586006.0));
+    assert_eq!(pid.product_type, "EDR");
+    assert_eq!(pid.sequence, Some(String::from("S0871444NCAM00545")));
+    assert_eq!(pid.version, None);
+
+    let pid = productid::parse("CR0_397506222PRC_F0052840CCAM01000M1.JPG").unwrap();
+    assert_eq!(pid.instrument, Instrument::MslChemCam);
+    assert_eq!(pid.eye, Eye::DontCare);
+    assert_eq!(pid.version, Some(String::from("1")));
+
+    let pid = productid::parse("0450MR0018470000301669E01_DXXX.jpg").unwrap();
+    assert_eq!(pid.instrument, Instrument::MslMastcamRight);
+    assert_eq!(pid.eye, Eye::Right);
+    assert_eq!(pid.sol, Some(450));
+    assert_eq!(pid.sequence, Some(String::from("001847")));
+    assert_eq!(pid.version, Some(String::from("01")));
+    assert!(!pid.thumbnail);
+
+    let pid = productid::parse("1234MH0005670010502317I01_DXXX.jpg").unwrap();
+    assert_eq!(pid.instrument, Instrument::MslMAHLI);
+    assert!(pid.thumbnail);
+}
+
+#[test]
+fn test_insight_product_id() {
+    let pid = productid::parse("D001R0007_598999612EDR_F0000_0900M_.PNG").unwrap();
+    assert_eq!(pid.mission, Mission::INSIGHT);
+    assert_eq!(pid.instrument, Instrument::NsytIDC);
+    assert_eq!(pid.sol, Some(7));
+    assert_eq!(pid.sclk, Some(598999612.0));
+    assert_eq!(pid.filter, Some(String::from("F0000")));
+
+    assert_eq!(
+        productid::instrument_for_file("C000M0100_607788939EDR_F0000_0461M_.PNG"),
+        Some(Instrument::NsytICC)
+    );
+}
+
+#[test]
+fn test_unrecognized_product_id() {
+    assert!(productid::parse("my_picture.png").is_err());
+    assert_eq!(productid::instrument_for_file("my_picture.png"), None);
+    assert_eq!(productid::eye_for_file("NL_picture.png"), Eye::DontCare);
+}
@@ -0,0 +1,136 @@
+use mars_raw_utils::projection::*;
+use sciimg::{prelude::*, vector::Vector};
+use std::str::FromStr;
+
+fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+    (a.0 - b.0).abs() < 1e-6 && (a.1 - b.1).abs() < 1e-6
+}
+
+/// A point at the given azimuth (clockwise from forward) and elevation, in degrees
+fn direction(azimuth: f64, elevation: f64) -> Vector {
+    let (az, el) = (azimuth.to_radians(), elevation.to_radians());
+    Vector::new(el.cos() * az.cos(), el.cos() * az.sin(), -el.sin()).scale(100.0)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        ProjectionType::from_str("Stereographic").unwrap(),
+        ProjectionType::Stereographic
+    );
+    assert_eq!(
+        ProjectionType::from_str("cahv").unwrap(),
+        ProjectionType::Camera
+    );
+    assert!(ProjectionType::from_str("mercator").is_err());
+
+    let fov = FieldOfView::from_str("-90,90,-30,20").unwrap();
+    assert_eq!(fov.azimuth_min, -90.0);
+    assert_eq!(fov.elevation_max, 20.0);
+    assert!(FieldOfView::from_str("90,-90,-30,20").is_err());
+    assert!(FieldOfView::from_str("0,90").is_err());
+}
+
+#[test]
+fn test_equirectangular() {
+    let p = Equirectangular::default();
+    let forward = p.project(&direction(0.0, 0.0)).unwrap();
+    assert!(close(forward, (std::f64::consts::PI, 0.0)));
+
+    // Right of forward is further along the map, above the horizon is further up it
+    let (x, y) = p.project(&direction(30.0, 10.0)).unwrap();
+    assert!((x - forward.0 - 30f64.to_radians()).abs() < 1e-9);
+    assert!((y + 10f64.to_radians()).abs() < 1e-9);
+
+    let fixed = Equirectangular {
+        fov: Some(FieldOfView::from_str("-45,45,-10,20").unwrap()),
+    }
+    .fixed_bounds()
+    .unwrap();
+    assert!((fixed.width() - 90f64.to_radians()).abs() < 1e-9);
+    assert!((fixed.min_y + 20f64.to_radians()).abs() < 1e-9);
+}
+
+#[test]
+fn test_cylindrical_perspective() {
+    let p = CylindricalPerspective::default();
+    let (_, y) = p.project(&direction(0.0, -45.0)).unwrap();
+    assert!((y - 1.0).abs() < 1e-9);
+    assert!(p.project(&direction(0.0, 85.0)).is_none());
+}
+
+#[test]
+fn test_azimuthal() {
+    let polar = Polar::default();
+    let stereo = Stereographic::default();
+    let gnomonic = VerticalPerspective::default();
+
+    // The nadir is at the center of each
+    for p in [
+        &polar as &dyn Projection,
+        &stereo as &dyn Projection,
+        &gnomonic as &dyn Projection,
+    ] {
+        assert!(close(
+            p.project(&direction(0.0, -90.0)).unwrap(),
+            (0.0, 0.0)
+        ));
+    }
+
+    // Looking down, forward is up the map and right is to the right
+    let (x, y) = polar.project(&direction(0.0, -45.0)).unwrap();
+    assert!(x.abs() < 1e-9 && (y + std::f64::consts::FRAC_PI_4).abs() < 1e-9);
+    let (x, y) = polar.project(&direction(90.0, -45.0)).unwrap();
+    assert!((x - std::f64::consts::FRAC_PI_4).abs() < 1e-9 && y.abs() < 1e-9);
+
+    // The horizon is at 2 on the stereographic plane and out of reach of the gnomonic one
+    let (_, y) = stereo.project(&direction(0.0, 0.0)).unwrap();
+    assert!((y + 2.0).abs() < 1e-9);
+    assert!(gnomonic.project(&direction(0.0, 0.0)).is_none());
+    let (_, y) = gnomonic.project(&direction(0.0, -45.0)).unwrap();
+    assert!((y + 1.0).abs() < 1e-9);
+
+    // A vertical perspective from one radius behind the center matches stereographic
+    let vp = VerticalPerspective {
+        center: AzimuthalCenter::nadir(),
+        distance: 1.0,
+    };
+    let d = direction(40.0, -20.0);
+    assert!(close(vp.project(&d).unwrap(), stereo.project(&d).unwrap()));
+
+    // Centered on the forward horizon, up is up
+    let forward = VerticalPerspective {
+        center: AzimuthalCenter::new(0.0, 0.0),
+        distance: 0.0,
+    };
+    let (x, y) = forward.project(&direction(0.0, 10.0)).unwrap();
+    assert!(x.abs() < 1e-9 && y < 0.0);
+}
+
+#[test]
+fn test_camera_projection() {
+    // Looking forward, 100 pixel focal length, center at 50, 50
+    let model = CameraModel::new(Box::new(Cahv {
+        c: Vector::new(0.0, 0.0, 0.0),
+        a: Vector::new(1.0, 0.0, 0.0),
+        h: Vector::new(50.0, 100.0, 0.0),
+        v: Vector::new(50.0, 0.0, 100.0),
+    }));
+    let p = CameraProjection {
+        model,
+        width: 100,
+        height: 100,
+    };
+
+    assert!(close(
+        p.project(&Vector::new(10.0, 0.0, 0.0)).unwrap(),
+        (50.0, 50.0)
+    ));
+    assert!(close(
+        p.project(&Vector::new(10.0, 1.0, -2.0)).unwrap(),
+        (60.0, 30.0)
+    ));
+    assert!(p.project(&Vector::new(-10.0, 0.0, 0.0)).is_none());
+    assert_eq!(p.units_per_pixel(0.001), 1.0);
+    assert_eq!(p.fixed_bounds().unwrap().width(), 100.0);
+}
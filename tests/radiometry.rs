@@ -0,0 +1,42 @@
+use mars_raw_utils::{enums::Instrument, image::MarsImage, radiometry};
+
+fn patch(name: &str, x: usize, reflectance: f32) -> radiometry::CalTargetPatch {
+    radiometry::CalTargetPatch {
+        name: name.to_string(),
+        x,
+        y: 0,
+        width: 4,
+        height: 4,
+        reflectance: vec![reflectance],
+    }
+}
+
+#[test]
+fn test_derive_coefficients() {
+    let mut img = MarsImage::new(8, 4, Instrument::None);
+    for y in 0..4 {
+        for x in 0..8 {
+            let v = if x < 4 { 200.0 } else { 50.0 };
+            img.image.put(x, y, v, 0);
+            img.image.put(x, y, v * 2.0, 1);
+            img.image.put(x, y, v * 4.0, 2);
+        }
+    }
+
+    let target = radiometry::CalTarget {
+        incidence_angle: 0.0,
+        patch: vec![patch("white", 0, 0.8), patch("gray", 4, 0.2)],
+    };
+
+    let coefficients = radiometry::derive_coefficients(&img, "ref.png", &target).unwrap();
+    assert_eq!(coefficients.gains.len(), 3);
+    assert!((coefficients.gains[0] - 0.004).abs() < 1e-6);
+    assert!((coefficients.gains[1] - 0.002).abs() < 1e-6);
+    assert!((coefficients.gains[2] - 0.001).abs() < 1e-6);
+
+    radiometry::apply_coefficients(&mut img, &coefficients).unwrap();
+    let expected = 0.8 * radiometry::IOF_SCALE;
+    for b in 0..3 {
+        assert!((img.image.get_band(b).get(0, 0).unwrap() - expected).abs() < 0.5);
+    }
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use mars_raw_utils::{
+    enums::Instrument,
+    filters::FilterPosition,
+    image::MarsImage,
+    metadata::RadiometricCoefficients,
+    spectral::{Roi, SpectralCube},
+};
+
+fn frame(value: f32, coefficients: Option<RadiometricCoefficients>) -> MarsImage {
+    let mut img = MarsImage::new(4, 4, Instrument::M20MastcamZLeft);
+    for y in 0..4 {
+        for x in 0..4 {
+            for b in 0..3 {
+                img.image.put(x, y, value, b);
+            }
+        }
+    }
+    let mut md = common::metadata("null", 1);
+    md.radiometric_coefficients = coefficients;
+    img.metadata = Some(md);
+    img
+}
+
+#[test]
+fn test_cube_units() {
+    let roi = Roi {
+        name: String::from("all"),
+        x: 0,
+        y: 0,
+        width: 4,
+        height: 4,
+    };
+    let filter = FilterPosition::from_name("L1").unwrap();
+
+    let mut cube = SpectralCube::new();
+    cube.add_frame(&frame(1000.0, None), &filter, &[800.0])
+        .unwrap();
+    assert_eq!(cube.value_units(), "dn");
+    assert_eq!(cube.roi_spectrum(&roi).unwrap()[0].mean, 1000.0);
+
+    // Frames converted to I/F are stored as I/F
+    let coefficients = RadiometricCoefficients {
+        reference_image: String::from("caltarget.png"),
+        reference_sclk: None,
+        gains: vec![0.001; 3],
+        iof_scale: 32768.0,
+    };
+    let mut cube = SpectralCube::new();
+    cube.add_frame(&frame(16384.0, Some(coefficients)), &filter, &[800.0])
+        .unwrap();
+    assert_eq!(cube.value_units(), "iof");
+    assert_eq!(cube.roi_spectrum(&roi).unwrap()[0].mean, 0.5);
+
+    assert!(cube
+        .add_frame(&frame(1000.0, None), &filter, &[900.0])
+        .is_err());
+}
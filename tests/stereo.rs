@@ -0,0 +1,160 @@
+mod common;
+
+use mars_raw_utils::stereo::{self, StereoOptions};
+use sciimg::{prelude::*, vector::Vector};
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 120;
+const FOCAL: f64 = 200.0;
+const BASELINE: f64 = 0.4;
+const WALL: f64 = 5.0;
+
+/// A camera looking level at the given azimuth from a point on the rover's y axis
+fn camera(y: f64, azimuth: f64) -> Cahv {
+    let az = azimuth.to_radians();
+    let a = Vector::new(az.cos(), az.sin(), 0.0);
+    let right = Vector::new(-az.sin(), az.cos(), 0.0);
+    let down = Vector::new(0.0, 0.0, 1.0);
+    Cahv {
+        c: Vector::new(0.0, y, 0.0),
+        a,
+        h: a.scale(WIDTH as f64 / 2.0).add(&right.scale(FOCAL)),
+        v: a.scale(HEIGHT as f64 / 2.0).add(&down.scale(FOCAL)),
+    }
+}
+
+/// Gray level of a wall facing the cameras: blocks of random brightness a few pixels across
+fn wall(y: f64, z: f64) -> f32 {
+    common::block_level((y / 0.03).floor() as i64, (z / 0.03).floor() as i64)
+}
+
+fn render(model: &Cahv) -> RgbImage {
+    let mut img = RgbImage::new_with_bands(WIDTH, HEIGHT, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let lv = model
+                .ls_to_look_vector(&ImageCoordinate {
+                    line: y as f64,
+                    sample: x as f64,
+                })
+                .unwrap();
+            let t = (WALL - lv.origin.x) / lv.look_direction.x;
+            let p = lv.origin.add(&lv.look_direction.scale(t));
+            img.put(x, y, wall(p.y, p.z) * 1000.0, 0);
+        }
+    }
+    img
+}
+
+#[test]
+fn test_rectify_models() {
+    let left = CameraModel::new(Box::new(camera(-BASELINE / 2.0, 3.0)));
+    let right = CameraModel::new(Box::new(camera(BASELINE / 2.0, -2.0)));
+    let (left_rect, right_rect) = stereo::rectify_models(&left, &right, WIDTH, HEIGHT).unwrap();
+
+    // Any point lands on the same line in both rectified images
+    for p in [
+        Vector::new(5.0, 0.3, -0.2),
+        Vector::new(3.0, -0.5, 0.4),
+        Vector::new(20.0, 2.0, 1.0),
+    ] {
+        let a = left_rect.xyz_to_ls(&p, false);
+        let b = right_rect.xyz_to_ls(&p, false);
+        assert!((a.line - b.line).abs() < 1e-6);
+        assert!(a.sample > b.sample);
+    }
+
+    // Swapped cameras are rejected
+    assert!(stereo::rectify_models(&right, &left, WIDTH, HEIGHT).is_err());
+
+    let geometry = stereo::StereoGeometry::from_models(&left, &right);
+    assert!((geometry.baseline.unwrap() - BASELINE).abs() < 1e-9);
+    assert!((geometry.convergence.unwrap() - 5.0).abs() < 1e-9);
+    let rectified = stereo::StereoGeometry::from_models(
+        &CameraModel::new(Box::new(left_rect)),
+        &CameraModel::new(Box::new(right_rect)),
+    );
+    assert!(rectified.convergence.unwrap().abs() < 1e-9);
+}
+
+#[test]
+fn test_triangulate() {
+    let left = LookVector {
+        origin: Vector::new(0.0, 0.0, 0.0),
+        look_direction: Vector::new(1.0, 0.1, 0.0).normalized(),
+    };
+    let right = LookVector {
+        origin: Vector::new(0.0, 1.0, 0.0),
+        look_direction: Vector::new(1.0, -0.1, 0.0).normalized(),
+    };
+    let p = stereo::triangulate(&left, &right).unwrap();
+    assert!(p.subtract(&Vector::new(5.0, 0.5, 0.0)).len() < 1e-9);
+
+    // Diverging rays meet behind the cameras
+    let diverging = LookVector {
+        origin: Vector::new(0.0, 1.0, 0.0),
+        look_direction: Vector::new(1.0, 0.2, 0.0).normalized(),
+    };
+    assert!(stereo::triangulate(&left, &diverging).is_none());
+}
+
+#[test]
+fn test_range_to_wall() {
+    let left_cahv = camera(-BASELINE / 2.0, 2.0);
+    let right_cahv = camera(BASELINE / 2.0, -1.0);
+    let left = CameraModel::new(Box::new(left_cahv.clone()));
+    let right = CameraModel::new(Box::new(right_cahv.clone()));
+
+    let (left_rect, right_rect) = stereo::rectify_models(&left, &right, WIDTH, HEIGHT).unwrap();
+    let left_img = stereo::rectify_image(&render(&left_cahv), &left, &left_rect);
+    let right_img = stereo::rectify_image(&render(&right_cahv), &right, &right_rect);
+
+    let options = StereoOptions {
+        num_disparities: 32,
+        ..Default::default()
+    };
+    let disparity = stereo::disparity(left_img.get_band(0), right_img.get_band(0), &options);
+    let range = stereo::range_from_disparity(&disparity, &left_rect, &right_rect);
+
+    let mut total = 0;
+    let mut good = 0;
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            if range.x.get_mask_at_point(x, y) {
+                total += 1;
+                // About a pixel of disparity
+                if (range.x.get(x, y).unwrap() as f64 - WALL).abs() < 0.3 {
+                    good += 1;
+                }
+            }
+        }
+    }
+    assert!(total > WIDTH * HEIGHT / 2, "{} pixels with a range", total);
+    assert!(
+        good as f32 > total as f32 * 0.95,
+        "{} of {} on the wall",
+        good,
+        total
+    );
+}
+
+#[test]
+fn test_disparity_large_penalty() {
+    // Eight paths of costs with a penalty near the largest allowed would overflow 16 bit sums
+    let mut left = ImageBuffer::new(48, 32).unwrap();
+    let mut right = ImageBuffer::new(48, 32).unwrap();
+    for y in 0..32 {
+        for x in 0..48 {
+            left.put(x, y, common::block_level(x as i64, y as i64) * 1000.0);
+            right.put(x, y, common::block_level(x as i64 + 4, y as i64) * 1000.0);
+        }
+    }
+    let options = StereoOptions {
+        num_disparities: 8,
+        p2: u16::MAX,
+        ..Default::default()
+    };
+    let disparity = stereo::disparity(&left, &right, &options);
+    assert!(disparity.get_mask_at_point(24, 16));
+    assert!((disparity.get(24, 16).unwrap() - 4.0).abs() < 0.5);
+}
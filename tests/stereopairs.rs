@@ -0,0 +1,76 @@
+use mars_raw_utils::stereopairs::{self, StereoPair};
+
+fn strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| String::from(*n)).collect()
+}
+
+#[test]
+fn test_find_pairs() {
+    let files = strings(&[
+        // Navcam pair, one calibrated with a different version
+        "NLF_0001_0667022389_000ECM_N0010052AUT_04096_00_0LLJ01-rjcal.png",
+        "NRF_0001_0667022389_000ECM_N0010052AUT_04096_00_0LLJ02-rjcal.png",
+        // Mastcam-Z at different focal lengths
+        "ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png",
+        "ZR0_0053_0671642352_402ECM_N0032046ZCAM05025_034085J01.png",
+        // Mastcam-Z pair, with millisecond clocks differing
+        "ZL0_0038_0670307360_057ECM_N0031392ZCAM08007_0340LUJ01.png",
+        "ZR0_0038_0670307360_061ECM_N0031392ZCAM08007_0340LUJ01.png",
+        // MSL Navcam pair and a thumbnail of the left eye
+        "NLB_670586006EDR_S0871444NCAM00545M_.jpg",
+        "NRB_670586006EDR_S0871444NCAM00545M_.jpg",
+        "NLB_670586006ILT_S0871444NCAM00545M_.jpg",
+        // MSL Mastcam ids have no spacecraft clock to pair by
+        "3372ML0176490011203399C00_DXXX.jpg",
+        "3372MR0176490011203400C00_DXXX.jpg",
+        // Not a stereo camera
+        "1234MH0005670010502317C00_DXXX.jpg",
+        "not_a_product.png",
+    ]);
+
+    let matches = stereopairs::find_pairs(&files);
+    assert_eq!(
+        matches.pairs,
+        vec![
+            StereoPair {
+                left: files[6].clone(),
+                right: files[7].clone()
+            },
+            StereoPair {
+                left: files[0].clone(),
+                right: files[1].clone()
+            },
+            StereoPair {
+                left: files[4].clone(),
+                right: files[5].clone()
+            },
+        ]
+    );
+    assert_eq!(
+        matches.unpaired,
+        vec![
+            files[9].clone(),
+            files[10].clone(),
+            files[8].clone(),
+            files[2].clone(),
+            files[3].clone()
+        ]
+    );
+}
+
+#[test]
+fn test_image_files_in() {
+    let dir = std::env::temp_dir().join("mru_test_stereopairs");
+    std::fs::create_dir_all(&dir).unwrap();
+    for f in ["b.PNG", "a.jpg", "a.json", "notes.txt"] {
+        std::fs::write(dir.join(f), "").unwrap();
+    }
+    let files = stereopairs::image_files_in(dir.to_str().unwrap()).unwrap();
+    let names: Vec<String> = files
+        .iter()
+        .map(|f| mars_raw_utils::path::basename(f))
+        .collect();
+    assert_eq!(names, vec!["a.jpg", "b.PNG"]);
+
+    assert!(stereopairs::image_files_in("/nonexistent/directory").is_err());
+}
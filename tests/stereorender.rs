@@ -0,0 +1,200 @@
+mod common;
+
+use mars_raw_utils::{
+    drawable::Drawable,
+    stereo::StereoGeometry,
+    stereorender::{self, StereoRenderMode},
+};
+use sciimg::prelude::*;
+use std::str::FromStr;
+
+fn value(img: &RgbImage, x: usize, y: usize) -> f32 {
+    img.get_band(0).get(x, y).unwrap()
+}
+
+#[test]
+fn test_layouts() {
+    assert_eq!(
+        StereoRenderMode::from_str("Side-By-Side").unwrap(),
+        StereoRenderMode::SideBySide
+    );
+    assert_eq!(
+        StereoRenderMode::from_str("apng").unwrap(),
+        StereoRenderMode::WiggleApng
+    );
+    assert!(StereoRenderMode::from_str("hologram").is_err());
+
+    let left = common::solid(8, 6, [50.0; 3]);
+    let right = common::solid(8, 6, [200.0; 3]);
+
+    let sbs = stereorender::side_by_side(&left, &right);
+    assert_eq!((sbs.width, sbs.height), (16, 6));
+    assert_eq!(value(&sbs, 7, 3), 50.0);
+    assert_eq!(value(&sbs, 8, 3), 200.0);
+
+    let ou = stereorender::over_under(&left, &right);
+    assert_eq!((ou.width, ou.height), (8, 12));
+    assert_eq!(value(&ou, 4, 5), 50.0);
+    assert_eq!(value(&ou, 4, 6), 200.0);
+
+    let mut ramp = RgbImage::create(8, 1);
+    for x in 0..8 {
+        ramp.put(x, 0, x as f32, 0);
+    }
+    let shifted = stereorender::shift_horizontal(&ramp, 2);
+    assert_eq!(value(&shifted, 0, 0), 0.0);
+    assert_eq!(value(&shifted, 5, 0), 3.0);
+    let shifted = stereorender::shift_horizontal(&ramp, -2);
+    assert_eq!(value(&shifted, 0, 0), 2.0);
+    assert_eq!(value(&shifted, 7, 0), 0.0);
+}
+
+#[test]
+fn test_wiggle() {
+    let dir = std::env::temp_dir().join("mru_test_stereorender");
+    std::fs::create_dir_all(&dir).unwrap();
+    let left = common::solid(8, 6, [50.0; 3]);
+    let right = common::solid(8, 6, [200.0; 3]);
+
+    let gif_file = dir.join("wiggle.gif");
+    stereorender::save(
+        &left,
+        &right,
+        StereoRenderMode::WiggleGif,
+        200,
+        &StereoGeometry::default(),
+        gif_file.to_str().unwrap(),
+    )
+    .unwrap();
+    let mut decoder = gif::DecodeOptions::new()
+        .read_info(std::fs::File::open(&gif_file).unwrap())
+        .unwrap();
+    let mut delays = vec![];
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    assert_eq!(delays, vec![20, 20]);
+
+    let apng_file = dir.join("wiggle.png");
+    stereorender::save(
+        &left,
+        &right,
+        StereoRenderMode::WiggleApng,
+        150,
+        &StereoGeometry::default(),
+        apng_file.to_str().unwrap(),
+    )
+    .unwrap();
+    let decoder = png::Decoder::new(std::fs::File::open(&apng_file).unwrap());
+    let mut reader = decoder.read_info().unwrap();
+    let control = reader.info().animation_control.unwrap();
+    assert_eq!(control.num_frames, 2);
+    assert_eq!(control.num_plays, 0);
+    let mut buf = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buf).unwrap();
+    assert_eq!(buf[0], 50);
+    reader.next_frame(&mut buf).unwrap();
+    assert_eq!(buf[0], 200);
+
+    assert!(stereorender::save(
+        &left,
+        &common::solid(4, 6, [0.0; 3]),
+        StereoRenderMode::WiggleGif,
+        200,
+        &StereoGeometry::default(),
+        gif_file.to_str().unwrap()
+    )
+    .is_err());
+}
+
+fn u32_le(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[test]
+fn test_mpo() {
+    let dir = std::env::temp_dir().join("mru_test_stereorender");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mpo_file = dir.join("pair.mpo");
+    stereorender::save(
+        &common::solid(16, 16, [50.0; 3]),
+        &common::solid(16, 16, [200.0; 3]),
+        StereoRenderMode::Mpo,
+        0,
+        &StereoGeometry {
+            baseline: Some(0.42),
+            convergence: Some(-1.5),
+        },
+        mpo_file.to_str().unwrap(),
+    )
+    .unwrap();
+    let data = std::fs::read(&mpo_file).unwrap();
+
+    let mpf = data
+        .windows(4)
+        .position(|w| w == b"MPF\0")
+        .expect("No MPF segment");
+    let tiff = mpf + 4;
+    assert_eq!(&data[tiff..tiff + 4], &[0x49, 0x49, 0x2a, 0x00]);
+    let ifd = tiff + u32_le(&data, tiff + 4) as usize;
+    assert_eq!(u16::from_le_bytes([data[ifd], data[ifd + 1]]), 3);
+    // Number of images, then the MP entries
+    assert_eq!(u32_le(&data, ifd + 2 + 12 + 8), 2);
+    let entries = tiff + u32_le(&data, ifd + 2 + 24 + 8) as usize;
+    let first_size = u32_le(&data, entries + 4) as usize;
+    assert_eq!(u32_le(&data, entries + 8), 0);
+    let second_size = u32_le(&data, entries + 16 + 4) as usize;
+    let second_offset = tiff + u32_le(&data, entries + 16 + 8) as usize;
+    assert_eq!(first_size, second_offset);
+    assert_eq!(first_size + second_size, data.len());
+
+    let left = image::load_from_memory(&data[..first_size])
+        .unwrap()
+        .into_rgb8();
+    let right = image::load_from_memory(&data[second_offset..])
+        .unwrap()
+        .into_rgb8();
+    assert!((left.get_pixel(8, 8)[0] as i32 - 50).abs() <= 2);
+    assert!((right.get_pixel(8, 8)[0] as i32 - 200).abs() <= 2);
+
+    // Both images are disparity images with the base viewpoint, convergence and baseline
+    let attributes = |tiff: usize, attribute_ifd: usize| {
+        assert_eq!(
+            u16::from_le_bytes([data[attribute_ifd], data[attribute_ifd + 1]]),
+            5
+        );
+        let entry = |k: usize| attribute_ifd + 2 + 12 * k;
+        let tags: Vec<u16> = (0..5)
+            .map(|k| u16::from_le_bytes([data[entry(k)], data[entry(k) + 1]]))
+            .collect();
+        assert_eq!(tags, vec![0xb000, 0xb101, 0xb204, 0xb205, 0xb206]);
+        let rational = |k: usize| {
+            let at = tiff + u32_le(&data, entry(k) + 8) as usize;
+            (u32_le(&data, at), u32_le(&data, at + 4))
+        };
+        (
+            u32_le(&data, entry(1) + 8),
+            u32_le(&data, entry(2) + 8),
+            rational(3),
+            rational(4),
+        )
+    };
+    let (number, base, convergence, baseline) =
+        attributes(tiff, tiff + u32_le(&data, ifd + 2 + 36) as usize);
+    assert_eq!((number, base), (1, 1));
+    assert_eq!(convergence.0 as i32 as f64 / convergence.1 as f64, -1.5);
+    assert_eq!(baseline.0 as f64 / baseline.1 as f64, 0.42);
+
+    let right_mpf = second_offset
+        + data[second_offset..]
+            .windows(4)
+            .position(|w| w == b"MPF\0")
+            .expect("No MPF segment in the right image");
+    let right_tiff = right_mpf + 4;
+    let (number, base, _, right_baseline) = attributes(
+        right_tiff,
+        right_tiff + u32_le(&data, right_tiff + 4) as usize,
+    );
+    assert_eq!((number, base), (2, 1));
+    assert_eq!(right_baseline, baseline);
+}
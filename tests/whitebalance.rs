@@ -0,0 +1,68 @@
+use mars_raw_utils::{
+    calprofile::CalProfile, enums::Instrument, image::MarsImage, whitebalance,
+    whitebalance::WhiteBalanceMethod,
+};
+use std::str::FromStr;
+
+fn tinted_image() -> MarsImage {
+    let mut img = MarsImage::new(10, 10, Instrument::None);
+    for y in 0..10 {
+        for x in 0..10 {
+            let v = if y < 2 { 200.0 } else { 20.0 + x as f32 };
+            img.image.put(x, y, v, 0);
+            img.image.put(x, y, v * 0.5, 1);
+            img.image.put(x, y, v * 0.25, 2);
+        }
+    }
+    img
+}
+
+#[test]
+fn test_method_from_str() {
+    assert_eq!(
+        WhiteBalanceMethod::from_str("gray-world"),
+        Ok(WhiteBalanceMethod::GrayWorld)
+    );
+    assert_eq!(
+        WhiteBalanceMethod::from_str("WhitePatch"),
+        Ok(WhiteBalanceMethod::WhitePatch)
+    );
+    assert!(WhiteBalanceMethod::from_str("tungsten").is_err());
+}
+
+#[test]
+fn test_gains_neutralize_tint() {
+    let img = tinted_image();
+    for gains in [
+        whitebalance::gray_world_gains(&img).unwrap(),
+        whitebalance::white_patch_gains(&img).unwrap(),
+        whitebalance::sky_gains(&img, 0.2, [1.0, 1.0, 1.0]).unwrap(),
+    ] {
+        assert!((gains[0] - 0.5).abs() < 1e-5);
+        assert_eq!(gains[1], 1.0);
+        assert!((gains[2] - 2.0).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_color_matrix_applied_in_calibration() {
+    let dir = std::env::temp_dir().join("mru_test_color_matrix");
+    std::fs::create_dir_all(&dir).unwrap();
+    let matrix_file = dir.join("matrix.toml");
+    let matrix_file = matrix_file.to_str().unwrap();
+
+    whitebalance::ColorMatrix::from_gains([0.5, 1.0, 2.0])
+        .save(matrix_file)
+        .unwrap();
+
+    let cal_context = CalProfile {
+        color_matrix_file: Some(matrix_file.to_string()),
+        ..CalProfile::default()
+    };
+    let mut img = tinted_image();
+    whitebalance::apply_white_balance(&mut img, &cal_context).unwrap();
+    let v: Vec<f32> = (0..3)
+        .map(|b| img.image.get_band(b).get(0, 0).unwrap())
+        .collect();
+    assert_eq!(v, vec![100.0, 100.0, 100.0]);
+}
@@ -44,3 +44,26 @@ fn test_focal_length_from_file_name() {
         Err("Invalid value")
     );
 }
+
+#[test]
+fn test_flat_stops_for_focal_length() {
+    assert_eq!(zcam::flat_stops_for_focal_length(26.0), (0, 0, 0.0));
+    assert_eq!(zcam::flat_stops_for_focal_length(110.0), (6, 6, 0.0));
+    assert_eq!(zcam::flat_stops_for_focal_length(34.0), (1, 1, 0.0));
+    assert_eq!(zcam::flat_stops_for_focal_length(20.0), (0, 0, 0.0));
+
+    let (lo, hi, w) = zcam::flat_stops_for_focal_length(41.0);
+    assert_eq!((lo, hi), (1, 2));
+    assert!((w - 0.5).abs() < 0.0001);
+
+    let (lo, hi, w) = zcam::flat_stops_for_focal_length(105.0);
+    assert_eq!((lo, hi), (5, 6));
+    assert!((w - 0.5).abs() < 0.0001);
+}
+
+#[test]
+fn test_motor_count_from_focal_length() {
+    assert_eq!(zcam::motor_count_from_focal_length(26.0), 0.0);
+    assert_eq!(zcam::motor_count_from_focal_length(63.0), 5196.0);
+    assert_eq!(zcam::motor_count_from_focal_length(30.0), 1224.0);
+}